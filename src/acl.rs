@@ -0,0 +1,168 @@
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, RwLock};
+use std::time::{Duration, Instant, SystemTime};
+
+/* How often the policy file is checked for changes */
+const RELOAD_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Permission {
+    Read,
+    Write,
+    Delete,
+}
+
+/* Grants permissions on the file or directory `prefix` and everything below it, `*` as identity matches every
+ * client. A prefix only matches whole path components, `alice` and `alice/` cover `alice/notes` but not `alice2` */
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Rule {
+    pub identity: String,
+    pub prefix: String,
+    pub permissions: Vec<Permission>,
+}
+
+/**Access policy, anything that isn't granted by a rule is denied */
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct Policy {
+    pub rules: Vec<Rule>,
+}
+
+fn covers(prefix: &str, filename: &str) -> bool {
+    match filename.strip_prefix(prefix) {
+        Some(rest) => prefix.is_empty() || prefix.ends_with('/') || rest.is_empty() || rest.starts_with('/'),
+        None => false,
+    }
+}
+
+impl Policy {
+    /* `identity` is None for clients that didn't authenticate, only `*` rules apply to them */
+    pub fn allows(&self, identity: Option<&str>, permission: Permission, filename: &str) -> bool {
+        self.rules.iter().any(|rule| {
+            (rule.identity == "*" || Some(rule.identity.as_str()) == identity)
+                && covers(&rule.prefix, filename)
+                && rule.permissions.contains(&permission)
+        })
+    }
+}
+
+/**Policy loaded from a JSON file, picked up again whenever the file changes so rules can be
+ * edited without restarting the server. A file that doesn't parse leaves the previous policy in place */
+#[derive(Debug)]
+pub struct Acl {
+    path: PathBuf,
+    policy: RwLock<Policy>,
+    /* When the file was last checked and its modification time at that point */
+    checked: Mutex<(Instant, Option<SystemTime>)>,
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    std::fs::metadata(path).and_then(|metadata| metadata.modified()).ok()
+}
+
+fn read_policy(path: &Path) -> std::io::Result<Policy> {
+    let data = std::fs::read(path)?;
+    Ok(serde_json::from_slice(data.as_slice())?)
+}
+
+impl Acl {
+    pub fn load(path: &Path) -> std::io::Result<Acl> {
+        let modified = modified(path);
+        let policy = read_policy(path)?;
+        Ok(Acl {
+            path: path.to_path_buf(),
+            policy: RwLock::new(policy),
+            checked: Mutex::new((Instant::now(), modified)),
+        })
+    }
+
+    fn maybe_reload(&self) {
+        let mut checked = self.checked.lock().unwrap();
+        if checked.0.elapsed() < RELOAD_INTERVAL {
+            return;
+        }
+
+        let modified = modified(&self.path);
+        *checked = (Instant::now(), checked.1);
+        if modified == checked.1 {
+            return;
+        }
+
+        match read_policy(&self.path) {
+            Ok(policy) => {
+                println!("Reloaded access policy with {} rules", policy.rules.len());
+                *self.policy.write().unwrap() = policy;
+                checked.1 = modified;
+            }
+            Err(e) => println!("Unable to reload access policy, keeping the previous one: {}", e),
+        }
+    }
+
+    pub fn allows(&self, identity: Option<&str>, permission: Permission, filename: &str) -> bool {
+        self.maybe_reload();
+        self.policy.read().unwrap().allows(identity, permission, filename)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rule(identity: &str, prefix: &str, permissions: &[Permission]) -> Rule {
+        Rule { identity: identity.to_string(), prefix: prefix.to_string(), permissions: permissions.to_vec() }
+    }
+
+    #[test]
+    fn only_what_a_rule_grants_is_allowed() {
+        assert!(!Policy::default().allows(Some("alice"), Permission::Read, "notes"));
+
+        let policy = Policy {
+            rules: vec![
+                rule("alice", "", &[Permission::Read, Permission::Write]),
+                rule("*", "public/", &[Permission::Read]),
+            ],
+        };
+        assert!(policy.allows(Some("alice"), Permission::Write, "notes"));
+        assert!(!policy.allows(Some("alice"), Permission::Delete, "notes"));
+        assert!(!policy.allows(Some("bob"), Permission::Read, "notes"));
+        assert!(policy.allows(Some("bob"), Permission::Read, "public/notes"));
+        assert!(policy.allows(None, Permission::Read, "public/notes"));
+        assert!(!policy.allows(None, Permission::Write, "public/notes"));
+        assert!(!policy.allows(None, Permission::Read, "notes"));
+    }
+
+    #[test]
+    fn prefixes_match_whole_path_components() {
+        let policy = Policy { rules: vec![rule("*", "alice", &[Permission::Read]), rule("*", "bob/", &[Permission::Read])] };
+        assert!(policy.allows(None, Permission::Read, "alice"));
+        assert!(policy.allows(None, Permission::Read, "alice/notes"));
+        assert!(!policy.allows(None, Permission::Read, "alice2/secret"));
+        assert!(policy.allows(None, Permission::Read, "bob/notes"));
+        assert!(!policy.allows(None, Permission::Read, "bob"));
+        assert!(!policy.allows(None, Permission::Read, "bobby"));
+    }
+
+    #[test]
+    fn changed_policies_are_picked_up_and_broken_ones_ignored() {
+        let dir = std::env::temp_dir().join(format!("acl-reload-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("acl.json");
+        std::fs::write(&path, r#"{"rules": [{"identity": "*", "prefix": "a/", "permissions": ["read"]}]}"#).unwrap();
+        let acl = Acl::load(&path).unwrap();
+        assert!(acl.allows(None, Permission::Read, "a/notes"));
+        /* Pretend the reload interval is over instead of waiting for it */
+        let expire = || acl.checked.lock().unwrap().0 -= RELOAD_INTERVAL;
+
+        std::fs::write(&path, r#"{"rules": [{"identity": "*", "prefix": "b/", "permissions": ["read"]}]}"#).unwrap();
+        expire();
+        assert!(!acl.allows(None, Permission::Read, "a/notes"));
+        assert!(acl.allows(None, Permission::Read, "b/notes"));
+
+        std::fs::write(&path, "{\"rules\": [").unwrap();
+        expire();
+        assert!(acl.allows(None, Permission::Read, "b/notes"));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,324 @@
+use crate::storage::{ObjectStat, Storage};
+use chacha20poly1305::aead::{Aead, KeyInit, Payload};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::sync::Arc;
+
+const MAGIC: &[u8; 4] = b"ZFR1";
+const NONCE_LEN: usize = 12;
+const TAG_LEN: usize = 16;
+const KEY_LEN: usize = 32;
+/* Magic, id of the key encryption key, nonce and the wrapped data key */
+const HEADER_LEN: usize = 4 + 4 + NONCE_LEN + KEY_LEN + TAG_LEN;
+/* Plaintext bytes per AEAD segment, segments can be decrypted on their own for range reads */
+const SEGMENT_SIZE: u64 = 64 * 1024;
+const SEGMENT_LEN: u64 = SEGMENT_SIZE + TAG_LEN as u64;
+
+fn invalid_data(msg: &str) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, msg.to_string())
+}
+
+/**Key encryption keys, read from a file with one `<id> <hex key>` pair per line. New blobs are
+ * encrypted under the key with the highest id, older keys are kept around to read existing blobs */
+pub struct KeyRing {
+    keys: BTreeMap<u32, [u8; KEY_LEN]>,
+}
+
+impl KeyRing {
+    pub fn load(path: &Path) -> std::io::Result<KeyRing> {
+        let mut keys = BTreeMap::new();
+        for line in std::fs::read_to_string(path)?.lines() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let (id, key) = line.split_once(char::is_whitespace).ok_or_else(|| invalid_data("expected `<id> <hex key>`"))?;
+            let id = id.parse::<u32>().map_err(|_| invalid_data("key id must be a number"))?;
+            let key = hex::decode(key.trim()).ok()
+                .and_then(|key| <[u8; KEY_LEN]>::try_from(key).ok())
+                .ok_or_else(|| invalid_data("key must be 32 hex encoded bytes"))?;
+            keys.insert(id, key);
+        }
+
+        if keys.is_empty() {
+            return Err(invalid_data("no keys in key file"));
+        }
+        Ok(KeyRing { keys })
+    }
+
+    fn current(&self) -> (u32, &[u8; KEY_LEN]) {
+        self.keys.iter().next_back().map(|(id, key)| (*id, key)).unwrap()
+    }
+
+    fn get(&self, id: u32) -> std::io::Result<&[u8; KEY_LEN]> {
+        self.keys.get(&id).ok_or_else(|| invalid_data(&format!("key {} is not in the key file", id)))
+    }
+}
+
+/* Nonce of a segment, the last one is marked so a blob can't be cut short at a segment boundary */
+fn segment_nonce(index: u64, last: bool) -> [u8; NONCE_LEN] {
+    let mut nonce = [0u8; NONCE_LEN];
+    nonce[..8].copy_from_slice(&index.to_be_bytes());
+    nonce[NONCE_LEN - 1] = last as u8;
+    nonce
+}
+
+/* Blobs stored before at-rest encryption was turned on are plaintext, they are read as is until a rotation encrypts them */
+fn is_encrypted(header: &[u8]) -> bool {
+    header.get(..4) == Some(MAGIC.as_slice())
+}
+
+/* Size of the plaintext of a blob with `size` bytes of ciphertext */
+fn plaintext_size(size: u64) -> u64 {
+    let body = size.saturating_sub(HEADER_LEN as u64);
+    let segments = (body + SEGMENT_LEN - 1) / SEGMENT_LEN;
+    body.saturating_sub(segments * TAG_LEN as u64)
+}
+
+/**Envelope encryption on top of another backend. Every blob gets a random data key that encrypts the
+ * contents in segments, and the data key is stored in the blob header wrapped by a key encryption key
+ * from the key ring. Rotating the key encryption key re-wraps the data keys, the contents are never decrypted.
+ * Objects are still addressed by the hash of their plaintext, so the Merkle tree doesn't change */
+pub struct EncryptedStorage {
+    inner: Arc<dyn Storage>,
+    keys: KeyRing,
+}
+
+/* Keys stay out of debug output, only the id of the current one is shown */
+impl std::fmt::Debug for EncryptedStorage {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("EncryptedStorage").field("current_key", &self.keys.current().0).finish_non_exhaustive()
+    }
+}
+
+impl EncryptedStorage {
+    pub fn new(inner: Arc<dyn Storage>, keys: KeyRing) -> EncryptedStorage {
+        EncryptedStorage { inner, keys }
+    }
+
+    /* The wrapped data key is bound to the blob and to the id of the key that wrapped it */
+    fn header_aad(key_id: u32, object: &str) -> Vec<u8> {
+        let mut aad = MAGIC.to_vec();
+        aad.extend_from_slice(&key_id.to_be_bytes());
+        aad.extend_from_slice(object.as_bytes());
+        aad
+    }
+
+    fn wrap(&self, object: &str, data_key: &[u8; KEY_LEN]) -> Vec<u8> {
+        let (key_id, kek) = self.keys.current();
+        let nonce = rand::random::<[u8; NONCE_LEN]>();
+        let aad = Self::header_aad(key_id, object);
+        let wrapped = ChaCha20Poly1305::new(Key::from_slice(kek))
+            .encrypt(Nonce::from_slice(&nonce), Payload { msg: data_key, aad: aad.as_slice() })
+            .expect("data key fits in a single AEAD message");
+
+        let mut header = MAGIC.to_vec();
+        header.extend_from_slice(&key_id.to_be_bytes());
+        header.extend_from_slice(&nonce);
+        header.extend(wrapped);
+        header
+    }
+
+    /* Unwrap the data key from a blob header, together with the id of the key it was wrapped with */
+    fn unwrap_key(&self, object: &str, header: &[u8]) -> std::io::Result<(u32, [u8; KEY_LEN])> {
+        if header.len() < HEADER_LEN || &header[..4] != MAGIC {
+            return Err(invalid_data("not an encrypted blob"));
+        }
+
+        let key_id = u32::from_be_bytes(header[4..8].try_into().unwrap());
+        let aad = Self::header_aad(key_id, object);
+        let data_key = ChaCha20Poly1305::new(Key::from_slice(self.keys.get(key_id)?))
+            .decrypt(Nonce::from_slice(&header[8..8 + NONCE_LEN]), Payload { msg: &header[8 + NONCE_LEN..HEADER_LEN], aad: aad.as_slice() })
+            .map_err(|_| invalid_data("unable to unwrap data key"))?;
+        let data_key = <[u8; KEY_LEN]>::try_from(data_key).map_err(|_| invalid_data("invalid data key"))?;
+        Ok((key_id, data_key))
+    }
+
+    fn unwrap(&self, object: &str, header: &[u8]) -> std::io::Result<(u32, ChaCha20Poly1305)> {
+        let (key_id, data_key) = self.unwrap_key(object, header)?;
+        Ok((key_id, ChaCha20Poly1305::new(Key::from_slice(&data_key))))
+    }
+
+    fn decrypt_segment(cipher: &ChaCha20Poly1305, object: &str, index: u64, segment: &[u8], last: bool) -> std::io::Result<Vec<u8>> {
+        cipher.decrypt(Nonce::from_slice(&segment_nonce(index, last)), Payload { msg: segment, aad: object.as_bytes() })
+            .map_err(|_| invalid_data("blob failed to decrypt"))
+    }
+
+    /**Re-wrap the data key of a blob with the current key encryption key, the segments stay as they are. Backends
+     * can only replace a blob as a whole, so the blob is written again with the new header in front of the old
+     * segments, which keeps the change atomic. A blob stored before encryption was turned on is encrypted.
+     * Returns false if the blob already used the current key */
+    pub fn rewrap(&self, object: &str) -> std::io::Result<bool> {
+        let header = self.inner.get_range(object, 0, HEADER_LEN as u64)?;
+        if !is_encrypted(header.as_slice()) {
+            let data = self.inner.get(object)?;
+            self.put(object, data.as_slice())?;
+            return Ok(true);
+        }
+        let (key_id, _) = self.unwrap_key(object, header.as_slice())?;
+        if key_id == self.keys.current().0 {
+            return Ok(false);
+        }
+
+        let mut blob = self.inner.get(object)?;
+        let (_, data_key) = self.unwrap_key(object, blob.as_slice())?;
+        blob.splice(..HEADER_LEN, self.wrap(object, &data_key));
+        self.inner.put(object, blob.as_slice())?;
+        Ok(true)
+    }
+
+    /* Every blob in the backend, the candidates for a rotation */
+    pub fn objects(&self) -> std::io::Result<Vec<String>> {
+        self.inner.list()
+    }
+}
+
+impl Storage for EncryptedStorage {
+    fn put(&self, key: &str, data: &[u8]) -> std::io::Result<()> {
+        let data_key = rand::random::<[u8; KEY_LEN]>();
+        let cipher = ChaCha20Poly1305::new(Key::from_slice(&data_key));
+
+        let mut blob = self.wrap(key, &data_key);
+        /* An empty blob is still one (empty) last segment */
+        let segments = data.chunks(SEGMENT_SIZE as usize).collect::<Vec<&[u8]>>();
+        let segments = if segments.is_empty() { vec![&data[..0]] } else { segments };
+        for (i, segment) in segments.iter().enumerate() {
+            let nonce = segment_nonce(i as u64, i == segments.len() - 1);
+            let ciphertext = cipher.encrypt(Nonce::from_slice(&nonce), Payload { msg: segment, aad: key.as_bytes() })
+                .expect("segment fits in a single AEAD message");
+            blob.extend(ciphertext);
+        }
+
+        self.inner.put(key, blob.as_slice())
+    }
+
+    fn get(&self, key: &str) -> std::io::Result<Vec<u8>> {
+        let blob = self.inner.get(key)?;
+        if !is_encrypted(blob.as_slice()) {
+            return Ok(blob);
+        }
+        let (_, cipher) = self.unwrap(key, blob.as_slice())?;
+
+        let segments = blob[HEADER_LEN..].chunks(SEGMENT_LEN as usize).collect::<Vec<&[u8]>>();
+        if segments.is_empty() {
+            return Err(invalid_data("blob was truncated"));
+        }
+        let mut data = Vec::with_capacity(blob.len());
+        for (i, segment) in segments.iter().enumerate() {
+            data.extend(Self::decrypt_segment(&cipher, key, i as u64, segment, i == segments.len() - 1)?);
+        }
+        Ok(data)
+    }
+
+    fn delete(&self, key: &str) -> std::io::Result<()> {
+        self.inner.delete(key)
+    }
+
+    fn list(&self) -> std::io::Result<Vec<String>> {
+        self.inner.list()
+    }
+
+    fn stat(&self, key: &str) -> std::io::Result<Option<ObjectStat>> {
+        let stat = match self.inner.stat(key)? {
+            Some(stat) => stat,
+            None => return Ok(None),
+        };
+        if !is_encrypted(self.inner.get_range(key, 0, MAGIC.len() as u64)?.as_slice()) {
+            return Ok(Some(stat));
+        }
+        Ok(Some(ObjectStat { size: plaintext_size(stat.size) }))
+    }
+
+    /* Only the segments covering the range are read and decrypted */
+    fn get_range(&self, key: &str, offset: u64, len: u64) -> std::io::Result<Vec<u8>> {
+        if len == 0 {
+            return Ok(vec![]);
+        }
+        let header = self.inner.get_range(key, 0, HEADER_LEN as u64)?;
+        if !is_encrypted(header.as_slice()) {
+            return self.inner.get_range(key, offset, len);
+        }
+        let (_, cipher) = self.unwrap(key, header.as_slice())?;
+
+        let first = offset / SEGMENT_SIZE;
+        let last = (offset + len - 1) / SEGMENT_SIZE;
+        let body = self.inner.get_range(key, HEADER_LEN as u64 + first * SEGMENT_LEN, (last - first + 1) * SEGMENT_LEN)?;
+
+        let mut data = Vec::with_capacity(body.len());
+        for (i, segment) in body.chunks(SEGMENT_LEN as usize).enumerate() {
+            let index = first + i as u64;
+            /* A short segment is the last one, a full one may or may not be */
+            let plaintext = if (segment.len() as u64) < SEGMENT_LEN {
+                Self::decrypt_segment(&cipher, key, index, segment, true)?
+            } else {
+                Self::decrypt_segment(&cipher, key, index, segment, false)
+                    .or_else(|_| Self::decrypt_segment(&cipher, key, index, segment, true))?
+            };
+            data.extend(plaintext);
+        }
+
+        let start = usize::min((offset - first * SEGMENT_SIZE) as usize, data.len());
+        let end = usize::min(start.saturating_add(len as usize), data.len());
+        Ok(data[start..end].to_vec())
+    }
+
+    fn is_persistent(&self) -> bool {
+        self.inner.is_persistent()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::MemoryStorage;
+
+    fn key_ring(name: &str, keys: &[(u32, u8)]) -> KeyRing {
+        let path = std::env::temp_dir().join(format!("atrest-{}-{}", name, std::process::id()));
+        let lines = keys.iter().map(|(id, key)| format!("{} {}\n", id, hex::encode([*key; KEY_LEN]))).collect::<String>();
+        std::fs::write(&path, lines).unwrap();
+        let keys = KeyRing::load(&path).unwrap();
+        let _ = std::fs::remove_file(&path);
+        keys
+    }
+
+    #[test]
+    fn rewrapped_blobs_only_need_the_current_key() {
+        let inner = Arc::new(MemoryStorage::default());
+        let data = (0..SEGMENT_SIZE as usize + 10).map(|i| i as u8).collect::<Vec<u8>>();
+        EncryptedStorage::new(inner.clone(), key_ring("old", &[(1, 1)])).put("blob", data.as_slice()).unwrap();
+        let before = inner.get("blob").unwrap();
+
+        let both = EncryptedStorage::new(inner.clone(), key_ring("both", &[(1, 1), (2, 2)]));
+        assert!(both.rewrap("blob").unwrap());
+        assert!(!both.rewrap("blob").unwrap());
+        /* Only the header changed, the segments are still encrypted under the same data key */
+        let after = inner.get("blob").unwrap();
+        assert_ne!(before[..HEADER_LEN], after[..HEADER_LEN]);
+        assert_eq!(before[HEADER_LEN..], after[HEADER_LEN..]);
+
+        let new = EncryptedStorage::new(inner.clone(), key_ring("new", &[(2, 2)]));
+        assert_eq!(new.get("blob").unwrap(), data);
+        assert_eq!(new.get_range("blob", SEGMENT_SIZE - 5, 10).unwrap(), data[SEGMENT_SIZE as usize - 5..SEGMENT_SIZE as usize + 5]);
+    }
+
+    #[test]
+    fn blobs_stored_before_encryption_are_read_as_is_until_rotated() {
+        let inner = Arc::new(MemoryStorage::default());
+        let data = (0..SEGMENT_SIZE as usize + 10).map(|i| i as u8).collect::<Vec<u8>>();
+        inner.put("plain", data.as_slice()).unwrap();
+
+        let storage = EncryptedStorage::new(inner.clone(), key_ring("legacy", &[(1, 1)]));
+        assert_eq!(storage.stat("plain").unwrap(), Some(ObjectStat { size: data.len() as u64 }));
+        assert_eq!(storage.get("plain").unwrap(), data);
+        assert_eq!(storage.get_range("plain", SEGMENT_SIZE - 5, 10).unwrap(), data[SEGMENT_SIZE as usize - 5..SEGMENT_SIZE as usize + 5]);
+
+        /* A rotation encrypts it, after that it reads the same */
+        assert!(storage.rewrap("plain").unwrap());
+        assert!(!storage.rewrap("plain").unwrap());
+        assert!(is_encrypted(inner.get("plain").unwrap().as_slice()));
+        assert_eq!(storage.stat("plain").unwrap(), Some(ObjectStat { size: data.len() as u64 }));
+        assert_eq!(storage.get("plain").unwrap(), data);
+    }
+}
@@ -0,0 +1,201 @@
+use chrono::prelude::*;
+use chrono::Duration;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::path::Path;
+
+type HmacSha256 = Hmac<Sha256>;
+
+pub const NONCE_LEN: usize = 32;
+/* How long a client has to answer a challenge */
+const CHALLENGE_TTL_SECS: i64 = 30;
+/* How long an address stays authenticated without sending anything */
+const AUTH_SESSION_TTL_SECS: i64 = 30 * 60;
+
+/* Domain separation, so a MAC made for the handshake can't be reused for anything else */
+const AUTH_CONTEXT: &[u8] = b"zama-fileserver auth v1";
+
+/* Read a hex encoded key from a file */
+pub fn load_key(path: &Path) -> std::io::Result<Vec<u8>> {
+    let key = std::fs::read_to_string(path)?;
+    hex::decode(key.trim()).map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e.to_string()))
+}
+
+/**Pre-shared keys of the identities allowed to use the server, read from a file with one
+ * `<identity> <hex key>` pair per line. Empty lines and lines starting with `#` are skipped */
+#[derive(Debug, Default)]
+pub struct KeyStore {
+    keys: HashMap<String, Vec<u8>>,
+}
+
+impl KeyStore {
+    pub fn load(path: &Path) -> std::io::Result<KeyStore> {
+        let mut keys = HashMap::new();
+        for (n, line) in std::fs::read_to_string(path)?.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let key = line.split_once(char::is_whitespace)
+                .and_then(|(identity, key)| hex::decode(key.trim()).ok().map(|key| (identity.to_string(), key)));
+            match key {
+                Some((identity, key)) => { keys.insert(identity, key); }
+                None => return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, format!("invalid key on line {}", n + 1))),
+            }
+        }
+        Ok(KeyStore { keys })
+    }
+
+    pub fn get(&self, identity: &str) -> Option<&[u8]> {
+        self.keys.get(identity).map(|key| key.as_slice())
+    }
+
+    pub fn len(&self) -> usize {
+        self.keys.len()
+    }
+}
+
+fn auth_mac(key: &[u8], identity: &str, nonce: &[u8]) -> HmacSha256 {
+    let mut mac = HmacSha256::new_from_slice(key).expect("HMAC accepts keys of any length");
+    mac.update(AUTH_CONTEXT);
+    mac.update(&(identity.len() as u64).to_be_bytes());
+    mac.update(identity.as_bytes());
+    mac.update(nonce);
+    mac
+}
+
+/**Answer to a challenge, proves possession of the key without sending it */
+pub fn sign_challenge(key: &[u8], identity: &str, nonce: &[u8]) -> Vec<u8> {
+    auth_mac(key, identity, nonce).finalize().into_bytes().to_vec()
+}
+
+/* Constant time comparison of the answer to a challenge */
+pub fn verify_challenge(key: &[u8], identity: &str, nonce: &[u8], response: &[u8]) -> bool {
+    auth_mac(key, identity, nonce).verify_slice(response).is_ok()
+}
+
+/**Challenges and authentications are tied to the secure session they happened on. There is no authentication
+ * without one, an address alone can be spoofed by anyone on the path */
+#[derive(Debug, Clone)]
+struct Challenge {
+    identity: String,
+    nonce: Vec<u8>,
+    secure_session: u64,
+    expires_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone)]
+struct AuthSession {
+    identity: String,
+    secure_session: u64,
+    expires_at: DateTime<Utc>,
+}
+
+/**Handshake state of the server: outstanding challenges and authenticated addresses. An address stays
+ * authenticated only as long as it's on the secure session it authenticated on */
+#[derive(Debug, Default)]
+pub struct Authenticator {
+    challenges: HashMap<SocketAddr, Challenge>,
+    sessions: HashMap<SocketAddr, AuthSession>,
+}
+
+impl Authenticator {
+    fn expire(&mut self, now: DateTime<Utc>) {
+        self.challenges.retain(|_, challenge| challenge.expires_at >= now);
+        self.sessions.retain(|addr, session| {
+            if session.expires_at < now {
+                println!("Authentication of {} at {:?} expired", session.identity, addr);
+            }
+            session.expires_at >= now
+        });
+    }
+
+    /* Hand out a fresh nonce for an identity, the client has to return its MAC */
+    pub fn challenge(&mut self, addr: SocketAddr, secure_session: u64, identity: &str) -> Vec<u8> {
+        let now = Utc::now();
+        self.expire(now);
+
+        let nonce = rand::random::<[u8; NONCE_LEN]>().to_vec();
+        self.challenges.insert(addr, Challenge {
+            identity: identity.to_string(),
+            nonce: nonce.clone(),
+            secure_session,
+            expires_at: now + Duration::seconds(CHALLENGE_TTL_SECS),
+        });
+        nonce
+    }
+
+    /**Check the answer to the outstanding challenge of an address, returns the identity on success.
+     * A challenge can only be answered once */
+    pub fn respond(&mut self, keys: &KeyStore, addr: SocketAddr, secure_session: u64, response: &[u8]) -> Option<String> {
+        let now = Utc::now();
+        let challenge = self.challenges.remove(&addr)
+            .filter(|challenge| challenge.expires_at >= now && challenge.secure_session == secure_session)?;
+        let key = keys.get(&challenge.identity)?;
+
+        if !verify_challenge(key, &challenge.identity, &challenge.nonce, response) {
+            return None;
+        }
+
+        self.sessions.insert(addr, AuthSession {
+            identity: challenge.identity.clone(),
+            secure_session,
+            expires_at: now + Duration::seconds(AUTH_SESSION_TTL_SECS),
+        });
+        Some(challenge.identity)
+    }
+
+    /* Identity an address authenticated as, keeps the session alive. A new secure session ends it */
+    pub fn identity(&mut self, addr: &SocketAddr, secure_session: u64) -> Option<String> {
+        let now = Utc::now();
+        if self.sessions.get(addr).is_some_and(|session| session.secure_session != secure_session) {
+            let session = self.sessions.remove(addr).unwrap();
+            println!("Authentication of {} at {:?} ended with its secure session", session.identity, addr);
+            return None;
+        }
+        let session = self.sessions.get_mut(addr).filter(|session| session.expires_at >= now)?;
+        session.expires_at = now + Duration::seconds(AUTH_SESSION_TTL_SECS);
+        Some(session.identity.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn authenticate(auth: &mut Authenticator, keys: &KeyStore, addr: SocketAddr, secure_session: u64) -> Option<String> {
+        let nonce = auth.challenge(addr, secure_session, "alice");
+        auth.respond(keys, addr, secure_session, &sign_challenge(b"key", "alice", &nonce))
+    }
+
+    #[test]
+    fn identity_is_bound_to_the_secure_session() {
+        let keys = KeyStore { keys: HashMap::from([("alice".to_string(), b"key".to_vec())]) };
+        let addr: SocketAddr = "192.0.2.1:4000".parse().unwrap();
+        let mut auth = Authenticator::default();
+
+        assert_eq!(authenticate(&mut auth, &keys, addr, 1), Some("alice".to_string()));
+        assert_eq!(auth.identity(&addr, 1), Some("alice".to_string()));
+
+        /* Someone else on a new session from the same address doesn't inherit it, and it's gone for good */
+        assert_eq!(auth.identity(&addr, 2), None);
+        assert_eq!(auth.identity(&addr, 1), None);
+    }
+
+    #[test]
+    fn challenges_are_answered_on_the_session_they_were_issued_on() {
+        let keys = KeyStore { keys: HashMap::from([("alice".to_string(), b"key".to_vec())]) };
+        let addr: SocketAddr = "192.0.2.1:4000".parse().unwrap();
+        let mut auth = Authenticator::default();
+
+        let nonce = auth.challenge(addr, 1, "alice");
+        assert_eq!(auth.respond(&keys, addr, 2, &sign_challenge(b"key", "alice", &nonce)), None);
+        assert_eq!(authenticate(&mut auth, &keys, addr, 2), Some("alice".to_string()));
+
+        let nonce = auth.challenge(addr, 2, "alice");
+        assert_eq!(auth.respond(&keys, addr, 2, &sign_challenge(b"wrong", "alice", &nonce)), None);
+    }
+}
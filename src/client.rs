@@ -1,164 +1,1649 @@
-use crate::merkletree::*;
-use crate::protocol::Message;
-use crate::Opts;
-use chrono::prelude::*;
-use tokio::io::{AsyncWriteExt, AsyncReadExt};
-use std::net::SocketAddr;
-use std::path::Path;
-use std::ffi::OsString;
-
-use hydroflow::hydroflow_syntax;
-use hydroflow::util::{UdpSink, UdpStream};
-use hydroflow::futures;
-use futures::executor::block_on;
-
-use std::cell::RefCell;
-
-thread_local! {
-    static ROOT: RefCell<OsString> = RefCell::new(OsString::new());
-}
-
-const DATA_DIR: &str = "./.client/";
-const N_OF_FILES: usize = 5;
-
-async fn save_file(filename: String, data: Vec<u8>, merkleproof: Vec<Vec<u8>>) -> String {
-    /* Convert from Vec<Vec<u8>> to Vec<OSString> and try to verify before saving */
-    let proof: Vec<OsString> = merkleproof.iter().map(|v| OsString::from(String::from_utf8(v.to_vec()).unwrap_or_default())).collect();
-
-    let root = ROOT.with(|r| r.borrow().clone());
-
-    let is_proof_valid = MerkleTree::verify_data_with_proof(&data, proof, root).await;
-    println!("Is proof for file {} valid: {}", filename, is_proof_valid);
-
-    if let Ok(mut file) = tokio::fs::File::create(format!("./.client/{}", filename.clone())).await {
-        let _ = file.write_all(data.as_slice()).await;
-        format!("Saved file {}", filename)
-    } else {
-        format!("Unable to save file {}", filename)
-    }
-}
-
-pub(crate) async fn run_client(outbound: UdpSink, inbound: UdpStream, opts: Opts) {
-    // server_addr is required for client
-    let server_addr = match opts.server_addr {
-        Some(addr) => {
-            println!("Connecting to server at {:?}", addr);
-            addr
-        }
-        None => panic!("Client requires a server address"),
-    };
-
-    println!("Client live!");
-
-
-    let (input, recv) = hydroflow::util::unbounded_channel::<Message>();
-
-    let mut flow = hydroflow_syntax! {
-        // Define shared inbound and outbound channels
-        inbound_chan = source_stream_serde(inbound) -> map(|udp_msg| udp_msg.unwrap()) -> tee() ;
-        outbound_chan = dest_sink_serde(outbound);
-
-        // Write all received messages for debugging purposes to the .log file
-        inbound_chan[1]
-            -> map(|(m, a): (Message, SocketAddr)| format!("{}: Got {:?} from {:?}", Utc::now(), m, a))
-            -> dest_file("client.log", true);
-
-        inbound_demuxed = inbound_chan[0]
-            ->  demux(|(msg, addr), var_args!(file_save_ch, errs_ch)|
-                    match msg {
-                        Message::FileAck {filename, hash} => println!("Upload of file {} with hash {} was successful!", filename, hash),
-                        Message::File {filename, data, merkle_proof} => file_save_ch.give((filename, data, merkle_proof, addr)),
-                        Message::DeleteFileAck {filename, deleted} => println!("File {} removed from server: {}", filename, deleted),
-                        _ => errs_ch.give((msg, addr)),
-                    }
-                );
-
-        /* When we receive a message to file_save_ch containing file data we save the file locally */
-        /* save_file() does verification of the data and the proof against the root hash stored in memory */
-        inbound_demuxed[file_save_ch]
-                -> map(|(filename, data, merkleproof, _addr)| {
-                    block_on(async {
-                        save_file(filename, data, merkleproof).await
-                    })
-                } )
-                -> dest_file("client.log", true);
-
-        // Print unexpected messages
-        inbound_demuxed[errs_ch]
-            -> for_each(|(msg, addr)| println!("Received unexpected message type: {:?} from {:?}", msg, addr));
-
-        /* Send directly to the server */
-        source_stream(recv) 
-            -> map(|l| (l, server_addr) )
-            -> outbound_chan;
-    };
-
-    /* Step 1: Generate test files */
-    let mut filenames = vec![];
-
-    for i in 1..=N_OF_FILES {
-        filenames.push(format!("file{}.txt", i));
-    }
-
-    /* Step 2: Generate test files */
-    let _ = tokio::fs::create_dir_all(DATA_DIR).await;
-    filenames.iter().for_each(|filename| {
-        block_on(async {
-            if let Ok(mut file) = tokio::fs::File::create(format!("./.client/{}", filename)).await {
-                let _ = file.write_all(format!("Hello, world! This is {}", filename).as_bytes()).await;
-                println!("Created ./files/{}", filename);
-            }
-        });
-    });
-
-    /* Step 3: Create merkle tree locally and store root hash */
-    let mt = MerkleTree::from_folder(&Path::new(DATA_DIR)).await;
-    println!("Merkle tree root hash: {:#?}", mt.root);
-    println!("{:#?}", mt);
-    
-    /* Step 4: Update ROOT hash and store it for the duration of the program */
-    let root_hash = mt.root.unwrap();
-    ROOT.with(|r| r.replace(root_hash.clone()));
-    println!("Updated root hash");
-
-    /* Step 5: Upload files to the server */
-    filenames.iter().for_each(|filename| {
-        block_on(async {
-            if let Ok(mut file) = tokio::fs::File::open(format!("./.client/{}", filename)).await {
-                let mut data = Vec::new();
-                let _ = file.read_to_end(&mut data).await;
-                let _ = input.send(Message::FileUpload { filename: filename.clone(), data: data });
-            }
-       });
-    });
-
-    println!("Running available");
-    flow.run_available_async().await;
-
-    /* Step 6: Delete local test files */
-    filenames.iter().for_each(|filename| {
-        block_on(async {
-            let _ = tokio::fs::remove_file(format!("./.client/{}", filename)).await;
-            println!("Deleted ./.client/{}", filename);
-        });
-    });
-
-    /* Step 7: Request files back from the server, verifying their integrity */
-    filenames.iter().for_each(|filename| {
-        block_on(async {
-            let _ = input.send(Message::FileRequest { filename: filename.clone() });
-        });
-    });
-
-    flow.run_available_async().await;
-
-    /* Step 8: Delete files from the server */
-    filenames.iter().for_each(|filename| {
-        block_on(async {
-            let _ = input.send(Message::DeleteFileRequest { filename: filename.clone() });
-        });
-    });
-
-    /* Step 9: Run the flow until termination */
-    flow.run_async().await;
-}
+use crate::auth::{load_key, sign_challenge};
+use crate::download::{part_path, save_chunks, DownloadManifest};
+use crate::e2e::MasterKey;
+use crate::fsutil::{is_temp_file, list_files, write_file_atomic};
+use crate::ignore::IgnorePatterns;
+use crate::index::{is_valid_path, FileIndex, FileMeta};
+use crate::merkletree::*;
+use crate::protocol::{DirEntry, Listing, Message, Preconditions, ProtocolError, VersionInfo, CHUNK_SIZE};
+use crate::upload::upload_session_id;
+use crate::validate::TOKEN_LEN;
+#[cfg(target_os = "linux")]
+use crate::watch::{WatchEvent, Watcher};
+use crate::secure::{SecureSink, SecureStream};
+use crate::{Command, ConflictPolicy, Opts};
+use chrono::prelude::*;
+use tokio::io::AsyncWriteExt;
+use tokio::sync::mpsc::UnboundedSender;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::ffi::OsString;
+use std::future::Future;
+use std::time::Duration;
+
+use hydroflow::futures::{future, stream, StreamExt};
+use hydroflow::hydroflow_syntax;
+use hydroflow::scheduled::graph::Hydroflow;
+
+use std::cell::RefCell;
+
+/* What the server told us about an upload session */
+#[derive(Debug, Clone)]
+enum UploadState {
+    Missing(Vec<u32>),
+    NotFound,
+    Done,
+}
+
+/* What sync does about a file that differs between the client directory and the server */
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum SyncAction {
+    Upload,
+    Download,
+    DeleteRemote,
+    DeleteLocal,
+}
+
+/* Hashes of a file that differs, in the client directory and on the server */
+type Difference = (Option<String>, Option<String>);
+
+/* Filename, version, index and data of a received download chunk */
+type ChunkWrite = (String, Option<u64>, u32, Vec<u8>);
+/* Index and data of the chunks of one download, keyed by filename and version */
+type ChunkBatch = BTreeMap<(String, Option<u64>), Vec<(u32, Vec<u8>)>>;
+
+/* Size, hash and merkle proof of a file on the server, or of a kept version with the root of its epoch */
+#[derive(Debug, Clone)]
+struct RemoteFile {
+    version: Option<u64>,
+    hash: String,
+    size: u64,
+    merkle_proof: Vec<Vec<u8>>,
+    root: Option<String>,
+}
+
+thread_local! {
+    static ROOT: RefCell<OsString> = RefCell::new(OsString::new());
+    /* Upload sessions in progress, keyed by session id */
+    static UPLOADS: RefCell<HashMap<String, UploadState>> = RefCell::new(HashMap::new());
+    /* Replies to FileInfoRequest, None if the server doesn't have the file */
+    static REMOTE_FILES: RefCell<HashMap<String, Option<RemoteFile>>> = RefCell::new(HashMap::new());
+    /* Nonce of the last challenge the server sent us */
+    static CHALLENGE: RefCell<Option<Vec<u8>>> = RefCell::new(None);
+    /* Identity the server confirmed we're authenticated as, Some(None) if the server doesn't authenticate clients */
+    static AUTHENTICATED: RefCell<Option<Option<String>>> = RefCell::new(None);
+    /* Whether the server confirmed it has seen us echo a retry token from our address */
+    static VALIDATED: RefCell<bool> = RefCell::new(false);
+    /* Heartbeats sent since we last heard from the server, and whether we think it's still there */
+    static MISSED_HEARTBEATS: RefCell<u32> = RefCell::new(0);
+    static SERVER_REACHABLE: RefCell<bool> = RefCell::new(true);
+    /* Replies to ListVersions, keyed by filename */
+    static VERSIONS: RefCell<HashMap<String, Vec<VersionInfo>>> = RefCell::new(HashMap::new());
+    /* Pages of directory listings, keyed by directory and offset */
+    static LISTINGS: RefCell<HashMap<(String, u32), Box<Listing>>> = RefCell::new(HashMap::new());
+    /* Roots the server acked for changes, keyed by the filename that changed, the new one for renames and copies */
+    static ACKED: RefCell<HashMap<String, String>> = RefCell::new(HashMap::new());
+    /* Files whose conditional upload or delete the server turned down, retrying won't help */
+    static PRECONDITION_FAILED: RefCell<HashSet<String>> = RefCell::new(HashSet::new());
+    /* Patterns of local files that are never uploaded, so they're left out of the trusted root as well */
+    static IGNORED: RefCell<IgnorePatterns> = RefCell::new(IgnorePatterns::default());
+    /* Files the server confirmed it deleted */
+    static DELETED: RefCell<HashSet<String>> = RefCell::new(HashSet::new());
+    /* Chunks waiting to be written by the chunk writer task */
+    static CHUNKS: RefCell<Option<UnboundedSender<ChunkWrite>>> = RefCell::new(None);
+    /* Results of the file writes, appended to the log by the flow */
+    static LOG: RefCell<Option<UnboundedSender<String>>> = RefCell::new(None);
+}
+
+const DATA_DIR: &str = "./.client/";
+/* Trusted root hash and partial downloads live here, outside of the merkle tree */
+const STATE_DIR: &str = "./.client-state/";
+const N_OF_FILES: usize = 5;
+/* With end-to-end encryption the tree is built from the encrypted names and ciphertext hashes of our uploads, kept here */
+const E2E_INDEX: &str = "e2e-index.json";
+/* Hashes of the files that were the same on both sides after the last sync, changes on either side are told apart with it */
+const SYNC_STATE: &str = "sync.json";
+/* Patterns given with --ignore in earlier runs, they keep shaping the trusted root */
+const IGNORE_STATE: &str = "ignore";
+
+/* How long to wait for the server before asking again */
+const REPLY_TIMEOUT: Duration = Duration::from_millis(500);
+const MAX_RETRIES: usize = 10;
+/* Number of chunks requested at once during a download */
+const CHUNK_WINDOW: usize = 64;
+/* A watched file is pushed once nothing happened in the client directory for this long */
+const WATCH_DEBOUNCE: Duration = Duration::from_millis(500);
+/* The server is considered unreachable after this many heartbeats in a row went unanswered */
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(1);
+const MAX_MISSED_HEARTBEATS: u32 = 3;
+
+/**Path of a file in the client directory, creating the directories it is in. Names coming from the server are
+ * checked, so they can't point outside of the client directory */
+async fn local_path(filename: &str) -> std::io::Result<PathBuf> {
+    if !is_valid_path(filename) {
+        return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, format!("{} is not a valid path", filename)));
+    }
+    let path = Path::new(DATA_DIR).join(filename);
+    tokio::fs::create_dir_all(path.parent().unwrap_or(Path::new(DATA_DIR))).await?;
+    Ok(path)
+}
+
+async fn save_file(filename: String, data: Vec<u8>, merkleproof: Vec<Vec<u8>>, root: OsString) -> String {
+    /* Convert from Vec<Vec<u8>> to Vec<OSString> and try to verify before saving */
+    let proof: Vec<OsString> = merkleproof.iter().map(|v| OsString::from(String::from_utf8(v.to_vec()).unwrap_or_default())).collect();
+
+    let is_proof_valid = MerkleTree::verify_data_with_proof(&filename, &data, proof, root).await;
+    println!("Is proof for file {} valid: {}", filename, is_proof_valid);
+
+    let file = match local_path(&filename).await {
+        Ok(path) => tokio::fs::File::create(path).await,
+        Err(e) => Err(e),
+    };
+    if let Ok(mut file) = file {
+        let _ = file.write_all(data.as_slice()).await;
+        format!("Saved file {}", filename)
+    } else {
+        format!("Unable to save file {}", filename)
+    }
+}
+
+/**Keep the root hash around for later runs, so `download` can verify files on its own. Every root we trusted
+ * is also added to the history, older versions of files are verified against the root of their epoch */
+async fn store_root(root: OsString) {
+    ROOT.with(|r| r.replace(root.clone()));
+    let root = root.into_string().unwrap_or_default();
+    let _ = tokio::fs::create_dir_all(STATE_DIR).await;
+    let _ = tokio::fs::write(Path::new(STATE_DIR).join("root"), root.as_str()).await;
+
+    if !is_trusted_root(&root).await {
+        let history = tokio::fs::OpenOptions::new().create(true).append(true).open(Path::new(STATE_DIR).join("roots")).await;
+        if let Ok(mut history) = history {
+            let _ = history.write_all(format!("{}\n", root).as_bytes()).await;
+        }
+    }
+}
+
+/* Whether we trusted a root hash at some point */
+async fn is_trusted_root(root: &str) -> bool {
+    let history = tokio::fs::read_to_string(Path::new(STATE_DIR).join("roots")).await.unwrap_or_default();
+    !root.is_empty() && history.lines().any(|line| line == root)
+}
+
+async fn load_root() {
+    if let Ok(root) = tokio::fs::read_to_string(Path::new(STATE_DIR).join("root")).await {
+        ROOT.with(|r| r.replace(OsString::from(root)));
+    } else {
+        println!("No trusted root hash found, downloads can't be verified");
+    }
+}
+
+/* Tree of the files in the client directory, without the ignored ones */
+async fn local_tree() -> MerkleTree {
+    let mt = MerkleTree::from_folder(Path::new(DATA_DIR)).await;
+    let ignored = IGNORED.with(|i| i.borrow().clone());
+    MerkleTree::from_files(mt.files.into_iter()
+        .filter(|(path, _)| !ignored.is_ignored(path))
+        .map(|(path, hash)| (path, hash.to_string_lossy().into_owned())))
+}
+
+/* Recompute the root hash from the local files, or from what we uploaded when encrypting, and store it */
+async fn update_root() {
+    if let Some(root) = local_tree().await.root {
+        println!("Merkle tree root hash: {:?}", root);
+        store_root(root).await;
+    }
+}
+
+/**Trust the root of the server's tree after changes to files encrypted end-to-end. Their ciphertext isn't kept
+ * locally, so the root comes from a recursive listing of the server instead: the listing has to add up to its root,
+ * that root has to be one the server acked for one of our changes, and the files we changed have to be in it the
+ * way we left them. Otherwise the trusted root stays where it was */
+async fn update_e2e_root(flow: &mut Hydroflow, input: &UnboundedSender<Message>) {
+    let acked = ACKED.with(|a| a.borrow().clone());
+    if acked.is_empty() {
+        return;
+    }
+    let (node, entries) = match fetch_listing(flow, input, "", true).await {
+        Some(Some(pages)) => {
+            let (node, _, entries) = merge_pages(pages);
+            (node, entries)
+        }
+        /* Nothing left on the server */
+        Some(None) => (String::new(), Vec::new()),
+        None => {
+            println!("Unable to list the server, the trusted root stays where it was");
+            return;
+        }
+    };
+    if !node.is_empty() && !rebuilds_node("", &node, entries.as_slice()) {
+        println!("Listing of the server doesn't add up to its root {}, not trusting it", node);
+        return;
+    }
+    if !acked.values().any(|root| *root == node) {
+        println!("The server changed since our changes, not trusting its root {}", node);
+        return;
+    }
+
+    let listed = entries.iter()
+        .filter(|entry| !entry.hidden && !entry.is_dir)
+        .map(|entry| (entry.path.as_str(), entry.hash.as_str()))
+        .collect::<HashMap<&str, &str>>();
+    let written = FileIndex::load(&Path::new(STATE_DIR).join(E2E_INDEX)).unwrap_or_default();
+    if let Some(filename) = acked.keys().find(|filename| listed.get(filename.as_str()).copied() != written.get(filename).map(String::as_str)) {
+        println!("{} isn't on the server the way we left it, not trusting its root {}", filename, node);
+        return;
+    }
+    println!("Merkle tree root hash: {:?}", node);
+    store_root(OsString::from(node)).await;
+}
+
+/* A late UploadSessionNotFound must not hide that the upload already went through */
+fn set_upload_state(session_id: String, state: UploadState) {
+    UPLOADS.with(|u| {
+        let mut uploads = u.borrow_mut();
+        if !matches!(uploads.get(&session_id), Some(UploadState::Done)) {
+            uploads.insert(session_id, state);
+        }
+    });
+}
+
+/* Verify and write a received file in a separate task, so the flow keeps processing replies meanwhile */
+fn spawn_save_file(filename: String, data: Vec<u8>, merkleproof: Vec<Vec<u8>>) {
+    let root = ROOT.with(|r| r.borrow().clone());
+    let log = LOG.with(|log| log.borrow().clone());
+    tokio::spawn(async move {
+        let line = save_file(filename, data, merkleproof, root).await;
+        if let Some(log) = log {
+            let _ = log.send(line);
+        }
+    });
+}
+
+/**Write chunks of resumable downloads from one task, so updates of a manifest can't overwrite each other. Chunks
+ * that queued up while the last ones were written are saved together, with one manifest write per download */
+fn spawn_chunk_writer() -> UnboundedSender<ChunkWrite> {
+    let (chunks, mut queue) = tokio::sync::mpsc::unbounded_channel::<ChunkWrite>();
+    let log = LOG.with(|log| log.borrow().clone());
+    tokio::spawn(async move {
+        while let Some(first) = queue.recv().await {
+            let mut batch = vec![first];
+            while batch.len() < CHUNK_WINDOW {
+                match queue.try_recv() {
+                    Ok(chunk) => batch.push(chunk),
+                    Err(_) => break,
+                }
+            }
+
+            let mut downloads = ChunkBatch::new();
+            for (filename, version, index, data) in batch {
+                downloads.entry((filename, version)).or_default().push((index, data));
+            }
+            for ((filename, version), chunks) in downloads {
+                for line in save_chunks(Path::new(STATE_DIR), &filename, version, chunks).await {
+                    if let Some(log) = log.as_ref() {
+                        let _ = log.send(line);
+                    }
+                }
+            }
+        }
+    });
+    chunks
+}
+
+fn queue_chunk(filename: String, version: Option<u64>, index: u32, data: Vec<u8>) {
+    CHUNKS.with(|chunks| {
+        if let Some(chunks) = chunks.borrow().as_ref() {
+            let _ = chunks.send((filename, version, index, data));
+        }
+    });
+}
+
+/**Next heartbeat for the server, noting that the previous one wasn't answered if nothing came back since */
+fn heartbeat() -> Message {
+    let missed = MISSED_HEARTBEATS.with(|m| {
+        *m.borrow_mut() += 1;
+        *m.borrow()
+    });
+    if missed > MAX_MISSED_HEARTBEATS && SERVER_REACHABLE.with(|r| r.replace(false)) {
+        println!("Server is unreachable, no answer to the last {} heartbeats", missed - 1);
+    }
+    Message::Heartbeat
+}
+
+/* Anything from the server shows it's alive */
+fn heard_from_server() {
+    MISSED_HEARTBEATS.with(|m| m.replace(0));
+    if !SERVER_REACHABLE.with(|r| r.replace(true)) {
+        println!("Server is reachable again");
+    }
+}
+
+fn server_unreachable() -> bool {
+    !SERVER_REACHABLE.with(|r| *r.borrow())
+}
+
+/* Give the flow some time to send our requests and process the replies */
+async fn run_for(flow: &mut Hydroflow, timeout: Duration) {
+    let _ = tokio::time::timeout(timeout, flow.run_async()).await;
+}
+
+/* Wait for the replies to our requests, while the flow runs next to us in with_flow */
+async fn wait_for_replies() {
+    tokio::time::sleep(REPLY_TIMEOUT).await;
+}
+
+/**Run transfers with the flow running next to them, so several of them can wait for their replies at once.
+ * None if the flow stopped before the transfers were done */
+async fn with_flow<F: Future>(flow: &mut Hydroflow, transfers: F) -> Option<F::Output> {
+    tokio::select! {
+        output = transfers => Some(output),
+        _ = flow.run_async() => None,
+    }
+}
+
+/* Run transfers `parallel` at a time, returns how many of them succeeded */
+async fn transfer_all<F: Future<Output = bool>>(transfers: impl Iterator<Item = F>, parallel: usize) -> usize {
+    stream::iter(transfers)
+        .buffer_unordered(parallel.max(1))
+        .filter(|done| future::ready(*done))
+        .count()
+        .await
+}
+
+/* Set the ignore patterns from the ignore file in the client directory, from earlier runs and from the command line */
+fn set_ignored(patterns: &[String]) {
+    IGNORED.with(|i| i.replace(IgnorePatterns::load(Path::new(DATA_DIR), &Path::new(STATE_DIR).join(IGNORE_STATE), patterns)));
+}
+
+/* "." and "" are the whole directory, trailing slashes are dropped */
+fn directory_path(path: &str) -> &str {
+    match path.trim_matches('/') {
+        "." => "",
+        path => path,
+    }
+}
+
+/* Files below the given directories of the client directory that aren't ignored, files given directly are kept */
+fn local_files(paths: &[String]) -> Vec<String> {
+    let ignored = IGNORED.with(|i| i.borrow().clone());
+    let mut files = Vec::new();
+    for path in paths.iter().map(|path| directory_path(path)) {
+        if !Path::new(DATA_DIR).join(path).is_dir() {
+            files.push(path.to_string());
+            continue;
+        }
+        match list_files(Path::new(DATA_DIR), path) {
+            Ok(found) => files.extend(found.into_iter().filter(|file| !ignored.is_ignored(file))),
+            Err(e) => println!("Unable to read directory {}: {}", path, e),
+        }
+    }
+    files
+}
+
+/**Validate our address with the server before asking for anything large. We send a token of zeroes, which
+ * the server can't accept, but it makes the request as large as the Retry it's answered with. The flow
+ * echoes the token of the Retry right away, the same as for a Retry we get later on */
+async fn validate_address(flow: &mut Hydroflow, input: &UnboundedSender<Message>) -> bool {
+    for _ in 0..MAX_RETRIES {
+        if VALIDATED.with(|v| *v.borrow()) {
+            return true;
+        }
+        let _ = input.send(Message::ValidateAddress { token: vec![0; TOKEN_LEN] });
+        run_for(flow, REPLY_TIMEOUT).await;
+    }
+    VALIDATED.with(|v| *v.borrow())
+}
+
+/**Authenticate with the server by answering its challenge with an HMAC made with our pre-shared key */
+async fn authenticate(flow: &mut Hydroflow, input: &UnboundedSender<Message>, identity: &str, key: &[u8]) -> bool {
+    for _ in 0..MAX_RETRIES {
+        if AUTHENTICATED.with(|a| a.borrow().is_some()) {
+            return true;
+        }
+
+        match CHALLENGE.with(|c| c.borrow_mut().take()) {
+            Some(nonce) => { let _ = input.send(Message::ChallengeResponse { mac: sign_challenge(key, identity, nonce.as_slice()) }); }
+            None => { let _ = input.send(Message::Hello { identity: identity.to_string() }); }
+        }
+        run_for(flow, REPLY_TIMEOUT).await;
+    }
+
+    AUTHENTICATED.with(|a| a.borrow().is_some())
+}
+
+/* Upload sessions are bound to the identity the server knows us by */
+fn own_session_id(filename: &str, hash: &str) -> String {
+    AUTHENTICATED.with(|a| upload_session_id(a.borrow().as_ref().and_then(|identity| identity.as_deref()), filename, hash))
+}
+
+/* Keep track of the encrypted files we wrote, the server's listing has to have them before its root is trusted */
+fn update_e2e_index(update: impl FnOnce(&mut FileIndex)) {
+    let path = Path::new(STATE_DIR).join(E2E_INDEX);
+    let mut index = FileIndex::load(&path).unwrap_or_default();
+    update(&mut index);
+    if let Err(e) = std::fs::create_dir_all(STATE_DIR).and_then(|_| index.save(&path)) {
+        println!("Unable to save the index of encrypted files: {}", e);
+    }
+}
+
+/**Upload a file in chunks, asking the server for the missing ones until it acknowledges the file.
+ * With a master key the contents and the name are encrypted first, the server only sees the ciphertext */
+async fn upload_file(input: &UnboundedSender<Message>, local_name: &str, key: Option<&MasterKey>, preconditions: &Preconditions) -> bool {
+    let data = match tokio::fs::read(Path::new(DATA_DIR).join(local_name)).await {
+        Ok(data) => data,
+        Err(_) => {
+            println!("Unable to read file {}", local_name);
+            return false;
+        }
+    };
+    let (filename, data) = match key {
+        Some(key) => (key.encrypt_filename(local_name), key.encrypt(local_name, data.as_slice())),
+        None => (local_name.to_string(), data),
+    };
+    let filename = filename.as_str();
+    let hash = blake3::hash(data.as_slice()).to_string();
+    let session_id = own_session_id(filename, &hash);
+    let start = Message::UploadStart { filename: filename.to_string(), hash: hash.clone(), size: data.len() as u64, preconditions: Box::new(preconditions.clone()) };
+
+    UPLOADS.with(|u| u.borrow_mut().remove(&session_id));
+    PRECONDITION_FAILED.with(|p| p.borrow_mut().remove(filename));
+    ACKED.with(|a| a.borrow_mut().remove(filename));
+    let _ = input.send(start.clone());
+
+    for _ in 0..MAX_RETRIES {
+        wait_for_replies().await;
+        if server_unreachable() {
+            println!("Server is unreachable, giving up on {}", filename);
+            return false;
+        }
+        if PRECONDITION_FAILED.with(|p| p.borrow_mut().remove(filename)) {
+            println!("Upload of {} was turned down, its preconditions don't hold", local_name);
+            return false;
+        }
+
+        match UPLOADS.with(|u| u.borrow_mut().remove(&session_id)) {
+            Some(UploadState::Done) => {
+                if key.is_some() {
+                    update_e2e_index(|index| { index.insert(filename.to_string(), hash.clone(), FileMeta { size: data.len() as u64, owner: None }); });
+                }
+                return true;
+            }
+            Some(UploadState::Missing(missing)) => {
+                println!("Sending {} chunks of {}", missing.len(), filename);
+                for index in missing {
+                    let from = index as usize * CHUNK_SIZE;
+                    if from >= data.len() {
+                        continue;
+                    }
+                    let to = usize::min(from + CHUNK_SIZE, data.len());
+                    let _ = input.send(Message::UploadChunk { session_id: session_id.clone(), index, data: data[from..to].to_vec() });
+                }
+                let _ = input.send(Message::ResumeUpload { session_id: session_id.clone() });
+            }
+            /* The session expired or we never heard back, start (or join) it again */
+            Some(UploadState::NotFound) | None => {
+                let _ = input.send(start.clone());
+            }
+        }
+    }
+
+    println!("Upload of {} didn't finish, run upload again to resume", local_name);
+    false
+}
+
+/* Ask the server for size, hash and proof of a file or of one of its kept versions */
+async fn fetch_file_info(input: &UnboundedSender<Message>, filename: &str, version: Option<u64>) -> Option<Option<RemoteFile>> {
+    REMOTE_FILES.with(|f| f.borrow_mut().remove(filename));
+
+    for _ in 0..MAX_RETRIES {
+        let _ = input.send(Message::FileInfoRequest { filename: filename.to_string(), version });
+        wait_for_replies().await;
+        if server_unreachable() {
+            return None;
+        }
+
+        /* A late answer for another version of the file is dropped */
+        match REMOTE_FILES.with(|f| f.borrow_mut().remove(filename)) {
+            Some(Some(remote)) if remote.version != version => {}
+            Some(remote) => return Some(remote),
+            None => {}
+        }
+    }
+    None
+}
+
+/**Download a file, or one of its kept versions, in chunks into a `.part` file, continuing a previous partial download
+ * of the same contents */
+async fn download_file(input: &UnboundedSender<Message>, local_name: &str, version: Option<u64>, key: Option<&MasterKey>) -> bool {
+    let dir = Path::new(STATE_DIR);
+    let filename = key.map_or(local_name.to_string(), |key| key.encrypt_filename(local_name));
+    let filename = filename.as_str();
+
+    let remote = match fetch_file_info(input, filename, version).await {
+        Some(Some(remote)) => remote,
+        Some(None) => return false,
+        None => {
+            println!("Server didn't answer, unable to download {}", filename);
+            return false;
+        }
+    };
+
+    let mut manifest = match DownloadManifest::load(dir, filename).await {
+        Some(manifest) if manifest.hash == remote.hash && manifest.version == version => {
+            println!("Resuming download of {}, {} chunks left", filename, manifest.missing().len());
+            manifest
+        }
+        _ => {
+            let Some(manifest) = DownloadManifest::new(filename, version, remote.hash, remote.size, remote.merkle_proof, remote.root) else {
+                println!("Unable to download {}, it's too large to transfer in chunks", filename);
+                return false;
+            };
+            if let Err(e) = manifest.create(dir).await {
+                println!("Unable to start download of {}: {}", filename, e);
+                return false;
+            }
+            manifest
+        }
+    };
+
+    /* Keep asking for missing chunks as long as we're making progress */
+    let mut retries = 0;
+    while !manifest.missing().is_empty() && retries < MAX_RETRIES {
+        let missing = manifest.missing();
+        for index in missing.iter().take(CHUNK_WINDOW) {
+            let _ = input.send(Message::FileChunkRequest { filename: filename.to_string(), version, index: *index });
+        }
+        wait_for_replies().await;
+        if server_unreachable() {
+            break;
+        }
+
+        manifest = DownloadManifest::load(dir, filename).await.unwrap_or(manifest);
+        if manifest.missing().len() < missing.len() {
+            retries = 0;
+        } else {
+            retries += 1;
+        }
+    }
+
+    if !manifest.missing().is_empty() {
+        println!("Download of {} interrupted, run download again to resume", filename);
+        return false;
+    }
+
+    finish_download(manifest, local_name, key).await
+}
+
+/**Verify a completed `.part` file and move it into place, decrypting it first when it's encrypted. The current
+ * contents are checked against the trusted root, a kept version against the root of the epoch it was made in,
+ * which has to be a root we trusted at some point */
+async fn finish_download(manifest: DownloadManifest, local_name: &str, key: Option<&MasterKey>) -> bool {
+    let dir = Path::new(STATE_DIR);
+    let data = tokio::fs::read(part_path(dir, &manifest.filename)).await.unwrap_or_default();
+    let proof: Vec<OsString> = manifest.merkle_proof.iter().map(|v| OsString::from(String::from_utf8(v.to_vec()).unwrap_or_default())).collect();
+    let (root, is_root_trusted) = match manifest.root.as_deref() {
+        Some(root) => (OsString::from(root), is_trusted_root(root).await),
+        None => (ROOT.with(|r| r.borrow().clone()), true),
+    };
+
+    let is_proof_valid = MerkleTree::verify_data_with_proof(&manifest.filename, &data, proof, root).await;
+    match manifest.version {
+        Some(version) => println!("Is proof for version {} of {} valid: {}, against a trusted root: {}", version, manifest.filename, is_proof_valid, is_root_trusted),
+        None => println!("Is proof for file {} valid: {}", manifest.filename, is_proof_valid),
+    }
+
+    if !is_proof_valid || !is_root_trusted {
+        DownloadManifest::discard(dir, &manifest.filename).await;
+        return false;
+    }
+
+    let saved = match (local_path(local_name).await, key) {
+        (Err(e), _) => Err(e),
+        (Ok(path), Some(key)) => match key.decrypt(local_name, data.as_slice()) {
+            Some(plaintext) => write_file_atomic(&path, plaintext.as_slice()),
+            None => Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "unable to decrypt")),
+        },
+        (Ok(path), None) => tokio::fs::rename(part_path(dir, &manifest.filename), path).await,
+    };
+
+    /* A plain `.part` file that couldn't be moved is kept, there's nothing wrong with it */
+    if saved.is_ok() || key.is_some() {
+        DownloadManifest::discard(dir, &manifest.filename).await;
+    }
+    match saved {
+        Ok(_) => {
+            println!("Saved file {}", local_name);
+            true
+        }
+        Err(e) => {
+            println!("Unable to save file {}: {}", local_name, e);
+            false
+        }
+    }
+}
+
+/* Ask the server which versions of a file it keeps */
+async fn fetch_versions(flow: &mut Hydroflow, input: &UnboundedSender<Message>, filename: &str) -> Option<Vec<VersionInfo>> {
+    VERSIONS.with(|v| v.borrow_mut().remove(filename));
+
+    for _ in 0..MAX_RETRIES {
+        let _ = input.send(Message::ListVersions { filename: filename.to_string() });
+        run_for(flow, REPLY_TIMEOUT).await;
+        if server_unreachable() {
+            return None;
+        }
+
+        if let Some(versions) = VERSIONS.with(|v| v.borrow_mut().remove(filename)) {
+            return Some(versions);
+        }
+    }
+    None
+}
+
+/**Restore a version of a file on the server. With end-to-end encryption the index of our uploads follows along,
+ * without it the trusted root is only updated once the restored file is downloaded again */
+async fn restore_version(flow: &mut Hydroflow, input: &UnboundedSender<Message>, local_name: &str, version: u64, key: Option<&MasterKey>) -> bool {
+    let filename = key.map_or(local_name.to_string(), |key| key.encrypt_filename(local_name));
+    let info = match fetch_versions(flow, input, &filename).await.and_then(|versions| versions.into_iter().find(|v| v.version == version)) {
+        Some(info) => info,
+        None => {
+            println!("Server doesn't keep version {} of {}", version, local_name);
+            return false;
+        }
+    };
+
+    /* Restoring adds a version every time, so it's sent only once and we just wait for the answer */
+    let _ = input.send(Message::Restore { filename: filename.clone(), version });
+    let session_id = info.hash.as_ref().map(|hash| own_session_id(&filename, hash));
+    let mut restored = false;
+    for _ in 0..MAX_RETRIES {
+        run_for(flow, REPLY_TIMEOUT).await;
+        restored = match session_id.as_ref() {
+            Some(session_id) => UPLOADS.with(|u| matches!(u.borrow_mut().remove(session_id), Some(UploadState::Done))),
+            None => DELETED.with(|d| d.borrow_mut().remove(&filename)),
+        };
+        if restored || server_unreachable() {
+            break;
+        }
+    }
+
+    if restored && key.is_some() {
+        update_e2e_index(|index| match info.hash {
+            Some(hash) => { index.insert(filename.clone(), hash, FileMeta { size: info.size, owner: None }); }
+            None => { index.remove(&filename); }
+        });
+    }
+    restored
+}
+
+/* Fetch all pages of a directory listing, Some(None) if the directory doesn't exist and None if the server didn't answer */
+async fn fetch_listing(flow: &mut Hydroflow, input: &UnboundedSender<Message>, path: &str, recursive: bool) -> Option<Option<Vec<Listing>>> {
+    REMOTE_FILES.with(|f| f.borrow_mut().remove(path));
+
+    let mut pages = Vec::new();
+    let mut offset = Some(0);
+    while let Some(next) = offset {
+        let mut page = None;
+        for _ in 0..MAX_RETRIES {
+            let _ = input.send(Message::ListRequest { path: path.to_string(), recursive, offset: next });
+            run_for(flow, REPLY_TIMEOUT).await;
+            page = LISTINGS.with(|l| l.borrow_mut().remove(&(path.to_string(), next)));
+            if page.is_some() || server_unreachable() {
+                break;
+            }
+            if REMOTE_FILES.with(|f| f.borrow_mut().remove(path)).is_some() {
+                return Some(None);
+            }
+        }
+        let page = page?;
+        /* A page that doesn't move the offset forward would have us asking for it forever */
+        if page.next.is_some_and(|after| after <= next) {
+            println!("Listing of {} doesn't make progress, giving up", if path.is_empty() { "/" } else { path });
+            return None;
+        }
+        offset = page.next;
+        pages.push(*page);
+    }
+    Some(Some(pages))
+}
+
+/**Check a complete listing against the trusted root. The node of the directory is checked with its proof, and then
+ * rebuilt from the entries */
+fn verify_listing(path: &str, node: &str, merkle_proof: &[Vec<u8>], entries: &[DirEntry]) -> bool {
+    let root = ROOT.with(|r| r.borrow().clone());
+    let proof: Vec<OsString> = merkle_proof.iter().map(|v| OsString::from(String::from_utf8(v.to_vec()).unwrap_or_default())).collect();
+    MerkleTree::verify_node_with_proof(path, OsString::from(node), proof, root) && rebuilds_node(path, node, entries)
+}
+
+/* Whether the entries of a listing make up the node of the directory */
+fn rebuilds_node(path: &str, node: &str, entries: &[DirEntry]) -> bool {
+    listing_node(path, entries) == Some(OsString::from(node))
+}
+
+/**Node of a directory from the entries of its listing, in the order of the tree. Files count with their contents,
+ * subdirectories and hidden entries with their nodes, and the entries further down in a recursive listing make up
+ * the node of the subdirectory they're in. None if an entry isn't below the directory */
+fn listing_node(path: &str, entries: &[DirEntry]) -> Option<OsString> {
+    let prefix = if path.is_empty() { String::new() } else { format!("{}/", path) };
+    /* Subdirectory an entry belongs to, None for an entry right in the directory */
+    let subdir = |entry: &DirEntry| -> Option<Option<String>> {
+        if entry.hidden && entry.path == path {
+            return Some(None);
+        }
+        let name = entry.path.strip_prefix(prefix.as_str()).filter(|name| !name.is_empty())?;
+        Some(match name.split_once('/') {
+            Some((dir, _)) => Some(format!("{}{}", prefix, dir)),
+            None if entry.hidden => Some(entry.path.clone()),
+            None => None,
+        })
+    };
+
+    let mut children = Vec::new();
+    let mut i = 0;
+    while i < entries.len() {
+        match subdir(&entries[i])? {
+            None => {
+                let entry = &entries[i];
+                children.push(match entry.is_dir || entry.hidden {
+                    true => OsString::from(&entry.hash),
+                    false => MerkleTree::file_node(&entry.path, &entry.hash),
+                });
+                i += 1;
+            }
+            Some(dir) => {
+                let len = entries[i..].iter().take_while(|entry| subdir(entry).flatten().as_ref() == Some(&dir)).count();
+                children.push(listing_node(&dir, &entries[i..i + len])?);
+                i += len;
+            }
+        }
+    }
+    Some(MerkleTree::directory_node(path, children))
+}
+
+/* Node, proof and entries of a listing from its pages. The tree may have changed while the pages came in,
+ * only pages of the same tree as the first one are kept */
+fn merge_pages(pages: Vec<Listing>) -> (String, Vec<Vec<u8>>, Vec<DirEntry>) {
+    let node = pages.first().map(|page| page.node.clone()).unwrap_or_default();
+    let merkle_proof = pages.first().map(|page| page.merkle_proof.clone()).unwrap_or_default();
+    let entries = pages.into_iter()
+        .filter(|page| page.node == node)
+        .flat_map(|page| page.entries)
+        .collect::<Vec<DirEntry>>();
+    (node, merkle_proof, entries)
+}
+
+/**Files below the given directories on the server that aren't ignored, from recursive listings checked against
+ * the trusted root. Encrypted names are flat on the server, with a master key everything is listed and the
+ * decrypted names are matched against the directories instead */
+async fn remote_files(flow: &mut Hydroflow, input: &UnboundedSender<Message>, paths: &[String], key: Option<&MasterKey>) -> Vec<String> {
+    let ignored = IGNORED.with(|i| i.borrow().clone());
+    let mut files = Vec::new();
+    for path in paths.iter().map(|path| directory_path(path)) {
+        let listed = if key.is_some() { "" } else { path };
+        let (node, merkle_proof, entries) = match fetch_listing(flow, input, listed, true).await {
+            Some(Some(pages)) => merge_pages(pages),
+            _ => {
+                println!("Unable to list {}", if path.is_empty() { "/" } else { path });
+                continue;
+            }
+        };
+        if !verify_listing(listed, &node, merkle_proof.as_slice(), entries.as_slice()) {
+            println!("Listing of {} doesn't match the trusted root, not downloading it", if path.is_empty() { "/" } else { path });
+            continue;
+        }
+
+        let prefix = format!("{}/", path);
+        files.extend(entries.into_iter()
+            .filter(|entry| !entry.is_dir && !entry.hidden)
+            .filter_map(|entry| match key {
+                Some(key) => key.decrypt_filename(&entry.path),
+                None => Some(entry.path),
+            })
+            .filter(|name| path.is_empty() || name.starts_with(&prefix))
+            .filter(|name| !ignored.is_ignored(name)));
+    }
+    files
+}
+
+/**List a directory on the server and check the listing against the trusted root. With end-to-end encryption
+ * the names are decrypted for display */
+async fn list_directory(flow: &mut Hydroflow, input: &UnboundedSender<Message>, path: &str, recursive: bool, key: Option<&MasterKey>) -> bool {
+    let pages = match fetch_listing(flow, input, path, recursive).await {
+        Some(Some(pages)) => pages,
+        _ => {
+            println!("Unable to list {}", if path.is_empty() { "/" } else { path });
+            return false;
+        }
+    };
+
+    let (node, merkle_proof, entries) = merge_pages(pages);
+    for entry in entries.iter().filter(|entry| !entry.hidden) {
+        let name = key.and_then(|key| key.decrypt_filename(&entry.path)).unwrap_or_else(|| entry.path.clone());
+        println!("{} {:>12} {}", if entry.is_dir { "d" } else { "-" }, entry.size, name);
+    }
+    let hidden = entries.iter().filter(|entry| entry.hidden).count();
+    if hidden > 0 {
+        println!("{} entries you may not read are hidden", hidden);
+    }
+    let is_valid = verify_listing(path, &node, merkle_proof.as_slice(), entries.as_slice());
+    println!("Is listing of {} valid: {}", if path.is_empty() { "/" } else { path }, is_valid);
+    is_valid
+}
+
+/**Rename or copy a file on the server, returns the root the server acked. A rename only goes through if `from` has
+ * the hash in `if_match` and `to` doesn't have the one in `if_none_match`, when they are given */
+async fn request_move(input: &UnboundedSender<Message>, from: &str, to: &str, copy: bool, if_match: Option<String>, if_none_match: Option<String>) -> Option<String> {
+    ACKED.with(|a| a.borrow_mut().remove(to));
+    REMOTE_FILES.with(|f| f.borrow_mut().remove(from));
+
+    /* Renames can't be repeated, so the request is sent once and we just wait for the answer */
+    let request = if copy {
+        Message::CopyRequest { from: from.to_string(), to: to.to_string() }
+    } else {
+        Message::RenameRequest { from: from.to_string(), to: to.to_string(), if_match, if_none_match }
+    };
+    let _ = input.send(request);
+    for _ in 0..MAX_RETRIES {
+        wait_for_replies().await;
+        let root = ACKED.with(|a| a.borrow_mut().remove(to));
+        if root.is_some() {
+            return root;
+        }
+        if server_unreachable() || REMOTE_FILES.with(|f| f.borrow_mut().remove(from)).is_some() {
+            break;
+        }
+    }
+    println!("Unable to {} {} to {}", if copy { "copy" } else { "rename" }, from, to);
+    None
+}
+
+/**Rename or copy a file on the server without sending it again. The file in the client directory follows along,
+ * so the trusted root computed from it matches the root the server acked. Returns false if either side failed */
+async fn move_file(flow: &mut Hydroflow, input: &UnboundedSender<Message>, from: &str, to: &str, copy: bool, key: Option<&MasterKey>) -> bool {
+    if key.is_some() {
+        println!("Encrypted files are bound to their name, download {} and upload it as {} instead", from, to);
+        return false;
+    }
+    let root = match with_flow(flow, request_move(input, from, to, copy, None, None)).await.flatten() {
+        Some(root) => root,
+        None => return false,
+    };
+
+    let (local_from, local_to) = (Path::new(DATA_DIR).join(from), Path::new(DATA_DIR).join(to));
+    if local_from.exists() {
+        if let Some(parent) = local_to.parent() {
+            let _ = tokio::fs::create_dir_all(parent).await;
+        }
+        let moved = if copy {
+            tokio::fs::copy(&local_from, &local_to).await.map(|_| ())
+        } else {
+            tokio::fs::rename(&local_from, &local_to).await
+        };
+        if let Err(e) = moved {
+            println!("Unable to update local file {}: {}", from, e);
+            update_root().await;
+            return false;
+        }
+    }
+    update_root().await;
+    if ROOT.with(|r| r.borrow().to_string_lossy() != root) {
+        println!("Local files don't match the server, the server's root is {}", root);
+    }
+    true
+}
+
+/* Delete a file on the server and wait for the server to confirm it */
+async fn delete_file(input: &UnboundedSender<Message>, filename: &str, preconditions: Preconditions) -> bool {
+    DELETED.with(|d| d.borrow_mut().remove(filename));
+    PRECONDITION_FAILED.with(|p| p.borrow_mut().remove(filename));
+    ACKED.with(|a| a.borrow_mut().remove(filename));
+
+    /* Sent once, a conditional delete that went through would fail when repeated */
+    let _ = input.send(Message::DeleteFileRequest { filename: filename.to_string(), preconditions: Box::new(preconditions) });
+    for _ in 0..MAX_RETRIES {
+        wait_for_replies().await;
+        if DELETED.with(|d| d.borrow_mut().remove(filename)) {
+            return true;
+        }
+        if server_unreachable() || PRECONDITION_FAILED.with(|p| p.borrow_mut().remove(filename)) {
+            break;
+        }
+    }
+    println!("Unable to delete {} from server", filename);
+    false
+}
+
+/* Remove a file from the client directory, together with the directories it leaves empty */
+async fn remove_local_file(filename: &str) -> bool {
+    if let Err(e) = tokio::fs::remove_file(Path::new(DATA_DIR).join(filename)).await {
+        println!("Unable to delete local file {}: {}", filename, e);
+        return false;
+    }
+    let mut dir = Path::new(filename).parent();
+    while let Some(parent) = dir.filter(|parent| !parent.as_os_str().is_empty()) {
+        if tokio::fs::remove_dir(Path::new(DATA_DIR).join(parent)).await.is_err() {
+            break;
+        }
+        dir = parent.parent();
+    }
+    true
+}
+
+/**Files that differ between the local tree and the server's tree, together with the root of the server's tree.
+ * The server's tree is walked from the root down, one listing per directory, and a directory is only listed when
+ * its node isn't the same on both sides. Every listing must make up the node its parent listed for the directory */
+async fn remote_diff(flow: &mut Hydroflow, input: &UnboundedSender<Message>, local: &MerkleTree) -> Option<(OsString, BTreeMap<String, Difference>)> {
+    let ignored = IGNORED.with(|i| i.borrow().clone());
+    let mut root = OsString::new();
+    let mut diff = BTreeMap::new();
+    let mut dirs = vec![(String::new(), None)];
+    while let Some((dir, expected)) = dirs.pop() {
+        /* A directory that isn't there anymore has been emptied since its parent was listed, nothing is left in it */
+        let (node, entries) = match fetch_listing(flow, input, &dir, false).await? {
+            Some(pages) => {
+                let (node, _, entries) = merge_pages(pages);
+                (node, entries)
+            }
+            None => (String::new(), Vec::new()),
+        };
+        if !node.is_empty() && (!rebuilds_node(&dir, &node, entries.as_slice()) || expected.is_some_and(|expected| expected != node)) {
+            println!("Listing of {} doesn't match the server's tree", if dir.is_empty() { "/" } else { dir.as_str() });
+            return None;
+        }
+        if dir.is_empty() {
+            root = OsString::from(&node);
+        }
+
+        let mut local_only = local.children(&dir).into_iter().collect::<BTreeSet<String>>();
+        /* Hidden entries are out of our hands, whatever is at their place here stays local */
+        for entry in entries.into_iter().filter(|entry| !entry.hidden && !ignored.is_ignored(&entry.path)) {
+            local_only.remove(&entry.path);
+            let local_file = local.files.get(&entry.path).map(|hash| hash.to_string_lossy().into_owned());
+            if entry.is_dir {
+                if local.dirs.get(&entry.path) != Some(&OsString::from(&entry.hash)) {
+                    dirs.push((entry.path.clone(), Some(entry.hash)));
+                }
+                if local_file.is_some() {
+                    diff.insert(entry.path, (local_file, None));
+                }
+            } else {
+                if local.dirs.contains_key(&entry.path) {
+                    local_only.insert(entry.path.clone());
+                }
+                if local_file.as_deref() != Some(entry.hash.as_str()) {
+                    diff.insert(entry.path, (local_file, Some(entry.hash)));
+                }
+            }
+        }
+
+        /* Everything below what's only here, the server has none of it */
+        for path in local_only {
+            let below = format!("{}/", path);
+            diff.extend(local.files.iter()
+                .filter(|(file, _)| **file == path || file.starts_with(&below))
+                .map(|(file, hash)| (file.clone(), (Some(hash.to_string_lossy().into_owned()), None))));
+        }
+    }
+    Some((root, diff))
+}
+
+/**What to do about a file that differs, from its hash here, on the server and after the last sync. The side that
+ * still has the hash of the last sync didn't change, the other side's change is applied to it. A file that changed
+ * on both sides is a conflict, decided by the policy, None leaves it alone */
+fn sync_action(local: Option<&str>, remote: Option<&str>, base: Option<&str>, policy: &ConflictPolicy) -> Option<SyncAction> {
+    let push = if local.is_some() { SyncAction::Upload } else { SyncAction::DeleteRemote };
+    let pull = if remote.is_some() { SyncAction::Download } else { SyncAction::DeleteLocal };
+    if remote == base {
+        Some(push)
+    } else if local == base {
+        Some(pull)
+    } else {
+        match policy {
+            ConflictPolicy::Skip => None,
+            ConflictPolicy::Local => Some(push),
+            ConflictPolicy::Remote => Some(pull),
+        }
+    }
+}
+
+/**Bring the client directory and the server in line, transferring only the files that differ. Changes are applied
+ * on the condition that the file on the server is still the one we compared with. The root of the server's tree
+ * must be one we trusted before, or the one named by `accept_root` after checking it some other way, and downloads
+ * are verified against it. Afterwards the files that are the same on both sides are kept as the state of this sync */
+async fn sync(flow: &mut Hydroflow, input: &UnboundedSender<Message>, dry_run: bool, policy: &ConflictPolicy, accept_root: Option<&str>, parallel: usize) -> bool {
+    load_root().await;
+    let state_path = Path::new(STATE_DIR).join(SYNC_STATE);
+    let base = std::fs::read(&state_path).ok()
+        .and_then(|data| serde_json::from_slice::<BTreeMap<String, String>>(data.as_slice()).ok())
+        .unwrap_or_default();
+
+    let local = local_tree().await;
+    let (root, diff) = match remote_diff(flow, input, &local).await {
+        Some(diff) => diff,
+        None => {
+            println!("Unable to compare with the server");
+            return false;
+        }
+    };
+
+    /* The listings only show the tree is consistent, not that it's the one we trust. A server that isn't at a root
+     * we trust could make us download anything, or delete our files */
+    let server_root = root.to_string_lossy().into_owned();
+    let is_trusted = ROOT.with(|r| *r.borrow() == root) || is_trusted_root(&server_root).await || accept_root == Some(server_root.as_str());
+    if !is_trusted {
+        println!("The server's root {:?} isn't one we trusted, the server changed since we last synced", server_root);
+        println!("Check it with the other clients and run sync again with --accept-root {:?} to take its changes", server_root);
+        if !dry_run {
+            return false;
+        }
+    }
+
+    let mut plan = Vec::new();
+    for (path, (local_hash, remote_hash)) in diff.iter() {
+        let base_hash = base.get(path).map(String::as_str);
+        let conflict = local_hash.as_deref() != base_hash && remote_hash.as_deref() != base_hash;
+        match sync_action(local_hash.as_deref(), remote_hash.as_deref(), base_hash, policy) {
+            Some(action) => {
+                println!("{:?} {}{}", action, path, if conflict { ", changed on both sides" } else { "" });
+                plan.push((path.as_str(), action, remote_hash.clone()));
+            }
+            None => println!("Conflict on {}, changed on both sides, skipping it", path),
+        }
+    }
+    if dry_run {
+        println!("Dry run, {} of {} differing files would be synced", plan.len(), diff.len());
+        return true;
+    }
+
+    let mut synced = Vec::new();
+    for (path, _, _) in plan.iter().filter(|(_, action, _)| *action == SyncAction::DeleteLocal) {
+        if remove_local_file(path).await {
+            synced.push(*path);
+        }
+    }
+    ROOT.with(|r| r.replace(root));
+    let transfers = plan.iter()
+        .filter(|(_, action, _)| *action != SyncAction::DeleteLocal)
+        .map(|(path, action, remote_hash)| {
+            let preconditions = Preconditions {
+                if_match: remote_hash.clone(),
+                if_none_match: remote_hash.is_none().then(|| "*".to_string()),
+                if_root_is: None,
+            };
+            async move {
+                let done = match action {
+                    SyncAction::Upload => upload_file(input, path, None, &preconditions).await,
+                    SyncAction::DeleteRemote => delete_file(input, path, preconditions).await,
+                    _ => download_file(input, path, None, None).await,
+                };
+                (*path, done)
+            }
+        });
+    let transferred = with_flow(flow, stream::iter(transfers).buffer_unordered(parallel.max(1)).collect::<Vec<(&str, bool)>>()).await.unwrap_or_default();
+    synced.extend(transferred.into_iter().filter(|(_, done)| *done).map(|(path, _)| path));
+    println!("Synced {} of {} differing files", synced.len(), diff.len());
+    update_root().await;
+
+    /* Files that were the same on both sides and the ones synced now are the state of this sync. Whatever still
+     * differs keeps the state of the last sync, so it's still told apart next time */
+    let mut state = local.files.iter()
+        .filter(|(path, _)| !diff.contains_key(*path))
+        .map(|(path, hash)| (path.clone(), hash.to_string_lossy().into_owned()))
+        .collect::<BTreeMap<String, String>>();
+    for (path, (local_hash, remote_hash)) in diff.iter() {
+        let hash = if synced.contains(&path.as_str()) {
+            match plan.iter().find(|(planned, _, _)| planned == path).map(|(_, action, _)| *action) {
+                Some(SyncAction::Upload) => local_hash.clone(),
+                Some(SyncAction::Download) => remote_hash.clone(),
+                _ => None,
+            }
+        } else {
+            base.get(path).cloned()
+        };
+        if let Some(hash) = hash {
+            state.insert(path.clone(), hash);
+        }
+    }
+    let saved = std::fs::create_dir_all(STATE_DIR).and_then(|_| write_file_atomic(&state_path, serde_json::to_vec(&state).unwrap_or_default().as_slice()));
+    if let Err(e) = saved {
+        println!("Unable to save the state of the sync: {}", e);
+    }
+    let left = diff.len() - synced.len();
+    println!("{} files differ from the server after the sync", left);
+    left == 0
+}
+
+/* Hash of a file in the client directory, None if there's no file at the path */
+async fn local_hash(path: &str) -> Option<String> {
+    let path = Path::new(DATA_DIR).join(path);
+    if !tokio::fs::metadata(&path).await.ok()?.is_file() {
+        return None;
+    }
+    tokio::fs::read(path).await.ok().map(|data| blake3::hash(data.as_slice()).to_string())
+}
+
+/**Files on the server with their hashes, from a recursive listing checked against the trusted root. None if
+ * the listing doesn't match it */
+async fn remote_tree(flow: &mut Hydroflow, input: &UnboundedSender<Message>) -> Option<BTreeMap<String, String>> {
+    let (node, merkle_proof, entries) = match fetch_listing(flow, input, "", true).await? {
+        Some(pages) => merge_pages(pages),
+        /* An empty server has no root directory */
+        None => return ROOT.with(|r| r.borrow().is_empty()).then(BTreeMap::new),
+    };
+    if !verify_listing("", &node, merkle_proof.as_slice(), entries.as_slice()) {
+        return None;
+    }
+    if entries.iter().any(|entry| entry.hidden) {
+        println!("Some files on the server are hidden from us, the trusted root won't follow our changes");
+    }
+    Some(entries.into_iter().filter(|entry| !entry.hidden).map(|entry| (entry.path, entry.hash)).collect())
+}
+
+/* Trust the root of the files we pushed, but only if the server acked that root for one of our changes */
+async fn store_pushed_root(pushed: &BTreeMap<String, String>, acked: &HashSet<String>) {
+    if acked.is_empty() {
+        return;
+    }
+    let root = MerkleTree::from_files(pushed.iter().map(|(path, hash)| (path.clone(), hash.clone()))).root;
+    let root = root.map(|root| root.to_string_lossy().into_owned()).unwrap_or_default();
+    if acked.contains(&root) {
+        store_root(OsString::from(root)).await;
+    } else {
+        println!("Files pushed so far don't match the server, not trusting {}", root);
+    }
+}
+
+/**Push the changes at the dirty paths and below them. `pushed` has the hash of every file on the server as far as
+ * we know, every change is conditional on the server still having that. Moved files the server still has as they
+ * were are renamed there instead of sent again. The root of the result is trusted once the server acked it */
+async fn push_changes(input: &UnboundedSender<Message>, pushed: &mut BTreeMap<String, String>, dirty: BTreeSet<String>, moves: Vec<(String, String)>, parallel: usize) {
+    let ignored = IGNORED.with(|i| i.borrow().clone());
+    let mut acked = HashSet::new();
+
+    for (from, to) in moves {
+        let below = format!("{}/", from);
+        let files = pushed.keys().filter(|file| **file == from || file.starts_with(&below)).cloned().collect::<Vec<String>>();
+        for file in files {
+            let target = format!("{}{}", to, &file[from.len()..]);
+            let hash = pushed[&file].clone();
+            if ignored.is_ignored(&target) || pushed.contains_key(&target) || local_hash(&target).await.as_deref() != Some(hash.as_str()) {
+                continue;
+            }
+            if let Some(root) = request_move(input, &file, &target, false, Some(hash.clone()), Some("*".to_string())).await {
+                pushed.remove(&file);
+                pushed.insert(target, hash);
+                acked.insert(root);
+            }
+        }
+    }
+
+    let mut paths = BTreeSet::new();
+    for path in dirty {
+        let below = format!("{}/", path);
+        paths.extend(pushed.keys().filter(|file| path.is_empty() || **file == path || file.starts_with(&below)).cloned());
+        match list_files(Path::new(DATA_DIR), &path) {
+            Ok(files) => paths.extend(files),
+            Err(_) => { paths.insert(path); }
+        }
+    }
+
+    let mut changes = Vec::new();
+    for path in paths {
+        let name = path.rsplit('/').next().unwrap_or_default();
+        if ignored.is_ignored(&path) || is_temp_file(name) {
+            continue;
+        }
+        let hash = local_hash(&path).await;
+        if hash.as_ref() != pushed.get(&path) {
+            let preconditions = Preconditions {
+                if_match: pushed.get(&path).cloned(),
+                if_none_match: (!pushed.contains_key(&path)).then(|| "*".to_string()),
+                if_root_is: None,
+            };
+            changes.push((path, hash, preconditions));
+        }
+    }
+
+    let mut results = stream::iter(changes.iter())
+        .map(|(path, hash, preconditions)| async move {
+            let done = match hash {
+                Some(_) => upload_file(input, path, None, preconditions).await,
+                None => delete_file(input, path, preconditions.clone()).await,
+            };
+            (path, hash, done)
+        })
+        .buffer_unordered(parallel.max(1));
+    while let Some((path, hash, done)) = results.next().await {
+        if !done {
+            continue;
+        }
+        match hash {
+            Some(hash) => pushed.insert(path.clone(), hash.clone()),
+            None => pushed.remove(path),
+        };
+        acked.extend(ACKED.with(|a| a.borrow_mut().remove(path)));
+    }
+    /* Changes may be acked in any order, the last one the server applied has the root of all of them */
+    store_pushed_root(pushed, &acked).await;
+}
+
+/**Sync the client directory once, then push changes in it to the server as they happen. Events are debounced,
+ * the changes are pushed once the directory has been quiet for a moment. The watch is set up before the sync, so
+ * nothing that changes during the sync is missed */
+#[cfg(target_os = "linux")]
+async fn watch(flow: &mut Hydroflow, input: &UnboundedSender<Message>, policy: &ConflictPolicy, accept_root: Option<&str>, parallel: usize) {
+    let mut watcher = match Watcher::new(Path::new(DATA_DIR)) {
+        Ok(watcher) => watcher,
+        Err(e) => {
+            println!("Unable to watch {}: {}", DATA_DIR, e);
+            return;
+        }
+    };
+    if !sync(flow, input, false, policy, accept_root, parallel).await {
+        println!("Files that still differ from the server are only pushed if the server has what we think it has");
+    }
+
+    /* Changes are pushed against what the server has, not against our files, which may still differ after the sync */
+    let mut pushed = match remote_tree(flow, input).await {
+        Some(pushed) => pushed,
+        None => {
+            println!("Unable to get a listing of the server that matches the trusted root, not watching");
+            return;
+        }
+    };
+    println!("Watching {} for changes", DATA_DIR);
+
+    let watching = async {
+        let mut dirty = BTreeSet::new();
+        let mut moves = Vec::new();
+        loop {
+            tokio::select! {
+                event = watcher.next() => match event {
+                    Some(Ok(WatchEvent::Changed(path))) => { dirty.insert(path); }
+                    Some(Ok(WatchEvent::Moved { from, to })) => {
+                        dirty.insert(from.clone());
+                        dirty.insert(to.clone());
+                        moves.push((from, to));
+                    }
+                    /* Look at everything again */
+                    Some(Ok(WatchEvent::Overflow)) => { dirty.insert(String::new()); }
+                    Some(Err(e)) => {
+                        println!("Unable to watch {}: {}", DATA_DIR, e);
+                        return;
+                    }
+                    None => return,
+                },
+                _ = tokio::time::sleep(WATCH_DEBOUNCE), if !dirty.is_empty() => {
+                    push_changes(input, &mut pushed, std::mem::take(&mut dirty), std::mem::take(&mut moves), parallel).await;
+                }
+            }
+        }
+    };
+    with_flow(flow, watching).await;
+}
+
+#[cfg(not(target_os = "linux"))]
+async fn watch(_flow: &mut Hydroflow, _input: &UnboundedSender<Message>, _policy: &ConflictPolicy, _accept_root: Option<&str>, _parallel: usize) {
+    println!("Watching needs inotify, it's only supported on Linux");
+}
+
+pub(crate) async fn run_client(outbound: SecureSink, inbound: SecureStream, opts: Opts) {
+    // server_addr is required for client
+    let server_addr = match opts.server_addr {
+        Some(addr) => {
+            println!("Connecting to server at {:?}", addr);
+            addr
+        }
+        None => panic!("Client requires a server address"),
+    };
+
+    println!("Client live!");
+
+
+    let (input, recv) = hydroflow::util::unbounded_channel::<Message>();
+    let (log_send, log_recv) = hydroflow::util::unbounded_channel::<String>();
+    LOG.with(|log| log.replace(Some(log_send)));
+    CHUNKS.with(|chunks| chunks.replace(Some(spawn_chunk_writer())));
+
+    let mut flow = hydroflow_syntax! {
+        // Define shared inbound and outbound channels
+        inbound_chan = source_stream_serde(inbound) -> map(|udp_msg| udp_msg.unwrap()) -> tee() ;
+        outbound_chan = union() -> dest_sink_serde(outbound);
+
+        // Write all received messages for debugging purposes to the .log file
+        inbound_chan[2] -> for_each(|_| heard_from_server());
+
+        inbound_chan[1]
+            -> map(|(m, a): (Message, SocketAddr)| format!("{}: Got {:?} from {:?}", Utc::now(), m, a))
+            -> dest_file("client.log", true);
+
+        inbound_demuxed = inbound_chan[0]
+            ->  demux(|(msg, addr), var_args!(file_save_ch, chunk_save_ch, retry_ch, errs_ch)|
+                    match msg {
+                        Message::FileAck {filename, hash, root} => {
+                            println!("Upload of file {} with hash {} was successful!", filename, hash);
+                            set_upload_state(own_session_id(&filename, &hash), UploadState::Done);
+                            ACKED.with(|a| a.borrow_mut().insert(filename, root));
+                        },
+                        Message::File {filename, data, merkle_proof} => file_save_ch.give((filename, data, merkle_proof, addr)),
+                        Message::FileNotFound {filename} => {
+                            println!("File {} not found on server", filename);
+                            REMOTE_FILES.with(|f| f.borrow_mut().insert(filename, None));
+                        },
+                        Message::DeleteFileAck {filename, deleted, root} => {
+                            println!("File {} removed from server: {}", filename, deleted);
+                            if deleted {
+                                ACKED.with(|a| a.borrow_mut().insert(filename.clone(), root));
+                                DELETED.with(|d| d.borrow_mut().insert(filename));
+                            }
+                        },
+                        Message::Listing(listing) => { LISTINGS.with(|l| l.borrow_mut().insert((listing.path.clone(), listing.offset), listing)); },
+                        Message::RenameAck {from, to, root} => {
+                            println!("Renamed {} to {} on server, root is now {}", from, to, root);
+                            ACKED.with(|a| a.borrow_mut().insert(to, root));
+                        },
+                        Message::CopyAck {from, to, root} => {
+                            println!("Copied {} to {} on server, root is now {}", from, to, root);
+                            ACKED.with(|a| a.borrow_mut().insert(to, root));
+                        },
+                        Message::Error {filename, error} => {
+                            println!("Server error for file {}: {:?}", filename, error);
+                            if matches!(error, ProtocolError::PreconditionFailed(_)) {
+                                PRECONDITION_FAILED.with(|p| p.borrow_mut().insert(filename));
+                            }
+                        },
+                        Message::UploadStatus {session_id, missing} => set_upload_state(session_id, UploadState::Missing(missing)),
+                        Message::UploadSessionNotFound {session_id} => set_upload_state(session_id, UploadState::NotFound),
+                        Message::FileInfo {filename, version, hash, size, merkle_proof, root} => {
+                            REMOTE_FILES.with(|f| f.borrow_mut().insert(filename, Some(RemoteFile { version, hash, size, merkle_proof, root })));
+                        },
+                        Message::FileChunk {filename, version, index, data} => chunk_save_ch.give((filename, version, index, data)),
+                        Message::Challenge {nonce} => { CHALLENGE.with(|c| c.replace(Some(nonce))); },
+                        Message::Authenticated {identity} => {
+                            match identity.as_deref() {
+                                Some(identity) => println!("Authenticated as {}", identity),
+                                None => println!("Server doesn't authenticate clients"),
+                            }
+                            AUTHENTICATED.with(|a| a.replace(Some(identity)));
+                        },
+                        Message::Retry {token} => retry_ch.give((token, addr)),
+                        Message::AddressValidated => { VALIDATED.with(|v| v.replace(true)); },
+                        Message::HeartbeatAck => {},
+                        Message::Versions {filename, versions} => {
+                            for version in versions.iter() {
+                                println!("{} version {}: {} {} bytes at {} by {}, epoch {}",
+                                    filename,
+                                    version.version,
+                                    version.hash.as_deref().unwrap_or("deleted"),
+                                    version.size,
+                                    version.timestamp,
+                                    version.owner.as_deref().unwrap_or("anonymous"),
+                                    version.epoch);
+                            }
+                            VERSIONS.with(|v| v.borrow_mut().insert(filename, versions));
+                        },
+                        Message::UsageReport(report) => {
+                            println!("Server: {} files, {} bytes, limits {:?}", report.total.files, report.total.bytes, report.total_quota);
+                            if let (Some(identity), Some(usage)) = (report.identity, report.usage) {
+                                println!("{}: {} files, {} bytes, limits {:?}", identity, usage.files, usage.bytes, report.quota);
+                            }
+                        },
+                        _ => errs_ch.give((msg, addr)),
+                    }
+                );
+
+        /* When we receive a message to file_save_ch containing file data we save the file locally */
+        /* save_file() does verification of the data and the proof against the root hash stored in memory */
+        inbound_demuxed[file_save_ch]
+                -> for_each(|(filename, data, merkleproof, _addr)| spawn_save_file(filename, data, merkleproof));
+
+        /* Chunks of a resumable download go into the `.part` file of that download */
+        inbound_demuxed[chunk_save_ch]
+                -> for_each(|(filename, version, index, data)| queue_chunk(filename, version, index, data));
+
+        /* The server wants us to prove we're at our address before it sends anything large, requests that
+         * got a Retry instead of a reply are sent again by the retries of the commands */
+        inbound_demuxed[retry_ch]
+                -> map(|(token, addr)| (Message::ValidateAddress { token }, addr))
+                -> [1]outbound_chan;
+
+        /* Results of the writes above */
+        source_stream(log_recv) -> dest_file("client.log", true);
+
+        // Print unexpected messages
+        inbound_demuxed[errs_ch]
+            -> for_each(|(msg, addr)| println!("Received unexpected message type: {:?} from {:?}", msg, addr));
+
+        /* Send directly to the server */
+        source_stream(recv) 
+            -> map(|l| (l, server_addr) )
+            -> [0]outbound_chan;
+
+        /* Heartbeats keep our session on the server alive and tell us when the server is gone */
+        source_interval(HEARTBEAT_INTERVAL)
+            -> map(|_| (heartbeat(), server_addr))
+            -> [2]outbound_chan;
+    };
+
+    if !validate_address(&mut flow, &input).await {
+        println!("Server didn't validate our address");
+        return;
+    }
+
+    /* Authenticate first when we have an identity, the server rejects file requests otherwise */
+    match (opts.identity.as_ref(), opts.key_file.as_ref()) {
+        (Some(identity), Some(key_file)) => {
+            let key = load_key(Path::new(key_file)).expect("Unable to read key file");
+            if !authenticate(&mut flow, &input, identity, key.as_slice()).await {
+                println!("Unable to authenticate as {}", identity);
+                return;
+            }
+        }
+        (None, None) => {}
+        _ => panic!("Authentication requires both an identity and a key file"),
+    }
+
+    let key = opts.master_key_file.as_ref().map(|path| MasterKey::load(Path::new(path)).expect("Unable to read master key"));
+    let key = key.as_ref();
+    set_ignored(&[]);
+
+    match opts.command {
+        Some(Command::Upload { files, recursive, ignore, preconditions }) => {
+            let preconditions = Preconditions::from(preconditions);
+            set_ignored(&ignore);
+            let files = if recursive { local_files(&files) } else { files };
+            let transfers = files.iter().map(|filename| upload_file(&input, filename, key, &preconditions));
+            if let Some(uploaded) = with_flow(&mut flow, transfer_all(transfers, opts.parallel)).await {
+                println!("Uploaded {} of {} files", uploaded, files.len());
+            }
+            match key {
+                Some(_) => update_e2e_root(&mut flow, &input).await,
+                None => update_root().await,
+            }
+        }
+        Some(Command::Download { files, version, recursive, ignore }) => {
+            load_root().await;
+            set_ignored(&ignore);
+            let files = if recursive { remote_files(&mut flow, &input, &files, key).await } else { files };
+            let transfers = files.iter().map(|filename| download_file(&input, filename, version, key));
+            if let Some(downloaded) = with_flow(&mut flow, transfer_all(transfers, opts.parallel)).await {
+                println!("Downloaded {} of {} files", downloaded, files.len());
+            }
+        }
+        Some(Command::Sync { dry_run, conflicts, accept_root, ignore }) => {
+            if key.is_some() {
+                println!("Encrypted names and contents can't be compared with the local files, sync needs plaintext files");
+                return;
+            }
+            set_ignored(&ignore);
+            sync(&mut flow, &input, dry_run, &conflicts, accept_root.as_deref(), opts.parallel).await;
+        }
+        Some(Command::Watch { conflicts, accept_root, ignore }) => {
+            if key.is_some() {
+                println!("Encrypted names and contents can't be compared with the local files, watching needs plaintext files");
+                return;
+            }
+            set_ignored(&ignore);
+            watch(&mut flow, &input, &conflicts, accept_root.as_deref(), opts.parallel).await;
+        }
+        Some(Command::Delete { files, preconditions }) => {
+            let preconditions = Preconditions::from(preconditions);
+            let remote_names = files.iter()
+                .map(|filename| key.map_or(filename.clone(), |key| key.encrypt_filename(filename)))
+                .collect::<Vec<String>>();
+            for filename in remote_names.iter() {
+                let _ = input.send(Message::DeleteFileRequest { filename: filename.clone(), preconditions: Box::new(preconditions.clone()) });
+            }
+            run_for(&mut flow, REPLY_TIMEOUT).await;
+
+            if key.is_some() {
+                let deleted = DELETED.with(|d| d.take());
+                update_e2e_index(|index| deleted.iter().for_each(|filename| { index.remove(filename); }));
+                update_e2e_root(&mut flow, &input).await;
+            }
+        }
+        Some(Command::Usage) => {
+            let _ = input.send(Message::UsageRequest);
+            run_for(&mut flow, REPLY_TIMEOUT).await;
+        }
+        Some(Command::Versions { files }) => {
+            for filename in files.iter() {
+                let remote_name = key.map_or(filename.clone(), |key| key.encrypt_filename(filename));
+                if fetch_versions(&mut flow, &input, &remote_name).await.is_none() {
+                    println!("Server didn't answer, unable to list versions of {}", filename);
+                }
+            }
+        }
+        Some(Command::List { path, recursive }) => {
+            load_root().await;
+            list_directory(&mut flow, &input, path.as_deref().unwrap_or(""), recursive, key).await;
+        }
+        Some(Command::Rename { from, to }) => {
+            move_file(&mut flow, &input, &from, &to, false, key).await;
+        }
+        Some(Command::Copy { from, to }) => {
+            move_file(&mut flow, &input, &from, &to, true, key).await;
+        }
+        Some(Command::Restore { file, version }) => {
+            if restore_version(&mut flow, &input, &file, version, key).await && key.is_some() {
+                update_e2e_root(&mut flow, &input).await;
+            }
+        }
+        None => run_demo(flow, input).await,
+    }
+}
+
+/**Upload a few generated files, download them again with verification and delete them from the server */
+async fn run_demo(mut flow: Hydroflow, input: UnboundedSender<Message>) {
+    /* Step 1: Generate test files */
+    let mut filenames = vec![];
+
+    for i in 1..=N_OF_FILES {
+        filenames.push(format!("file{}.txt", i));
+    }
+
+    /* Step 2: Generate test files */
+    let _ = tokio::fs::create_dir_all(DATA_DIR).await;
+    for filename in filenames.iter() {
+        if let Ok(mut file) = tokio::fs::File::create(format!("./.client/{}", filename)).await {
+            let _ = file.write_all(format!("Hello, world! This is {}", filename).as_bytes()).await;
+            println!("Created ./files/{}", filename);
+        }
+    }
+
+    /* Step 3: Create merkle tree locally and store root hash */
+    let mt = MerkleTree::from_folder(&Path::new(DATA_DIR)).await;
+    println!("Merkle tree root hash: {:#?}", mt.root);
+    println!("{:#?}", mt);
+    
+    /* Step 4: Update ROOT hash and store it for the duration of the program */
+    let root_hash = mt.root.unwrap();
+    store_root(root_hash).await;
+    println!("Updated root hash");
+
+    /* Step 5: Upload files to the server, each step waits for the replies to the previous one so the files
+     * are on the server before they're requested and requested before they're deleted */
+    let preconditions = Preconditions::default();
+    let uploads = filenames.iter().map(|filename| upload_file(&input, filename, None, &preconditions));
+    if let Some(uploaded) = with_flow(&mut flow, transfer_all(uploads, 1)).await {
+        println!("Uploaded {} of {} files", uploaded, filenames.len());
+    }
+
+    /* Step 6: Delete local test files */
+    for filename in filenames.iter() {
+        let _ = tokio::fs::remove_file(format!("./.client/{}", filename)).await;
+        println!("Deleted ./.client/{}", filename);
+    }
+
+    /* Step 7: Request files back from the server, verifying their integrity */
+    let downloads = filenames.iter().map(|filename| download_file(&input, filename, None, None));
+    if let Some(downloaded) = with_flow(&mut flow, transfer_all(downloads, 1)).await {
+        println!("Downloaded {} of {} files", downloaded, filenames.len());
+    }
+
+    /* Step 8: Delete files from the server */
+    let deletes = filenames.iter().map(|filename| delete_file(&input, filename, Preconditions::default()));
+    if let Some(deleted) = with_flow(&mut flow, transfer_all(deletes, 1)).await {
+        println!("Deleted {} of {} files from the server", deleted, filenames.len());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(path: &str, is_dir: bool, hash: &str, hidden: bool) -> DirEntry {
+        DirEntry { path: path.to_string(), is_dir, hash: hash.to_string(), size: 0, hidden }
+    }
+
+    #[test]
+    fn listings_rebuild_the_node_of_their_directory() {
+        let tree = MerkleTree::from_files([("a/b/open", "1"), ("a/b/secret", "2"), ("a/c", "3"), ("d/secret", "4"), ("e", "5")]
+            .map(|(file, hash)| (file.to_string(), hash.to_string())));
+        let node = |path: &str| tree.node(path).unwrap().to_string_lossy().to_string();
+
+        let recursive = [
+            entry("a/b/open", false, "1", false),
+            entry("a/b", false, &node("a/b/secret"), true),
+            entry("a/c", false, "3", false),
+            entry("", false, &node("d"), true),
+            entry("e", false, "5", false),
+        ];
+        assert_eq!(listing_node("", &recursive), tree.node(""));
+        assert_eq!(listing_node("a", &recursive[..3]), tree.node("a"));
+
+        let flat = [entry("a", true, &node("a"), false), entry("", false, &node("d"), true), entry("e", false, "5", false)];
+        assert_eq!(listing_node("", &flat), tree.node(""));
+
+        /* Leaving out or moving an entry changes the node */
+        assert_ne!(listing_node("", &recursive[1..]), tree.node(""));
+        assert_ne!(listing_node("", &[flat[1].clone(), flat[0].clone(), flat[2].clone()]), tree.node(""));
+        assert_eq!(listing_node("a", &recursive), None);
+    }
+
+    #[test]
+    fn sync_applies_the_side_that_changed() {
+        let policy = ConflictPolicy::Skip;
+        /* Changed or added here */
+        assert_eq!(sync_action(Some("new"), Some("old"), Some("old"), &policy), Some(SyncAction::Upload));
+        assert_eq!(sync_action(Some("new"), None, None, &policy), Some(SyncAction::Upload));
+        assert_eq!(sync_action(None, Some("old"), Some("old"), &policy), Some(SyncAction::DeleteRemote));
+        /* Changed or added on the server */
+        assert_eq!(sync_action(Some("old"), Some("new"), Some("old"), &policy), Some(SyncAction::Download));
+        assert_eq!(sync_action(None, Some("new"), None, &policy), Some(SyncAction::Download));
+        assert_eq!(sync_action(Some("old"), None, Some("old"), &policy), Some(SyncAction::DeleteLocal));
+    }
+
+    #[test]
+    fn sync_conflicts_follow_the_policy() {
+        let conflicts = [(Some("a"), Some("b"), Some("old")), (Some("a"), Some("b"), None), (Some("a"), None, Some("old")), (None, Some("b"), Some("old"))];
+        for (local, remote, base) in conflicts {
+            assert_eq!(sync_action(local, remote, base, &ConflictPolicy::Skip), None);
+            let push = if local.is_some() { SyncAction::Upload } else { SyncAction::DeleteRemote };
+            let pull = if remote.is_some() { SyncAction::Download } else { SyncAction::DeleteLocal };
+            assert_eq!(sync_action(local, remote, base, &ConflictPolicy::Local), Some(push));
+            assert_eq!(sync_action(local, remote, base, &ConflictPolicy::Remote), Some(pull));
+        }
+    }
+}
@@ -0,0 +1,417 @@
+use crate::secure::{SecureSink, SecureStream};
+use crate::storage::{ObjectStat, Storage};
+use clap::ValueEnum;
+use hydroflow::bytes::{Bytes, BytesMut};
+use hydroflow::futures::channel::mpsc;
+use hydroflow::futures::{SinkExt, StreamExt};
+use hydroflow::tokio;
+use std::collections::HashMap;
+use std::io::Read;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+
+/* Datagrams smaller than this aren't worth compressing */
+const MIN_COMPRESS_SIZE: usize = 128;
+/* Upper bound on the size of a decompressed datagram, so a tiny datagram can't expand into gigabytes */
+const MAX_DATAGRAM: usize = 1024 * 1024;
+/* Forget what peers support once there are this many, they tell us again with their next datagram */
+const MAX_PEERS: usize = 65536;
+const ZSTD_LEVEL: i32 = 3;
+
+/* Header of a compressed blob: magic, codec and the size of the uncompressed contents */
+const MAGIC: &[u8; 4] = b"ZFC2";
+const HEADER_LEN: usize = 4 + 1 + 8;
+/* Uncompressed bytes per frame, frames are compressed on their own so ranges can be read without the rest of the blob */
+const FRAME_SIZE: u64 = 64 * 1024;
+/* Entry of the frame table: the codec of the frame and where it ends in the body */
+const ENTRY_LEN: u64 = 1 + 8;
+
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum, Debug)]
+pub enum Codec {
+    None,
+    Lz4,
+    Zstd,
+}
+
+impl Codec {
+    fn id(self) -> u8 {
+        match self {
+            Codec::None => 0,
+            Codec::Lz4 => 1,
+            Codec::Zstd => 2,
+        }
+    }
+
+    fn from_id(id: u8) -> Option<Codec> {
+        match id {
+            0 => Some(Codec::None),
+            1 => Some(Codec::Lz4),
+            2 => Some(Codec::Zstd),
+            _ => None,
+        }
+    }
+
+    /* Bit of the codec in the set of codecs a peer can decompress */
+    fn bit(self) -> u8 {
+        1 << self.id()
+    }
+}
+
+/* Every codec this build can decompress, advertised to peers in each datagram */
+const SUPPORTED: u8 = 0b111;
+
+fn invalid_data(msg: &str) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, msg.to_string())
+}
+
+pub fn compress(codec: Codec, data: &[u8]) -> Vec<u8> {
+    match codec {
+        Codec::None => data.to_vec(),
+        Codec::Lz4 => lz4_flex::compress_prepend_size(data),
+        Codec::Zstd => zstd::bulk::compress(data, ZSTD_LEVEL).expect("compressing into memory doesn't fail"),
+    }
+}
+
+/* Decompress `data`, refusing anything that would expand past `limit` bytes */
+pub fn decompress(codec: Codec, data: &[u8], limit: usize) -> std::io::Result<Vec<u8>> {
+    match codec {
+        Codec::None => Ok(data.to_vec()),
+        Codec::Lz4 => {
+            let size = data.get(..4).map(|size| u32::from_le_bytes(size.try_into().unwrap()) as usize).unwrap_or(usize::MAX);
+            if size > limit {
+                return Err(invalid_data("decompressed size is over the limit"));
+            }
+            lz4_flex::decompress_size_prepended(data).map_err(|e| invalid_data(&e.to_string()))
+        }
+        /* Streamed, so only what the data really expands to is allocated */
+        Codec::Zstd => {
+            let mut decompressed = Vec::new();
+            zstd::stream::read::Decoder::with_buffer(data)?.take(limit as u64 + 1).read_to_end(&mut decompressed)?;
+            if decompressed.len() > limit {
+                return Err(invalid_data("decompressed size is over the limit"));
+            }
+            Ok(decompressed)
+        }
+    }
+}
+
+/**Compress datagrams between the serde layer and the socket (or the encrypted transport). Every datagram
+ * starts with the codec it was compressed with and the set of codecs its sender can decompress, so each
+ * side learns what the other accepts and replies with `codec` only to peers that support it. Anything
+ * that doesn't get smaller is sent as is */
+pub fn compress_udp(mut raw_outbound: SecureSink, mut raw_inbound: SecureStream, codec: Codec) -> (SecureSink, SecureStream) {
+    let (outbound, mut outgoing) = mpsc::unbounded::<(Bytes, SocketAddr)>();
+    let (inbound, stream) = hydroflow::util::unbounded_channel();
+    let peers = Arc::new(Mutex::new(HashMap::<SocketAddr, u8>::new()));
+
+    tokio::spawn({
+        let peers = peers.clone();
+        async move {
+            while let Some(item) = raw_inbound.next().await {
+                let (data, addr) = match item {
+                    Ok(item) => item,
+                    Err(e) => {
+                        println!("Transport error: {:?}", e);
+                        continue;
+                    }
+                };
+                if data.len() < 2 {
+                    continue;
+                }
+
+                let payload = match Codec::from_id(data[0]).map(|codec| decompress(codec, &data[2..], MAX_DATAGRAM)) {
+                    Some(Ok(payload)) => payload,
+                    _ => {
+                        println!("Dropping datagram from {} that failed to decompress", addr);
+                        continue;
+                    }
+                };
+
+                /* Only a datagram that decoded tells us what the peer accepts, so a corrupt one can't change our replies */
+                let mut peers = peers.lock().unwrap();
+                if peers.len() >= MAX_PEERS && !peers.contains_key(&addr) {
+                    peers.clear();
+                }
+                peers.insert(addr, data[1]);
+                drop(peers);
+                if inbound.send(Ok((BytesMut::from(payload.as_slice()), addr))).is_err() {
+                    break;
+                }
+            }
+        }
+    });
+
+    tokio::spawn(async move {
+        while let Some((data, addr)) = outgoing.next().await {
+            let accepted = peers.lock().unwrap().get(&addr).copied().unwrap_or(0);
+            let compressed = Some(codec)
+                .filter(|codec| *codec != Codec::None && accepted & codec.bit() != 0 && data.len() >= MIN_COMPRESS_SIZE)
+                .map(|codec| (codec, compress(codec, &data)))
+                .filter(|(_, compressed)| compressed.len() < data.len());
+
+            let (codec, payload) = compressed.unwrap_or((Codec::None, data.to_vec()));
+            let mut packet = Vec::with_capacity(payload.len() + 2);
+            packet.push(codec.id());
+            packet.push(SUPPORTED);
+            packet.extend(payload);
+            let _ = raw_outbound.send((Bytes::from(packet), addr)).await;
+        }
+    });
+
+    (outbound, stream)
+}
+
+/**Compresses blobs before they reach another backend. Blobs are split into frames of FRAME_SIZE bytes that are
+ * compressed on their own, frames that don't compress are stored as is. The header records the codec and the
+ * uncompressed size, and is followed by a table of where each frame ends, so a range read only reads and
+ * decompresses the frames it covers. Objects keep their key, the hash of the uncompressed contents */
+pub struct CompressedStorage {
+    inner: Arc<dyn Storage>,
+    codec: Codec,
+}
+
+impl CompressedStorage {
+    pub fn new(inner: Arc<dyn Storage>, codec: Codec) -> CompressedStorage {
+        CompressedStorage { inner, codec }
+    }
+
+    /* Size of the uncompressed contents, None for a blob stored before compression was turned on, which is read as is */
+    fn header(data: &[u8]) -> std::io::Result<Option<u64>> {
+        if data.get(..4) != Some(MAGIC.as_slice()) {
+            return Ok(None);
+        }
+        if data.len() < HEADER_LEN {
+            return Err(invalid_data("blob was truncated"));
+        }
+        Codec::from_id(data[4]).ok_or_else(|| invalid_data("unknown codec"))?;
+        Ok(Some(u64::from_be_bytes(data[5..HEADER_LEN].try_into().unwrap())))
+    }
+
+    /* Codec and end of every frame in a slice of the frame table */
+    fn entries(table: &[u8]) -> std::io::Result<Vec<(Codec, u64)>> {
+        table.chunks(ENTRY_LEN as usize)
+            .map(|entry| {
+                let codec = Codec::from_id(entry[0]).ok_or_else(|| invalid_data("unknown codec"))?;
+                let end = entry.get(1..).and_then(|end| <[u8; 8]>::try_from(end).ok()).ok_or_else(|| invalid_data("blob was truncated"))?;
+                Ok((codec, u64::from_be_bytes(end)))
+            })
+            .collect()
+    }
+
+    /* Decompress frame `index` of a blob of `size` bytes, every frame but the last one is full */
+    fn frame(codec: Codec, data: &[u8], index: u64, size: u64) -> std::io::Result<Vec<u8>> {
+        let expected = u64::min(FRAME_SIZE, size - index * FRAME_SIZE);
+        let frame = decompress(codec, data, FRAME_SIZE as usize)?;
+        if frame.len() as u64 != expected {
+            return Err(invalid_data("blob was truncated"));
+        }
+        Ok(frame)
+    }
+
+    fn frame_count(size: u64) -> u64 {
+        size / FRAME_SIZE + u64::from(size % FRAME_SIZE != 0)
+    }
+}
+
+impl Storage for CompressedStorage {
+    fn put(&self, key: &str, data: &[u8]) -> std::io::Result<()> {
+        let frames = data.chunks(FRAME_SIZE as usize)
+            .map(|frame| {
+                let compressed = compress(self.codec, frame);
+                if compressed.len() < frame.len() { (self.codec, compressed) } else { (Codec::None, frame.to_vec()) }
+            })
+            .collect::<Vec<(Codec, Vec<u8>)>>();
+
+        let mut blob = MAGIC.to_vec();
+        blob.push(self.codec.id());
+        blob.extend_from_slice(&(data.len() as u64).to_be_bytes());
+        let mut end = 0;
+        for (codec, frame) in frames.iter() {
+            end += frame.len() as u64;
+            blob.push(codec.id());
+            blob.extend_from_slice(&end.to_be_bytes());
+        }
+        for (_, frame) in frames {
+            blob.extend(frame);
+        }
+        self.inner.put(key, blob.as_slice())
+    }
+
+    fn get(&self, key: &str) -> std::io::Result<Vec<u8>> {
+        let blob = self.inner.get(key)?;
+        let size = match Self::header(blob.as_slice())? {
+            Some(size) => size,
+            None => return Ok(blob),
+        };
+        let body = HEADER_LEN + (Self::frame_count(size) * ENTRY_LEN) as usize;
+        let entries = Self::entries(blob.get(HEADER_LEN..body).ok_or_else(|| invalid_data("blob was truncated"))?)?;
+        let frames = &blob[body..];
+
+        let mut data = Vec::new();
+        let mut start = 0;
+        for (index, (codec, end)) in entries.into_iter().enumerate() {
+            let frame = frames.get(start as usize..end as usize).ok_or_else(|| invalid_data("blob was truncated"))?;
+            data.extend(Self::frame(codec, frame, index as u64, size)?);
+            start = end;
+        }
+        Ok(data)
+    }
+
+    fn delete(&self, key: &str) -> std::io::Result<()> {
+        self.inner.delete(key)
+    }
+
+    fn list(&self) -> std::io::Result<Vec<String>> {
+        self.inner.list()
+    }
+
+    fn stat(&self, key: &str) -> std::io::Result<Option<ObjectStat>> {
+        let stat = match self.inner.stat(key)? {
+            Some(stat) => stat,
+            None => return Ok(None),
+        };
+        match Self::header(self.inner.get_range(key, 0, HEADER_LEN as u64)?.as_slice())? {
+            Some(size) => Ok(Some(ObjectStat { size })),
+            None => Ok(Some(stat)),
+        }
+    }
+
+    /* Only the table entries and the frames covering the range are read and decompressed */
+    fn get_range(&self, key: &str, offset: u64, len: u64) -> std::io::Result<Vec<u8>> {
+        let size = match Self::header(self.inner.get_range(key, 0, HEADER_LEN as u64)?.as_slice())? {
+            Some(size) => size,
+            None => return self.inner.get_range(key, offset, len),
+        };
+        if len == 0 || offset >= size {
+            return Ok(vec![]);
+        }
+        let first = offset / FRAME_SIZE;
+        let last = u64::min(offset.saturating_add(len), size).saturating_sub(1) / FRAME_SIZE;
+
+        /* The entry before the first frame says where it starts */
+        let from = first.saturating_sub(1);
+        let table = self.inner.get_range(key, HEADER_LEN as u64 + from * ENTRY_LEN, (last - from + 1) * ENTRY_LEN)?;
+        let mut entries = Self::entries(table.as_slice())?;
+        if entries.len() as u64 != last - from + 1 {
+            return Err(invalid_data("blob was truncated"));
+        }
+        let begin = if first > 0 { entries.remove(0).1 } else { 0 };
+        let finish = entries.last().map_or(begin, |(_, end)| *end);
+        if finish < begin {
+            return Err(invalid_data("blob is corrupt"));
+        }
+
+        let body = HEADER_LEN as u64 + Self::frame_count(size) * ENTRY_LEN;
+        let frames = self.inner.get_range(key, body + begin, finish - begin)?;
+        let mut data = Vec::new();
+        let mut start = begin;
+        for (i, (codec, end)) in entries.into_iter().enumerate() {
+            let frame = frames.get((start - begin) as usize..end.saturating_sub(begin) as usize).ok_or_else(|| invalid_data("blob was truncated"))?;
+            data.extend(Self::frame(codec, frame, first + i as u64, size)?);
+            start = end;
+        }
+        let start = usize::min((offset - first * FRAME_SIZE) as usize, data.len());
+        let end = usize::min(start.saturating_add(len as usize), data.len());
+        Ok(data[start..end].to_vec())
+    }
+
+    fn is_persistent(&self) -> bool {
+        self.inner.is_persistent()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::objects::ObjectStore;
+    use crate::protocol::{Message, Preconditions};
+    use crate::quota::Limits;
+    use crate::storage::{FsStorage, MemoryStorage};
+    use crate::store::{Store, DEFAULT_VERSIONS};
+
+    /* Compressible, but not so much that frames are all alike */
+    fn contents(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i / 7 % 251) as u8).collect()
+    }
+
+    #[test]
+    fn ranges_are_read_from_the_frames_they_cover() {
+        for codec in [Codec::Lz4, Codec::Zstd] {
+            let inner = Arc::new(MemoryStorage::default());
+            let storage = CompressedStorage::new(inner.clone(), codec);
+            let data = contents(3 * FRAME_SIZE as usize + 100);
+            storage.put("blob", data.as_slice()).unwrap();
+            assert!(inner.get("blob").unwrap().len() < data.len());
+
+            assert_eq!(storage.get("blob").unwrap(), data);
+            assert_eq!(storage.stat("blob").unwrap().unwrap().size, data.len() as u64);
+            for (offset, len) in [(0, 10), (FRAME_SIZE - 5, 10), (FRAME_SIZE, FRAME_SIZE), (100, 3 * FRAME_SIZE), (3 * FRAME_SIZE + 90, 50)] {
+                let end = usize::min((offset + len) as usize, data.len());
+                assert_eq!(storage.get_range("blob", offset, len).unwrap(), data[offset as usize..end], "{:?} {} {}", codec, offset, len);
+            }
+            assert!(storage.get_range("blob", data.len() as u64, 10).unwrap().is_empty());
+        }
+    }
+
+    #[test]
+    fn frames_that_dont_compress_are_stored_as_is() {
+        let storage = CompressedStorage::new(Arc::new(MemoryStorage::default()), Codec::Zstd);
+        let data = (0..FRAME_SIZE + 10).map(|_| rand::random::<u8>()).collect::<Vec<u8>>();
+        storage.put("random", data.as_slice()).unwrap();
+        assert_eq!(storage.get("random").unwrap(), data);
+        assert_eq!(storage.get_range("random", FRAME_SIZE, 20).unwrap(), data[FRAME_SIZE as usize..]);
+
+        storage.put("empty", &[]).unwrap();
+        assert!(storage.get("empty").unwrap().is_empty());
+        assert!(storage.get_range("empty", 0, 10).unwrap().is_empty());
+    }
+
+    #[test]
+    fn truncated_blobs_are_rejected() {
+        let inner = Arc::new(MemoryStorage::default());
+        let storage = CompressedStorage::new(inner.clone(), Codec::Zstd);
+        let data = contents(2 * FRAME_SIZE as usize);
+        storage.put("blob", data.as_slice()).unwrap();
+        let blob = inner.get("blob").unwrap();
+        inner.put("blob", &blob[..blob.len() - 1]).unwrap();
+        assert!(storage.get("blob").is_err());
+        assert!(storage.get_range("blob", FRAME_SIZE, 10).is_err());
+        assert_eq!(storage.get_range("blob", 0, 10).unwrap(), data[..10]);
+    }
+
+    #[test]
+    fn blobs_stored_before_compression_are_read_as_is() {
+        let dir = std::env::temp_dir().join(format!("compress-test-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let open = |storage: Arc<dyn Storage>| {
+            let runtime = tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap();
+            runtime.block_on(Store::open(&dir, &dir.join("data"), ObjectStore::new(storage), Limits::default(), DEFAULT_VERSIONS)).unwrap()
+        };
+        let inner: Arc<dyn Storage> = Arc::new(FsStorage::new(&dir.join("objects")));
+        let data = contents(FRAME_SIZE as usize + 10);
+        let hash = blake3::hash(data.as_slice()).to_string();
+        let mut store = open(inner.clone());
+        assert!(matches!(store.save("plain", data.as_slice(), None, None, &Preconditions::default(), None), Message::FileAck { .. }));
+        drop(store);
+
+        /* Turning compression on keeps the file and its blob, which is read back without a header */
+        let storage = Arc::new(CompressedStorage::new(inner.clone(), Codec::Zstd));
+        let store = open(storage.clone());
+        assert_eq!(store.shared().read().unwrap().index.get("plain"), Some(&hash));
+        assert_eq!(storage.stat(&hash).unwrap().unwrap().size, data.len() as u64);
+        assert_eq!(storage.get(&hash).unwrap(), data);
+        assert_eq!(storage.get_range(&hash, FRAME_SIZE - 2, 5).unwrap(), data[FRAME_SIZE as usize - 2..FRAME_SIZE as usize + 3]);
+        assert!(dir.join("objects").join(&hash).exists());
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn decompression_stops_at_the_limit() {
+        let data = vec![0; 4096];
+        for codec in [Codec::Lz4, Codec::Zstd] {
+            let compressed = compress(codec, data.as_slice());
+            assert_eq!(decompress(codec, compressed.as_slice(), 4096).unwrap(), data);
+            assert!(decompress(codec, compressed.as_slice(), 4095).is_err());
+        }
+    }
+}
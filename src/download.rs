@@ -0,0 +1,164 @@
+use crate::protocol::{chunk_count, CHUNK_SIZE};
+use serde::{Deserialize, Serialize};
+use std::io::SeekFrom;
+use std::path::{Path, PathBuf};
+use tokio::io::{AsyncSeekExt, AsyncWriteExt};
+
+/**Sidecar manifest of a partial download, stored next to the `.part` file so an interrupted
+ * download can continue where it left off */
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct DownloadManifest {
+    pub filename: String,
+    /* Kept version being downloaded, None for the current contents */
+    #[serde(default)]
+    pub version: Option<u64>,
+    pub hash: String,
+    pub size: u64,
+    pub merkle_proof: Vec<Vec<u8>>,
+    /* Root of the epoch the proof of a version goes up to, the current contents are checked against the trusted root */
+    #[serde(default)]
+    pub root: Option<String>,
+    /* Bitmap of the chunks already written to the `.part` file */
+    pub received: Vec<bool>,
+}
+
+pub fn part_path(dir: &Path, filename: &str) -> PathBuf {
+    dir.join(format!("{}.part", filename))
+}
+
+pub fn manifest_path(dir: &Path, filename: &str) -> PathBuf {
+    dir.join(format!("{}.part.manifest", filename))
+}
+
+impl DownloadManifest {
+    /* None if the file has more chunks than chunk indices can address */
+    pub fn new(filename: &str, version: Option<u64>, hash: String, size: u64, merkle_proof: Vec<Vec<u8>>, root: Option<String>) -> Option<DownloadManifest> {
+        Some(DownloadManifest {
+            filename: filename.to_string(),
+            version,
+            hash,
+            size,
+            merkle_proof,
+            root,
+            received: vec![false; chunk_count(size)? as usize],
+        })
+    }
+
+    pub async fn load(dir: &Path, filename: &str) -> Option<DownloadManifest> {
+        let data = tokio::fs::read(manifest_path(dir, filename)).await.ok()?;
+        serde_json::from_slice(data.as_slice()).ok()
+    }
+
+    pub async fn save(&self, dir: &Path) -> std::io::Result<()> {
+        let data = serde_json::to_vec(self)?;
+        tokio::fs::write(manifest_path(dir, &self.filename), data).await
+    }
+
+    /* Start a fresh download, throwing away whatever partial data was there before */
+    pub async fn create(&self, dir: &Path) -> std::io::Result<()> {
+        /* Files in subdirectories keep their partial downloads in the same subdirectories of `dir` */
+        tokio::fs::create_dir_all(part_path(dir, &self.filename).parent().unwrap_or(dir)).await?;
+        let file = tokio::fs::File::create(part_path(dir, &self.filename)).await?;
+        file.set_len(self.size).await?;
+        self.save(dir).await
+    }
+
+    pub fn missing(&self) -> Vec<u32> {
+        self.received.iter()
+            .enumerate()
+            .filter(|(_, &received)| !received)
+            .map(|(i, _)| i as u32)
+            .collect()
+    }
+
+    /* Remove both the `.part` file and the manifest */
+    pub async fn discard(dir: &Path, filename: &str) {
+        let _ = tokio::fs::remove_file(part_path(dir, filename)).await;
+        let _ = tokio::fs::remove_file(manifest_path(dir, filename)).await;
+    }
+}
+
+/**Write received chunks of one download into the `.part` file and mark them in the manifest, which is saved once
+ * for the whole batch. Chunks written before a crash that aren't in the manifest yet are only downloaded again */
+pub async fn save_chunks(dir: &Path, filename: &str, version: Option<u64>, chunks: Vec<(u32, Vec<u8>)>) -> Vec<String> {
+    let mut manifest = match DownloadManifest::load(dir, filename).await {
+        Some(manifest) => manifest,
+        None => return chunks.iter().map(|(index, _)| format!("Got chunk {} of {} without a download in progress", index, filename)).collect(),
+    };
+    if manifest.version != version {
+        return chunks.iter().map(|(index, _)| format!("Got chunk {} of {} for another version than the one being downloaded", index, filename)).collect();
+    }
+
+    let mut file = match tokio::fs::OpenOptions::new().write(true).open(part_path(dir, filename)).await {
+        Ok(file) => file,
+        Err(e) => return chunks.iter().map(|(index, _)| format!("Unable to save chunk {} of {}: {}", index, filename, e)).collect(),
+    };
+    let mut lines = Vec::with_capacity(chunks.len());
+    let mut written = Vec::new();
+    for (index, data) in chunks {
+        let start = index as u64 * CHUNK_SIZE as u64;
+        let expected = u64::min(CHUNK_SIZE as u64, manifest.size.saturating_sub(start));
+        if index as usize >= manifest.received.len() || data.len() as u64 != expected {
+            lines.push(format!("Got invalid chunk {} of {}", index, filename));
+            continue;
+        }
+
+        let result = async {
+            file.seek(SeekFrom::Start(start)).await?;
+            file.write_all(data.as_slice()).await
+        }.await;
+        match result {
+            Ok(_) => written.push(index),
+            Err(e) => lines.push(format!("Unable to save chunk {} of {}: {}", index, filename, e)),
+        }
+    }
+
+    /* The manifest only marks chunks that made it into the file */
+    if written.is_empty() {
+        return lines;
+    }
+    if let Err(e) = file.flush().await {
+        lines.extend(written.iter().map(|index| format!("Unable to save chunk {} of {}: {}", index, filename, e)));
+        return lines;
+    }
+    for index in written.iter() {
+        manifest.received[*index as usize] = true;
+    }
+    let _ = manifest.save(dir).await;
+    lines.extend(written.iter().map(|index| format!("Saved chunk {} of {}", index, filename)));
+    lines
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn chunks_are_saved_in_one_batch() {
+        let dir = std::env::temp_dir().join(format!("download-test-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let size = CHUNK_SIZE as u64 * 2 + 3;
+        tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap().block_on(async {
+            let manifest = DownloadManifest::new("a", None, "hash".to_string(), size, Vec::new(), None).unwrap();
+            manifest.create(&dir).await.unwrap();
+
+            let chunks = vec![(2, vec![3; 3]), (0, vec![1; CHUNK_SIZE]), (1, vec![2; 5]), (7, vec![0; 3])];
+            let lines = save_chunks(&dir, "a", None, chunks).await;
+            assert_eq!(lines.len(), 4);
+            assert_eq!(DownloadManifest::load(&dir, "a").await.unwrap().missing(), vec![1]);
+
+            /* Chunks of another version than the one being downloaded are left out */
+            save_chunks(&dir, "a", Some(1), vec![(1, vec![2; CHUNK_SIZE])]).await;
+            assert_eq!(DownloadManifest::load(&dir, "a").await.unwrap().missing(), vec![1]);
+
+            save_chunks(&dir, "a", None, vec![(1, vec![2; CHUNK_SIZE])]).await;
+            assert!(DownloadManifest::load(&dir, "a").await.unwrap().missing().is_empty());
+        });
+
+        let data = std::fs::read(part_path(&dir, "a")).unwrap();
+        assert_eq!(data.len() as u64, size);
+        assert_eq!(&data[CHUNK_SIZE - 1..CHUNK_SIZE + 1], &[1, 2]);
+        assert_eq!(&data[2 * CHUNK_SIZE..], &[3; 3]);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
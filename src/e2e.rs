@@ -0,0 +1,174 @@
+use chacha20poly1305::aead::{Aead, KeyInit, Payload};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use std::path::Path;
+
+/* Marks the format of encrypted files */
+const MAGIC: &[u8; 4] = b"ZFE1";
+/* Random per upload, so a file that changes never reuses the key and nonces of a previous version */
+const SALT_LEN: usize = 16;
+const TAG_LEN: usize = 16;
+const NONCE_LEN: usize = 12;
+/* Plaintext bytes per AEAD chunk */
+const SEGMENT_SIZE: usize = 64 * 1024;
+
+const FILE_KEY_CONTEXT: &str = "zama-fileserver 2023 file key";
+const NAME_KEY_CONTEXT: &str = "zama-fileserver 2023 filename key";
+
+/**Master key of the client-side encryption. Contents and filenames are encrypted before they leave
+ * the client, so the server only ever sees ciphertext and hex encoded encrypted names */
+pub struct MasterKey {
+    key: [u8; 32],
+}
+
+/* Nonce of a chunk, the last chunk is marked so a file can't be truncated at a chunk boundary unnoticed */
+fn segment_nonce(index: u64, last: bool) -> [u8; NONCE_LEN] {
+    let mut nonce = [0u8; NONCE_LEN];
+    nonce[..8].copy_from_slice(&index.to_be_bytes());
+    nonce[NONCE_LEN - 1] = last as u8;
+    nonce
+}
+
+impl MasterKey {
+    /* Read a hex encoded 32 byte key from a file */
+    pub fn load(path: &Path) -> std::io::Result<MasterKey> {
+        let invalid = || std::io::Error::new(std::io::ErrorKind::InvalidData, "master key must be 32 hex encoded bytes");
+        let key = hex::decode(std::fs::read_to_string(path)?.trim()).map_err(|_| invalid())?;
+        Ok(MasterKey { key: key.try_into().map_err(|_| invalid())? })
+    }
+
+    fn file_cipher(&self, filename: &str, salt: &[u8]) -> ChaCha20Poly1305 {
+        let mut material = self.key.to_vec();
+        material.extend_from_slice(salt);
+        material.extend_from_slice(filename.as_bytes());
+        let key = blake3::derive_key(FILE_KEY_CONTEXT, material.as_slice());
+        ChaCha20Poly1305::new(Key::from_slice(&key))
+    }
+
+    fn name_key(&self) -> [u8; 32] {
+        blake3::derive_key(NAME_KEY_CONTEXT, &self.key)
+    }
+
+    /**Encrypt a filename deterministically, so the same name always maps to the same name on the server.
+     * The nonce is a MAC of the name, which makes it safe to reuse for the same name only */
+    pub fn encrypt_filename(&self, filename: &str) -> String {
+        let name_key = self.name_key();
+        let mac = blake3::keyed_hash(&name_key, filename.as_bytes());
+        let nonce = &mac.as_bytes()[..NONCE_LEN];
+        let cipher = ChaCha20Poly1305::new(Key::from_slice(&name_key));
+        let ciphertext = cipher.encrypt(Nonce::from_slice(nonce), filename.as_bytes()).expect("filename fits in a single AEAD message");
+
+        let mut encrypted = nonce.to_vec();
+        encrypted.extend(ciphertext);
+        hex::encode(encrypted)
+    }
+
+    pub fn decrypt_filename(&self, encrypted: &str) -> Option<String> {
+        let encrypted = hex::decode(encrypted).ok()?;
+        if encrypted.len() < NONCE_LEN + TAG_LEN {
+            return None;
+        }
+
+        let cipher = ChaCha20Poly1305::new(Key::from_slice(&self.name_key()));
+        let filename = cipher.decrypt(Nonce::from_slice(&encrypted[..NONCE_LEN]), &encrypted[NONCE_LEN..]).ok()?;
+        String::from_utf8(filename).ok()
+    }
+
+    /**Encrypt file contents in chunks of SEGMENT_SIZE with a key derived from the master key, the filename
+     * and a random salt. The filename is authenticated too, so contents can't be swapped between files */
+    pub fn encrypt(&self, filename: &str, data: &[u8]) -> Vec<u8> {
+        let salt = rand::random::<[u8; SALT_LEN]>();
+        let cipher = self.file_cipher(filename, &salt);
+
+        let mut encrypted = Vec::with_capacity(MAGIC.len() + SALT_LEN + data.len() + (data.len() / SEGMENT_SIZE + 1) * TAG_LEN);
+        encrypted.extend_from_slice(MAGIC);
+        encrypted.extend_from_slice(&salt);
+
+        /* An empty file is still one (empty) last chunk */
+        let segments = data.chunks(SEGMENT_SIZE).collect::<Vec<&[u8]>>();
+        let segments = if segments.is_empty() { vec![&data[..0]] } else { segments };
+        for (i, segment) in segments.iter().enumerate() {
+            let nonce = segment_nonce(i as u64, i == segments.len() - 1);
+            let ciphertext = cipher.encrypt(Nonce::from_slice(&nonce), Payload { msg: segment, aad: filename.as_bytes() })
+                .expect("segment fits in a single AEAD message");
+            encrypted.extend(ciphertext);
+        }
+        encrypted
+    }
+
+    /* Decrypt contents produced by `encrypt`, None if anything was tampered with */
+    pub fn decrypt(&self, filename: &str, encrypted: &[u8]) -> Option<Vec<u8>> {
+        let body = encrypted.strip_prefix(MAGIC.as_slice())?;
+        if body.len() < SALT_LEN {
+            return None;
+        }
+        let (salt, body) = body.split_at(SALT_LEN);
+        let cipher = self.file_cipher(filename, salt);
+
+        let segments = body.chunks(SEGMENT_SIZE + TAG_LEN).collect::<Vec<&[u8]>>();
+        let mut data = Vec::with_capacity(body.len());
+        for (i, segment) in segments.iter().enumerate() {
+            let nonce = segment_nonce(i as u64, i == segments.len() - 1);
+            let plaintext = cipher.decrypt(Nonce::from_slice(&nonce), Payload { msg: segment, aad: filename.as_bytes() }).ok()?;
+            data.extend(plaintext);
+        }
+
+        /* Without a last chunk the file was cut short */
+        if segments.is_empty() {
+            return None;
+        }
+        Some(data)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(byte: u8) -> MasterKey {
+        MasterKey { key: [byte; 32] }
+    }
+
+    fn data(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i % 251) as u8).collect()
+    }
+
+    #[test]
+    fn contents_round_trip() {
+        let key = key(1);
+        for len in [0, 1, SEGMENT_SIZE, SEGMENT_SIZE + 1, 3 * SEGMENT_SIZE + 17] {
+            let data = data(len);
+            let encrypted = key.encrypt("notes", &data);
+            assert_eq!(key.decrypt("notes", &encrypted), Some(data));
+        }
+    }
+
+    #[test]
+    fn tampered_contents_are_refused() {
+        let key = key(1);
+        let encrypted = key.encrypt("notes", &data(2 * SEGMENT_SIZE + 5));
+
+        /* Cut right after the first or second segment, and right after the header */
+        let header = MAGIC.len() + SALT_LEN;
+        for segments in 0..3 {
+            assert_eq!(key.decrypt("notes", &encrypted[..header + segments * (SEGMENT_SIZE + TAG_LEN)]), None);
+        }
+        assert_eq!(key.decrypt("other", &encrypted), None);
+        assert_eq!(self::key(2).decrypt("notes", &encrypted), None);
+
+        let mut flipped = encrypted.clone();
+        flipped[header + 1] ^= 1;
+        assert_eq!(key.decrypt("notes", &flipped), None);
+    }
+
+    #[test]
+    fn filenames_are_encrypted_deterministically() {
+        let key = key(1);
+        let encrypted = key.encrypt_filename("dir/notes");
+        assert_eq!(key.encrypt_filename("dir/notes"), encrypted);
+        assert_ne!(key.encrypt_filename("dir/notes2"), encrypted);
+        assert_ne!(self::key(2).encrypt_filename("dir/notes"), encrypted);
+        assert_eq!(key.decrypt_filename(&encrypted).as_deref(), Some("dir/notes"));
+        assert_eq!(self::key(2).decrypt_filename(&encrypted), None);
+        assert_eq!(key.decrypt_filename("not hex"), None);
+    }
+}
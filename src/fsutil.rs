@@ -0,0 +1,58 @@
+use std::fs::File;
+use std::io::prelude::*;
+use std::path::{Path, PathBuf};
+
+/* Temporary files are hidden and end with .tmp, so leftovers can be recognized after a crash */
+pub fn temp_path(path: &Path) -> PathBuf {
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    path.with_file_name(format!(".{}.tmp", name))
+}
+
+pub fn is_temp_file(name: &str) -> bool {
+    name.starts_with('.') && name.ends_with(".tmp")
+}
+
+/* Make creates, renames and removals of the directory entries durable */
+pub fn sync_dir(dir: &Path) -> std::io::Result<()> {
+    File::open(dir)?.sync_all()
+}
+
+/**Write to a temp file in the same directory, fsync it, rename it over the target and fsync the directory.
+ * A crash leaves either the old or the new contents on disk, never a partially written file */
+pub fn write_file_atomic(path: &Path, data: &[u8]) -> std::io::Result<()> {
+    let tmp = temp_path(path);
+
+    let res = (|| {
+        let mut file = File::create(&tmp)?;
+        file.write_all(data)?;
+        file.sync_all()?;
+        std::fs::rename(&tmp, path)
+    })();
+    if res.is_err() {
+        let _ = std::fs::remove_file(&tmp);
+    }
+    res?;
+
+    sync_dir(path.parent().unwrap_or(Path::new(".")))
+}
+
+/**Paths of the files in a directory and all of its subdirectories, relative to `root` and separated by `/`.
+ * `dir` is relative to `root` as well, "" for `root` itself. Leftover temp files are skipped */
+pub fn list_files(root: &Path, dir: &str) -> std::io::Result<Vec<String>> {
+    let mut files = Vec::new();
+    let mut dirs = vec![dir.trim_matches('/').to_string()];
+    while let Some(dir) = dirs.pop() {
+        for entry in std::fs::read_dir(root.join(&dir))? {
+            let entry = entry?;
+            let name = entry.file_name().to_string_lossy().into_owned();
+            let path = if dir.is_empty() { name.clone() } else { format!("{}/{}", dir, name) };
+            if entry.file_type()?.is_dir() {
+                dirs.push(path);
+            } else if !is_temp_file(&name) {
+                files.push(path);
+            }
+        }
+    }
+    files.sort();
+    Ok(files)
+}
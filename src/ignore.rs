@@ -0,0 +1,144 @@
+use std::path::Path;
+
+/* File in the client directory with more patterns, one per line. Empty lines and lines starting with `#` are skipped */
+pub const IGNORE_FILE: &str = ".zfignore";
+
+/**Patterns of paths that recursive transfers skip, in the style of .gitignore. `*` matches anything but `/`,
+ * `**` anything including `/` and `?` a single character other than `/`. A pattern without a `/` matches the name
+ * of a file or of any directory it's in, a pattern with one matches the path of a file or of any directory it's in */
+#[derive(Debug, Clone, Default)]
+pub struct IgnorePatterns {
+    patterns: Vec<String>,
+}
+
+/* Match a glob pattern against a whole path */
+fn glob_match(pattern: &[u8], text: &[u8]) -> bool {
+    let mut memo = vec![None; (pattern.len() + 1) * (text.len() + 1)];
+    glob_match_at(pattern, text, 0, 0, &mut memo)
+}
+
+/**Whether the pattern from `p` on matches the text from `t` on. Stars try every position they could stop at, so the
+ * outcome for each pair of positions is kept in `memo` and worked out only once, however many stars there are */
+fn glob_match_at(pattern: &[u8], text: &[u8], p: usize, t: usize, memo: &mut [Option<bool>]) -> bool {
+    let key = p * (text.len() + 1) + t;
+    if let Some(matched) = memo[key] {
+        return matched;
+    }
+
+    let matched = match &pattern[p..] {
+        [] => t == text.len(),
+        /* `**` followed by `/` matches any number of whole directories, on its own anything at all */
+        [b'*', b'*', b'/', ..] => {
+            glob_match_at(pattern, text, p + 3, t, memo) || (t..text.len()).any(|i| text[i] == b'/' && glob_match_at(pattern, text, p + 3, i + 1, memo))
+        }
+        [b'*', b'*', ..] => (t..=text.len()).any(|i| glob_match_at(pattern, text, p + 2, i, memo)),
+        [b'*', ..] => {
+            let within_name = text[t..].iter().position(|&c| c == b'/').map_or(text.len(), |i| t + i);
+            (t..=within_name).any(|i| glob_match_at(pattern, text, p + 1, i, memo))
+        }
+        [b'?', ..] => text.get(t).is_some_and(|&c| c != b'/') && glob_match_at(pattern, text, p + 1, t + 1, memo),
+        [c, ..] => text.get(t) == Some(c) && glob_match_at(pattern, text, p + 1, t + 1, memo),
+    };
+    memo[key] = Some(matched);
+    matched
+}
+
+impl IgnorePatterns {
+    /**Patterns in the ignore file of `dir` and in `remembered`, together with the ones given on the command line.
+     * Those are added to `remembered` so they keep applying in later runs, which build the trusted root from the
+     * same files */
+    pub fn load(dir: &Path, remembered: &Path, patterns: &[String]) -> IgnorePatterns {
+        let mut kept = std::fs::read_to_string(remembered).unwrap_or_default();
+        let new = patterns.iter().filter(|pattern| !kept.lines().any(|line| line == pattern.as_str())).cloned().collect::<Vec<String>>();
+        if !new.is_empty() {
+            if !kept.is_empty() && !kept.ends_with('\n') {
+                kept.push('\n');
+            }
+            kept.extend(new.iter().map(|pattern| format!("{}\n", pattern)));
+            let saved = remembered.parent().map_or(Ok(()), std::fs::create_dir_all).and_then(|_| std::fs::write(remembered, kept.as_bytes()));
+            if let Err(e) = saved {
+                println!("Unable to remember the ignore patterns: {}", e);
+            }
+        }
+
+        let from_file = std::fs::read_to_string(dir.join(IGNORE_FILE)).unwrap_or_default();
+        let patterns = kept.lines()
+            .chain(from_file.lines())
+            .map(str::trim)
+            .filter(|pattern| !pattern.is_empty() && !pattern.starts_with('#'))
+            .map(|pattern| pattern.trim_end_matches('/').to_string())
+            .collect();
+        IgnorePatterns { patterns }
+    }
+
+    /* The ignore file itself is never transferred */
+    pub fn is_ignored(&self, path: &str) -> bool {
+        if path == IGNORE_FILE {
+            return true;
+        }
+
+        /* The path itself and the paths of the directories it's in */
+        let mut paths = path.match_indices('/').map(|(i, _)| &path[..i]).collect::<Vec<&str>>();
+        paths.push(path);
+
+        self.patterns.iter().any(|pattern| match pattern.strip_prefix('/').or(pattern.contains('/').then_some(pattern.as_str())) {
+            Some(pattern) => paths.iter().any(|path| glob_match(pattern.as_bytes(), path.as_bytes())),
+            None => path.split('/').any(|name| glob_match(pattern.as_bytes(), name.as_bytes())),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn matches(pattern: &str, text: &str) -> bool {
+        glob_match(pattern.as_bytes(), text.as_bytes())
+    }
+
+    #[test]
+    fn globs_match_like_gitignore() {
+        assert!(matches("*.txt", "a.txt"));
+        assert!(!matches("*.txt", "docs/a.txt"));
+        assert!(matches("docs/**/*.txt", "docs/a.txt"));
+        assert!(matches("docs/**/*.txt", "docs/x/y/a.txt"));
+        assert!(!matches("docs/**/*.txt", "docsx/a.txt"));
+        assert!(matches("**", "a/b/c"));
+        assert!(matches("a?c", "abc"));
+        assert!(!matches("a?c", "a/c"));
+        assert!(!matches("a*", "ab/c"));
+    }
+
+    #[test]
+    fn many_stars_stay_cheap() {
+        let text = "a".repeat(1000);
+        assert!(!matches(&format!("{}b", "**a".repeat(20)), &text));
+        assert!(!matches(&format!("{}b", "*a".repeat(20)), &text));
+        assert!(matches(&"**a".repeat(20), &text));
+    }
+
+    #[test]
+    fn patterns_match_names_or_paths() {
+        let ignored = IgnorePatterns { patterns: vec!["*.bak".to_string(), "build".to_string(), "/docs/tmp".to_string()] };
+        assert!(ignored.is_ignored("x/y.bak"));
+        assert!(ignored.is_ignored("src/build/out.o"));
+        assert!(ignored.is_ignored("docs/tmp/a"));
+        assert!(!ignored.is_ignored("x/docs/tmp/a"));
+        assert!(ignored.is_ignored(IGNORE_FILE));
+        assert!(!ignored.is_ignored("src/main.rs"));
+    }
+
+    #[test]
+    fn command_line_patterns_are_remembered() {
+        let dir = std::env::temp_dir().join(format!("ignore-test-{}", std::process::id()));
+        let remembered = dir.join("state/ignore");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+
+        assert!(IgnorePatterns::load(&dir, &remembered, &["*.bak".to_string()]).is_ignored("a.bak"));
+        assert!(IgnorePatterns::load(&dir, &remembered, &[]).is_ignored("a.bak"));
+        IgnorePatterns::load(&dir, &remembered, &["*.bak".to_string(), "*.tmp".to_string()]);
+        assert_eq!(std::fs::read_to_string(&remembered).unwrap(), "*.bak\n*.tmp\n");
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
@@ -0,0 +1,311 @@
+use crate::fsutil::{is_temp_file, write_file_atomic};
+use crate::merkletree::MerkleTree;
+use crate::protocol::{Usage, VersionInfo};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::Path;
+
+/* Size of a file and the identity that uploaded its current contents */
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct FileMeta {
+    pub size: u64,
+    pub owner: Option<String>,
+}
+
+/* A version kept in the history of a file, with the root of the tree of its epoch and its proof against that root */
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Version {
+    pub info: VersionInfo,
+    pub root: String,
+    pub merkle_proof: Vec<Vec<u8>>,
+}
+
+/* Files and bytes stored, overall and per owner. Kept up to date with every change, so checking a quota doesn't walk the index */
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Totals {
+    pub all: Usage,
+    pub owners: BTreeMap<String, Usage>,
+}
+
+impl Totals {
+    pub fn usage(&self, owner: Option<&str>) -> Usage {
+        match owner {
+            Some(owner) => self.owners.get(owner).copied().unwrap_or_default(),
+            None => self.all,
+        }
+    }
+
+    /* Apply a change to the overall usage and to the usage of `owner`, owners that have nothing left are forgotten */
+    pub fn adjust(&mut self, owner: Option<&str>, change: impl Fn(&mut Usage)) {
+        change(&mut self.all);
+        if let Some(owner) = owner {
+            let usage = self.owners.entry(owner.to_string()).or_default();
+            change(usage);
+            if *usage == Usage::default() {
+                self.owners.remove(owner);
+            }
+        }
+    }
+}
+
+/* Longest path and deepest nesting allowed, so a listing entry and a proof always fit in a datagram */
+pub const MAX_PATH_LEN: usize = 1024;
+pub const MAX_PATH_DEPTH: usize = 32;
+
+/**Paths are relative and separated by `/`, directories exist as long as there are files below them.
+ * Empty, `.` and `..` components are not allowed, so a path can't escape the folder it's stored in on the client */
+pub fn is_valid_path(path: &str) -> bool {
+    !path.is_empty()
+        && path.len() <= MAX_PATH_LEN
+        && !path.contains(['\\', '\0'])
+        && path.split('/').count() <= MAX_PATH_DEPTH
+        && path.split('/').all(|part| !part.is_empty() && part != "." && part != "..")
+}
+
+/**Persisted filename -> content hash index of the server's files, the merkle tree is built from it */
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct FileIndex {
+    pub files: BTreeMap<String, String>,
+    /* Indexes written by older versions don't have this, it's filled in from the object store when loading */
+    #[serde(default)]
+    pub meta: BTreeMap<String, FileMeta>,
+    /* History of every filename, oldest version first. Deleted files keep theirs until it's pruned */
+    #[serde(default)]
+    pub versions: BTreeMap<String, Vec<Version>>,
+    /* Number of changes made to the index, every version records the one it was made in */
+    #[serde(default)]
+    pub epoch: u64,
+    /* Number of filenames and versions referencing each blob, rebuilt from `files` and `versions` when loading */
+    #[serde(skip)]
+    pub refs: BTreeMap<String, u64>,
+    /* Current files and the size of every kept version, rebuilt the same way */
+    #[serde(skip)]
+    pub totals: Totals,
+}
+
+impl FileIndex {
+    /* A missing file is reported as NotFound, one that doesn't parse as InvalidData */
+    pub fn load(path: &Path) -> std::io::Result<FileIndex> {
+        let data = std::fs::read(path)?;
+        let mut index: FileIndex = serde_json::from_slice(data.as_slice())?;
+        index.recount();
+        Ok(index)
+    }
+
+    pub fn save(&self, path: &Path) -> std::io::Result<()> {
+        write_file_atomic(path, serde_json::to_vec(self)?.as_slice())
+    }
+
+    /* Hash all files in a folder, used when there is no persisted index yet */
+    pub async fn from_folder(path: &Path) -> FileIndex {
+        let mut index = FileIndex::default();
+        if let Ok(mut rd) = tokio::fs::read_dir(path).await {
+            while let Ok(Some(child)) = rd.next_entry().await {
+                let name = child.file_name().to_string_lossy().to_string();
+                if is_temp_file(&name) || child.metadata().await.map_or(true, |m| m.is_dir()) {
+                    continue;
+                }
+                if let Ok(data) = tokio::fs::read(child.path()).await {
+                    let meta = FileMeta { size: data.len() as u64, owner: None };
+                    index.insert(name, blake3::hash(data.as_slice()).to_string(), meta);
+                }
+            }
+        }
+        index
+    }
+
+    fn recount(&mut self) {
+        self.refs.clear();
+        let versions = self.versions.values().flatten().filter_map(|version| version.info.hash.as_ref());
+        for hash in self.files.values().chain(versions) {
+            *self.refs.entry(hash.clone()).or_insert(0) += 1;
+        }
+        let mut totals = Totals::default();
+        for meta in self.meta.values() {
+            totals.adjust(meta.owner.as_deref(), |usage| usage.files += 1);
+        }
+        for version in self.versions.values().flatten().filter(|version| version.info.hash.is_some()) {
+            totals.adjust(version.info.owner.as_deref(), |usage| usage.bytes += version.info.size);
+        }
+        self.totals = totals;
+    }
+
+    pub fn get(&self, filename: &str) -> Option<&String> {
+        self.files.get(filename)
+    }
+
+    pub fn meta(&self, filename: &str) -> Option<&FileMeta> {
+        self.meta.get(filename)
+    }
+
+    /* Point a filename at a blob, returns the hash it pointed at before */
+    pub fn insert(&mut self, filename: String, hash: String, meta: FileMeta) -> Option<String> {
+        *self.refs.entry(hash.clone()).or_insert(0) += 1;
+        self.totals.adjust(meta.owner.as_deref(), |usage| usage.files += 1);
+        if let Some(old) = self.meta.insert(filename.clone(), meta) {
+            self.totals.adjust(old.owner.as_deref(), |usage| usage.files = usage.files.saturating_sub(1));
+        }
+        let old = self.files.insert(filename, hash);
+        if let Some(old) = &old {
+            self.release(old);
+        }
+        old
+    }
+
+    pub fn remove(&mut self, filename: &str) -> Option<String> {
+        if let Some(old) = self.meta.remove(filename) {
+            self.totals.adjust(old.owner.as_deref(), |usage| usage.files = usage.files.saturating_sub(1));
+        }
+        let old = self.files.remove(filename);
+        if let Some(old) = &old {
+            self.release(old);
+        }
+        old
+    }
+
+    fn release(&mut self, hash: &str) {
+        if let Some(count) = self.refs.get_mut(hash) {
+            *count = count.saturating_sub(1);
+            if *count == 0 {
+                self.refs.remove(hash);
+            }
+        }
+    }
+
+    /* Versions of a file that are kept, oldest first */
+    pub fn versions(&self, filename: &str) -> &[Version] {
+        self.versions.get(filename).map_or(&[], |versions| versions.as_slice())
+    }
+
+    pub fn version(&self, filename: &str, version: u64) -> Option<&Version> {
+        self.versions(filename).iter().find(|v| v.info.version == version)
+    }
+
+    /* Numbers keep counting up when old versions are pruned, so a number always means the same contents */
+    pub fn next_version(&self, filename: &str) -> u64 {
+        self.versions(filename).last().map_or(1, |v| v.info.version + 1)
+    }
+
+    /**Add a version to the history of a file, dropping the oldest ones beyond `keep`. Their blobs are released
+     * like the ones of overwritten files, so only the versions that are kept hold on to their contents */
+    pub fn push_version(&mut self, filename: &str, version: Version, keep: usize) {
+        if let Some(hash) = &version.info.hash {
+            *self.refs.entry(hash.clone()).or_insert(0) += 1;
+            self.totals.adjust(version.info.owner.as_deref(), |usage| usage.bytes += version.info.size);
+        }
+        let versions = self.versions.entry(filename.to_string()).or_default();
+        versions.push(version);
+        let pruned = versions.drain(..versions.len().saturating_sub(keep.max(1))).collect::<Vec<Version>>();
+        for version in pruned.iter().filter(|version| version.info.hash.is_some()) {
+            self.totals.adjust(version.info.owner.as_deref(), |usage| usage.bytes = usage.bytes.saturating_sub(version.info.size));
+        }
+        for hash in pruned.iter().filter_map(|version| version.info.hash.as_ref()) {
+            self.release(hash);
+        }
+    }
+
+    /* Versions of a file that a new one would push out when `keep` are kept */
+    pub fn pruned_by_next(&self, filename: &str, keep: usize) -> &[Version] {
+        let versions = self.versions(filename);
+        &versions[..(versions.len() + 1).saturating_sub(keep.max(1)).min(versions.len())]
+    }
+
+    /* Forget versions, e.g. the ones whose contents the storage backend lost */
+    pub fn retain_versions(&mut self, mut keep: impl FnMut(&Version) -> bool) {
+        let mut released = Vec::new();
+        for versions in self.versions.values_mut() {
+            versions.retain(|version| {
+                let kept = keep(version);
+                if !kept {
+                    released.extend(version.info.hash.clone().map(|hash| (hash, version.info.owner.clone(), version.info.size)));
+                }
+                kept
+            });
+        }
+        self.versions.retain(|_, versions| !versions.is_empty());
+        for (hash, owner, size) in released {
+            self.totals.adjust(owner.as_deref(), |usage| usage.bytes = usage.bytes.saturating_sub(size));
+            self.release(&hash);
+        }
+    }
+
+    /**Number of current files and the size of all kept versions, of everyone or only of the ones uploaded by `owner`.
+     * The newest version of a file is its current contents, so those aren't counted twice. Versions count with
+     * their full size even when their contents are shared with other files or versions */
+    pub fn usage(&self, owner: Option<&str>) -> Usage {
+        self.totals.usage(owner)
+    }
+
+    pub fn is_referenced(&self, hash: &str) -> bool {
+        self.refs.contains_key(hash)
+    }
+
+    /**Whether a file can be stored at a path without clashing with the directories implied by the other paths:
+     * none of its parents may be a file, and it may not be a directory itself */
+    pub fn conflicts(&self, path: &str) -> bool {
+        let is_parent_a_file = path.match_indices('/').any(|(i, _)| self.files.contains_key(&path[..i]));
+        let below = format!("{}/", path);
+        let is_directory = self.files.range(below.clone()..).next().is_some_and(|(file, _)| file.starts_with(&below));
+        is_parent_a_file || is_directory
+    }
+
+    /* Files are placed in the tree by their path, the same way MerkleTree::from_folder places them */
+    pub fn tree(&self) -> MerkleTree {
+        MerkleTree::from_files(self.files.iter().map(|(filename, hash)| (filename.clone(), hash.clone())))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Utc;
+
+    /* Upload `hash` as the contents of `filename` and record the version the way the store does */
+    fn upload(index: &mut FileIndex, filename: &str, hash: &str, size: u64, keep: usize) {
+        let owner = Some("alice".to_string());
+        index.insert(filename.to_string(), hash.to_string(), FileMeta { size, owner: owner.clone() });
+        index.epoch += 1;
+        let info = VersionInfo { version: index.next_version(filename), hash: Some(hash.to_string()), size, timestamp: Utc::now(), owner, epoch: index.epoch };
+        index.push_version(filename, Version { info, root: String::new(), merkle_proof: Vec::new() }, keep);
+    }
+
+    fn numbers(index: &FileIndex, filename: &str) -> Vec<u64> {
+        index.versions(filename).iter().map(|version| version.info.version).collect()
+    }
+
+    #[test]
+    fn old_versions_are_pruned_and_release_their_blobs() {
+        let mut index = FileIndex::default();
+        upload(&mut index, "a", "1", 10, 2);
+        upload(&mut index, "a", "2", 20, 2);
+        assert_eq!(index.pruned_by_next("a", 2).len(), 1);
+        upload(&mut index, "a", "3", 30, 2);
+
+        assert_eq!(numbers(&index, "a"), vec![2, 3]);
+        assert!(!index.is_referenced("1"));
+        assert!(index.is_referenced("2") && index.is_referenced("3"));
+        assert_eq!(index.usage(Some("alice")), Usage { files: 1, bytes: 50 });
+        /* Numbers go on where they left off */
+        assert_eq!(index.next_version("a"), 4);
+
+        /* A blob shared with another file stays referenced when a version of it is pruned */
+        upload(&mut index, "b", "2", 20, 2);
+        upload(&mut index, "a", "4", 40, 2);
+        assert!(index.is_referenced("2"));
+    }
+
+    #[test]
+    fn forgotten_versions_release_their_blobs() {
+        let mut index = FileIndex::default();
+        upload(&mut index, "a", "1", 10, 5);
+        upload(&mut index, "a", "2", 20, 5);
+        upload(&mut index, "b", "3", 30, 5);
+        index.remove("b");
+
+        index.retain_versions(|version| version.info.hash.as_deref() != Some("1") && version.info.hash.as_deref() != Some("3"));
+        assert_eq!(numbers(&index, "a"), vec![2]);
+        assert!(!index.versions.contains_key("b"));
+        assert!(!index.is_referenced("1") && !index.is_referenced("3"));
+        assert_eq!(index.usage(None), Usage { files: 1, bytes: 20 });
+    }
+}
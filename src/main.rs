@@ -1,57 +1,372 @@
-#![feature(os_str_bytes)]
-#![feature(async_closure)]
-
-use clap::{Parser, ValueEnum};
-use client::run_client;
-use hydroflow::tokio;
-use hydroflow::util::{bind_udp_bytes, ipv4_resolve};
-use server::run_server;
-use std::net::SocketAddr;
-
-mod client;
-mod protocol;
-mod server;
-
-mod merkletree;
-
-#[derive(Clone, ValueEnum, Debug)]
-enum Role {
-    Client,
-    Server,
-}
-
-#[derive(Parser, Debug)]
-struct Opts {
-    #[clap(value_enum, long)]
-    role: Role,
-    #[clap(long, value_parser = ipv4_resolve)]
-    addr: Option<SocketAddr>,
-    #[clap(long, value_parser = ipv4_resolve)]
-    server_addr: Option<SocketAddr>,
-    //Directory to store files
-    #[clap(long)]
-    dir: Option<String>
-}
-
-#[hydroflow::main]
-async fn main() {
-    // parse command line arguments
-    let opts = Opts::parse();
-    // if no addr was provided, we ask the OS to assign a local port by passing in "localhost:0"
-    let addr = opts
-        .addr
-        .unwrap_or_else(|| ipv4_resolve("localhost:0").unwrap());
-
-    // allocate `outbound` sink and `inbound` stream
-    let (outbound, inbound, addr) = bind_udp_bytes(addr).await;
-    println!("Listening on {:?}", addr);
-
-    match opts.role {
-        Role::Server => {
-            run_server(outbound, inbound).await;
-        }
-        Role::Client => {
-            run_client(outbound, inbound, opts).await;
-        }
-    }
-}
+#![feature(os_str_bytes)]
+#![feature(async_closure)]
+
+use clap::{Parser, Subcommand, ValueEnum};
+use client::run_client;
+use compress::{compress_udp, Codec, CompressedStorage};
+use hydroflow::tokio;
+use hydroflow::util::{bind_udp_bytes, ipv4_resolve};
+use acl::Acl;
+use atrest::{EncryptedStorage, KeyRing};
+use auth::KeyStore;
+use protocol::{Preconditions, Quota};
+use quota::Limits;
+use ratelimit::RateLimits;
+use server::{run_server, ServerConfig};
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::Arc;
+use s3::S3Storage;
+use secure::{load_or_create_keypair, secure_udp, Security, SessionIds};
+use storage::{FsStorage, MemoryStorage, Storage};
+
+mod acl;
+mod atrest;
+mod auth;
+mod client;
+mod compress;
+mod download;
+mod e2e;
+mod fsutil;
+mod ignore;
+mod index;
+mod net;
+mod objects;
+mod protocol;
+mod quota;
+mod ratelimit;
+mod s3;
+mod secure;
+mod server;
+mod session;
+mod storage;
+mod store;
+mod upload;
+mod validate;
+mod wal;
+#[cfg(target_os = "linux")]
+mod watch;
+
+mod merkletree;
+
+#[derive(Clone, ValueEnum, Debug)]
+enum Role {
+    Client,
+    Server,
+}
+
+#[derive(Clone, ValueEnum, Debug)]
+enum StorageBackend {
+    Fs,
+    Memory,
+    S3,
+}
+
+/* Which side wins for a file that changed both locally and on the server since the last sync */
+#[derive(Clone, ValueEnum, Debug)]
+enum ConflictPolicy {
+    /// Leave the file as it is on both sides
+    Skip,
+    /// Keep the local file, it's uploaded, or deleted from the server
+    Local,
+    /// Keep the server's file, it's downloaded, or deleted locally
+    Remote,
+}
+
+/* Preconditions of uploads and deletes as given on the command line */
+#[derive(clap::Args, Clone, Debug)]
+struct PreconditionArgs {
+    /// Only apply the change if the file on the server currently has this hash
+    #[clap(long)]
+    if_match: Option<String>,
+    /// Only apply the change if the file doesn't have this hash, `*` if it must not exist yet
+    #[clap(long)]
+    if_none_match: Option<String>,
+    /// Only apply the change if the root of the server's tree is this one, empty if the store must be empty
+    #[clap(long)]
+    if_root_is: Option<String>,
+}
+
+impl From<PreconditionArgs> for Preconditions {
+    fn from(args: PreconditionArgs) -> Preconditions {
+        Preconditions { if_match: args.if_match, if_none_match: args.if_none_match, if_root_is: args.if_root_is }
+    }
+}
+
+#[derive(Subcommand, Clone, Debug)]
+enum Command {
+    /// Upload files from the client directory, resuming interrupted uploads
+    Upload {
+        files: Vec<String>,
+        /// Upload everything in the given directories and their subdirectories, `.` for the whole client directory
+        #[clap(short, long)]
+        recursive: bool,
+        /// Skip files matching this pattern in recursive uploads, on top of the patterns in `.client/.zfignore`
+        #[clap(long)]
+        ignore: Vec<String>,
+        #[clap(flatten)]
+        preconditions: PreconditionArgs,
+    },
+    /// Download files into the client directory, resuming from a `.part` file if there is one
+    Download {
+        files: Vec<String>,
+        /// Download this version of the files instead of the current one, see `versions`
+        #[clap(long)]
+        version: Option<u64>,
+        /// Download every file below the given directories on the server, `.` for everything
+        #[clap(short, long)]
+        recursive: bool,
+        /// Skip files matching this pattern in recursive downloads, on top of the patterns in `.client/.zfignore`
+        #[clap(long)]
+        ignore: Vec<String>,
+    },
+    /// Bring the client directory and the server in line both ways, transferring only the files that differ
+    Sync {
+        /// Show what would be transferred and deleted without changing anything
+        #[clap(long)]
+        dry_run: bool,
+        /// What to do with files that changed on both sides since the last sync
+        #[clap(value_enum, long, default_value = "skip")]
+        conflicts: ConflictPolicy,
+        /// Trust the server at this root even though we haven't trusted it before, `''` for an empty server
+        #[clap(long)]
+        accept_root: Option<String>,
+        /// Skip files matching this pattern, on top of the patterns in `.client/.zfignore`
+        #[clap(long)]
+        ignore: Vec<String>,
+    },
+    /// Sync the client directory once, then push changes in it to the server as they happen. Needs inotify, Linux only
+    Watch {
+        /// What to do with files that changed on both sides in the first sync
+        #[clap(value_enum, long, default_value = "skip")]
+        conflicts: ConflictPolicy,
+        /// Trust the server at this root in the first sync even though we haven't trusted it before
+        #[clap(long)]
+        accept_root: Option<String>,
+        /// Skip files matching this pattern, on top of the patterns in `.client/.zfignore`
+        #[clap(long)]
+        ignore: Vec<String>,
+    },
+    /// Delete files from the server
+    Delete {
+        files: Vec<String>,
+        #[clap(flatten)]
+        preconditions: PreconditionArgs,
+    },
+    /// List a directory on the server, the root directory without a path
+    List {
+        path: Option<String>,
+        /// List every file below the directory instead of its files and subdirectories
+        #[clap(short, long)]
+        recursive: bool,
+    },
+    /// Rename a file on the server without sending it again
+    Rename { from: String, to: String },
+    /// Copy a file on the server without sending it again
+    Copy { from: String, to: String },
+    /// Show how much storage is used on the server and the limits on it
+    Usage,
+    /// List the versions the server keeps of files
+    Versions { files: Vec<String> },
+    /// Make a kept version of a file its current contents on the server again
+    Restore { file: String, version: u64 },
+}
+
+#[derive(Parser, Debug)]
+struct Opts {
+    #[clap(value_enum, long)]
+    role: Role,
+    #[clap(long, value_parser = ipv4_resolve)]
+    addr: Option<SocketAddr>,
+    #[clap(long, value_parser = ipv4_resolve)]
+    server_addr: Option<SocketAddr>,
+    //Directory to store files
+    #[clap(long)]
+    dir: Option<String>,
+    //Where the server keeps file contents
+    #[clap(value_enum, long, default_value = "fs")]
+    storage: StorageBackend,
+    //S3 endpoint and bucket for the s3 backend, credentials are read from AWS_ACCESS_KEY_ID and AWS_SECRET_ACCESS_KEY
+    #[clap(long, default_value = "http://localhost:9000")]
+    s3_endpoint: String,
+    #[clap(long, default_value = "zama-fileserver")]
+    s3_bucket: String,
+    //Prefix of the keys of our objects in the bucket, only keys under it are listed and garbage collected
+    #[clap(long, default_value = "objects/")]
+    s3_prefix: String,
+    #[clap(long, default_value = "us-east-1")]
+    s3_region: String,
+    //Number of server workers, each runs its own flow on its own thread and serves a share of the clients
+    #[clap(long, default_value_t = 1)]
+    workers: usize,
+    //Codec used for datagrams to peers that support it, payloads that don't shrink are sent as is
+    #[clap(value_enum, long, default_value = "zstd")]
+    compression: Codec,
+    //Server: compress blobs before storing them
+    #[clap(value_enum, long)]
+    compress_storage: Option<Codec>,
+    //Server: storage limits in bytes and files, for everything stored and for the files uploaded by each identity
+    #[clap(long)]
+    max_file_size: Option<u64>,
+    #[clap(long)]
+    max_total_bytes: Option<u64>,
+    #[clap(long)]
+    max_files: Option<u64>,
+    #[clap(long)]
+    identity_max_file_size: Option<u64>,
+    #[clap(long)]
+    identity_max_total_bytes: Option<u64>,
+    #[clap(long)]
+    identity_max_files: Option<u64>,
+    //Server: requests per second allowed from each address and from each identity, with bursts of up to twice that
+    //by default. Nothing is rate limited without --rate-limit
+    #[clap(long)]
+    rate_limit: Option<f64>,
+    #[clap(long)]
+    rate_burst: Option<f64>,
+    #[clap(long)]
+    identity_rate_limit: Option<f64>,
+    #[clap(long)]
+    identity_rate_burst: Option<f64>,
+    //Server: addresses that have this many requests dropped within 10 seconds are banned for --ban-secs
+    #[clap(long, default_value_t = 100)]
+    ban_after: u32,
+    #[clap(long, default_value_t = 60)]
+    ban_secs: u64,
+    //Server: seconds without any request or heartbeat after which a client's session and its partial uploads are dropped
+    #[clap(long, default_value_t = server::DEFAULT_SESSION_TIMEOUT_SECS)]
+    session_timeout_secs: u64,
+    //Server: number of versions kept of each file, older ones are pruned and their contents garbage collected
+    #[clap(long, default_value_t = store::DEFAULT_VERSIONS)]
+    versions: usize,
+    //Server: file with the `<id> <hex key>` key encryption keys for blobs at rest, the highest id encrypts new blobs. Blobs are plaintext without it
+    #[clap(long)]
+    at_rest_key_file: Option<String>,
+    //Server: re-wrap the data keys of blobs still under an older key with the current one, in the background
+    #[clap(long, requires = "at_rest_key_file")]
+    rotate_at_rest_keys: bool,
+    //Server: file with the `<identity> <hex key>` pairs of the clients allowed in, authentication is disabled without it.
+    //Authentication only holds on the encrypted transport, so it needs --noise-key
+    #[clap(long, requires = "noise_key")]
+    keys: Option<String>,
+    //Server: JSON file with the access rules, reloaded when it changes. Everything is allowed without it
+    #[clap(long)]
+    policy: Option<String>,
+    //Client: identity to authenticate as and the file holding its hex encoded pre-shared key, needs --server-key
+    #[clap(long, requires = "server_key")]
+    identity: Option<String>,
+    #[clap(long)]
+    key_file: Option<String>,
+    //Server: file with the static key pair used to encrypt traffic, created if it doesn't exist. Traffic is plaintext without it
+    #[clap(long)]
+    noise_key: Option<String>,
+    //Client: hex encoded public key of the server, pinned for the encrypted session
+    #[clap(long)]
+    server_key: Option<String>,
+    //Client: file with a hex encoded 32 byte master key, uploads and downloads are encrypted end-to-end with it
+    #[clap(long)]
+    master_key_file: Option<String>,
+    //Client: number of files transferred at once by recursive uploads and downloads
+    #[clap(long, default_value_t = 4)]
+    parallel: usize,
+    //Client command to run, without one the client runs the demo
+    #[clap(subcommand)]
+    command: Option<Command>,
+}
+
+#[hydroflow::main]
+async fn main() {
+    // parse command line arguments
+    let opts = Opts::parse();
+    // if no addr was provided, we ask the OS to assign a local port by passing in "localhost:0"
+    let addr = opts
+        .addr
+        .unwrap_or_else(|| ipv4_resolve("localhost:0").unwrap());
+
+    match opts.role {
+        Role::Server => {
+            let storage: Arc<dyn Storage> = match opts.storage {
+                StorageBackend::Fs => Arc::new(FsStorage::new(&Path::new(server::DATA_DIR).join(server::OBJECTS_DIR))),
+                StorageBackend::Memory => Arc::new(MemoryStorage::default()),
+                StorageBackend::S3 => Arc::new(S3Storage::new(
+                    &opts.s3_endpoint,
+                    &opts.s3_bucket,
+                    &opts.s3_prefix,
+                    &opts.s3_region,
+                    &std::env::var("AWS_ACCESS_KEY_ID").unwrap_or_default(),
+                    &std::env::var("AWS_SECRET_ACCESS_KEY").unwrap_or_default(),
+                )),
+            };
+            let encrypted = opts.at_rest_key_file.as_ref().map(|path| {
+                let keys = KeyRing::load(Path::new(path)).expect("Unable to read at-rest key file");
+                Arc::new(EncryptedStorage::new(storage.clone(), keys))
+            });
+            let storage: Arc<dyn Storage> = match encrypted.clone() {
+                Some(encrypted) => encrypted,
+                None => storage,
+            };
+            /* Compress before encrypting, ciphertext doesn't compress */
+            let storage: Arc<dyn Storage> = match opts.compress_storage {
+                Some(codec) if codec != Codec::None => Arc::new(CompressedStorage::new(storage, codec)),
+                _ => storage,
+            };
+            let keys = opts.keys.as_ref().map(|path| Arc::new(KeyStore::load(Path::new(path)).expect("Unable to read keys file")));
+            let security = match opts.noise_key.as_ref() {
+                Some(path) => {
+                    let (private_key, public_key) = load_or_create_keypair(Path::new(path)).expect("Unable to read server key");
+                    println!("Traffic is encrypted, server key: {}", hex::encode(public_key));
+                    Security::Server { private_key }
+                }
+                None => Security::Plain,
+            };
+            let acl = opts.policy.as_ref().map(|path| Arc::new(Acl::load(Path::new(path)).expect("Unable to read access policy")));
+            let limits = Limits {
+                total: Quota { max_file_size: opts.max_file_size, max_bytes: opts.max_total_bytes, max_files: opts.max_files },
+                per_identity: Quota { max_file_size: opts.identity_max_file_size, max_bytes: opts.identity_max_total_bytes, max_files: opts.identity_max_files },
+                versions: opts.versions.max(1),
+                reserved: Default::default(),
+            };
+            let rate_limits = opts.rate_limit.map(|rate| {
+                let identity_rate = opts.identity_rate_limit.unwrap_or(rate);
+                RateLimits {
+                    rate,
+                    burst: opts.rate_burst.unwrap_or(rate * 2.0),
+                    identity_rate,
+                    identity_burst: opts.identity_rate_burst.unwrap_or(identity_rate * 2.0),
+                    ban_after: opts.ban_after,
+                    ban_duration: std::time::Duration::from_secs(opts.ban_secs),
+                }
+            });
+            let config = ServerConfig {
+                workers: opts.workers.max(1),
+                keys,
+                acl,
+                security,
+                compression: opts.compression,
+                limits,
+                rate_limits,
+                session_timeout: std::time::Duration::from_secs(opts.session_timeout_secs),
+                versions: opts.versions.max(1),
+                rotate_at_rest_keys: encrypted.filter(|_| opts.rotate_at_rest_keys),
+            };
+            if let Err(e) = run_server(addr, storage, config).await {
+                println!("Unable to start the server: {}", e);
+                std::process::exit(1);
+            }
+        }
+        Role::Client => {
+            // allocate `outbound` sink and `inbound` stream
+            let (outbound, inbound, addr) = bind_udp_bytes(addr).await;
+            println!("Listening on {:?}", addr);
+
+            let security = match opts.server_key.as_ref() {
+                Some(key) => Security::Client { server_key: hex::decode(key.trim()).expect("Server key must be hex encoded") },
+                None => Security::Plain,
+            };
+            let (outbound, inbound) = secure_udp(outbound, inbound, security, SessionIds::default());
+            let (outbound, inbound) = compress_udp(outbound, inbound, opts.compression);
+
+            run_client(outbound, inbound, opts).await;
+        }
+    }
+}
@@ -1,192 +1,406 @@
-use std::collections::HashMap;
-use std::path::Path;
-use std::ffi::OsString;
-
-
-#[derive(Debug, Clone, Default)]
-pub struct MTNode {
-    pub hash: OsString,
-    pub l: Option<OsString>,
-    pub r: Option<OsString>,
-    pub parent: Option<OsString>,
-}
-
-
-#[derive(Debug, Clone, Default)]
-pub struct MerkleTree {
-    pub root: Option<OsString>,
-    pub data: HashMap<OsString, MTNode>,
-}
-
-impl MerkleTree {
-    pub async fn from_folder(path: &Path) -> MerkleTree {
-        let mut leaves = Vec::new();
-        let dir = tokio::fs::read_dir(path).await;
-        match dir {
-            Ok(mut rd) => {
-                while let Some(child) = rd.next_entry().await.unwrap() {
-                    if !child.metadata().await.unwrap().is_dir() {
-                        let _ = tokio::fs::read(child.path()).await.map(|data| {
-                            leaves.push(MTNode {
-                                hash: OsString::from(
-                                    blake3::hash(data.as_slice()).to_string()
-                                ),
-                                ..MTNode::default()
-                            });
-                        });
-                    }
-                }
-            }
-            Err(e) => println!("Error: {}", e),
-        }
-        Self::from(leaves)
-    }
-
-    /* Construct a merkle tree from a set of leaf nodes */
-    pub fn from(leaves: Vec<MTNode>) -> MerkleTree {
-        if leaves.len() == 0 {
-            return MerkleTree::default();
-        }
-        
-        let mut hashmap: HashMap<OsString, MTNode> = HashMap::new();
-
-        /* Iterate over vector of nodes, updating it after each iteration until we have only one node left */
-        /* that should be the root node */
-        let mut nodes = leaves;
-        while nodes.len() > 1 {
-            nodes = nodes.chunks_mut(2).flat_map(|pair| {
-                let mut pair = pair.into_iter();
-
-                /* The first value in the pair will always exist, we can unwrap safely */
-                /* The second value however is not guaranteed */
-                let left = pair.next().unwrap();
-                if let Some(right) = pair.next() {
-                    let l = left.hash.as_os_str_bytes();
-
-                    /* Make sure the second vector has same length of 64 bytes */
-                    let mut rv = right.hash.as_os_str_bytes().to_vec();
-                    rv.resize(64, 0);
-                    let r = &rv[..];
-
-                    let parent = MTNode {
-                        hash: OsString::from(
-                            blake3::hash([
-
-                                /* XOR both hashes into one to get the parent hash */
-                                l.iter().zip(r.iter())
-                                .map(|(&x1, &x2)| x1 ^ x2)
-                                .collect::<Vec<u8>>()
-                                .as_slice()
-
-                            ].concat().as_slice()).to_string()
-                        ),
-                        l: Some(left.hash.clone()),
-                        r: Some(right.hash.clone()),
-                        ..MTNode::default()
-                    };
-
-                    left.parent = Some(parent.hash.clone());
-                    right.parent = Some(parent.hash.clone());
-
-                    hashmap.insert(left.hash.clone(), left.clone());
-                    hashmap.insert(right.hash.clone(), right.clone());
-
-                    vec![parent]
-                } else {
-                    let parent = MTNode {
-                        hash: OsString::from(
-                            blake3::hash(
-                                /* Here we don't need to XOR and just hash the first value */
-                                left.hash.as_os_str_bytes()
-                            ).to_string()
-                        ),
-                        l: Some(left.hash.clone()),
-                        ..MTNode::default()
-                    };
-                    left.parent = Some(parent.hash.clone());
-                    hashmap.insert(left.hash.clone(), left.clone());
-
-                    vec![parent]
-                }
-            }).collect::<Vec<MTNode>>();
-            
-            //println!("Added nodes: {:?}", nodes);
-
-            hashmap.extend(
-                nodes.iter()
-                .map(|leaf| (leaf.hash.clone(), leaf.clone()))
-                .collect::<HashMap<OsString, MTNode>>()
-            );
-        }
-
-        let root = nodes.pop().unwrap();
-
-        /* Add root node to hashmap as well */
-        hashmap.insert(root.hash.clone(), root.clone());
-
-        MerkleTree {
-            root: Some(root.hash),
-            data: hashmap,
-        }
-    }
-
-    /* Get merkle proof from file path */
-    pub async fn get_proof(&self, path: &Path) -> Option<Vec<OsString>> {
-        /* Read data from file and hash it */
-        let mut data = Vec::new();
-        let _ = tokio::fs::read(path).await.map(|d| data = d);
-        let file_hash = blake3::hash(data.as_slice()).to_string();
-
-        /* Create proof by going up the chain */
-        let mut proof = Vec::new();
-        let mut node = self.data.get(&OsString::from(file_hash)).unwrap();
-        while let Some(parent) = node.parent.clone() {
-            /* Find whether node is on the left or right of parent */
-            if let Some(parent_node) = self.data.get(&parent) {
-                if parent_node.l.clone().unwrap() == node.hash {
-                    //And add it's opposite sibling to the proof
-                    proof.push(parent_node.r.clone().unwrap_or_default());
-                } else {
-                    //And add it's opposite sibling to the proof
-                    proof.push(parent_node.l.clone().unwrap());
-                }
-                node = parent_node;
-            } else {
-                break;
-            }
-        }
-
-        Some(proof)
-    }
-
-    pub async fn verify_data_with_proof(data: &Vec<u8>, proof: Vec<OsString>, root: OsString) -> bool {
-        let file_hash = blake3::hash(data.as_slice()).to_string();
-
-        /* Iterate over proof, folding into the final root hash */
-        let root_hash = proof.iter().fold(OsString::from(file_hash), |acc_hash, h| {
-            /* Make sure the proof hashes have the same length of 64 bytes if empty */
-            let mut hv = h.as_os_str_bytes().to_vec();
-            hv.resize(64, 0);
-            let other = &hv[..];
-
-            /* XOR both hashes into one to get the parent hash */
-            let combined_hash = acc_hash.as_os_str_bytes().iter()
-            .zip(other.iter())
-            .map(|(&x1, &x2)| x1 ^ x2)
-            .collect::<Vec<u8>>();
-
-            let parent_hash = OsString::from(blake3::hash(combined_hash.as_slice()).to_string());
-            parent_hash
-        });
-
-        root_hash == root
-    }
-    /* Unused 
-    pub async fn verify_file_with_proof(path: &Path, proof: Vec<OsString>, root: OsString) -> bool {
-        /* Read file contents and call the function above supplying data to it */
-        let mut data = Vec::new();
-        let _ = tokio::fs::read(path).await.map(|d| data = d);
-        MerkleTree::verify_data_with_proof(&data, proof, root).await
-    }
-    */
-}
\ No newline at end of file
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::path::Path;
+use std::ffi::OsString;
+
+
+#[derive(Debug, Clone, Default)]
+pub struct MTNode {
+    pub hash: OsString,
+    pub l: Option<OsString>,
+    pub r: Option<OsString>,
+    pub parent: Option<OsString>,
+    /* Path of the file or directory the node is for, None for the nodes inside a directory */
+    pub path: Option<String>,
+}
+
+
+/**Directory-aware merkle tree. Every directory is a binary tree over its children ordered by path, and its node
+ * binds the root of that tree to the path of the directory. Files are bound to their path the same way, so equal
+ * contents under different paths make different nodes. A proof is the list of steps up to the root, for a
+ * single file as well as for a whole directory: `L:<sibling>` or `R:<sibling>` for a sibling on that side, and
+ * `D` where the node of a directory is made. The path of that directory isn't in the proof, the verifier takes it
+ * from the path it is verifying */
+#[derive(Debug, Clone, Default)]
+pub struct MerkleTree {
+    pub root: Option<OsString>,
+    pub data: HashMap<OsString, MTNode>,
+    /* Content hash of every file and node of every directory, keyed by path. The root directory is "" */
+    pub files: HashMap<String, OsString>,
+    pub dirs: HashMap<String, OsString>,
+    /* Nodes of the files and subdirectories right in each directory, in tree order. A subdirectory is keyed by
+     * its path with a trailing `/`, which sorts it where the files below it sort among the others */
+    children: HashMap<String, BTreeMap<String, OsString>>,
+    /* Nodes made inside each directory, dropped when the directory is hashed again */
+    inner: HashMap<String, Vec<OsString>>,
+}
+
+/* Hashes are separated by a tag for the kind of node, and the parts by NUL, which paths and hashes never contain */
+fn tagged_hash(tag: &str, a: &str, b: &str) -> OsString {
+    OsString::from(blake3::hash(format!("{}\0{}\0{}", tag, a, b).as_bytes()).to_string())
+}
+
+/* Node of two nodes next to each other inside a directory, a missing right node is empty */
+fn pair_hash(l: &OsString, r: Option<&OsString>) -> OsString {
+    tagged_hash("node", &l.to_string_lossy(), &r.map(|r| r.to_string_lossy()).unwrap_or_default())
+}
+
+/* Directory a path is in, "" for the root directory */
+fn parent_dir(path: &str) -> &str {
+    path.rsplit_once('/').map_or("", |(parent, _)| parent)
+}
+
+/* Directories a path is in, innermost first and ending with the root directory */
+fn ancestors(path: &str) -> Vec<&str> {
+    let mut dirs = path.match_indices('/').rev().map(|(i, _)| &path[..i]).collect::<Vec<&str>>();
+    if !path.is_empty() {
+        dirs.push("");
+    }
+    dirs
+}
+
+impl MerkleTree {
+    /* Build the tree of a folder and its subfolders, paths are relative to it and separated by `/`. Symbolic links
+     * aren't followed, a link to a parent folder would never end */
+    pub async fn from_folder(path: &Path) -> MerkleTree {
+        let mut files = Vec::new();
+        let mut folders = vec![(path.to_path_buf(), String::new())];
+        while let Some((folder, prefix)) = folders.pop() {
+            let mut rd = match tokio::fs::read_dir(&folder).await {
+                Ok(rd) => rd,
+                Err(e) => {
+                    println!("Error: {}", e);
+                    continue;
+                }
+            };
+            while let Ok(Some(child)) = rd.next_entry().await {
+                let name = format!("{}{}", prefix, child.file_name().to_string_lossy());
+                match tokio::fs::symlink_metadata(child.path()).await {
+                    Ok(meta) if meta.is_dir() => folders.push((child.path(), format!("{}/", name))),
+                    Ok(meta) if meta.is_file() => {
+                        let _ = tokio::fs::read(child.path()).await.map(|data| {
+                            files.push((name, blake3::hash(data.as_slice()).to_string()));
+                        });
+                    }
+                    _ => {}
+                }
+            }
+        }
+
+        Self::from_files(files)
+    }
+
+    /* Construct a merkle tree from the paths of files and the hashes of their contents */
+    pub fn from_files<I: IntoIterator<Item = (String, String)>>(files: I) -> MerkleTree {
+        let mut tree = MerkleTree::default();
+        let mut changed = BTreeSet::new();
+        for (path, hash) in files {
+            tree.place(&path, Some(OsString::from(hash)), &mut changed);
+        }
+        tree.rehash(changed);
+        tree
+    }
+
+    /**Set the contents of files, or remove files without a hash. Only the directories they're in are hashed again, once
+     * each, and directories left empty are removed */
+    pub fn update<'a, I: IntoIterator<Item = (&'a str, Option<&'a str>)>>(&mut self, changes: I) {
+        let mut changed = BTreeSet::new();
+        for (path, hash) in changes {
+            self.place(path, hash.map(OsString::from), &mut changed);
+        }
+        self.rehash(changed);
+    }
+
+    /* Node of a file with the given contents at a path */
+    pub fn file_node(path: &str, hash: &str) -> OsString {
+        tagged_hash("file", path, hash)
+    }
+
+    /* Node of a directory from the nodes of its children, in the order they are in the tree */
+    pub fn directory_node(path: &str, children: Vec<OsString>) -> OsString {
+        let inner = MerkleTree::default().add_level(children);
+        tagged_hash("dir", path, &inner.to_string_lossy())
+    }
+
+    /* Node of a file or a directory in this tree */
+    pub fn node(&self, path: &str) -> Option<OsString> {
+        match self.files.get(path) {
+            Some(hash) => Some(Self::file_node(path, &hash.to_string_lossy())),
+            None => self.dirs.get(path).cloned(),
+        }
+    }
+
+    /* Paths of the files and subdirectories right in a directory, in tree order */
+    pub fn children(&self, dir: &str) -> Vec<String> {
+        self.children.get(dir)
+            .map(|children| children.keys().map(|key| key.trim_end_matches('/').to_string()).collect())
+            .unwrap_or_default()
+    }
+
+    /**Put the node of a file in its directory, or take it out without a hash. The directories it's in are added
+     * to `changed`, their nodes are made by rehash */
+    fn place(&mut self, path: &str, hash: Option<OsString>, changed: &mut BTreeSet<String>) {
+        if let Some(old) = self.node(path).filter(|_| self.files.contains_key(path)) {
+            self.data.remove(&old);
+        }
+        let dir = parent_dir(path).to_string();
+        match hash {
+            Some(hash) => {
+                let node = MTNode { hash: Self::file_node(path, &hash.to_string_lossy()), path: Some(path.to_string()), ..MTNode::default() };
+                self.children.entry(dir).or_default().insert(path.to_string(), node.hash.clone());
+                self.data.insert(node.hash.clone(), node);
+                self.files.insert(path.to_string(), hash);
+            }
+            None => {
+                if let Some(children) = self.children.get_mut(&dir) {
+                    children.remove(path);
+                }
+                self.files.remove(path);
+            }
+        }
+        changed.extend(ancestors(path).into_iter().map(str::to_string));
+    }
+
+    /**Make the nodes of changed directories again, the innermost ones first since their nodes are children of
+     * the others. A directory without children is removed from the one it's in */
+    fn rehash(&mut self, changed: BTreeSet<String>) {
+        let mut changed = changed.into_iter().collect::<Vec<String>>();
+        changed.sort_by_key(|dir| std::cmp::Reverse(if dir.is_empty() { 0 } else { dir.matches('/').count() + 1 }));
+
+        for dir in changed {
+            for node in self.inner.remove(&dir).unwrap_or_default() {
+                self.data.remove(&node);
+            }
+            if let Some(old) = self.dirs.remove(&dir) {
+                self.data.remove(&old);
+            }
+            let key = format!("{}/", dir);
+
+            let children = self.children.get(&dir).map(|children| children.values().cloned().collect::<Vec<OsString>>()).unwrap_or_default();
+            if children.is_empty() {
+                self.children.remove(&dir);
+                if dir.is_empty() {
+                    self.root = None;
+                } else if let Some(siblings) = self.children.get_mut(parent_dir(&dir)) {
+                    siblings.remove(&key);
+                }
+                continue;
+            }
+
+            let mut made = Vec::new();
+            let inner = self.add_level_tracked(children, &mut made);
+            let node = MTNode {
+                hash: tagged_hash("dir", &dir, &inner.to_string_lossy()),
+                l: Some(inner.clone()),
+                path: Some(dir.clone()),
+                ..MTNode::default()
+            };
+            self.set_parent(&inner, &node.hash);
+            self.inner.insert(dir.clone(), made);
+            self.dirs.insert(dir.clone(), node.hash.clone());
+            if dir.is_empty() {
+                self.root = Some(node.hash.clone());
+            } else {
+                self.children.entry(parent_dir(&dir).to_string()).or_default().insert(key, node.hash.clone());
+            }
+            self.data.insert(node.hash.clone(), node);
+        }
+    }
+
+    /* Build the binary tree over the nodes of the children of a directory, returns its root */
+    fn add_level(&mut self, nodes: Vec<OsString>) -> OsString {
+        self.add_level_tracked(nodes, &mut Vec::new())
+    }
+
+    /* Same as add_level, the nodes it makes are added to `made` */
+    fn add_level_tracked(&mut self, mut nodes: Vec<OsString>, made: &mut Vec<OsString>) -> OsString {
+        /* Iterate over vector of nodes, updating it after each iteration until we have only one node left */
+        /* that should be the root node */
+        while nodes.len() > 1 {
+            nodes = nodes.chunks(2).map(|pair| {
+                let left = &pair[0];
+                let right = pair.get(1);
+                let parent = MTNode {
+                    hash: pair_hash(left, right),
+                    l: Some(left.clone()),
+                    r: right.cloned(),
+                    ..MTNode::default()
+                };
+
+                for child in pair {
+                    self.set_parent(child, &parent.hash);
+                }
+                let hash = parent.hash.clone();
+                made.push(hash.clone());
+                self.data.insert(hash.clone(), parent);
+                hash
+            }).collect::<Vec<OsString>>();
+        }
+
+        /* A directory always has at least one child, it wouldn't exist otherwise */
+        nodes.pop().unwrap_or_default()
+    }
+
+    fn set_parent(&mut self, child: &OsString, parent: &OsString) {
+        if let Some(node) = self.data.get_mut(child) {
+            node.parent = Some(parent.clone());
+        }
+    }
+
+    /**Get merkle proof of a file or a directory from its path. The proof starts at the node of the file or
+     * directory, which the verifier makes itself for a file */
+    pub fn get_proof(&self, path: &str) -> Option<Vec<OsString>> {
+        /* Create proof by going up the chain */
+        let mut proof = Vec::new();
+        let mut node = self.data.get(&self.node(path)?)?;
+        while let Some(parent) = node.parent.clone() {
+            let parent_node = self.data.get(&parent)?;
+            if parent_node.path.is_some() {
+                proof.push(OsString::from("D"));
+            } else if parent_node.l.as_ref() == Some(&node.hash) {
+                //And add it's opposite sibling to the proof, with the side it's on
+                proof.push(OsString::from(format!("R:{}", parent_node.r.as_ref().map(|r| r.to_string_lossy()).unwrap_or_default())));
+            } else {
+                proof.push(OsString::from(format!("L:{}", parent_node.l.as_ref().map(|l| l.to_string_lossy()).unwrap_or_default())));
+            }
+            node = parent_node;
+        }
+
+        Some(proof)
+    }
+
+    /* Follow a proof from the node of a path up to the root, None if the proof doesn't fit the path */
+    fn fold_proof(path: &str, node: OsString, proof: &[OsString]) -> Option<OsString> {
+        let mut dirs = ancestors(path).into_iter();
+        let mut acc = node;
+        for step in proof {
+            acc = match step.to_str()?.split_once(':') {
+                None if step == "D" => tagged_hash("dir", dirs.next()?, &acc.to_string_lossy()),
+                Some(("L", sibling)) if !sibling.is_empty() => pair_hash(&OsString::from(sibling), Some(&acc)),
+                Some(("R", sibling)) => pair_hash(&acc, Some(&OsString::from(sibling)).filter(|sibling| !sibling.is_empty())),
+                _ => return None,
+            };
+        }
+        /* The proof must go all the way up to the root directory */
+        match dirs.next() {
+            None => Some(acc),
+            Some(_) => None,
+        }
+    }
+
+    /* Verify the contents of the file at a path, from the hash of the contents */
+    pub fn verify_file_with_proof(path: &str, hash: &str, proof: Vec<OsString>, root: OsString) -> bool {
+        Self::fold_proof(path, Self::file_node(path, hash), proof.as_slice()) == Some(root)
+    }
+
+    pub async fn verify_data_with_proof(path: &str, data: &[u8], proof: Vec<OsString>, root: OsString) -> bool {
+        let file_hash = blake3::hash(data).to_string();
+        Self::verify_file_with_proof(path, &file_hash, proof, root)
+    }
+
+    /* Verify the node of the directory at a path against the root */
+    pub fn verify_node_with_proof(path: &str, node: OsString, proof: Vec<OsString>, root: OsString) -> bool {
+        Self::fold_proof(path, node, proof.as_slice()) == Some(root)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hash(data: &str) -> String {
+        blake3::hash(data.as_bytes()).to_string()
+    }
+
+    fn tree() -> MerkleTree {
+        MerkleTree::from_files(["a.txt", "b.txt", "docs/c.txt", "docs/d.txt", "docs/deep/e.txt"]
+            .into_iter()
+            .map(|path| (path.to_string(), hash(path))))
+    }
+
+    #[test]
+    fn proofs_of_files_and_directories_verify() {
+        let tree = tree();
+        let root = tree.root.clone().unwrap();
+        for path in ["a.txt", "b.txt", "docs/c.txt", "docs/d.txt", "docs/deep/e.txt"] {
+            let proof = tree.get_proof(path).unwrap();
+            assert!(MerkleTree::verify_file_with_proof(path, &hash(path), proof, root.clone()), "{}", path);
+        }
+        for dir in ["", "docs", "docs/deep"] {
+            let proof = tree.get_proof(dir).unwrap();
+            assert!(MerkleTree::verify_node_with_proof(dir, tree.dirs[dir].clone(), proof, root.clone()), "{}", dir);
+        }
+    }
+
+    #[test]
+    fn other_contents_dont_verify() {
+        let tree = tree();
+        let proof = tree.get_proof("docs/c.txt").unwrap();
+        assert!(!MerkleTree::verify_file_with_proof("docs/c.txt", &hash("something else"), proof, tree.root.clone().unwrap()));
+    }
+
+    #[test]
+    fn proof_of_another_path_doesnt_verify() {
+        let tree = tree();
+        let root = tree.root.clone().unwrap();
+        /* The contents and proof of b.txt served for a.txt */
+        let proof = tree.get_proof("b.txt").unwrap();
+        assert!(!MerkleTree::verify_file_with_proof("a.txt", &hash("b.txt"), proof, root.clone()));
+        /* Same file name in another directory */
+        let proof = tree.get_proof("docs/c.txt").unwrap();
+        assert!(!MerkleTree::verify_file_with_proof("c.txt", &hash("docs/c.txt"), proof.clone(), root.clone()));
+        assert!(!MerkleTree::verify_file_with_proof("docs/deep/c.txt", &hash("docs/c.txt"), proof, root));
+    }
+
+    #[test]
+    fn tampered_proofs_dont_verify() {
+        let tree = tree();
+        let root = tree.root.clone().unwrap();
+        let proof = tree.get_proof("docs/d.txt").unwrap();
+
+        /* Flipped side of a sibling */
+        let mut flipped = proof.clone();
+        let step = flipped[0].to_string_lossy().replacen("L:", "X:", 1).replacen("R:", "L:", 1).replacen("X:", "R:", 1);
+        flipped[0] = OsString::from(step);
+        assert!(!MerkleTree::verify_file_with_proof("docs/d.txt", &hash("docs/d.txt"), flipped, root.clone()));
+
+        /* Cut short, or without the directory steps */
+        assert!(!MerkleTree::verify_file_with_proof("docs/d.txt", &hash("docs/d.txt"), proof[..proof.len() - 1].to_vec(), root.clone()));
+        let no_dirs = proof.iter().filter(|step| *step != "D").cloned().collect();
+        assert!(!MerkleTree::verify_file_with_proof("docs/d.txt", &hash("docs/d.txt"), no_dirs, root.clone()));
+
+        /* A single sibling chosen to land on the root can't be made */
+        let forged = vec![OsString::from(format!("R:{}", root.to_string_lossy()))];
+        assert!(!MerkleTree::verify_file_with_proof("a.txt", &hash("forged"), forged, root.clone()));
+        assert!(!MerkleTree::verify_file_with_proof("a.txt", &hash("forged"), Vec::new(), root));
+    }
+
+    #[test]
+    fn incremental_updates_match_a_full_build() {
+        let (f, changed) = (hash("docs/deep/f.txt"), hash("changed"));
+        let mut tree = tree();
+        tree.update([("docs/deep/f.txt", Some(f.as_str())), ("a.txt", Some(changed.as_str()))]);
+        tree.update([("docs/c.txt", None), ("docs/deep/e.txt", None)]);
+
+        let rebuilt = MerkleTree::from_files([
+            ("a.txt".to_string(), hash("changed")),
+            ("b.txt".to_string(), hash("b.txt")),
+            ("docs/d.txt".to_string(), hash("docs/d.txt")),
+            ("docs/deep/f.txt".to_string(), hash("docs/deep/f.txt")),
+        ]);
+        assert_eq!(tree.root, rebuilt.root);
+        assert_eq!(tree.data.len(), rebuilt.data.len());
+
+        tree.update([("docs/deep/f.txt", None)]);
+        assert!(!tree.dirs.contains_key("docs/deep"));
+        assert_eq!(tree.children("docs"), vec!["docs/d.txt".to_string()]);
+        tree.update(["a.txt", "b.txt", "docs/d.txt"].map(|path| (path, None)));
+        assert_eq!(tree.root, None);
+        assert!(tree.data.is_empty());
+    }
+
+    #[test]
+    fn children_are_in_tree_order() {
+        let tree = MerkleTree::from_files(["a/x", "a.txt", "a b/y"].into_iter().map(|path| (path.to_string(), hash(path))));
+        assert_eq!(tree.children(""), vec!["a b", "a.txt", "a"]);
+    }
+}
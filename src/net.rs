@@ -0,0 +1,55 @@
+use crate::ratelimit::RateLimiter;
+use crate::validate::RawBytes;
+use hydroflow::bytes::BytesMut;
+use hydroflow::futures::{Stream, StreamExt};
+use hydroflow::tokio_util::codec::LengthDelimitedCodec;
+use hydroflow::tokio_util::udp::UdpFramed;
+use hydroflow::util::{UdpSink, UdpStream};
+use socket2::{Domain, Protocol, Socket, Type};
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+
+/* Length prefix `LengthDelimitedCodec` puts in front of every datagram */
+pub const LENGTH_PREFIX: u64 = 4;
+
+/**Bind a UDP socket that other sockets can share the port with. The kernel spreads incoming datagrams
+ * over the sockets by source address, so a client consistently ends up at the same server worker */
+pub fn bind_udp_reuseport(addr: SocketAddr) -> std::io::Result<std::net::UdpSocket> {
+    let socket = Socket::new(Domain::for_address(addr), Type::DGRAM, Some(Protocol::UDP))?;
+    #[cfg(unix)]
+    socket.set_reuse_port(true)?;
+    socket.set_nonblocking(true)?;
+    socket.bind(&addr.into())?;
+    Ok(socket.into())
+}
+
+/* Same framing as `bind_udp_bytes`, has to be called on the runtime that's going to poll the socket */
+pub fn udp_bytes(socket: std::net::UdpSocket) -> std::io::Result<(UdpSink, UdpStream)> {
+    let socket = hydroflow::tokio::net::UdpSocket::from_std(socket)?;
+    Ok(UdpFramed::new(socket, LengthDelimitedCodec::new()).split())
+}
+
+/**Count every datagram as it came off the socket, before it's decrypted and decompressed, so what a client
+ * sent is measured in the bytes it actually put on the wire */
+pub fn count_received<S>(inbound: S, received: RawBytes) -> impl Stream<Item = std::io::Result<(BytesMut, SocketAddr)>> + Unpin + Send + 'static
+where
+    S: Stream<Item = std::io::Result<(BytesMut, SocketAddr)>> + Unpin + Send + 'static,
+{
+    inbound.inspect(move |item| {
+        if let Ok((data, addr)) = item {
+            received.add(*addr, data.len() as u64 + LENGTH_PREFIX);
+        }
+    })
+}
+
+/**Drop datagrams of banned addresses and of addresses over their rate limit as they come off the socket, before
+ * anything is spent on decrypting or decompressing them */
+pub fn limit_received(inbound: UdpStream, limiter: Option<Arc<Mutex<RateLimiter>>>) -> impl Stream<Item = std::io::Result<(BytesMut, SocketAddr)>> + Unpin + Send + 'static {
+    inbound.filter(move |item| {
+        let allowed = match (item, &limiter) {
+            (Ok((_, addr)), Some(limiter)) => limiter.lock().unwrap().allow_datagram(*addr),
+            _ => true,
+        };
+        std::future::ready(allowed)
+    })
+}
@@ -0,0 +1,54 @@
+use crate::storage::{ObjectStat, Storage};
+use std::sync::Arc;
+
+/**Content-addressed blob store on top of a storage backend, every blob is stored once under its blake3 hash */
+#[derive(Clone)]
+pub struct ObjectStore {
+    storage: Arc<dyn Storage>,
+}
+
+impl ObjectStore {
+    pub fn new(storage: Arc<dyn Storage>) -> ObjectStore {
+        ObjectStore { storage }
+    }
+
+    pub fn is_persistent(&self) -> bool {
+        self.storage.is_persistent()
+    }
+
+    /* Ok(None) only when the backend says there is no such blob, failing to ask it is an error */
+    pub fn stat(&self, hash: &str) -> std::io::Result<Option<ObjectStat>> {
+        self.storage.stat(hash)
+    }
+
+    pub fn contains(&self, hash: &str) -> std::io::Result<bool> {
+        Ok(self.stat(hash)?.is_some())
+    }
+
+    /* Write a blob unless it's already stored, returns whether it was new */
+    pub fn put(&self, hash: &str, data: &[u8]) -> std::io::Result<bool> {
+        if self.contains(hash)? {
+            return Ok(false);
+        }
+        self.storage.put(hash, data)?;
+        Ok(true)
+    }
+
+    pub fn get(&self, hash: &str) -> std::io::Result<Vec<u8>> {
+        self.storage.get(hash)
+    }
+
+    pub fn get_range(&self, hash: &str, offset: u64, len: u64) -> std::io::Result<Vec<u8>> {
+        self.storage.get_range(hash, offset, len)
+    }
+
+    /**Garbage collection pass, removes every blob that `is_referenced` doesn't know about.
+     * Returns the number of removed blobs */
+    pub fn gc<F: Fn(&str) -> bool>(&self, is_referenced: F) -> usize {
+        self.storage.list()
+            .unwrap_or_default()
+            .into_iter()
+            .filter(|hash| !is_referenced(hash) && self.storage.delete(hash).is_ok())
+            .count()
+    }
+}
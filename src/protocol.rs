@@ -1,17 +1,262 @@
-//use chrono::prelude::*;
-use serde::{Deserialize, Serialize};
-
-#[derive(PartialEq, Clone, Serialize, Deserialize, Debug)]
-pub enum Message {
-    //Echo { payload: String, ts: DateTime<Utc> },
-    FileUpload { filename: String, data: Vec<u8> },
-    FileAck { filename: String, hash: String },
-    FileRequest { filename: String },
-    File { filename: String, data: Vec<u8>, merkle_proof: Vec<Vec<u8>> },
-    FileNotFound { filename: String },
-    DeleteFileRequest { filename: String },
-    DeleteFileAck { filename: String, deleted: bool },
-    
-    Heartbeat,
-    HeartbeatAck,
-}
+//use chrono::prelude::*;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+/* Size of a single chunk in resumable uploads and downloads, small enough to fit in one UDP datagram */
+pub const CHUNK_SIZE: usize = 32 * 1024;
+
+/* Number of chunks needed to transfer `size` bytes, None if chunk indices can't address all of them */
+pub fn chunk_count(size: u64) -> Option<u32> {
+    let chunk_size = CHUNK_SIZE as u64;
+    u32::try_from(size / chunk_size + u64::from(size % chunk_size != 0)).ok()
+}
+
+/* Reasons a request can fail on the server */
+#[derive(PartialEq, Clone, Serialize, Deserialize, Debug)]
+pub enum ProtocolError {
+    Io(String),
+    /* The uploaded data doesn't match the hash announced for it */
+    HashMismatch,
+    /* The request needs an authenticated client, see Hello */
+    Unauthenticated,
+    /* The answer to the challenge was wrong, the identity is unknown or the challenge expired */
+    AuthFailed,
+    /* The access policy doesn't grant the client this operation on the file */
+    AccessDenied,
+    /* Storing the file would go over a limit, the limit is described in the message */
+    QuotaExceeded(String),
+    /* A precondition of a conditional write or delete didn't hold, the message says which one */
+    PreconditionFailed(String),
+    /* The path isn't valid, or a file can't be stored there because of a file or directory in the way */
+    InvalidPath(String),
+}
+
+/**Conditions a write or delete is only applied under, checked by the server in the same step as the change.
+ * `if_match` is the hash the file must currently have, `if_none_match` a hash it must not have, or `*` for a file
+ * that must not exist yet. `if_root_is` is the root the whole tree must have, empty for an empty store */
+#[derive(PartialEq, Clone, Serialize, Deserialize, Debug, Default)]
+pub struct Preconditions {
+    pub if_match: Option<String>,
+    pub if_none_match: Option<String>,
+    pub if_root_is: Option<String>,
+}
+
+impl Preconditions {
+    /* Check against the current hash of the file and the current root, the failed condition is returned */
+    pub fn check(&self, hash: Option<&str>, root: Option<&str>) -> Result<(), String> {
+        if let Some(expected) = self.if_match.as_deref() {
+            if hash != Some(expected) {
+                return Err(format!("if-match {}, the file is at {}", expected, hash.unwrap_or("nothing")));
+            }
+        }
+        match self.if_none_match.as_deref() {
+            Some("*") if hash.is_some() => return Err("if-none-match *, the file exists".to_string()),
+            Some(unexpected) if hash == Some(unexpected) => return Err(format!("if-none-match {}", unexpected)),
+            _ => {}
+        }
+        if let Some(expected) = self.if_root_is.as_deref() {
+            if root.unwrap_or("") != expected {
+                return Err(format!("if-root-is {}, the root is {}", expected, root.unwrap_or("empty")));
+            }
+        }
+        Ok(())
+    }
+}
+
+/* Number of files and bytes stored */
+#[derive(PartialEq, Clone, Copy, Serialize, Deserialize, Debug, Default)]
+pub struct Usage {
+    pub files: u64,
+    pub bytes: u64,
+}
+
+/* Limits on what can be stored, None means unlimited */
+#[derive(PartialEq, Clone, Serialize, Deserialize, Debug, Default)]
+pub struct Quota {
+    pub max_file_size: Option<u64>,
+    pub max_bytes: Option<u64>,
+    pub max_files: Option<u64>,
+}
+
+/* Storage used on the server and the limits on it, overall and for the identity of the client if it has one */
+#[derive(PartialEq, Clone, Serialize, Deserialize, Debug)]
+pub struct UsageReport {
+    pub total: Usage,
+    pub total_quota: Quota,
+    pub identity: Option<String>,
+    pub usage: Option<Usage>,
+    pub quota: Quota,
+}
+
+/* A version of a file kept by the server, `hash` is None for a version that deleted the file */
+#[derive(PartialEq, Clone, Serialize, Deserialize, Debug)]
+pub struct VersionInfo {
+    pub version: u64,
+    pub hash: Option<String>,
+    pub size: u64,
+    pub timestamp: DateTime<Utc>,
+    pub owner: Option<String>,
+    /* Change of the server's tree the version was made in */
+    pub epoch: u64,
+}
+
+/* A file, or a subdirectory, in a directory listing. `hash` is the hash of the contents of a file and the node
+ * of a directory in the tree, `size` the total size of everything below a directory. A hidden entry stands in
+ * for a file or directory the client may not read: `path` is the directory it's in and `hash` its node */
+#[derive(PartialEq, Clone, Serialize, Deserialize, Debug)]
+pub struct DirEntry {
+    pub path: String,
+    pub is_dir: bool,
+    pub hash: String,
+    pub size: u64,
+    pub hidden: bool,
+}
+
+/**Page of a directory listing, entries are in the order of the tree. `node` is the node of the directory and
+ * `merkle_proof` its proof against the root, `next` is the offset of the next page if there is one */
+#[derive(PartialEq, Clone, Serialize, Deserialize, Debug)]
+pub struct Listing {
+    pub path: String,
+    pub recursive: bool,
+    pub offset: u32,
+    pub entries: Vec<DirEntry>,
+    pub node: String,
+    pub merkle_proof: Vec<Vec<u8>>,
+    pub next: Option<u32>,
+}
+
+#[derive(PartialEq, Clone, Serialize, Deserialize, Debug)]
+pub enum Message {
+    //Echo { payload: String, ts: DateTime<Utc> },
+    FileUpload { filename: String, data: Vec<u8>, preconditions: Box<Preconditions> },
+    /* Acks of changes carry the root of the tree after the change */
+    FileAck { filename: String, hash: String, root: String },
+    /* The current contents in one reply. Kept versions are downloaded in chunks only, their proof needs the root of
+     * their epoch that comes with FileInfo */
+    FileRequest { filename: String },
+    File { filename: String, data: Vec<u8>, merkle_proof: Vec<Vec<u8>> },
+    FileNotFound { filename: String },
+    DeleteFileRequest { filename: String, preconditions: Box<Preconditions> },
+    DeleteFileAck { filename: String, deleted: bool, root: String },
+    /* Paths are separated by `/`, the root directory is "". A recursive listing has every file below the directory,
+     * otherwise it has the files and subdirectories right in it */
+    ListRequest { path: String, recursive: bool, offset: u32 },
+    Listing(Box<Listing>),
+    /* Metadata operations, the contents stay where they are. A rename only goes through if `from` has the hash in
+     * `if_match` and `to` doesn't have the one in `if_none_match`, `*` for any, when they are given */
+    RenameRequest { from: String, to: String, if_match: Option<String>, if_none_match: Option<String> },
+    RenameAck { from: String, to: String, root: String },
+    CopyRequest { from: String, to: String },
+    CopyAck { from: String, to: String, root: String },
+    Error { filename: String, error: ProtocolError },
+
+    /* Resumable uploads, the file is sent in chunks of CHUNK_SIZE bytes */
+    /* The preconditions are checked when the upload starts and again when the assembled file is saved */
+    UploadStart { filename: String, hash: String, size: u64, preconditions: Box<Preconditions> },
+    UploadChunk { session_id: String, index: u32, data: Vec<u8> },
+    ResumeUpload { session_id: String },
+    UploadStatus { session_id: String, missing: Vec<u32> },
+    UploadSessionNotFound { session_id: String },
+
+    /* Resumable downloads, the client asks for the file info first and then for the chunks it's missing. Kept versions
+     * are downloaded the same way, the info of a version has the root of the tree of its epoch its proof goes up to */
+    FileInfoRequest { filename: String, version: Option<u64> },
+    FileInfo { filename: String, version: Option<u64>, hash: String, size: u64, merkle_proof: Vec<Vec<u8>>, root: Option<String> },
+    FileChunkRequest { filename: String, version: Option<u64>, index: u32 },
+    FileChunk { filename: String, version: Option<u64>, index: u32, data: Vec<u8> },
+
+    Heartbeat,
+    HeartbeatAck,
+
+    /* Authentication handshake, the client proves it has the pre-shared key of its identity
+     * by returning an HMAC over the nonce of the challenge. A server that doesn't authenticate
+     * clients answers the Hello with Authenticated without an identity */
+    Hello { identity: String },
+    Challenge { nonce: Vec<u8> },
+    ChallengeResponse { mac: Vec<u8> },
+    Authenticated { identity: Option<String> },
+
+    UsageRequest,
+    UsageReport(Box<UsageReport>),
+
+    /* File history, versions are listed newest first. Restoring a version makes it the current contents again */
+    ListVersions { filename: String },
+    Versions { filename: String, versions: Vec<VersionInfo> },
+    Restore { filename: String, version: u64 },
+
+    /* Address validation, a reply too large for an address that isn't validated yet is replaced by a Retry.
+     * Echoing its token in ValidateAddress validates the address */
+    Retry { token: Vec<u8> },
+    ValidateAddress { token: Vec<u8> },
+    AddressValidated,
+}
+
+impl Message {
+    /* Requests that touch files, only served to authenticated clients when authentication is enabled */
+    pub fn requires_auth(&self) -> bool {
+        matches!(self,
+            Message::FileUpload { .. } | Message::FileRequest { .. } | Message::DeleteFileRequest { .. } |
+            Message::UploadStart { .. } | Message::UploadChunk { .. } | Message::ResumeUpload { .. } |
+            Message::FileInfoRequest { .. } | Message::FileChunkRequest { .. } | Message::UsageRequest |
+            Message::ListVersions { .. } | Message::Restore { .. } | Message::RenameRequest { .. } | Message::CopyRequest { .. } |
+            Message::ListRequest { .. })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn preconditions(if_match: Option<&str>, if_none_match: Option<&str>, if_root_is: Option<&str>) -> Preconditions {
+        Preconditions {
+            if_match: if_match.map(str::to_string),
+            if_none_match: if_none_match.map(str::to_string),
+            if_root_is: if_root_is.map(str::to_string),
+        }
+    }
+
+    #[test]
+    fn no_preconditions_always_hold() {
+        assert!(Preconditions::default().check(None, None).is_ok());
+        assert!(Preconditions::default().check(Some("a"), Some("root")).is_ok());
+    }
+
+    #[test]
+    fn if_match_needs_the_current_hash() {
+        let if_match = preconditions(Some("a"), None, None);
+        assert!(if_match.check(Some("a"), None).is_ok());
+        assert!(if_match.check(Some("b"), None).is_err());
+        assert!(if_match.check(None, None).is_err());
+    }
+
+    #[test]
+    fn if_none_match_rules_out_a_hash_or_any_file() {
+        let any = preconditions(None, Some("*"), None);
+        assert!(any.check(None, None).is_ok());
+        assert!(any.check(Some("a"), None).is_err());
+
+        let hash = preconditions(None, Some("a"), None);
+        assert!(hash.check(None, None).is_ok());
+        assert!(hash.check(Some("b"), None).is_ok());
+        assert!(hash.check(Some("a"), None).is_err());
+    }
+
+    #[test]
+    fn if_root_is_treats_an_empty_store_as_empty_root() {
+        let empty = preconditions(None, None, Some(""));
+        assert!(empty.check(None, None).is_ok());
+        assert!(empty.check(None, Some("root")).is_err());
+
+        let root = preconditions(None, None, Some("root"));
+        assert!(root.check(Some("a"), Some("root")).is_ok());
+        assert!(root.check(Some("a"), None).is_err());
+    }
+
+    #[test]
+    fn every_condition_has_to_hold() {
+        let both = preconditions(Some("a"), None, Some("root"));
+        assert!(both.check(Some("a"), Some("root")).is_ok());
+        assert!(both.check(Some("a"), Some("other")).is_err());
+        assert!(both.check(Some("b"), Some("root")).is_err());
+    }
+}
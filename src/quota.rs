@@ -0,0 +1,239 @@
+use crate::index::{FileIndex, Totals};
+use crate::protocol::{Quota, Usage};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+/**Storage limits of the server, for everything stored and for the files of each client identity.
+ * Anonymous uploads only count against the overall limits */
+#[derive(Debug, Clone, Default)]
+pub struct Limits {
+    pub total: Quota,
+    pub per_identity: Quota,
+    /* Versions kept per filename, kept versions count towards the quotas until a newer one pushes them out */
+    pub versions: usize,
+    /* Space held for uploads that are still coming in, shared by the server workers and the store worker */
+    pub reserved: Arc<Mutex<Reservations>>,
+}
+
+/* Usage of the uploads in progress, keyed by upload session */
+#[derive(Debug, Default)]
+pub struct Reservations {
+    sessions: HashMap<String, (Option<String>, Usage)>,
+    totals: Totals,
+}
+
+/* Whether a file of `size` bytes may be stored when that leaves `usage` stored in the scope of `quota` */
+fn check_quota(quota: &Quota, size: u64, usage: Usage, scope: &str) -> Result<(), String> {
+    if quota.max_file_size.is_some_and(|max| size > max) {
+        return Err(format!("{} file size limit of {} bytes", scope, quota.max_file_size.unwrap()));
+    }
+    if quota.max_files.is_some_and(|max| usage.files > max) {
+        return Err(format!("{} limit of {} files", scope, quota.max_files.unwrap()));
+    }
+    if quota.max_bytes.is_some_and(|max| usage.bytes > max) {
+        return Err(format!("{} limit of {} bytes", scope, quota.max_bytes.unwrap()));
+    }
+    Ok(())
+}
+
+/**A new version of `size` bytes for a file, owned by `owner`. `moved_from` is the file it's renamed from, which gets
+ * a version marking its deletion in the same step */
+struct Change<'a> {
+    filename: &'a str,
+    size: u64,
+    owner: Option<&'a str>,
+    moved_from: Option<&'a str>,
+}
+
+impl Limits {
+    pub fn is_unlimited(&self) -> bool {
+        self.total == Quota::default() && self.per_identity == Quota::default()
+    }
+
+    /* Usage of everyone, or of `scope` only, after the change. Versions the change pushes out free their space */
+    fn usage_after(&self, index: &FileIndex, reserved: &Reservations, change: &Change, scope: Option<&str>) -> Usage {
+        let in_scope = |owner: Option<&str>| scope.is_none() || owner == scope;
+        let old = index.meta(change.filename);
+        let replaces = old.is_some_and(|meta| in_scope(meta.owner.as_deref()));
+        let adds = in_scope(change.owner);
+        /* A renamed file leaves its old name, so it doesn't add to the files of its owner */
+        let added = u64::from(adds && change.moved_from.is_none());
+
+        let pruned = std::iter::once(change.filename).chain(change.moved_from)
+            .flat_map(|filename| index.pruned_by_next(filename, self.versions))
+            .filter(|version| version.info.hash.is_some() && in_scope(version.info.owner.as_deref()))
+            .map(|version| version.info.size)
+            .sum::<u64>();
+        let usage = index.usage(scope);
+        let pending = reserved.totals.usage(scope);
+        Usage {
+            files: (usage.files + pending.files + added).saturating_sub(u64::from(replaces)),
+            bytes: (usage.bytes + pending.bytes + if adds { change.size } else { 0 }).saturating_sub(pruned),
+        }
+    }
+
+    fn check_change(&self, index: &FileIndex, reserved: &Reservations, change: &Change) -> Result<(), String> {
+        check_quota(&self.total, change.size, self.usage_after(index, reserved, change, None), "Server")?;
+        if let Some(owner) = change.owner {
+            check_quota(&self.per_identity, change.size, self.usage_after(index, reserved, change, Some(owner)), "Per identity")?;
+        }
+        Ok(())
+    }
+
+    /**Check whether `identity` may store `size` bytes under `filename`. Overwriting a file frees the space of the
+     * versions the new one pushes out, and the contents stay counted as long as they are kept as a version */
+    pub fn check(&self, index: &FileIndex, filename: &str, size: u64, identity: Option<&str>) -> Result<(), String> {
+        if self.is_unlimited() {
+            return Ok(());
+        }
+        let reserved = self.reserved.lock().unwrap();
+        self.check_change(index, &reserved, &Change { filename, size, owner: identity, moved_from: None })
+    }
+
+    /* A rename keeps the contents of `from` as a version, and adds them again as a version of `to` for the same owner */
+    pub fn check_rename(&self, index: &FileIndex, from: &str, to: &str) -> Result<(), String> {
+        if self.is_unlimited() {
+            return Ok(());
+        }
+        let meta = index.meta(from).cloned().unwrap_or_default();
+        let reserved = self.reserved.lock().unwrap();
+        self.check_change(index, &reserved, &Change { filename: to, size: meta.size, owner: meta.owner.as_deref(), moved_from: Some(from) })
+    }
+
+    /**Check an upload like `check` and hold its space until the upload session is released, so uploads that are
+     * still coming in can't overbook the quotas together. Holding the space again for the same session is a no-op */
+    pub fn reserve(&self, index: &FileIndex, session_id: &str, filename: &str, size: u64, identity: Option<&str>) -> Result<(), String> {
+        if self.is_unlimited() {
+            return Ok(());
+        }
+        let mut reserved = self.reserved.lock().unwrap();
+        if reserved.sessions.contains_key(session_id) {
+            return Ok(());
+        }
+        self.check_change(index, &reserved, &Change { filename, size, owner: identity, moved_from: None })?;
+
+        let usage = Usage { files: u64::from(index.meta(filename).is_none()), bytes: size };
+        reserved.totals.adjust(identity, |total| { total.files += usage.files; total.bytes += usage.bytes; });
+        reserved.sessions.insert(session_id.to_string(), (identity.map(str::to_string), usage));
+        Ok(())
+    }
+
+    /* Give back the space of an upload session, once it's saved, failed or expired */
+    pub fn release(&self, session_id: &str) {
+        let mut reserved = self.reserved.lock().unwrap();
+        if let Some((identity, usage)) = reserved.sessions.remove(session_id) {
+            reserved.totals.adjust(identity.as_deref(), |total| {
+                total.files = total.files.saturating_sub(usage.files);
+                total.bytes = total.bytes.saturating_sub(usage.bytes);
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::index::{FileMeta, Version};
+    use crate::protocol::VersionInfo;
+    use chrono::Utc;
+
+    fn quota(max_file_size: Option<u64>, max_bytes: Option<u64>, max_files: Option<u64>) -> Quota {
+        Quota { max_file_size, max_bytes, max_files }
+    }
+
+    fn limits(total: Quota, per_identity: Quota, versions: usize) -> Limits {
+        Limits { total, per_identity, versions, reserved: Default::default() }
+    }
+
+    /* Store a file the way the store worker does, the new contents become its newest version */
+    fn store(index: &mut FileIndex, filename: &str, size: u64, owner: Option<&str>, keep: usize) {
+        let hash = format!("{}-{}", filename, index.next_version(filename));
+        index.insert(filename.to_string(), hash.clone(), FileMeta { size, owner: owner.map(str::to_string) });
+        let info = VersionInfo { version: index.next_version(filename), hash: Some(hash), size, timestamp: Utc::now(), owner: owner.map(str::to_string), epoch: 0 };
+        index.push_version(filename, Version { info, root: String::new(), merkle_proof: Vec::new() }, keep);
+    }
+
+    #[test]
+    fn check_quota_compares_the_usage_after_the_change() {
+        let usage = Usage { files: 2, bytes: 100 };
+        assert!(check_quota(&Quota::default(), u64::MAX, Usage { files: u64::MAX, bytes: u64::MAX }, "Server").is_ok());
+        assert!(check_quota(&quota(Some(10), None, None), 10, usage, "Server").is_ok());
+        assert!(check_quota(&quota(Some(10), None, None), 11, usage, "Server").unwrap_err().contains("file size limit of 10"));
+        assert!(check_quota(&quota(None, Some(100), Some(2)), 1, usage, "Server").is_ok());
+        assert!(check_quota(&quota(None, Some(99), None), 1, usage, "Server").unwrap_err().contains("limit of 99 bytes"));
+        assert!(check_quota(&quota(None, None, Some(1)), 1, usage, "Per identity").unwrap_err().starts_with("Per identity limit of 1 files"));
+    }
+
+    #[test]
+    fn kept_versions_count_until_they_are_pruned() {
+        let mut index = FileIndex::default();
+        let limits = limits(quota(None, Some(250), None), Quota::default(), 2);
+        store(&mut index, "a", 100, None, 2);
+        assert_eq!(index.usage(None), Usage { files: 1, bytes: 100 });
+
+        /* The old contents are kept as a version, so they still take up space */
+        assert!(limits.check(&index, "a", 150, None).is_ok());
+        store(&mut index, "a", 100, None, 2);
+        assert_eq!(index.usage(None), Usage { files: 1, bytes: 200 });
+        assert!(limits.check(&index, "b", 51, None).is_err());
+
+        /* Another version of `a` pushes out the oldest one and frees its space */
+        assert!(limits.check(&index, "a", 150, None).is_ok());
+        store(&mut index, "a", 150, None, 2);
+        assert_eq!(index.usage(None), Usage { files: 1, bytes: 250 });
+
+        index.retain_versions(|version| version.info.size != 100);
+        assert_eq!(index.usage(None), Usage { files: 1, bytes: 150 });
+    }
+
+    #[test]
+    fn identities_only_count_what_they_uploaded() {
+        let mut index = FileIndex::default();
+        let limits = limits(Quota::default(), quota(None, Some(100), Some(1)), 1);
+        store(&mut index, "alice", 100, Some("alice"), 1);
+        store(&mut index, "anonymous", 1000, None, 1);
+        assert_eq!(index.usage(Some("alice")), Usage { files: 1, bytes: 100 });
+        assert_eq!(index.usage(Some("bob")), Usage::default());
+
+        assert!(limits.check(&index, "bob", 100, Some("bob")).is_ok());
+        assert!(limits.check(&index, "other", 1, Some("alice")).unwrap_err().contains("limit of 1 files"));
+        /* With one version kept, overwriting her own file frees its space */
+        assert!(limits.check(&index, "alice", 100, Some("alice")).is_ok());
+        /* Taking over someone else's file doesn't free anything of hers */
+        assert!(limits.check(&index, "anonymous", 1, Some("bob")).is_ok());
+        assert!(limits.check(&index, "anonymous", 101, Some("bob")).is_err());
+
+        index.remove("alice");
+        assert_eq!(index.usage(Some("alice")), Usage { files: 0, bytes: 100 });
+    }
+
+    #[test]
+    fn renames_keep_the_contents_under_both_names() {
+        let mut index = FileIndex::default();
+        let limits = limits(Quota::default(), quota(None, Some(150), Some(1)), 2);
+        store(&mut index, "a", 100, Some("alice"), 2);
+
+        /* The file count stays the same, but the contents would be kept twice */
+        assert!(limits.check_rename(&index, "a", "b").unwrap_err().contains("limit of 150 bytes"));
+        let limits = Limits { per_identity: quota(None, Some(200), Some(1)), ..limits };
+        assert!(limits.check_rename(&index, "a", "b").is_ok());
+    }
+
+    #[test]
+    fn uploads_in_progress_hold_their_space() {
+        let index = FileIndex::default();
+        let limits = limits(quota(None, Some(100), Some(2)), quota(None, Some(60), None), 1);
+        assert!(limits.reserve(&index, "one", "a", 50, Some("alice")).is_ok());
+        /* Resuming the same session doesn't hold its space twice */
+        assert!(limits.reserve(&index, "one", "a", 50, Some("alice")).is_ok());
+        assert!(limits.reserve(&index, "two", "b", 50, Some("alice")).unwrap_err().starts_with("Per identity"));
+        assert!(limits.reserve(&index, "two", "b", 50, Some("bob")).is_ok());
+        assert!(limits.check(&index, "c", 0, None).unwrap_err().contains("limit of 2 files"));
+
+        /* Other server workers and the store worker see the same reservations */
+        let shared = limits.clone();
+        shared.release("one");
+        assert!(limits.check(&index, "c", 50, None).is_ok());
+        assert!(limits.reserve(&index, "three", "c", 50, Some("alice")).is_ok());
+    }
+}
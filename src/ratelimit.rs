@@ -0,0 +1,230 @@
+use std::collections::{HashMap, HashSet};
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+/* Limit violations are counted over this long when deciding whether to ban a peer */
+const STRIKE_WINDOW: Duration = Duration::from_secs(10);
+/* How often idle buckets and expired bans are cleaned up */
+const SWEEP_INTERVAL: Duration = Duration::from_secs(1);
+
+/* Rate limits of the server, rates are in requests per second and bursts in requests */
+#[derive(Debug, Clone)]
+pub struct RateLimits {
+    pub rate: f64,
+    pub burst: f64,
+    pub identity_rate: f64,
+    pub identity_burst: f64,
+    /* Number of dropped requests within STRIKE_WINDOW after which an address is banned */
+    pub ban_after: u32,
+    pub ban_duration: Duration,
+}
+
+#[derive(Debug, Clone)]
+struct TokenBucket {
+    tokens: f64,
+    last: Instant,
+}
+
+impl TokenBucket {
+    fn new(burst: f64, now: Instant) -> TokenBucket {
+        TokenBucket { tokens: burst, last: now }
+    }
+
+    /* Refill for the time since the last request, then take a token if there is one */
+    fn take(&mut self, rate: f64, burst: f64, now: Instant) -> bool {
+        self.tokens = f64::min(burst, self.tokens + now.duration_since(self.last).as_secs_f64() * rate);
+        self.last = now;
+        if self.tokens < 1.0 {
+            return false;
+        }
+        self.tokens -= 1.0;
+        true
+    }
+
+    /* A bucket that would be full again is the same as no bucket at all */
+    fn is_idle(&self, rate: f64, burst: f64, now: Instant) -> bool {
+        self.tokens + now.duration_since(self.last).as_secs_f64() * rate >= burst
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Strikes {
+    count: u32,
+    since: Instant,
+}
+
+/**Token buckets per source address and per identity, plus a list of temporarily banned addresses.
+ * Addresses are limited per datagram as they come off the socket, identities per request once it's decoded.
+ * Datagrams from banned addresses are dropped without touching their buckets. Only addresses that proved
+ * they receive our datagrams collect strikes, anyone could send as an unvalidated one and get it banned */
+#[derive(Debug)]
+pub struct RateLimiter {
+    limits: RateLimits,
+    addrs: HashMap<SocketAddr, TokenBucket>,
+    identities: HashMap<String, TokenBucket>,
+    strikes: HashMap<SocketAddr, Strikes>,
+    bans: HashMap<SocketAddr, Instant>,
+    /* Addresses that were validated as of their last request, the socket can't tell by itself */
+    validated: HashSet<SocketAddr>,
+    last_sweep: Instant,
+}
+
+impl RateLimiter {
+    pub fn new(limits: RateLimits) -> RateLimiter {
+        RateLimiter {
+            limits,
+            addrs: HashMap::new(),
+            identities: HashMap::new(),
+            strikes: HashMap::new(),
+            bans: HashMap::new(),
+            validated: HashSet::new(),
+            last_sweep: Instant::now(),
+        }
+    }
+
+    /* Forget buckets that refilled completely, strikes that are too old and bans that ran out */
+    fn sweep(&mut self, now: Instant) {
+        let limits = &self.limits;
+        self.addrs.retain(|_, bucket| !bucket.is_idle(limits.rate, limits.burst, now));
+        self.identities.retain(|_, bucket| !bucket.is_idle(limits.identity_rate, limits.identity_burst, now));
+        self.strikes.retain(|_, strikes| now.duration_since(strikes.since) < STRIKE_WINDOW);
+        self.bans.retain(|addr, until| {
+            if *until <= now {
+                println!("Ban of {:?} lifted", addr);
+            }
+            *until > now
+        });
+        let (addrs, bans) = (&self.addrs, &self.bans);
+        self.validated.retain(|addr| addrs.contains_key(addr) || bans.contains_key(addr));
+        self.last_sweep = now;
+    }
+
+    /* Count a dropped request against an address, and ban it once it keeps going */
+    fn strike(&mut self, addr: SocketAddr, now: Instant) {
+        let strikes = self.strikes.entry(addr).or_insert(Strikes { count: 0, since: now });
+        if now.duration_since(strikes.since) >= STRIKE_WINDOW {
+            *strikes = Strikes { count: 0, since: now };
+        }
+        strikes.count += 1;
+
+        if strikes.count >= self.limits.ban_after {
+            println!("Banning {:?} for {:?} after {} dropped requests", addr, self.limits.ban_duration, strikes.count);
+            self.strikes.remove(&addr);
+            self.addrs.remove(&addr);
+            self.bans.insert(addr, now + self.limits.ban_duration);
+        }
+    }
+
+    fn is_banned(&mut self, addr: &SocketAddr, now: Instant) -> bool {
+        if now.duration_since(self.last_sweep) >= SWEEP_INTERVAL {
+            self.sweep(now);
+        }
+        self.bans.get(addr).is_some_and(|until| *until > now)
+    }
+
+    /* Whether a datagram from `addr` may go through, it needs a token from the bucket of the address */
+    pub fn allow_datagram(&mut self, addr: SocketAddr) -> bool {
+        let now = Instant::now();
+        if self.is_banned(&addr, now) {
+            return false;
+        }
+
+        let limits = &self.limits;
+        let allowed = self.addrs.entry(addr)
+            .or_insert_with(|| TokenBucket::new(limits.burst, now))
+            .take(limits.rate, limits.burst, now);
+        if !allowed && self.validated.contains(&addr) {
+            self.strike(addr, now);
+        }
+        allowed
+    }
+
+    /**Whether a request from `addr` that made it through the socket may go through, authenticated as `identity`
+     * if it is. It needs a token from the bucket of the identity */
+    pub fn allow_request(&mut self, addr: SocketAddr, identity: Option<&str>, validated: bool) -> bool {
+        let now = Instant::now();
+        if validated {
+            self.validated.insert(addr);
+        } else {
+            self.validated.remove(&addr);
+        }
+        if self.is_banned(&addr, now) {
+            return false;
+        }
+
+        let limits = &self.limits;
+        let allowed = identity.map_or(true, |identity| {
+            self.identities.entry(identity.to_string())
+                .or_insert_with(|| TokenBucket::new(limits.identity_burst, now))
+                .take(limits.identity_rate, limits.identity_burst, now)
+        });
+        if !allowed && validated {
+            self.strike(addr, now);
+        }
+        allowed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limits(ban_after: u32) -> RateLimits {
+        RateLimits { rate: 1.0, burst: 2.0, identity_rate: 1.0, identity_burst: 3.0, ban_after, ban_duration: Duration::from_secs(60) }
+    }
+
+    #[test]
+    fn bucket_refills_at_its_rate_up_to_the_burst() {
+        let start = Instant::now();
+        let mut bucket = TokenBucket::new(2.0, start);
+        assert!(bucket.take(1.0, 2.0, start));
+        assert!(bucket.take(1.0, 2.0, start));
+        assert!(!bucket.take(1.0, 2.0, start));
+
+        assert!(!bucket.take(1.0, 2.0, start + Duration::from_millis(500)));
+        assert!(bucket.take(1.0, 2.0, start + Duration::from_millis(1500)));
+        assert!(!bucket.is_idle(1.0, 2.0, start + Duration::from_millis(1500)));
+
+        /* Waiting longer doesn't save up more than the burst */
+        let later = start + Duration::from_secs(60);
+        assert!(bucket.is_idle(1.0, 2.0, later));
+        assert!(bucket.take(1.0, 2.0, later));
+        assert!(bucket.take(1.0, 2.0, later));
+        assert!(!bucket.take(1.0, 2.0, later));
+    }
+
+    #[test]
+    fn identities_share_a_bucket_across_addresses() {
+        let mut limiter = RateLimiter::new(limits(100));
+        let (a, b, c) = ("192.0.2.1:1".parse().unwrap(), "192.0.2.2:1".parse().unwrap(), "192.0.2.3:1".parse().unwrap());
+        assert!(limiter.allow_request(a, Some("alice"), true));
+        assert!(limiter.allow_request(a, Some("alice"), true));
+        assert!(limiter.allow_request(b, Some("alice"), true));
+        assert!(!limiter.allow_request(b, Some("alice"), true));
+        assert!(limiter.allow_request(c, Some("bob"), true));
+        /* The buckets of the addresses are up to the socket */
+        assert!(limiter.allow_datagram(b));
+    }
+
+    #[test]
+    fn only_validated_addresses_get_banned() {
+        let mut limiter = RateLimiter::new(limits(3));
+        let (validated, spoofable) = ("192.0.2.1:1".parse().unwrap(), "192.0.2.2:1".parse().unwrap());
+
+        assert!(limiter.allow_request(spoofable, None, false));
+        for _ in 0..10 {
+            limiter.allow_datagram(spoofable);
+        }
+        assert!(limiter.bans.is_empty());
+        assert!(!limiter.allow_datagram(spoofable));
+
+        /* Datagrams dropped at the socket count once the address was seen to be validated */
+        assert!(limiter.allow_request(validated, None, true));
+        for _ in 0..5 {
+            limiter.allow_datagram(validated);
+        }
+        assert!(limiter.bans.contains_key(&validated));
+        assert!(!limiter.bans.contains_key(&spoofable));
+        assert!(!limiter.allow_request(validated, None, true));
+    }
+}
@@ -0,0 +1,351 @@
+use crate::storage::{ObjectStat, Storage};
+use chrono::prelude::*;
+use hmac::{Hmac, Mac};
+use sha2::{Digest, Sha256};
+use std::io::prelude::*;
+use std::time::Duration;
+
+type HmacSha256 = Hmac<Sha256>;
+
+/* Requests to a store that stopped answering fail instead of holding up the store worker forever */
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+const IO_TIMEOUT: Duration = Duration::from_secs(60);
+
+/**Objects stored in a bucket of an S3-compatible object store, such as AWS S3 or MinIO.
+ * Requests use path-style addressing and are signed with AWS Signature Version 4. Keys are stored under
+ * `prefix`, so the bucket can hold other things and listing it only returns our objects */
+pub struct S3Storage {
+    agent: ureq::Agent,
+    /* Scheme and authority of the endpoint, e.g. http://localhost:9000 */
+    origin: String,
+    /* Authority of the endpoint as signed in the host header */
+    host: String,
+    /* Path of the endpoint in front of the bucket, empty unless the store is served below a path */
+    base_path: String,
+    bucket: String,
+    prefix: String,
+    region: String,
+    access_key: String,
+    secret_key: String,
+}
+
+fn hmac(key: &[u8], data: &str) -> Vec<u8> {
+    let mut mac = HmacSha256::new_from_slice(key).expect("HMAC accepts keys of any length");
+    mac.update(data.as_bytes());
+    mac.finalize().into_bytes().to_vec()
+}
+
+/* Percent-encode everything except the unreserved characters, as SigV4 expects */
+fn uri_encode(s: &str, encode_slash: bool) -> String {
+    s.bytes().map(|b| match b {
+        b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => (b as char).to_string(),
+        b'/' if !encode_slash => "/".to_string(),
+        _ => format!("%{:02X}", b),
+    }).collect()
+}
+
+fn io_error(e: ureq::Error) -> std::io::Error {
+    match e {
+        ureq::Error::Status(404, _) => std::io::Error::new(std::io::ErrorKind::NotFound, "object not found"),
+        ureq::Error::Status(416, _) => std::io::Error::new(std::io::ErrorKind::InvalidInput, "range not satisfiable"),
+        e => std::io::Error::new(std::io::ErrorKind::Other, e.to_string()),
+    }
+}
+
+/* Values of all occurrences of a tag in an XML document, enough for the ListObjectsV2 response */
+fn xml_values(xml: &str, tag: &str) -> Vec<String> {
+    let open = format!("<{}>", tag);
+    let close = format!("</{}>", tag);
+    xml.split(open.as_str())
+        .skip(1)
+        .filter_map(|rest| rest.split(close.as_str()).next())
+        .map(|value| value.replace("&quot;", "\"").replace("&apos;", "'").replace("&lt;", "<").replace("&gt;", ">").replace("&amp;", "&"))
+        .collect()
+}
+
+impl S3Storage {
+    pub fn new(endpoint: &str, bucket: &str, prefix: &str, region: &str, access_key: &str, secret_key: &str) -> S3Storage {
+        let (scheme, rest) = endpoint.split_once("://").unwrap_or(("http", endpoint));
+        let (host, base_path) = rest.split_at(rest.find('/').unwrap_or(rest.len()));
+        S3Storage {
+            agent: ureq::AgentBuilder::new().timeout_connect(CONNECT_TIMEOUT).timeout_read(IO_TIMEOUT).timeout_write(IO_TIMEOUT).build(),
+            origin: format!("{}://{}", scheme, host),
+            host: host.to_string(),
+            base_path: base_path.trim_end_matches('/').to_string(),
+            bucket: bucket.to_string(),
+            prefix: prefix.to_string(),
+            region: region.to_string(),
+            access_key: access_key.to_string(),
+            secret_key: secret_key.to_string(),
+        }
+    }
+
+    /**Send a signed request for `key` (or the bucket itself if empty), with query parameters and extra headers */
+    fn request(&self, method: &str, key: &str, query: &[(&str, &str)], headers: &[(&str, String)], body: &[u8]) -> std::io::Result<ureq::Response> {
+        let now = Utc::now();
+        let amz_date = now.format("%Y%m%dT%H%M%SZ").to_string();
+        let date = now.format("%Y%m%d").to_string();
+        let payload_hash = hex::encode(Sha256::digest(body));
+
+        let host = &self.host;
+        let path = format!("{}/{}/{}", uri_encode(&self.base_path, false), uri_encode(&self.bucket, true), uri_encode(key, false));
+
+        let mut query = query.iter()
+            .map(|(k, v)| format!("{}={}", uri_encode(k, true), uri_encode(v, true)))
+            .collect::<Vec<String>>();
+        query.sort();
+        let query = query.join("&");
+
+        let canonical_request = format!(
+            "{}\n{}\n{}\nhost:{}\nx-amz-content-sha256:{}\nx-amz-date:{}\n\nhost;x-amz-content-sha256;x-amz-date\n{}",
+            method, path, query, host, payload_hash, amz_date, payload_hash
+        );
+        let scope = format!("{}/{}/s3/aws4_request", date, self.region);
+        let string_to_sign = format!(
+            "AWS4-HMAC-SHA256\n{}\n{}\n{}",
+            amz_date, scope, hex::encode(Sha256::digest(canonical_request.as_bytes()))
+        );
+
+        let signing_key = ["s3", "aws4_request"].iter().fold(
+            hmac(hmac(format!("AWS4{}", self.secret_key).as_bytes(), &date).as_slice(), &self.region),
+            |key, part| hmac(key.as_slice(), part),
+        );
+        let signature = hex::encode(hmac(signing_key.as_slice(), &string_to_sign));
+
+        let url = if query.is_empty() {
+            format!("{}{}", self.origin, path)
+        } else {
+            format!("{}{}?{}", self.origin, path, query)
+        };
+        let mut request = self.agent.request(method, &url)
+            .set("x-amz-date", &amz_date)
+            .set("x-amz-content-sha256", &payload_hash)
+            .set("Authorization", &format!(
+                "AWS4-HMAC-SHA256 Credential={}/{}, SignedHeaders=host;x-amz-content-sha256;x-amz-date, Signature={}",
+                self.access_key, scope, signature
+            ));
+        for (name, value) in headers {
+            request = request.set(name, value);
+        }
+
+        request.send_bytes(body).map_err(io_error)
+    }
+
+    /* Key of an object in the bucket */
+    fn key(&self, key: &str) -> String {
+        format!("{}{}", self.prefix, key)
+    }
+
+    fn read_body(response: ureq::Response) -> std::io::Result<Vec<u8>> {
+        let mut data = Vec::new();
+        response.into_reader().read_to_end(&mut data)?;
+        Ok(data)
+    }
+}
+
+impl Storage for S3Storage {
+    fn put(&self, key: &str, data: &[u8]) -> std::io::Result<()> {
+        self.request("PUT", &self.key(key), &[], &[], data)?;
+        Ok(())
+    }
+
+    fn get(&self, key: &str) -> std::io::Result<Vec<u8>> {
+        Self::read_body(self.request("GET", &self.key(key), &[], &[], &[])?)
+    }
+
+    fn delete(&self, key: &str) -> std::io::Result<()> {
+        self.request("DELETE", &self.key(key), &[], &[], &[])?;
+        Ok(())
+    }
+
+    fn list(&self) -> std::io::Result<Vec<String>> {
+        let mut keys = Vec::new();
+        let mut token: Option<String> = None;
+
+        /* ListObjectsV2 returns at most 1000 keys per page */
+        loop {
+            let mut query = vec![("list-type", "2"), ("prefix", self.prefix.as_str())];
+            if let Some(token) = &token {
+                query.push(("continuation-token", token.as_str()));
+            }
+            let body = Self::read_body(self.request("GET", "", &query, &[], &[])?)?;
+            let xml = String::from_utf8_lossy(body.as_slice()).to_string();
+
+            keys.extend(xml_values(&xml, "Key").into_iter().filter_map(|key| key.strip_prefix(self.prefix.as_str()).map(str::to_string)));
+            token = xml_values(&xml, "NextContinuationToken").pop();
+            if token.is_none() {
+                return Ok(keys);
+            }
+        }
+    }
+
+    fn stat(&self, key: &str) -> std::io::Result<Option<ObjectStat>> {
+        match self.request("HEAD", &self.key(key), &[], &[], &[]) {
+            Ok(response) => Ok(Some(ObjectStat {
+                size: response.header("Content-Length").and_then(|l| l.parse().ok()).unwrap_or(0),
+            })),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn get_range(&self, key: &str, offset: u64, len: u64) -> std::io::Result<Vec<u8>> {
+        if len == 0 {
+            return Ok(Vec::new());
+        }
+        let range = format!("bytes={}-{}", offset, offset + len - 1);
+        match self.request("GET", &self.key(key), &[], &[("Range", range)], &[]) {
+            Ok(response) => Self::read_body(response),
+            /* Asking for a range past the end of the object */
+            Err(e) if e.kind() == std::io::ErrorKind::InvalidInput => Ok(Vec::new()),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::{BTreeMap, HashMap};
+    use std::io::BufReader;
+    use std::net::TcpListener;
+    use std::sync::{Arc, Mutex};
+
+    fn percent_decode(s: &str) -> String {
+        let mut out = Vec::new();
+        let mut bytes = s.bytes();
+        while let Some(b) = bytes.next() {
+            if b == b'%' {
+                let hex = [bytes.next().unwrap(), bytes.next().unwrap()];
+                out.push(u8::from_str_radix(std::str::from_utf8(&hex).unwrap(), 16).unwrap());
+            } else {
+                out.push(b);
+            }
+        }
+        String::from_utf8(out).unwrap()
+    }
+
+    /* Requests the mock saw: method, path, query and host header */
+    type Seen = Arc<Mutex<Vec<(String, String, String, String)>>>;
+
+    /**Just enough of S3 to serve one bucket below `base`, listing two keys per page. Every response closes its connection */
+    fn mock_s3(base: &'static str, objects: Arc<Mutex<BTreeMap<String, Vec<u8>>>>) -> (String, Seen) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let seen = Seen::default();
+
+        let requests = seen.clone();
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                let mut parts = line.split_whitespace();
+                let (method, target) = (parts.next().unwrap().to_string(), parts.next().unwrap().to_string());
+
+                let mut headers = HashMap::new();
+                loop {
+                    let mut header = String::new();
+                    reader.read_line(&mut header).unwrap();
+                    match header.trim_end().split_once(": ") {
+                        Some((name, value)) => headers.insert(name.to_ascii_lowercase(), value.to_string()),
+                        None => break,
+                    };
+                }
+                let mut body = vec![0; headers.get("content-length").map_or(0, |l| l.parse().unwrap())];
+                reader.read_exact(&mut body).unwrap();
+
+                let (path, query) = target.split_once('?').unwrap_or((target.as_str(), ""));
+                let host = headers.get("host").cloned().unwrap_or_default();
+                requests.lock().unwrap().push((method.clone(), path.to_string(), query.to_string(), host));
+                let params = query.split('&').filter_map(|p| p.split_once('=')).map(|(k, v)| (k.to_string(), percent_decode(v))).collect::<HashMap<_, _>>();
+
+                let key = path.strip_prefix(base).and_then(|path| path.strip_prefix("/bucket")).map(percent_decode).unwrap_or_default();
+                let mut objects = objects.lock().unwrap();
+                let (status, data) = match (method.as_str(), key.strip_prefix('/')) {
+                    ("GET", None) | ("GET", Some("")) if params.contains_key("list-type") => {
+                        let prefix = params.get("prefix").cloned().unwrap_or_default();
+                        let start = params.get("continuation-token").map_or(0, |t| t.parse().unwrap());
+                        let keys = objects.keys().filter(|key| key.starts_with(&prefix)).collect::<Vec<_>>();
+                        let mut xml = keys.iter().skip(start).take(2).map(|key| format!("<Contents><Key>{}</Key></Contents>", key)).collect::<String>();
+                        if start + 2 < keys.len() {
+                            xml.push_str(&format!("<NextContinuationToken>{}</NextContinuationToken>", start + 2));
+                        }
+                        (200, format!("<ListBucketResult>{}</ListBucketResult>", xml).into_bytes())
+                    }
+                    ("PUT", Some(key)) => {
+                        objects.insert(key.to_string(), body);
+                        (200, vec![])
+                    }
+                    ("DELETE", Some(key)) => (if objects.remove(key).is_some() { 204 } else { 404 }, vec![]),
+                    ("GET", Some(key)) | ("HEAD", Some(key)) => match objects.get(key) {
+                        Some(data) => match headers.get("range").and_then(|range| range.strip_prefix("bytes=")).and_then(|range| range.split_once('-')) {
+                            Some((start, end)) => {
+                                let start = usize::min(start.parse().unwrap(), data.len());
+                                let end = usize::min(end.parse::<usize>().unwrap() + 1, data.len());
+                                (206, data[start..end].to_vec())
+                            }
+                            None => (200, data.clone()),
+                        },
+                        None => (404, vec![]),
+                    },
+                    _ => (400, vec![]),
+                };
+                drop(objects);
+
+                let head = format!("HTTP/1.1 {} Mock\r\nContent-Length: {}\r\nConnection: close\r\n\r\n", status, data.len());
+                stream.write_all(head.as_bytes()).unwrap();
+                if method != "HEAD" {
+                    stream.write_all(&data).unwrap();
+                }
+            }
+        });
+        (format!("http://{}{}", addr, base), seen)
+    }
+
+    #[test]
+    fn objects_round_trip_under_the_prefix() {
+        let objects = Arc::new(Mutex::new(BTreeMap::new()));
+        objects.lock().unwrap().insert("other/thing".to_string(), b"not ours".to_vec());
+        let (endpoint, _) = mock_s3("", objects.clone());
+        let storage = S3Storage::new(&endpoint, "bucket", "objects/", "us-east-1", "ak", "sk");
+
+        for key in ["a", "b", "c", "d", "e"] {
+            storage.put(key, key.repeat(3).as_bytes()).unwrap();
+        }
+        assert_eq!(objects.lock().unwrap().get("objects/a"), Some(&b"aaa".to_vec()));
+        assert_eq!(storage.get("b").unwrap(), b"bbb");
+        assert_eq!(storage.get_range("c", 1, 5).unwrap(), b"cc");
+        assert_eq!(storage.stat("d").unwrap(), Some(ObjectStat { size: 3 }));
+        assert_eq!(storage.stat("missing").unwrap(), None);
+        assert_eq!(storage.get("missing").unwrap_err().kind(), std::io::ErrorKind::NotFound);
+
+        /* Listing pages through the prefix only */
+        storage.delete("e").unwrap();
+        let mut keys = storage.list().unwrap();
+        keys.sort();
+        assert_eq!(keys, vec!["a", "b", "c", "d"]);
+    }
+
+    #[test]
+    fn s3_backend() {
+        let (endpoint, _) = mock_s3("", Arc::default());
+        crate::storage::tests::check_backend(&S3Storage::new(&endpoint, "bucket", "objects/", "us-east-1", "ak", "sk"));
+    }
+
+    #[test]
+    fn endpoints_may_have_a_path() {
+        let (endpoint, seen) = mock_s3("/s3", Arc::default());
+        let storage = S3Storage::new(&format!("{}/", endpoint), "bucket", "objects/", "us-east-1", "ak", "sk");
+        assert_eq!(storage.host, endpoint.trim_start_matches("http://").trim_end_matches("/s3"));
+
+        storage.put("a", b"data").unwrap();
+        assert_eq!(storage.get("a").unwrap(), b"data");
+        assert_eq!(storage.list().unwrap(), vec!["a"]);
+
+        let seen = seen.lock().unwrap();
+        assert_eq!(seen[0].1, "/s3/bucket/objects/a");
+        assert!(seen.iter().all(|(_, _, _, host)| host == &storage.host));
+    }
+}
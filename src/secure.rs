@@ -0,0 +1,688 @@
+use crate::net::LENGTH_PREFIX;
+use hydroflow::bytes::{BufMut, Bytes, BytesMut};
+use hydroflow::futures::channel::mpsc;
+use hydroflow::futures::{SinkExt, Stream, StreamExt};
+use hydroflow::tokio;
+use hydroflow::tokio_stream::wrappers::UnboundedReceiverStream;
+use hydroflow::util::UdpSink;
+use snow::{Builder, HandshakeState, StatelessTransportState};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/* Client knows the static key of the server up front, the server doesn't authenticate clients at this layer */
+const NOISE_PARAMS: &str = "Noise_NK_25519_ChaChaPoly_BLAKE2s";
+const MAX_NOISE_MSG: usize = 65535;
+/* How often an unanswered handshake is sent again */
+const HANDSHAKE_RETRY: Duration = Duration::from_millis(500);
+/* Number of nonces below the highest one seen that are still accepted, UDP may reorder datagrams */
+const REPLAY_WINDOW: u64 = 128;
+/* Largest payload of a UDP datagram over IPv4 */
+const MAX_DATAGRAM: usize = 65507;
+/* Largest message that still fits in a datagram once it has its length prefix, kind, nonce and tag */
+const MAX_PLAINTEXT: usize = MAX_DATAGRAM - LENGTH_PREFIX as usize - 1 - 8 - 16;
+/* Sessions that didn't carry anything authentic for this long are forgotten, the same as authentication */
+const PEER_IDLE_TIMEOUT: Duration = Duration::from_secs(30 * 60);
+const PEER_SWEEP_INTERVAL: Duration = Duration::from_secs(10);
+/* Most sessions kept at once. Once full, a handshake only gets in by pushing out a session that never carried traffic */
+const MAX_PEERS: usize = 65536;
+/* UNKNOWN_SESSION isn't authenticated, so it's only believed once nothing from the server decrypted for this long */
+const UNKNOWN_SESSION_GRACE: Duration = Duration::from_secs(2);
+
+/* First byte of every datagram when encryption is on */
+const HANDSHAKE_INIT: u8 = 1;
+const HANDSHAKE_RESPONSE: u8 = 2;
+const TRANSPORT: u8 = 3;
+/* Sent by the server for transport datagrams it has no session for, e.g. after a restart */
+const UNKNOWN_SESSION: u8 = 4;
+
+/* Plaintext side of the transport, what the flows see instead of the socket */
+pub type SecureSink = mpsc::UnboundedSender<(Bytes, SocketAddr)>;
+pub type SecureStream = UnboundedReceiverStream<Result<(BytesMut, SocketAddr), std::io::Error>>;
+
+static NEXT_SESSION_ID: AtomicU64 = AtomicU64::new(1);
+
+/**Id of the secure session each peer is on, a peer gets a new one whenever its session is replaced or forgotten.
+ * State that belongs to a session, like the identity a client authenticated as, only holds while its id is current */
+#[derive(Debug, Clone, Default)]
+pub struct SessionIds(Arc<Mutex<HashMap<SocketAddr, u64>>>);
+
+impl SessionIds {
+    /* None without a secure session, which is always the case with `Security::Plain` */
+    pub fn get(&self, addr: &SocketAddr) -> Option<u64> {
+        self.0.lock().unwrap().get(addr).copied()
+    }
+
+    fn set(&self, addr: SocketAddr, id: u64) {
+        self.0.lock().unwrap().insert(addr, id);
+    }
+
+    fn remove(&self, addr: &SocketAddr) {
+        self.0.lock().unwrap().remove(addr);
+    }
+}
+
+/* Whether and how datagrams are encrypted */
+#[derive(Clone)]
+pub enum Security {
+    Plain,
+    /* Static private key of the server */
+    Server { private_key: Vec<u8> },
+    /* Pinned static public key of the server */
+    Client { server_key: Vec<u8> },
+}
+
+/**Load the static key pair of the server from `path`, or generate and store one if there is none yet.
+ * The file holds the hex encoded private key on the first line and the public key on the second */
+pub fn load_or_create_keypair(path: &Path) -> std::io::Result<(Vec<u8>, Vec<u8>)> {
+    let invalid = || std::io::Error::new(std::io::ErrorKind::InvalidData, "invalid key file");
+
+    if let Ok(data) = std::fs::read_to_string(path) {
+        let mut lines = data.lines();
+        let private_key = lines.next().and_then(|line| hex::decode(line.trim()).ok()).ok_or_else(invalid)?;
+        let public_key = lines.next().and_then(|line| hex::decode(line.trim()).ok()).ok_or_else(invalid)?;
+        return Ok((private_key, public_key));
+    }
+
+    let keypair = Builder::new(NOISE_PARAMS.parse().unwrap()).generate_keypair().map_err(|_| invalid())?;
+    crate::fsutil::write_file_atomic(path, format!("{}\n{}\n", hex::encode(&keypair.private), hex::encode(&keypair.public)).as_bytes())?;
+    Ok((keypair.private, keypair.public))
+}
+
+/* Tracks the nonces seen recently so a captured datagram can't be played back */
+#[derive(Default)]
+struct ReplayWindow {
+    highest: Option<u64>,
+    /* Bit i is set if nonce highest - i was seen */
+    seen: u128,
+}
+
+impl ReplayWindow {
+    fn accept(&mut self, nonce: u64) -> bool {
+        let highest = match self.highest {
+            None => {
+                self.highest = Some(nonce);
+                self.seen = 1;
+                return true;
+            }
+            Some(highest) => highest,
+        };
+
+        if nonce > highest {
+            let shift = nonce - highest;
+            self.seen = if shift >= REPLAY_WINDOW { 0 } else { self.seen << shift };
+            self.seen |= 1;
+            self.highest = Some(nonce);
+            true
+        } else {
+            let age = highest - nonce;
+            if age >= REPLAY_WINDOW || self.seen & (1 << age) != 0 {
+                return false;
+            }
+            self.seen |= 1 << age;
+            true
+        }
+    }
+}
+
+enum Peer {
+    /* Client side, waiting for the handshake response. Messages sent meanwhile are held back */
+    Handshaking { handshake: Box<HandshakeState>, init: Bytes, pending: Vec<Bytes> },
+    Established { transport: StatelessTransportState, next_nonce: u64, replay: ReplayWindow },
+}
+
+/* A peer's session together with what's needed to expire it or to roll it over to a new one */
+struct PeerState {
+    peer: Peer,
+    id: u64,
+    /* Server side: the session a new handshake from the same address is going to replace. It stays usable until
+     * traffic arrives under the new one, so a replayed or spoofed init can't tear down a working session */
+    replaced: Option<(Peer, u64)>,
+    /* Whether anything authentic arrived under `peer` yet */
+    confirmed: bool,
+    last_seen: Instant,
+}
+
+impl PeerState {
+    fn new(peer: Peer) -> PeerState {
+        PeerState { peer, id: NEXT_SESSION_ID.fetch_add(1, Ordering::Relaxed), replaced: None, confirmed: false, last_seen: Instant::now() }
+    }
+}
+
+fn established(handshake: HandshakeState) -> Option<Peer> {
+    let transport = handshake.into_stateless_transport_mode().ok()?;
+    Some(Peer::Established { transport, next_nonce: 0, replay: ReplayWindow::default() })
+}
+
+fn packet(kind: u8, body: &[u8]) -> Bytes {
+    let mut packet = BytesMut::with_capacity(body.len() + 1);
+    packet.put_u8(kind);
+    packet.put_slice(body);
+    packet.freeze()
+}
+
+struct Transport {
+    security: Security,
+    peers: HashMap<SocketAddr, PeerState>,
+    ids: SessionIds,
+    last_sweep: Instant,
+    raw: UdpSink,
+    inbound: tokio::sync::mpsc::UnboundedSender<Result<(BytesMut, SocketAddr), std::io::Error>>,
+}
+
+impl Transport {
+    async fn send_raw(&mut self, data: Bytes, addr: SocketAddr) {
+        if let Err(e) = self.raw.send((data, addr)).await {
+            println!("Unable to send to {:?}: {:?}", addr, e);
+        }
+    }
+
+    fn decrypt(peer: &mut Peer, body: &[u8]) -> Option<BytesMut> {
+        match peer {
+            Peer::Established { transport, replay, .. } if body.len() >= 8 => {
+                let nonce = u64::from_be_bytes(body[..8].try_into().unwrap());
+                let mut buf = vec![0u8; body.len()];
+                match transport.read_message(nonce, &body[8..], &mut buf) {
+                    /* Only count the nonce once the datagram turned out to be authentic */
+                    Ok(len) if replay.accept(nonce) => Some(BytesMut::from(&buf[..len])),
+                    _ => None,
+                }
+            }
+            _ => None,
+        }
+    }
+
+    fn encrypt(peer: &mut Peer, data: &[u8]) -> Option<Bytes> {
+        match peer {
+            Peer::Established { transport, next_nonce, .. } => {
+                let mut buf = vec![0u8; data.len() + 16];
+                let len = transport.write_message(*next_nonce, data, &mut buf).ok()?;
+
+                let mut packet = BytesMut::with_capacity(len + 9);
+                packet.put_u8(TRANSPORT);
+                packet.put_u64(*next_nonce);
+                packet.put_slice(&buf[..len]);
+                *next_nonce += 1;
+                Some(packet.freeze())
+            }
+            Peer::Handshaking { .. } => None,
+        }
+    }
+
+    /* Start a handshake with the server, the init is kept around to send it again if it gets lost */
+    fn start_handshake(server_key: &[u8]) -> Option<Peer> {
+        let mut handshake = Builder::new(NOISE_PARAMS.parse().unwrap()).remote_public_key(server_key).build_initiator().ok()?;
+        let mut buf = vec![0u8; MAX_NOISE_MSG];
+        let len = handshake.write_message(&[], &mut buf).ok()?;
+        Some(Peer::Handshaking { handshake: Box::new(handshake), init: packet(HANDSHAKE_INIT, &buf[..len]), pending: vec![] })
+    }
+
+    /* Forget a peer along with its session id */
+    fn forget(&mut self, addr: &SocketAddr) {
+        self.peers.remove(addr);
+        self.ids.remove(addr);
+    }
+
+    /* Make room for a new peer, returns false if every session is in use */
+    fn make_room(&mut self) -> bool {
+        if self.peers.len() < MAX_PEERS {
+            return true;
+        }
+        let unconfirmed = self.peers.iter()
+            .filter(|(_, state)| !state.confirmed)
+            .min_by_key(|(_, state)| state.last_seen)
+            .map(|(addr, _)| *addr);
+        match unconfirmed {
+            Some(addr) => {
+                self.forget(&addr);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /* Drop sessions that went quiet */
+    fn expire_peers(&mut self) {
+        let now = Instant::now();
+        if now.duration_since(self.last_sweep) < PEER_SWEEP_INTERVAL {
+            return;
+        }
+        self.last_sweep = now;
+
+        let idle = self.peers.iter()
+            .filter(|(_, state)| now.duration_since(state.last_seen) >= PEER_IDLE_TIMEOUT)
+            .map(|(addr, _)| *addr)
+            .collect::<Vec<SocketAddr>>();
+        for addr in idle {
+            self.forget(&addr);
+        }
+    }
+
+    async fn outgoing(&mut self, data: Bytes, addr: SocketAddr) {
+        let server_key = match &self.security {
+            Security::Plain => return self.send_raw(data, addr).await,
+            Security::Server { .. } => None,
+            Security::Client { server_key } => Some(server_key.clone()),
+        };
+        /* The sink can't fail a send, the layer above hears about it on the stream instead */
+        if data.len() > MAX_PLAINTEXT {
+            let error = format!("message of {} bytes to {:?} is too large to encrypt, the limit is {}", data.len(), addr, MAX_PLAINTEXT);
+            let _ = self.inbound.send(Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, error)));
+            return;
+        }
+
+        if !self.peers.contains_key(&addr) {
+            match server_key.as_deref().and_then(Self::start_handshake) {
+                Some(peer) if self.make_room() => { self.peers.insert(addr, PeerState::new(peer)); }
+                _ => {
+                    println!("No secure session with {:?}, dropping message", addr);
+                    return;
+                }
+            }
+        }
+        let state = self.peers.get_mut(&addr).unwrap();
+
+        /* Until the client confirms a new session it may not have it, so replies go out under the one it had */
+        let peer = match &mut state.replaced {
+            Some((replaced, _)) => replaced,
+            None => &mut state.peer,
+        };
+        let packet = match peer {
+            /* The first held back message kicks off the handshake, retries are up to retry_handshakes */
+            Peer::Handshaking { init, pending, .. } => {
+                pending.push(data);
+                if pending.len() > 1 {
+                    return;
+                }
+                Some(init.clone())
+            }
+            peer => Self::encrypt(peer, &data),
+        };
+        if let Some(packet) = packet {
+            self.send_raw(packet, addr).await;
+        }
+    }
+
+    /**Server side of the handshake. A new init doesn't replace a session that carried traffic right away,
+     * the old session is kept next to the new one until the client sends something under the new one */
+    async fn handshake_init(&mut self, body: &[u8], addr: SocketAddr) {
+        let private_key = match &self.security {
+            Security::Server { private_key } => private_key.clone(),
+            _ => return,
+        };
+
+        let mut buf = vec![0u8; MAX_NOISE_MSG];
+        let response = Builder::new(NOISE_PARAMS.parse().unwrap()).local_private_key(&private_key).build_responder().ok()
+            .and_then(|mut handshake| {
+                handshake.read_message(body, &mut buf).ok()?;
+                let len = handshake.write_message(&[], &mut buf).ok()?;
+                Some((packet(HANDSHAKE_RESPONSE, &buf[..len]), established(handshake)?))
+            });
+        let (response, peer) = match response {
+            Some(response) => response,
+            None => {
+                println!("Invalid handshake from {:?}", addr);
+                return;
+            }
+        };
+
+        let mut state = PeerState::new(peer);
+        match self.peers.remove(&addr) {
+            Some(old) if old.confirmed => state.replaced = Some((old.peer, old.id)),
+            Some(old) => state.replaced = old.replaced,
+            None if !self.make_room() => {
+                println!("Too many secure sessions, dropping handshake from {:?}", addr);
+                return;
+            }
+            None => {}
+        }
+        if state.replaced.is_none() {
+            self.ids.set(addr, state.id);
+        }
+        self.peers.insert(addr, state);
+        self.send_raw(response, addr).await;
+    }
+
+    /* Client side, the server proved it has the pinned key. Send what was held back */
+    async fn handshake_response(&mut self, body: &[u8], addr: SocketAddr) {
+        let (mut handshake, pending) = match self.peers.remove(&addr) {
+            Some(PeerState { peer: Peer::Handshaking { handshake, pending, .. }, .. }) => (handshake, pending),
+            Some(state) => {
+                self.peers.insert(addr, state);
+                return;
+            }
+            None => return,
+        };
+
+        let mut buf = vec![0u8; MAX_NOISE_MSG];
+        let peer = match handshake.read_message(body, &mut buf).ok().and_then(|_| established(*handshake)) {
+            Some(peer) => peer,
+            None => {
+                println!("Handshake with {:?} failed, the server key doesn't match the pinned one", addr);
+                self.ids.remove(&addr);
+                return;
+            }
+        };
+
+        let mut state = PeerState::new(peer);
+        state.confirmed = true;
+        self.ids.set(addr, state.id);
+        self.peers.insert(addr, state);
+        for data in pending {
+            if let Some(packet) = Self::encrypt(&mut self.peers.get_mut(&addr).unwrap().peer, &data) {
+                self.send_raw(packet, addr).await;
+            }
+        }
+    }
+
+    async fn transport(&mut self, body: &[u8], addr: SocketAddr) {
+        let state = match self.peers.get_mut(&addr) {
+            Some(state) => state,
+            None => {
+                if matches!(self.security, Security::Server { .. }) {
+                    self.send_raw(packet(UNKNOWN_SESSION, &[]), addr).await;
+                }
+                return;
+            }
+        };
+
+        let plaintext = match Self::decrypt(&mut state.peer, body) {
+            /* Traffic under a new session, the client has it and the session it replaces can go */
+            Some(plaintext) => {
+                state.confirmed = true;
+                if state.replaced.take().is_some() {
+                    println!("Secure session with {:?} was renewed", addr);
+                    self.ids.set(addr, state.id);
+                }
+                Some(plaintext)
+            }
+            None => state.replaced.as_mut().and_then(|(peer, _)| Self::decrypt(peer, body)),
+        };
+
+        match plaintext {
+            Some(plaintext) => {
+                state.last_seen = Instant::now();
+                let _ = self.inbound.send(Ok((plaintext, addr)));
+            }
+            None => println!("Dropped a datagram from {:?} that failed to decrypt or was replayed", addr),
+        }
+    }
+
+    async fn incoming(&mut self, data: BytesMut, addr: SocketAddr) {
+        if matches!(self.security, Security::Plain) {
+            let _ = self.inbound.send(Ok((data, addr)));
+            return;
+        }
+
+        let (kind, body) = match data.split_first() {
+            Some((kind, body)) => (*kind, body),
+            None => return,
+        };
+        match kind {
+            HANDSHAKE_INIT => self.handshake_init(body, addr).await,
+            HANDSHAKE_RESPONSE => self.handshake_response(body, addr).await,
+            TRANSPORT => self.transport(body, addr).await,
+            /* The server lost our session, the next message starts a new handshake. Anyone can send this, so
+             * it's ignored as long as the server's traffic still decrypts */
+            UNKNOWN_SESSION if matches!(self.security, Security::Client { .. }) => {
+                let lost = self.peers.get(&addr).is_some_and(|state| {
+                    matches!(state.peer, Peer::Established { .. }) && state.last_seen.elapsed() >= UNKNOWN_SESSION_GRACE
+                });
+                if lost {
+                    println!("Server {:?} lost the secure session, starting a new one", addr);
+                    self.forget(&addr);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /* Send the init of handshakes that weren't answered yet again */
+    async fn retry_handshakes(&mut self) {
+        let inits = self.peers.iter()
+            .filter_map(|(addr, state)| match &state.peer {
+                Peer::Handshaking { init, .. } => Some((init.clone(), *addr)),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        for (init, addr) in inits {
+            self.send_raw(init, addr).await;
+        }
+    }
+}
+
+/**Put a Noise session per peer between a socket and a flow. The returned sink and stream carry plaintext
+ * and can be used with `dest_sink_serde` and `source_stream_serde` like the socket itself. With
+ * `Security::Plain` datagrams are passed through unchanged */
+pub fn secure_udp<S>(mut raw_outbound: UdpSink, mut raw_inbound: S, security: Security, ids: SessionIds) -> (SecureSink, SecureStream)
+where
+    S: Stream<Item = std::io::Result<(BytesMut, SocketAddr)>> + Unpin + Send + 'static,
+{
+    let (outbound, mut outgoing) = mpsc::unbounded::<(Bytes, SocketAddr)>();
+    let (inbound, stream) = hydroflow::util::unbounded_channel();
+
+    if let Security::Plain = security {
+        tokio::spawn(async move {
+            while let Some(item) = raw_inbound.next().await {
+                if inbound.send(item).is_err() {
+                    break;
+                }
+            }
+        });
+        tokio::spawn(async move {
+            while let Some(item) = outgoing.next().await {
+                let _ = raw_outbound.send(item).await;
+            }
+        });
+        return (outbound, stream);
+    }
+
+    tokio::spawn(async move {
+        let mut transport = Transport { security, peers: HashMap::new(), ids, last_sweep: Instant::now(), raw: raw_outbound, inbound };
+        let mut retry = tokio::time::interval(HANDSHAKE_RETRY);
+
+        loop {
+            tokio::select! {
+                item = raw_inbound.next() => match item {
+                    Some(Ok((data, addr))) => transport.incoming(data, addr).await,
+                    Some(Err(e)) => println!("Error receiving datagram: {:?}", e),
+                    None => break,
+                },
+                item = outgoing.next() => match item {
+                    Some((data, addr)) => transport.outgoing(data, addr).await,
+                    None => break,
+                },
+                _ = retry.tick() => {
+                    transport.retry_handshakes().await;
+                    transport.expire_peers();
+                }
+            }
+        }
+    });
+
+    (outbound, stream)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::net::{bind_udp_reuseport, udp_bytes};
+    use hydroflow::util::UdpStream;
+
+    #[test]
+    fn replay_window_refuses_replays_and_old_nonces() {
+        let mut window = ReplayWindow::default();
+        assert!(window.accept(5));
+        assert!(!window.accept(5));
+        assert!(window.accept(3));
+        assert!(!window.accept(3));
+
+        assert!(window.accept(5 + REPLAY_WINDOW));
+        assert!(window.accept(6));
+        assert!(!window.accept(5));
+        assert!(!window.accept(4));
+        assert!(window.accept(1000));
+        assert!(!window.accept(1000 - REPLAY_WINDOW));
+        assert!(window.accept(1000 - REPLAY_WINDOW + 1));
+    }
+
+    struct Side {
+        transport: Transport,
+        addr: SocketAddr,
+        raw: UdpStream,
+        inbound: tokio::sync::mpsc::UnboundedReceiver<Result<(BytesMut, SocketAddr), std::io::Error>>,
+    }
+
+    impl Side {
+        fn new(security: Security) -> Side {
+            let socket = bind_udp_reuseport("127.0.0.1:0".parse().unwrap()).unwrap();
+            let addr = socket.local_addr().unwrap();
+            let (sink, raw) = udp_bytes(socket).unwrap();
+            let (inbound, receiver) = tokio::sync::mpsc::unbounded_channel();
+            let transport = Transport { security, peers: HashMap::new(), ids: SessionIds::default(), last_sweep: Instant::now(), raw: sink, inbound };
+            Side { transport, addr, raw, inbound: receiver }
+        }
+
+        /* Next datagram that arrived on the socket */
+        async fn datagram(&mut self) -> BytesMut {
+            self.raw.next().await.unwrap().unwrap().0
+        }
+
+        fn received(&mut self) -> Option<Vec<u8>> {
+            self.inbound.try_recv().ok().map(|item| item.unwrap().0.to_vec())
+        }
+    }
+
+    fn pair() -> (Side, Side) {
+        let keypair = Builder::new(NOISE_PARAMS.parse().unwrap()).generate_keypair().unwrap();
+        (Side::new(Security::Server { private_key: keypair.private }), Side::new(Security::Client { server_key: keypair.public }))
+    }
+
+    /* The client sends `data`, which goes out as soon as the handshake is done. Returns the encrypted datagram */
+    async fn connect(server: &mut Side, client: &mut Side, client_addr: SocketAddr, data: &[u8]) -> BytesMut {
+        client.transport.outgoing(Bytes::copy_from_slice(data), server.addr).await;
+        let init = server.datagram().await;
+        server.transport.incoming(init, client_addr).await;
+        let response = client.datagram().await;
+        client.transport.incoming(response, server.addr).await;
+        server.datagram().await
+    }
+
+    async fn send(from: &mut Side, to: &mut Side, data: &[u8]) -> BytesMut {
+        from.transport.outgoing(Bytes::copy_from_slice(data), to.addr).await;
+        to.datagram().await
+    }
+
+    fn run(test: impl std::future::Future<Output = ()>) {
+        tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap().block_on(test);
+    }
+
+    #[test]
+    fn replayed_and_stale_datagrams_are_dropped() {
+        run(async {
+            let (mut server, mut client) = pair();
+            let client_addr = client.addr;
+            let first = connect(&mut server, &mut client, client_addr, b"hello").await;
+            server.transport.incoming(first.clone(), client_addr).await;
+            assert_eq!(server.received(), Some(b"hello".to_vec()));
+            server.transport.incoming(first, client_addr).await;
+            assert_eq!(server.received(), None);
+
+            let mut datagrams = Vec::new();
+            for i in 0..=REPLAY_WINDOW {
+                datagrams.push(send(&mut client, &mut server, &i.to_be_bytes()).await);
+            }
+            let last = datagrams.pop().unwrap();
+            server.transport.incoming(last, client_addr).await;
+            assert_eq!(server.received(), Some(REPLAY_WINDOW.to_be_bytes().to_vec()));
+            /* Nonce 1 is just outside the window, nonce 2 just inside */
+            server.transport.incoming(datagrams[0].clone(), client_addr).await;
+            assert_eq!(server.received(), None);
+            server.transport.incoming(datagrams[1].clone(), client_addr).await;
+            assert_eq!(server.received(), Some(1u64.to_be_bytes().to_vec()));
+        });
+    }
+
+    #[test]
+    fn largest_message_fits_in_a_datagram() {
+        run(async {
+            let (mut server, mut client) = pair();
+            let client_addr = client.addr;
+            let first = connect(&mut server, &mut client, client_addr, b"hello").await;
+            server.transport.incoming(first, client_addr).await;
+            assert_eq!(server.received(), Some(b"hello".to_vec()));
+
+            let message = vec![7u8; MAX_PLAINTEXT];
+            let datagram = send(&mut client, &mut server, &message).await;
+            server.transport.incoming(datagram, client_addr).await;
+            assert_eq!(server.received(), Some(message));
+
+            client.transport.outgoing(Bytes::from(vec![7u8; MAX_PLAINTEXT + 1]), server.addr).await;
+            assert_eq!(client.inbound.try_recv().unwrap().unwrap_err().kind(), std::io::ErrorKind::InvalidInput);
+        });
+    }
+
+    #[test]
+    fn unknown_session_is_only_believed_after_the_grace_period() {
+        run(async {
+            let (mut server, mut client) = pair();
+            let client_addr = client.addr;
+            connect(&mut server, &mut client, client_addr, b"hello").await;
+            let id = client.transport.ids.get(&server.addr);
+            assert!(id.is_some());
+
+            client.transport.incoming(BytesMut::from(&[UNKNOWN_SESSION][..]), server.addr).await;
+            assert_eq!(client.transport.ids.get(&server.addr), id);
+            assert!(client.transport.peers.contains_key(&server.addr));
+
+            client.transport.peers.get_mut(&server.addr).unwrap().last_seen -= UNKNOWN_SESSION_GRACE;
+            client.transport.incoming(BytesMut::from(&[UNKNOWN_SESSION][..]), server.addr).await;
+            assert_eq!(client.transport.ids.get(&server.addr), None);
+            assert!(!client.transport.peers.contains_key(&server.addr));
+        });
+    }
+
+    #[test]
+    fn sessions_are_only_replaced_once_the_new_one_carries_traffic() {
+        run(async {
+            let (mut server, mut client) = pair();
+            let client_addr = client.addr;
+            let first = connect(&mut server, &mut client, client_addr, b"old").await;
+            server.transport.incoming(first, client_addr).await;
+            assert_eq!(server.received(), Some(b"old".to_vec()));
+            let old_id = server.transport.ids.get(&client_addr);
+
+            /* A second handshake from the same address, e.g. the client restarted */
+            let mut restarted = Side::new(Security::Client { server_key: match &client.transport.security {
+                Security::Client { server_key } => server_key.clone(),
+                _ => unreachable!(),
+            } });
+            restarted.transport.outgoing(Bytes::from_static(b"new"), server.addr).await;
+            let init = server.datagram().await;
+            server.transport.incoming(init, client_addr).await;
+            assert_eq!(server.transport.ids.get(&client_addr), old_id);
+
+            /* Until then the old session keeps working, and replies still go out under it */
+            let datagram = send(&mut client, &mut server, b"still old").await;
+            server.transport.incoming(datagram, client_addr).await;
+            assert_eq!(server.received(), Some(b"still old".to_vec()));
+            let response = client.datagram().await;
+            let reply = send(&mut server, &mut client, b"reply").await;
+            client.transport.incoming(reply, server.addr).await;
+            assert_eq!(client.received(), Some(b"reply".to_vec()));
+
+            restarted.transport.incoming(response, server.addr).await;
+            let datagram = server.datagram().await;
+            server.transport.incoming(datagram, client_addr).await;
+            assert_eq!(server.received(), Some(b"new".to_vec()));
+            assert_ne!(server.transport.ids.get(&client_addr), old_id);
+
+            let datagram = send(&mut client, &mut server, b"too old").await;
+            server.transport.incoming(datagram, client_addr).await;
+            assert_eq!(server.received(), None);
+        });
+    }
+}
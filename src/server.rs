@@ -1,144 +1,1022 @@
-use crate::merkletree::MerkleTree;
-use crate::protocol::Message;
-use chrono::prelude::*;
-
-use hydroflow::{hydroflow_syntax, futures};
-use hydroflow::scheduled::graph::Hydroflow;
-use hydroflow::util::{UdpSink, UdpStream};
-
-use std::net::SocketAddr;
-
-use std::fs::File;
-use std::io::prelude::*;
-use std::path::Path;
-
-use futures::executor::block_on;
-
-use std::cell::RefCell;
-
-thread_local! {
-    static MT: RefCell<MerkleTree> = RefCell::new(MerkleTree::default());
-}
-
-const DATA_DIR: &str = "./.server/";
-
-
-fn save_file(filename: &str, data: &[u8]) -> Message {
-    let mut file = File::create(Path::new(DATA_DIR).join(Path::new(filename))).unwrap();
-    file.write_all(data).unwrap();
-
-    println!("Saved file {}", filename);
-
-    /* Update merkle tree */
-    block_on(async {
-        let merkle_tree = MerkleTree::from_folder(Path::new(DATA_DIR)).await;
-        MT.with(|mt| mt.replace(merkle_tree.clone()));
-    });
-
-    Message::FileAck { filename: filename.to_string(), hash: blake3::hash(data).to_string() }
-}
-
-fn delete_file(filename: &str) -> Message {
-    let res = block_on(async {
-        tokio::fs::remove_file(Path::new(DATA_DIR).join(Path::new(filename))).await
-    });
-    match res {
-        Ok(_) => {
-            println!("Deleted file {}", filename);
-
-            /* Update merkle tree */
-            block_on(async {
-                let merkle_tree = MerkleTree::from_folder(Path::new(DATA_DIR)).await;
-                MT.with(|mt| mt.replace(merkle_tree.clone()));
-            });
-
-            Message::DeleteFileAck { filename: filename.to_string(), deleted: true }
-        }
-        Err(_) => {
-            println!("Unable to remove file {}", filename);
-            Message::DeleteFileAck { filename: filename.to_string(), deleted: false }
-        }
-    }
-}
-
-/**Read file stored on disk, get merkle proof and return Message::File */
-fn read_file(filename: &str) -> Message {
-    if let Ok(mut file) = File::open(Path::new(DATA_DIR).join(Path::new(filename))) {
-        /* Read file from disk */
-        let mut data = Vec::new();
-        file.read_to_end(&mut data).unwrap();
-
-        /* Generate merkle proof */
-        let p = block_on(async {
-            let merkle_tree = MT.with(|mt| mt.borrow().clone());
-            
-            let path = Path::new(DATA_DIR).join(Path::new(filename));
-            let proof = merkle_tree.get_proof(path.as_path()).await;
-
-            proof
-        });
-    
-        /* Convert merkle proof from OSString to Vec<Vec<u8>> */
-        let p = p.map(|p| 
-            p.into_iter()
-            .map(|s| s.into_string().unwrap_or_default().into_bytes())
-            .collect::<Vec<Vec<u8>>>()
-        );
-
-        println!("Read file {}", filename);
-        
-        Message::File { filename: filename.to_string() , data: data, merkle_proof: p.unwrap() }
-    } else {
-        Message::FileNotFound { filename: filename.to_string() }
-    }
-}
-
-pub(crate) async fn run_server(outbound: UdpSink, inbound: UdpStream) {
-    println!("Server live!");
-
-    let mut flow: Hydroflow = hydroflow_syntax! {
-        // Define shared inbound and outbound channels
-        inbound_chan = source_stream_serde(inbound) -> map(|udp_msg| udp_msg.unwrap()) -> tee();
-        outbound_chan = union() -> dest_sink_serde(outbound);
-
-        // Print all messages for debugging purposes
-        inbound_chan[1]
-            -> for_each(|(m, a): (Message, SocketAddr)| println!("{}: Got {:?} from {:?}", Utc::now(), m, a));
-
-        // Demux and destructure the inbound messages into separate streams
-        inbound_demuxed = inbound_chan[0]
-            ->  demux(|(msg, addr), var_args!(file_upload_ch, file_request_ch, del_file_request_ch, heartbeat_ch, errs_ch)|
-                    match msg {
-                        Message::FileUpload {filename, data} => file_upload_ch.give((filename, data, addr)),
-                        Message::FileRequest {filename} => file_request_ch.give((filename, addr)),
-                        Message::DeleteFileRequest {filename} => del_file_request_ch.give((filename, addr)),
-                        Message::Heartbeat => heartbeat_ch.give(addr),
-                        _ => errs_ch.give((msg, addr)),
-                    }
-                );
-
-        inbound_demuxed[file_upload_ch]
-            -> map(|(filename, data, addr)| (save_file(&filename, data.as_slice()), addr) )
-            -> map(|(file_ack, addr)| (file_ack, addr) ) -> [0]outbound_chan;
-
-        inbound_demuxed[del_file_request_ch]
-            -> map(|(filename, addr)| (delete_file(&filename), addr) )
-            -> map(|(del_file_ack, addr)| (del_file_ack, addr) ) -> [3]outbound_chan;
-
-        inbound_demuxed[file_request_ch] -> map(|(filename, addr)| (read_file(&filename) , addr)) -> [1]outbound_chan;
-
-        // Respond to Heartbeat messages
-        inbound_demuxed[heartbeat_ch] -> map(|addr| (Message::HeartbeatAck, addr)) -> [2]outbound_chan;
-
-        // Print unexpected messages
-        inbound_demuxed[errs_ch]
-            -> for_each(|(msg, addr)| println!("Received unexpected message type: {:?} from {:?}", msg, addr));
-
-    };
-
-    /* Create server data folder before running the flow */
-    let _ = tokio::fs::create_dir_all(DATA_DIR).await;
-
-    // run the server flow
-    flow.run_async().await;
-}
+use crate::acl::{Acl, Permission};
+use crate::atrest::EncryptedStorage;
+use crate::auth::{Authenticator, KeyStore};
+use crate::compress::{compress_udp, Codec};
+use crate::index::{is_valid_path, FileIndex};
+use crate::merkletree::MerkleTree;
+use crate::net::{bind_udp_reuseport, count_received, limit_received, udp_bytes};
+use crate::objects::ObjectStore;
+use crate::session::SessionTable;
+use crate::secure::{secure_udp, SecureSink, SecureStream, Security, SessionIds};
+use crate::protocol::{DirEntry, Listing, Message, Preconditions, ProtocolError, UsageReport, CHUNK_SIZE};
+use crate::quota::Limits;
+use crate::ratelimit::{RateLimiter, RateLimits};
+use crate::storage::Storage;
+use crate::store::{spawn_store_worker, Contents, SharedCatalog, Store, StoreEvent, StoreJob};
+use crate::upload::{check_client_uploads, clear_spool, upload_session_id, UploadSession};
+use crate::validate::{AddressValidator, RawBytes};
+use chrono::prelude::*;
+
+use hydroflow::hydroflow_syntax;
+use hydroflow::scheduled::graph::Hydroflow;
+use hydroflow::tokio_stream::wrappers::UnboundedReceiverStream;
+use tokio::sync::mpsc::UnboundedSender;
+
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+
+use std::path::Path;
+use std::time::Duration;
+
+use std::cell::RefCell;
+
+/* Every server worker runs its own flow on its own thread, so all of this is per worker */
+thread_local! {
+    /* The filename -> hash index and the tree, shared by all workers and kept up to date by the store worker */
+    static CATALOG: RefCell<SharedCatalog> = RefCell::new(SharedCatalog::default());
+    static OBJECTS: RefCell<Option<ObjectStore>> = RefCell::new(None);
+    /* Index of this worker, replies of the store worker are routed back by it */
+    static SHARD: RefCell<usize> = RefCell::new(0);
+    /* Mutations for the store worker */
+    static JOBS: RefCell<Option<UnboundedSender<StoreJob>>> = RefCell::new(None);
+    /* Results of the I/O workers, fed back into the flow */
+    static EVENTS: RefCell<Option<UnboundedSender<StoreEvent>>> = RefCell::new(None);
+    /* Partial uploads keyed by session id, clients stick to one worker so their sessions do too */
+    static UPLOADS: RefCell<HashMap<String, UploadSession>> = RefCell::new(HashMap::new());
+    /* Pre-shared keys of the clients, authentication is disabled without them */
+    static KEYS: RefCell<Option<Arc<KeyStore>>> = RefCell::new(None);
+    /* Outstanding challenges and authenticated addresses, clients stick to one worker so these do too */
+    static AUTH: RefCell<Authenticator> = RefCell::new(Authenticator::default());
+    static SECURE_SESSIONS: RefCell<SessionIds> = RefCell::new(SessionIds::default());
+    /* Access policy shared by all workers, everything is allowed without one */
+    static ACL: RefCell<Option<Arc<Acl>>> = RefCell::new(None);
+    /* Storage limits, enforced by the store worker and checked up front when a chunked upload starts */
+    static LIMITS: RefCell<Limits> = RefCell::new(Limits::default());
+    /* Request rates of the clients of this worker, nothing is limited without it */
+    static RATE: RefCell<Option<Arc<Mutex<RateLimiter>>>> = RefCell::new(None);
+    /* Request and reply sizes of addresses that haven't echoed a retry token yet, and the ones that have */
+    static VALIDATOR: RefCell<Option<AddressValidator>> = RefCell::new(None);
+    /* Last time each client was heard from and its transfers in progress */
+    static SESSIONS: RefCell<SessionTable> = RefCell::new(SessionTable::new(Duration::from_secs(DEFAULT_SESSION_TIMEOUT_SECS)));
+}
+
+pub(crate) const DATA_DIR: &str = "./.server/";
+/* Blobs are stored under their hash in the objects folder of DATA_DIR when using the filesystem backend */
+pub(crate) const OBJECTS_DIR: &str = "objects";
+/* Write-ahead log and persisted index, kept outside of the data folder */
+const STATE_DIR: &str = "./.server-state/";
+/* Chunks of uploads in progress are spooled to files in this folder of STATE_DIR */
+const SPOOL_DIR: &str = "uploads";
+/* Clients that send nothing, not even a heartbeat, for this long are considered gone */
+pub(crate) const DEFAULT_SESSION_TIMEOUT_SECS: u64 = 120;
+/* How often sessions are checked for timeouts */
+const SESSION_SWEEP_INTERVAL: Duration = Duration::from_secs(5);
+
+
+fn objects() -> ObjectStore {
+    OBJECTS.with(|objects| objects.borrow().clone().expect("storage backend is not set"))
+}
+
+/* Read the index and the tree, everything read in one go belongs to the same epoch */
+fn with_catalog<T>(read: impl FnOnce(&FileIndex, &MerkleTree) -> T) -> T {
+    CATALOG.with(|catalog| {
+        let catalog = catalog.borrow();
+        let catalog = catalog.read().unwrap();
+        read(&catalog.index, &catalog.tree)
+    })
+}
+
+/* Hash of the contents a filename points at */
+fn file_hash(filename: &str) -> Option<String> {
+    with_catalog(|index, _| index.get(filename).cloned())
+}
+
+/* Hand a mutation to the store worker, the reply comes back through the events stream */
+fn submit(job: StoreJob) {
+    JOBS.with(|jobs| {
+        if let Some(jobs) = jobs.borrow().as_ref() {
+            let _ = jobs.send(job);
+        }
+    });
+}
+
+/* Secure session the address is on, authentication only holds on the session it happened on */
+fn secure_session(addr: &SocketAddr) -> Option<u64> {
+    SECURE_SESSIONS.with(|sessions| sessions.borrow().get(addr))
+}
+
+/* Identity an address authenticated as, None when it didn't, isn't on a secure session or authentication is disabled */
+fn identity(addr: &SocketAddr) -> Option<String> {
+    let secure_session = secure_session(addr)?;
+    AUTH.with(|auth| auth.borrow_mut().identity(addr, secure_session))
+}
+
+/**Check the access policy before touching storage, the denial is returned as the reply to send */
+fn authorize(addr: &SocketAddr, permission: Permission, filename: &str) -> Result<(), Message> {
+    let acl = match ACL.with(|acl| acl.borrow().clone()) {
+        Some(acl) => acl,
+        None => return Ok(()),
+    };
+
+    let identity = identity(addr);
+    if acl.allows(identity.as_deref(), permission, filename) {
+        Ok(())
+    } else {
+        println!("Denied {:?} on {} to {:?}", permission, filename, identity.unwrap_or_else(|| format!("{:?}", addr)));
+        Err(Message::Error { filename: filename.to_string(), error: ProtocolError::AccessDenied })
+    }
+}
+
+/* Save a file that was sent in one piece */
+fn upload_file(filename: String, data: Vec<u8>, preconditions: Box<Preconditions>, addr: SocketAddr) -> Option<Message> {
+    if let Err(denied) = authorize(&addr, Permission::Write, &filename) {
+        return Some(denied);
+    }
+    submit(StoreJob::Save { filename, data: Contents::Data(data), expected_hash: None, session_id: None, preconditions, identity: identity(&addr), addr, shard: shard() });
+    None
+}
+
+fn delete_file(filename: String, preconditions: Box<Preconditions>, addr: SocketAddr) -> Option<Message> {
+    if let Err(denied) = authorize(&addr, Permission::Delete, &filename) {
+        return Some(denied);
+    }
+    submit(StoreJob::Delete { filename, preconditions, addr, shard: shard() });
+    None
+}
+
+/* Moving a file reads and deletes the old name and writes the new one */
+fn rename_file(from: String, to: String, if_match: Option<String>, if_none_match: Option<String>, addr: SocketAddr) -> Option<Message> {
+    let allowed = authorize(&addr, Permission::Read, &from)
+        .and_then(|_| authorize(&addr, Permission::Delete, &from))
+        .and_then(|_| authorize(&addr, Permission::Write, &to));
+    if let Err(denied) = allowed {
+        return Some(denied);
+    }
+    let replace = authorize(&addr, Permission::Delete, &to).is_ok();
+    submit(StoreJob::Rename { from, to, if_match, if_none_match, replace, addr, shard: shard() });
+    None
+}
+
+fn copy_file(from: String, to: String, addr: SocketAddr) -> Option<Message> {
+    if let Err(denied) = authorize(&addr, Permission::Read, &from).and_then(|_| authorize(&addr, Permission::Write, &to)) {
+        return Some(denied);
+    }
+    let replace = authorize(&addr, Permission::Delete, &to).is_ok();
+    submit(StoreJob::Copy { from, to, identity: identity(&addr), replace, addr, shard: shard() });
+    None
+}
+
+/**Run a read on the blocking thread pool and feed the reply back into the flow, the lookups in the index
+ * and the tree happen here so the reply matches the state the request was received in */
+fn spawn_read<F>(addr: SocketAddr, read: F)
+where
+    F: FnOnce(ObjectStore) -> Message + Send + 'static,
+{
+    let objects = objects();
+    let events = EVENTS.with(|events| events.borrow().clone());
+    tokio::task::spawn_blocking(move || {
+        let reply = read(objects);
+        if let Some(events) = events {
+            let _ = events.send(StoreEvent::Reply(reply, addr));
+        }
+    });
+}
+
+fn shard() -> usize {
+    SHARD.with(|shard| *shard.borrow())
+}
+
+/* Whether a request gets through the rate limit of its identity, everything else is dropped before it's even logged.
+ * Addresses were limited at the socket already */
+fn rate_limit(addr: &SocketAddr) -> bool {
+    let identity = identity(addr);
+    let validated = VALIDATOR.with(|validator| validator.borrow().as_ref().is_some_and(|validator| validator.is_validated(addr)));
+    RATE.with(|rate| rate.borrow().as_ref().map_or(true, |rate| rate.lock().unwrap().allow_request(*addr, identity.as_deref(), validated)))
+}
+
+/**Keep the session of an address alive and count what it sent towards what may be sent back to it. Only addresses
+ * that echoed a retry token or answered a challenge get a session, both need our reply to have reached them */
+fn note_request(addr: SocketAddr) {
+    let validated = VALIDATOR.with(|validator| {
+        let mut validator = validator.borrow_mut();
+        let validator = validator.as_mut()?;
+        validator.received(addr);
+        Some(validator.is_validated(&addr))
+    });
+    let validated = validated.unwrap_or(false) || identity(&addr).is_some();
+    SESSIONS.with(|sessions| sessions.borrow_mut().touch(addr, validated));
+}
+
+/**Drop the sessions of clients that went quiet, together with the partial uploads nobody else is sending, and the
+ * uploads that haven't seen a chunk for a while */
+fn expire_sessions() {
+    expire_uploads();
+    let orphaned = SESSIONS.with(|sessions| sessions.borrow_mut().expire());
+    UPLOADS.with(|uploads| {
+        let mut uploads = uploads.borrow_mut();
+        for session_id in orphaned {
+            if let Some(session) = uploads.remove(&session_id) {
+                println!("Freed upload session {} for {}, its client is gone", session_id, session.filename);
+                release_upload(&session_id);
+            }
+        }
+    });
+}
+
+/**Last check before a reply goes out, replies to addresses that aren't validated yet are kept small */
+fn limit_reply(reply: Message, addr: SocketAddr) -> Option<(Message, SocketAddr)> {
+    let reply = VALIDATOR.with(|validator| validator.borrow_mut().as_mut().expect("address validator is not set").limit(reply, addr));
+    if reply.is_none() {
+        println!("Dropped reply to {:?}, the address isn't validated", addr);
+    }
+    reply.map(|reply| (reply, addr))
+}
+
+/**Check a token echoed by a client, a bad or expired one gets a fresh Retry */
+fn validate_address(token: &[u8], addr: SocketAddr) -> Message {
+    VALIDATOR.with(|validator| {
+        let mut validator = validator.borrow_mut();
+        let validator = validator.as_mut().expect("address validator is not set");
+        if validator.validate(addr, token) {
+            println!("Validated address {:?}", addr);
+            Message::AddressValidated
+        } else {
+            Message::Retry { token: validator.issue_token(&addr) }
+        }
+    })
+}
+
+/**Replies of the store and the reads are passed on to the client, an upload that was saved ends its session */
+fn apply_event(event: StoreEvent) -> Option<(Message, SocketAddr)> {
+    match event {
+        StoreEvent::Reply(reply, addr) => Some((reply, addr)),
+        StoreEvent::Uploaded { session_id, saved, reply, addr } => {
+            upload_saved(&session_id, saved);
+            Some((reply, addr))
+        }
+    }
+}
+
+/**Get the merkle proof of a file or directory from the current tree, converted from OSString to Vec<Vec<u8>> */
+fn get_proof(tree: &MerkleTree, filename: &str) -> Option<Vec<Vec<u8>>> {
+    let p = tree.get_proof(filename);
+
+    p.map(|p|
+        p.into_iter()
+        .map(|s| s.into_string().unwrap_or_default().into_bytes())
+        .collect::<Vec<Vec<u8>>>()
+    )
+}
+
+/**Read file from the object store, get merkle proof and return Message::File */
+fn read_file(filename: String, addr: SocketAddr) -> Option<Message> {
+    if let Err(denied) = authorize(&addr, Permission::Read, &filename) {
+        return Some(denied);
+    }
+    /* Generate merkle proof */
+    let (hash, p) = with_catalog(|index, tree| (index.get(&filename).cloned(), get_proof(tree, &filename)));
+
+    spawn_read(addr, move |objects| {
+        match (hash.map(|hash| objects.get(&hash)), p) {
+            (Some(Ok(data)), Some(p)) => {
+                println!("Read file {}", filename);
+                Message::File { filename, data, merkle_proof: p }
+            }
+            _ => Message::FileNotFound { filename },
+        }
+    });
+    None
+}
+
+/* Versions of a file that are kept, newest first */
+fn list_versions(filename: String, addr: SocketAddr) -> Message {
+    if let Err(denied) = authorize(&addr, Permission::Read, &filename) {
+        return denied;
+    }
+    let versions = with_catalog(|index, _| index.versions(&filename).iter().rev().map(|version| version.info.clone()).collect());
+    Message::Versions { filename, versions }
+}
+
+/* Restoring changes the contents of the file, so it takes the same permission as uploading. Restoring a version
+ * that deleted the file deletes it, which the store only does for clients that may delete it */
+fn restore(filename: String, version: u64, addr: SocketAddr) -> Option<Message> {
+    if let Err(denied) = authorize(&addr, Permission::Write, &filename) {
+        return Some(denied);
+    }
+    let delete = authorize(&addr, Permission::Delete, &filename).is_ok();
+    submit(StoreJob::Restore { filename, version, identity: identity(&addr), delete, addr, shard: shard() });
+    None
+}
+
+/**Entries of a directory in the order of the tree: every file below it when recursive, otherwise the files and
+ * subdirectories right in it. What the client may not read is left out, a hidden entry with the node of the file or
+ * of the topmost directory with nothing readable in it takes its place, so the listing still makes up the node */
+fn directory_entries(index: &FileIndex, tree: &MerkleTree, path: &str, recursive: bool, readable: &dyn Fn(&str) -> bool) -> Vec<DirEntry> {
+    let hidden = |node: String| DirEntry { path: path.to_string(), is_dir: false, hash: node, size: 0, hidden: true };
+
+    let mut entries = Vec::new();
+    for child in tree.children(path) {
+        let node = tree.node(&child).map(|node| node.to_string_lossy().to_string()).unwrap_or_default();
+        if !tree.dirs.contains_key(&child) {
+            match index.get(&child).filter(|_| readable(&child)) {
+                Some(hash) => {
+                    let size = index.meta(&child).map_or(0, |meta| meta.size);
+                    entries.push(DirEntry { path: child, is_dir: false, hash: hash.clone(), size, hidden: false });
+                }
+                None => entries.push(hidden(node)),
+            }
+            continue;
+        }
+
+        /* Everything below a directory is next to each other in the index */
+        let prefix = format!("{}/", child);
+        let below = || index.files.range(prefix.clone()..).take_while(|(file, _)| file.starts_with(&prefix)).filter(|(file, _)| readable(file));
+        if below().next().is_none() {
+            entries.push(hidden(node));
+        } else if recursive {
+            entries.extend(directory_entries(index, tree, &child, recursive, readable));
+        } else {
+            let size = below().map(|(file, _)| index.meta(file).map_or(0, |meta| meta.size)).sum();
+            entries.push(DirEntry { path: child, is_dir: true, hash: node, size, hidden: false });
+        }
+    }
+    entries
+}
+
+/**List a directory with its node and proof, so the client can check the listing against its trusted root.
+ * Listings are sent in pages of about the size of a chunk, starting at entry `offset`. A page always has at least
+ * one entry, so the client gets through the listing whatever the entries look like. The client needn't be allowed
+ * to read the directory itself, something readable below it is enough, hidden entries stand in for the rest */
+fn list_directory(path: String, recursive: bool, offset: u32, addr: SocketAddr) -> Message {
+    let acl = ACL.with(|acl| acl.borrow().clone());
+    let identity = identity(&addr);
+    let readable = |file: &str| acl.as_ref().map_or(true, |acl| acl.allows(identity.as_deref(), Permission::Read, file));
+    let listing = with_catalog(|index, tree| {
+        let node = tree.dirs.get(&path).map(|node| node.to_string_lossy().to_string())?;
+        let merkle_proof = get_proof(tree, &path)?;
+        Some((node, merkle_proof, directory_entries(index, tree, &path, recursive, &readable)))
+    });
+
+    /* Only an empty store has a directory without entries, there is nothing in it to keep from anyone */
+    let visible = listing.as_ref().is_some_and(|(_, _, entries)| entries.is_empty() || entries.iter().any(|entry| !entry.hidden));
+    if !visible {
+        if let Err(denied) = authorize(&addr, Permission::Read, &path) {
+            return denied;
+        }
+    }
+    let (node, merkle_proof, entries) = match listing {
+        Some(listing) => listing,
+        None => return Message::FileNotFound { filename: path },
+    };
+    let total = entries.len();
+
+    /* The directory, its node and its proof go in every page */
+    let mut size = path.len() + node.len() + merkle_proof.iter().map(|step| step.len() + 8).sum::<usize>() + 32;
+    let mut page = Vec::new();
+    for entry in entries.into_iter().skip(offset as usize) {
+        size += entry.path.len() + entry.hash.len() + 24;
+        if size > CHUNK_SIZE && !page.is_empty() {
+            break;
+        }
+        page.push(entry);
+    }
+    let end = offset as usize + page.len();
+    let next = if end < total { Some(end as u32) } else { None };
+
+    Message::Listing(Box::new(Listing { path, recursive, offset, entries: page, node, merkle_proof, next }))
+}
+
+/**Return hash, size and merkle proof of a file so the client can download it in chunks. A kept version comes with
+ * the root of its epoch and the proof against it that were recorded when the version was made, so it can be
+ * verified long after the tree moved on */
+fn file_info(filename: String, version: Option<u64>, addr: SocketAddr) -> Option<Message> {
+    if let Err(denied) = authorize(&addr, Permission::Read, &filename) {
+        return Some(denied);
+    }
+    SESSIONS.with(|sessions| sessions.borrow_mut().start_download(addr, &filename));
+    let (hash, merkle_proof, root) = match version {
+        Some(version) => match with_catalog(|index, _| index.version(&filename, version).cloned()) {
+            Some(kept) => (kept.info.hash, kept.merkle_proof, Some(kept.root)),
+            None => (None, Vec::new(), None),
+        },
+        None => with_catalog(|index, tree| (index.get(&filename).cloned(), get_proof(tree, &filename).unwrap_or_default(), None)),
+    };
+
+    spawn_read(addr, move |objects| {
+        let stat = match hash {
+            Some(hash) => objects.stat(&hash).map(|stat| stat.map(|stat| (hash, stat))),
+            None => Ok(None),
+        };
+        match stat {
+            Ok(Some((hash, stat))) => Message::FileInfo { filename, version, hash, size: stat.size, merkle_proof, root },
+            Ok(None) => Message::FileNotFound { filename },
+            Err(e) => Message::Error { filename, error: ProtocolError::Io(e.to_string()) },
+        }
+    });
+    None
+}
+
+/**Read a single chunk of a file, or of one of its kept versions, from the object store */
+fn read_chunk(filename: String, version: Option<u64>, index: u32, addr: SocketAddr) -> Option<Message> {
+    if let Err(denied) = authorize(&addr, Permission::Read, &filename) {
+        return Some(denied);
+    }
+    let hash = match version {
+        Some(version) => with_catalog(|index, _| index.version(&filename, version).and_then(|kept| kept.info.hash.clone())),
+        None => file_hash(&filename),
+    };
+    SESSIONS.with(|sessions| sessions.borrow_mut().start_download(addr, &filename));
+
+    spawn_read(addr, move |objects| {
+        match hash.map(|hash| objects.get_range(&hash, index as u64 * CHUNK_SIZE as u64, CHUNK_SIZE as u64)) {
+            Some(Ok(data)) => Message::FileChunk { filename, version, index, data },
+            _ => Message::FileNotFound { filename },
+        }
+    });
+    None
+}
+
+/* Whether an address may make file requests, always the case when authentication is disabled */
+fn is_authenticated(addr: &SocketAddr) -> bool {
+    KEYS.with(|keys| keys.borrow().is_none()) || identity(addr).is_some()
+}
+
+/**Start the handshake with a challenge. Unknown identities get one too, so they can't be told apart from known ones.
+ * Plaintext requests carry nothing but their source address to tie them to the handshake, so clients have to be
+ * on the encrypted transport to authenticate */
+fn hello(identity: &str, addr: SocketAddr) -> Message {
+    if KEYS.with(|keys| keys.borrow().is_none()) {
+        return Message::Authenticated { identity: None };
+    }
+    let secure_session = match secure_session(&addr) {
+        Some(secure_session) => secure_session,
+        None => {
+            println!("Refused to authenticate {:?} without an encrypted transport", addr);
+            return Message::Error { filename: String::new(), error: ProtocolError::AuthFailed };
+        }
+    };
+
+    let nonce = AUTH.with(|auth| auth.borrow_mut().challenge(addr, secure_session, identity));
+    Message::Challenge { nonce }
+}
+
+/**Check the answer to a challenge, the address is authenticated as the identity of the challenge on success */
+fn challenge_response(mac: &[u8], addr: SocketAddr) -> Message {
+    let identity = KEYS.with(|keys| {
+        let keys = keys.borrow();
+        let (keys, secure_session) = (keys.as_ref()?, secure_session(&addr)?);
+        AUTH.with(|auth| auth.borrow_mut().respond(keys, addr, secure_session, mac))
+    });
+
+    match identity {
+        Some(identity) => {
+            println!("{:?} authenticated as {}", addr, identity);
+            Message::Authenticated { identity: Some(identity) }
+        }
+        None => {
+            println!("Authentication of {:?} failed", addr);
+            Message::Error { filename: String::new(), error: ProtocolError::AuthFailed }
+        }
+    }
+}
+
+/* Reject a request of an unauthenticated client, chunks have no filename to report and are dropped */
+fn reject_unauthenticated(msg: Message, addr: SocketAddr) -> Option<Message> {
+    println!("Rejected {:?} from unauthenticated {:?}", msg, addr);
+    match msg {
+        Message::FileUpload { filename, .. }
+        | Message::FileRequest { filename, .. }
+        | Message::DeleteFileRequest { filename, .. }
+        | Message::ListVersions { filename }
+        | Message::Restore { filename, .. }
+        | Message::ListRequest { path: filename, .. }
+        | Message::RenameRequest { from: filename, .. }
+        | Message::CopyRequest { from: filename, .. }
+        | Message::UploadStart { filename, .. }
+        | Message::FileInfoRequest { filename, .. }
+        | Message::FileChunkRequest { filename, .. } => Some(Message::Error { filename, error: ProtocolError::Unauthenticated }),
+        Message::UsageRequest => Some(Message::Error { filename: String::new(), error: ProtocolError::Unauthenticated }),
+        _ => None,
+    }
+}
+
+/* Drop upload sessions that haven't seen a chunk for a while */
+fn expire_uploads() {
+    let now = Utc::now();
+    UPLOADS.with(|uploads| uploads.borrow_mut().retain(|session_id, session| {
+        if session.is_expired(now) {
+            println!("Upload session {} for {} expired", session_id, session.filename);
+            SESSIONS.with(|sessions| sessions.borrow_mut().end_upload(session_id));
+            release_upload(session_id);
+        }
+        !session.is_expired(now)
+    }));
+}
+
+/* The space an upload session held in the quotas is free again */
+fn release_upload(session_id: &str) {
+    LIMITS.with(|limits| limits.borrow().release(session_id));
+}
+
+/**Start a chunked upload or pick up an existing session for the same file and contents */
+fn start_upload(filename: &str, hash: &str, size: u64, preconditions: Box<Preconditions>, addr: SocketAddr) -> Option<Message> {
+    if let Err(denied) = authorize(&addr, Permission::Write, filename).and_then(|_| require_validated(&addr)) {
+        return Some(denied);
+    }
+    if !is_valid_path(filename) || with_catalog(|index, _| index.conflicts(filename)) {
+        println!("Upload of {} doesn't fit in the tree", filename);
+        return Some(Message::Error { filename: filename.to_string(), error: ProtocolError::InvalidPath(filename.to_string()) });
+    }
+    /* Preconditions are checked up front as well, the store worker checks them again when saving. Only an upload
+     * of this session that was already saved is a retry whose ack got lost */
+    let session_id = upload_session_id(identity(&addr).as_deref(), filename, hash);
+    let (root, current) = with_catalog(|index, tree| (tree.root.as_ref().map(|root| root.to_string_lossy().to_string()), index.get(filename).cloned()));
+    if let Err(failed) = preconditions.check(current.as_deref(), root.as_deref()) {
+        if CATALOG.with(|catalog| catalog.borrow().read().unwrap().is_saved_upload(&session_id, filename, hash)) {
+            return Some(Message::FileAck { filename: filename.to_string(), hash: hash.to_string(), root: root.unwrap_or_default() });
+        }
+        println!("Precondition for {} failed: {}", filename, failed);
+        return Some(Message::Error { filename: filename.to_string(), error: ProtocolError::PreconditionFailed(failed) });
+    }
+    expire_uploads();
+
+    /* Turn down uploads that won't fit before buffering any of their chunks. The space is held until the session is
+     * over, so uploads in progress count towards the quotas as well, and the store worker checks again when saving */
+    let fits = with_catalog(|index, _| LIMITS.with(|limits| limits.borrow().reserve(index, &session_id, filename, size, identity(&addr).as_deref())));
+    if let Err(limit) = fits {
+        println!("Upload of {} is over the {}", filename, limit);
+        return Some(Message::Error { filename: filename.to_string(), error: ProtocolError::QuotaExceeded(limit) });
+    }
+    /* A new session has to fit the caps on uploads in progress of the client and of the server */
+    let opened = UPLOADS.with(|uploads| {
+        let mut uploads = uploads.borrow_mut();
+        if uploads.contains_key(&session_id) {
+            return Ok(());
+        }
+        let identity = identity(&addr);
+        check_client_uploads(uploads.values(), addr, identity.as_deref(), size)?;
+        let spool = Path::new(STATE_DIR).join(SPOOL_DIR).join(format!("{}-{}", shard(), session_id));
+        let mut session = UploadSession::new(filename, hash, size, identity, addr, spool)?;
+        session.preconditions = preconditions;
+        uploads.insert(session_id.clone(), session);
+        Ok(())
+    });
+    if let Err(limit) = opened {
+        release_upload(&session_id);
+        println!("Upload of {} is over the {}", filename, limit);
+        return Some(Message::Error { filename: filename.to_string(), error: ProtocolError::QuotaExceeded(limit) });
+    }
+    let status = UPLOADS.with(|uploads| uploads.borrow_mut().get_mut(&session_id).map(|session| {
+        check_upload_sender(session, &addr)?;
+        session.touch();
+        Ok((session.is_complete(), session.missing()))
+    }));
+    let (complete, missing) = match status {
+        Some(Ok(status)) => status,
+        Some(Err(denied)) => return Some(denied),
+        None => return Some(Message::UploadSessionNotFound { session_id }),
+    };
+    SESSIONS.with(|sessions| sessions.borrow_mut().start_upload(addr, &session_id));
+
+    /* Nothing to wait for with an empty file */
+    if complete {
+        return finish_upload(&session_id, addr);
+    }
+
+    Some(Message::UploadStatus { session_id, missing })
+}
+
+/**Uploads are only taken from addresses that proved they receive our replies, by echoing a retry token or answering
+ * a challenge, so spoofed requests can't open sessions or fill them. Anyone else gets a Retry to validate first */
+fn require_validated(addr: &SocketAddr) -> Result<(), Message> {
+    let authenticated = identity(addr).is_some();
+    VALIDATOR.with(|validator| {
+        let validator = validator.borrow();
+        let validator = validator.as_ref().expect("address validator is not set");
+        if authenticated || validator.is_validated(addr) {
+            return Ok(());
+        }
+        println!("Refused upload from {:?}, the address isn't validated", addr);
+        Err(Message::Retry { token: validator.issue_token(addr) })
+    })
+}
+
+/* Only the identity that started an upload may add to it, and only as long as the policy still lets it write the file */
+fn check_upload_sender(session: &UploadSession, addr: &SocketAddr) -> Result<(), Message> {
+    if identity(addr) != session.identity {
+        println!("Denied upload session for {} to {:?}, it belongs to {:?}", session.filename, addr, session.identity);
+        return Err(Message::Error { filename: session.filename.clone(), error: ProtocolError::AccessDenied });
+    }
+    authorize(addr, Permission::Write, &session.filename)
+}
+
+/**Store a chunk of an upload, once all chunks are in the file gets saved and acknowledged */
+fn upload_chunk(session_id: &str, index: u32, data: &[u8], addr: SocketAddr) -> Option<Message> {
+    if let Err(retry) = require_validated(&addr) {
+        return Some(retry);
+    }
+    let complete = UPLOADS.with(|uploads| {
+        uploads.borrow_mut().get_mut(session_id).map(|session| {
+            check_upload_sender(session, &addr)?;
+            match session.put_chunk(index, data) {
+                Ok(true) => {}
+                Ok(false) => println!("Dropped invalid chunk {} for upload session {}", index, session_id),
+                Err(e) => println!("Unable to spool chunk {} for upload session {}: {}", index, session_id, e),
+            }
+            Ok(session.is_complete())
+        })
+    });
+
+    match complete {
+        Some(Ok(true)) => finish_upload(session_id, addr),
+        Some(Ok(false)) => None,
+        Some(Err(denied)) => Some(denied),
+        None => Some(Message::UploadSessionNotFound { session_id: session_id.to_string() }),
+    }
+}
+
+/* Hand the assembled data to the store worker, which checks it against the announced hash before saving it.
+ * The policy is checked again since it may have changed while the chunks were coming in, a sender that's
+ * turned down leaves the session as it is. The session stays until the store worker is done, see `upload_saved` */
+fn finish_upload(session_id: &str, addr: SocketAddr) -> Option<Message> {
+    let allowed = UPLOADS.with(|uploads| uploads.borrow().get(session_id).map(|session| check_upload_sender(session, &addr)))?;
+    if let Err(denied) = allowed {
+        return Some(denied);
+    }
+
+    let job = UPLOADS.with(|uploads| uploads.borrow_mut().get_mut(session_id).map(|session| {
+        let filename = session.filename.clone();
+        session.take_spool().map(|spool| StoreJob::Save {
+            data: Contents::Spooled(spool),
+            filename: filename.clone(),
+            expected_hash: Some(session.hash.clone()),
+            session_id: Some(session_id.to_string()),
+            preconditions: session.preconditions.clone(),
+            identity: session.identity.clone(),
+            addr,
+            shard: shard(),
+        }).map_err(|e| {
+            println!("Unable to spool upload session {} for {}: {}", session_id, filename, e);
+            Message::Error { filename, error: ProtocolError::Io(e.to_string()) }
+        })
+    }))?;
+    let job = match job {
+        Ok(job) => job,
+        Err(failed) => return Some(failed),
+    };
+    /* The store worker checks the quotas again when saving, without the space of the upload itself */
+    release_upload(session_id);
+    submit(job);
+    None
+}
+
+/**An upload whose data didn't match its hash starts over with the same session, the client sends its chunks again.
+ * Otherwise the session is over, whether the file was saved or turned down */
+fn upload_saved(session_id: &str, saved: bool) {
+    UPLOADS.with(|uploads| {
+        let mut uploads = uploads.borrow_mut();
+        if !saved {
+            if let Some(session) = uploads.get_mut(session_id) {
+                println!("Upload session {} for {} starts over", session_id, session.filename);
+                session.reset();
+            }
+            return;
+        }
+        uploads.remove(session_id);
+        SESSIONS.with(|sessions| sessions.borrow_mut().end_upload(session_id));
+        /* Released again, in case the client joined the session while it was being saved */
+        release_upload(session_id);
+    });
+}
+
+/**Report which chunks of an upload the server is still missing */
+fn resume_upload(session_id: &str, addr: SocketAddr) -> Message {
+    expire_uploads();
+
+    UPLOADS.with(|uploads| {
+        match uploads.borrow_mut().get_mut(session_id) {
+            Some(session) => {
+                if let Err(denied) = check_upload_sender(session, &addr) {
+                    return denied;
+                }
+                session.touch();
+                Message::UploadStatus { session_id: session_id.to_string(), missing: session.missing() }
+            }
+            None => Message::UploadSessionNotFound { session_id: session_id.to_string() },
+        }
+    })
+}
+
+/**Report the storage used overall and by the identity of the client, together with the limits */
+fn usage(addr: &SocketAddr) -> Message {
+    let identity = identity(addr);
+    let limits = LIMITS.with(|limits| limits.borrow().clone());
+
+    with_catalog(|index, _| {
+        Message::UsageReport(Box::new(UsageReport {
+            total: index.usage(None),
+            total_quota: limits.total,
+            usage: identity.as_deref().map(|identity| index.usage(Some(identity))),
+            identity,
+            quota: limits.per_identity,
+        }))
+    })
+}
+
+/* Everything the server is started with besides its address and storage */
+#[derive(Clone)]
+pub(crate) struct ServerConfig {
+    pub workers: usize,
+    /* Pre-shared keys of the clients, authentication is disabled without them */
+    pub keys: Option<Arc<KeyStore>>,
+    pub acl: Option<Arc<Acl>>,
+    pub security: Security,
+    pub compression: Codec,
+    pub limits: Limits,
+    pub rate_limits: Option<RateLimits>,
+    pub session_timeout: Duration,
+    /* Versions kept per filename */
+    pub versions: usize,
+    /* Encrypted backend whose blobs get re-wrapped with the current at-rest key, by the store worker */
+    pub rotate_at_rest_keys: Option<Arc<EncryptedStorage>>,
+}
+
+/**Recover the store, then start `workers` server workers that share the UDP port. The kernel assigns
+ * each client to one of them, and the store worker keeps them all on the same tree */
+pub(crate) async fn run_server(addr: SocketAddr, storage: Arc<dyn Storage>, config: ServerConfig) -> std::io::Result<()> {
+    let workers = config.workers;
+    match config.keys.as_ref() {
+        Some(keys) => println!("Authentication enabled for {} identities", keys.len()),
+        None => println!("Authentication is disabled, anyone can access files"),
+    }
+
+    /* Create server data folder and bring it in line with the write-ahead log before serving anything */
+    let _ = tokio::fs::create_dir_all(DATA_DIR).await;
+    let objects = ObjectStore::new(storage);
+    let store = Store::open(Path::new(STATE_DIR), Path::new(DATA_DIR), objects.clone(), config.limits.clone(), config.versions).await?;
+    clear_spool(&Path::new(STATE_DIR).join(SPOOL_DIR))?;
+    let catalog = store.shared();
+
+    /* Bind all sockets up front, so a port picked by the OS is the same for every worker */
+    let first = bind_udp_reuseport(addr).expect("Unable to bind server socket");
+    let addr = first.local_addr().expect("Unable to get server address");
+    let mut sockets = vec![first];
+    for _ in 1..workers {
+        sockets.push(bind_udp_reuseport(addr).expect("Unable to bind server socket"));
+    }
+    println!("Listening on {:?} with {} workers", addr, workers);
+
+    /* Retry tokens are only valid for this run of the server */
+    let token_secret = Arc::new(rand::random::<[u8; 32]>());
+    let (events, results): (Vec<_>, Vec<_>) = (0..workers).map(|_| hydroflow::util::unbounded_channel::<StoreEvent>()).unzip();
+    let jobs = spawn_store_worker(store, events.clone());
+    if let Some(storage) = config.rotate_at_rest_keys.clone() {
+        let _ = jobs.send(StoreJob::Rotate { storage });
+    }
+
+    let mut shards = sockets.into_iter().zip(events).zip(results).enumerate()
+        .map(|(shard, ((socket, events), results))| {
+            let (objects, jobs, catalog, config) = (objects.clone(), jobs.clone(), catalog.clone(), config.clone());
+            let token_secret = token_secret.clone();
+            move || async move {
+                let (outbound, inbound) = udp_bytes(socket).expect("Unable to set up server socket");
+                let raw = RawBytes::default();
+                let secure_sessions = SessionIds::default();
+                /* Floods are dropped at the socket, before the secure and compression layers get to them */
+                let rate = config.rate_limits.map(|limits| Arc::new(Mutex::new(RateLimiter::new(limits))));
+                let inbound = count_received(limit_received(inbound, rate.clone()), raw.clone());
+                let (outbound, inbound) = secure_udp(outbound, inbound, config.security, secure_sessions.clone());
+                let (outbound, inbound) = compress_udp(outbound, inbound, config.compression);
+                SHARD.with(|s| s.replace(shard));
+                SECURE_SESSIONS.with(|s| s.replace(secure_sessions));
+                OBJECTS.with(|o| o.replace(Some(objects)));
+                JOBS.with(|j| j.replace(Some(jobs)));
+                EVENTS.with(|e| e.replace(Some(events)));
+                CATALOG.with(|c| c.replace(catalog));
+                KEYS.with(|k| k.replace(config.keys));
+                ACL.with(|a| a.replace(config.acl));
+                LIMITS.with(|l| l.replace(config.limits));
+                RATE.with(|r| r.replace(rate));
+                VALIDATOR.with(|v| v.replace(Some(AddressValidator::new(token_secret, raw))));
+                SESSIONS.with(|s| s.replace(SessionTable::new(config.session_timeout)));
+                run_shard(outbound, inbound, results).await;
+            }
+        })
+        .collect::<Vec<_>>();
+
+    /* The first worker runs right here, the others get a thread and a runtime of their own */
+    let first = shards.remove(0);
+    for (i, shard) in shards.into_iter().enumerate() {
+        std::thread::Builder::new()
+            .name(format!("server-worker-{}", i + 1))
+            .spawn(move || {
+                let runtime = tokio::runtime::Builder::new_current_thread().enable_all().build().expect("Unable to start runtime");
+                runtime.block_on(shard());
+            })
+            .expect("Unable to start server worker");
+    }
+    first().await;
+    Ok(())
+}
+
+/* Flow of a single server worker */
+async fn run_shard(outbound: SecureSink, inbound: SecureStream, results: UnboundedReceiverStream<StoreEvent>) {
+    println!("Server worker {} live!", shard());
+
+    let mut flow: Hydroflow = hydroflow_syntax! {
+        // Define shared inbound and outbound channels
+        // Requests over the rate limit of their identity are dropped right away
+        inbound_chan = source_stream_serde(inbound) -> map(|udp_msg| udp_msg.unwrap())
+            -> filter(|(_, addr): &(Message, SocketAddr)| rate_limit(addr))
+            -> inspect(|(_, addr): &(Message, SocketAddr)| note_request(*addr)) -> tee();
+        // Replies to addresses that aren't validated can't be much larger than their requests
+        outbound_chan = union() -> filter_map(|(msg, addr)| limit_reply(msg, addr)) -> dest_sink_serde(outbound);
+
+        // Print all messages for debugging purposes
+        inbound_chan[1]
+            -> for_each(|(m, a): (Message, SocketAddr)| println!("{}: Got {:?} from {:?}", Utc::now(), m, a));
+
+        // Demux and destructure the inbound messages into separate streams
+        inbound_demuxed = inbound_chan[0]
+            ->  demux(|(msg, addr), var_args!(file_upload_ch, file_request_ch, del_file_request_ch, upload_start_ch, upload_chunk_ch, resume_upload_ch, file_info_ch, file_chunk_ch, heartbeat_ch, hello_ch, auth_response_ch, usage_ch, validate_ch, list_versions_ch, restore_ch, rename_ch, copy_ch, list_ch, unauth_ch, errs_ch)|
+                    match msg {
+                        msg if msg.requires_auth() && !is_authenticated(&addr) => unauth_ch.give((msg, addr)),
+                        Message::FileUpload {filename, data, preconditions} => file_upload_ch.give((filename, data, preconditions, addr)),
+                        Message::FileRequest {filename} => file_request_ch.give((filename, addr)),
+                        Message::DeleteFileRequest {filename, preconditions} => del_file_request_ch.give((filename, preconditions, addr)),
+                        Message::UploadStart {filename, hash, size, preconditions} => upload_start_ch.give((filename, hash, size, preconditions, addr)),
+                        Message::UploadChunk {session_id, index, data} => upload_chunk_ch.give((session_id, index, data, addr)),
+                        Message::ResumeUpload {session_id} => resume_upload_ch.give((session_id, addr)),
+                        Message::FileInfoRequest {filename, version} => file_info_ch.give((filename, version, addr)),
+                        Message::FileChunkRequest {filename, version, index} => file_chunk_ch.give((filename, version, index, addr)),
+                        Message::Heartbeat => heartbeat_ch.give(addr),
+                        Message::Hello {identity} => hello_ch.give((identity, addr)),
+                        Message::ChallengeResponse {mac} => auth_response_ch.give((mac, addr)),
+                        Message::UsageRequest => usage_ch.give(addr),
+                        Message::ValidateAddress {token} => validate_ch.give((token, addr)),
+                        Message::ListVersions {filename} => list_versions_ch.give((filename, addr)),
+                        Message::Restore {filename, version} => restore_ch.give((filename, version, addr)),
+                        Message::RenameRequest {from, to, if_match, if_none_match} => rename_ch.give((from, to, if_match, if_none_match, addr)),
+                        Message::CopyRequest {from, to} => copy_ch.give((from, to, addr)),
+                        Message::ListRequest {path, recursive, offset} => list_ch.give((path, recursive, offset, addr)),
+                        _ => errs_ch.give((msg, addr)),
+                    }
+                );
+
+        // Mutations go to the store worker, reads to the blocking thread pool. Neither blocks the flow,
+        // the replies come back in through the events stream
+        // Each of them checks the access policy first, denials are replied to right away
+        inbound_demuxed[file_upload_ch]
+            -> filter_map(|(filename, data, preconditions, addr)| upload_file(filename, data, preconditions, addr).map(|m| (m, addr))) -> [8]outbound_chan;
+
+        inbound_demuxed[del_file_request_ch]
+            -> filter_map(|(filename, preconditions, addr)| delete_file(filename, preconditions, addr).map(|m| (m, addr))) -> [9]outbound_chan;
+
+        inbound_demuxed[file_request_ch]
+            -> filter_map(|(filename, addr)| read_file(filename, addr).map(|m| (m, addr))) -> [10]outbound_chan;
+
+        source_stream(results) -> filter_map(apply_event) -> [0]outbound_chan;
+
+        // Resumable uploads, chunks are only answered once the upload is complete
+        inbound_demuxed[upload_start_ch]
+            -> filter_map(|(filename, hash, size, preconditions, addr)| start_upload(&filename, &hash, size, preconditions, addr).map(|m| (m, addr)))
+            -> [1]outbound_chan;
+        inbound_demuxed[upload_chunk_ch]
+            -> filter_map(|(session_id, index, data, addr)| upload_chunk(&session_id, index, data.as_slice(), addr).map(|m| (m, addr)))
+            -> [3]outbound_chan;
+        inbound_demuxed[resume_upload_ch] -> map(|(session_id, addr)| (resume_upload(&session_id, addr), addr)) -> [4]outbound_chan;
+
+        // Resumable downloads
+        inbound_demuxed[file_info_ch] -> filter_map(|(filename, version, addr)| file_info(filename, version, addr).map(|m| (m, addr))) -> [11]outbound_chan;
+        inbound_demuxed[file_chunk_ch]
+            -> filter_map(|(filename, version, index, addr)| read_chunk(filename, version, index, addr).map(|m| (m, addr))) -> [12]outbound_chan;
+
+        // Sessions of clients that stopped sending requests and heartbeats time out, freeing their partial uploads
+        source_interval(SESSION_SWEEP_INTERVAL) -> for_each(|_| expire_sessions());
+
+        // Respond to Heartbeat messages, receiving them already kept the session alive
+        inbound_demuxed[heartbeat_ch] -> map(|addr| (Message::HeartbeatAck, addr)) -> [2]outbound_chan;
+
+        // Authentication handshake, file requests of clients that didn't complete it are rejected
+        inbound_demuxed[hello_ch] -> map(|(identity, addr)| (hello(&identity, addr), addr)) -> [5]outbound_chan;
+        inbound_demuxed[auth_response_ch] -> map(|(mac, addr)| (challenge_response(mac.as_slice(), addr), addr)) -> [6]outbound_chan;
+        inbound_demuxed[unauth_ch]
+            -> filter_map(|(msg, addr)| reject_unauthenticated(msg, addr).map(|m| (m, addr)))
+            -> [7]outbound_chan;
+
+        inbound_demuxed[usage_ch] -> map(|addr| (usage(&addr), addr)) -> [13]outbound_chan;
+        inbound_demuxed[validate_ch] -> map(|(token, addr)| (validate_address(token.as_slice(), addr), addr)) -> [14]outbound_chan;
+
+        // File history, restoring goes through the store worker like any other change
+        inbound_demuxed[list_versions_ch] -> map(|(filename, addr)| (list_versions(filename, addr), addr)) -> [15]outbound_chan;
+        inbound_demuxed[restore_ch]
+            -> filter_map(|(filename, version, addr)| restore(filename, version, addr).map(|m| (m, addr))) -> [16]outbound_chan;
+
+        // Renames and copies only change the index, no data is sent
+        inbound_demuxed[rename_ch] -> filter_map(|(from, to, if_match, if_none_match, addr)| rename_file(from, to, if_match, if_none_match, addr).map(|m| (m, addr))) -> [17]outbound_chan;
+        inbound_demuxed[copy_ch] -> filter_map(|(from, to, addr)| copy_file(from, to, addr).map(|m| (m, addr))) -> [18]outbound_chan;
+        inbound_demuxed[list_ch]
+            -> map(|(path, recursive, offset, addr)| (list_directory(path, recursive, offset, addr), addr)) -> [19]outbound_chan;
+
+        // Print unexpected messages
+        inbound_demuxed[errs_ch]
+            -> for_each(|(msg, addr)| println!("Received unexpected message type: {:?} from {:?}", msg, addr));
+
+    };
+
+    // run the server flow
+    flow.run_async().await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::index::{FileMeta, MAX_PATH_LEN};
+    use crate::store::Catalog;
+    use std::sync::RwLock;
+
+    fn install(files: &[(&str, &str)]) {
+        let mut index = FileIndex::default();
+        for (file, hash) in files {
+            index.insert(file.to_string(), hash.to_string(), FileMeta { size: 1, owner: None });
+        }
+        let tree = MerkleTree::from_files(index.files.clone());
+        CATALOG.with(|c| c.replace(Arc::new(RwLock::new(Catalog { index, tree, ..Default::default() }))));
+    }
+
+    #[test]
+    fn listing_pages_always_make_progress() {
+        let files: Vec<(String, String)> = (0..100).map(|i| (format!("{:03}{}", i, "x".repeat(MAX_PATH_LEN - 3)), "h".repeat(64))).collect();
+        install(&files.iter().map(|(file, hash)| (file.as_str(), hash.as_str())).collect::<Vec<_>>());
+        let addr: SocketAddr = "127.0.0.1:1".parse().unwrap();
+
+        let mut offset = 0;
+        let mut listed = Vec::new();
+        loop {
+            let page = match list_directory(String::new(), false, offset, addr) {
+                Message::Listing(page) => page,
+                other => panic!("unexpected reply {:?}", other),
+            };
+            assert!(!page.entries.is_empty());
+            assert!(bincode::serialize(&Message::Listing(page.clone())).unwrap().len() <= CHUNK_SIZE + 1024);
+            listed.extend(page.entries.into_iter().map(|entry| entry.path));
+            match page.next {
+                Some(next) => {
+                    assert!(next > offset);
+                    offset = next;
+                }
+                None => break,
+            }
+        }
+        assert_eq!(listed, files.into_iter().map(|(file, _)| file).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn unreadable_entries_are_hidden_behind_their_nodes() {
+        install(&[("a/open", "1"), ("a/secret", "2"), ("b/secret", "3"), ("c", "4")]);
+        let readable = |file: &str| !file.ends_with("secret");
+        let catalog = CATALOG.with(|c| c.borrow().clone());
+        let catalog = catalog.read().unwrap();
+        let (index, tree) = (&catalog.index, &catalog.tree);
+        let node = |path: &str| tree.node(path).unwrap().to_string_lossy().to_string();
+
+        let entries = directory_entries(index, tree, "", true, &readable);
+        let listed: Vec<_> = entries.iter().map(|entry| (entry.path.as_str(), entry.hidden, entry.hash.clone())).collect();
+        assert_eq!(listed, vec![
+            ("a/open", false, "1".to_string()),
+            ("a", true, node("a/secret")),
+            ("", true, node("b")),
+            ("c", false, "4".to_string()),
+        ]);
+
+        let entries = directory_entries(index, tree, "", false, &readable);
+        let listed: Vec<_> = entries.iter().map(|entry| (entry.path.as_str(), entry.is_dir, entry.hidden, entry.size)).collect();
+        assert_eq!(listed, vec![("a", true, false, 1), ("", false, true, 0), ("c", false, false, 1)]);
+    }
+
+    #[test]
+    fn directories_can_be_listed_with_something_readable_below_them() {
+        let dir = std::env::temp_dir().join(format!("server-listing-acl-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let policy = dir.join("acl.json");
+        std::fs::write(&policy, r#"{"rules": [{"identity": "*", "prefix": "alice/", "permissions": ["read"]}]}"#).unwrap();
+        ACL.with(|acl| acl.replace(Some(Arc::new(Acl::load(&policy).unwrap()))));
+        install(&[("alice/notes", "1"), ("bob/secret", "2")]);
+        let addr: SocketAddr = "127.0.0.1:1".parse().unwrap();
+
+        for path in ["", "alice"] {
+            match list_directory(path.to_string(), false, 0, addr) {
+                Message::Listing(page) => assert!(page.entries.iter().any(|entry| !entry.hidden)),
+                other => panic!("unexpected reply {:?}", other),
+            }
+        }
+        match list_directory("bob".to_string(), false, 0, addr) {
+            Message::Error { error: ProtocolError::AccessDenied, .. } => {}
+            other => panic!("unexpected reply {:?}", other),
+        }
+
+        ACL.with(|acl| acl.replace(None));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,146 @@
+use std::collections::{HashMap, HashSet};
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+/* Most sessions a server worker keeps, clients beyond that are still served but nothing is tracked for them */
+const MAX_SESSIONS: usize = 16 * 1024;
+
+/* What the server knows about a client address: when it last heard from it and its transfers in progress */
+#[derive(Debug, Clone)]
+struct Session {
+    last_seen: Instant,
+    /* Ids of the upload sessions the client is sending chunks for */
+    uploads: HashSet<String>,
+    /* Files the client is downloading in chunks, with when it last asked for one of them */
+    downloads: HashMap<String, Instant>,
+}
+
+/**Sessions of the clients of a server worker, keyed by address. Any request, heartbeats included, keeps a
+ * session alive. A client that goes quiet for longer than the timeout is considered gone. Sessions are only
+ * made for addresses that proved they receive our replies, so spoofed requests can't fill the table */
+#[derive(Debug)]
+pub struct SessionTable {
+    timeout: Duration,
+    sessions: HashMap<SocketAddr, Session>,
+}
+
+impl SessionTable {
+    pub fn new(timeout: Duration) -> SessionTable {
+        SessionTable { timeout, sessions: HashMap::new() }
+    }
+
+    /* Keep the session of an address alive, a validated address gets one if it has none yet and there is room */
+    pub fn touch(&mut self, addr: SocketAddr, validated: bool) {
+        let now = Instant::now();
+        if let Some(session) = self.sessions.get_mut(&addr) {
+            session.last_seen = now;
+            return;
+        }
+        if !validated {
+            return;
+        }
+        if self.sessions.len() >= MAX_SESSIONS {
+            println!("No session for {:?}, there are {} already", addr, self.sessions.len());
+            return;
+        }
+        println!("New session for {:?}", addr);
+        self.sessions.insert(addr, Session { last_seen: now, uploads: HashSet::new(), downloads: HashMap::new() });
+    }
+
+    pub fn start_upload(&mut self, addr: SocketAddr, session_id: &str) {
+        if let Some(session) = self.sessions.get_mut(&addr) {
+            session.uploads.insert(session_id.to_string());
+        }
+    }
+
+    /* An upload is done or gone, whoever was sending it */
+    pub fn end_upload(&mut self, session_id: &str) {
+        for session in self.sessions.values_mut() {
+            session.uploads.remove(session_id);
+        }
+    }
+
+    /* The server can't tell when a download is done, so it's over once the client stops asking for its chunks */
+    pub fn start_download(&mut self, addr: SocketAddr, filename: &str) {
+        if let Some(session) = self.sessions.get_mut(&addr) {
+            session.downloads.insert(filename.to_string(), Instant::now());
+        }
+    }
+
+    /**Drop the sessions that timed out, and the downloads nobody asked for a chunk of within the timeout. Returns
+     * the uploads that only the dropped sessions were sending, a client resuming an upload from another address keeps it alive */
+    pub fn expire(&mut self) -> Vec<String> {
+        let now = Instant::now();
+        let timeout = self.timeout;
+        let (expired, mut live): (HashMap<SocketAddr, Session>, HashMap<SocketAddr, Session>) = self.sessions.drain()
+            .partition(|(_, session)| now.duration_since(session.last_seen) >= timeout);
+        for session in live.values_mut() {
+            session.downloads.retain(|_, last_seen| now.duration_since(*last_seen) < timeout);
+        }
+        self.sessions = live;
+
+        let mut orphaned = HashSet::new();
+        for (addr, session) in expired {
+            println!("Session of {:?} expired with {} uploads and {} downloads in progress", addr, session.uploads.len(), session.downloads.len());
+            orphaned.extend(session.uploads);
+        }
+        orphaned.into_iter()
+            .filter(|session_id| !self.sessions.values().any(|session| session.uploads.contains(session_id)))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addr(port: u16) -> SocketAddr {
+        SocketAddr::from(([127, 0, 0, 1], port))
+    }
+
+    #[test]
+    fn only_validated_addresses_get_a_session() {
+        let mut table = SessionTable::new(Duration::from_secs(60));
+        table.touch(addr(1), false);
+        table.start_upload(addr(1), "upload");
+        assert!(table.sessions.is_empty());
+
+        table.touch(addr(1), true);
+        table.start_upload(addr(1), "upload");
+        /* Once there is a session, requests keep it alive whether or not they come with proof */
+        table.touch(addr(1), false);
+        assert_eq!(table.sessions.len(), 1);
+        assert!(table.sessions[&addr(1)].uploads.contains("upload"));
+    }
+
+    #[test]
+    fn the_table_is_capped() {
+        let mut table = SessionTable::new(Duration::from_secs(60));
+        for port in 0..=MAX_SESSIONS as u16 {
+            table.touch(addr(port), true);
+        }
+        assert_eq!(table.sessions.len(), MAX_SESSIONS);
+        assert!(!table.sessions.contains_key(&addr(MAX_SESSIONS as u16)));
+    }
+
+    #[test]
+    fn expired_sessions_give_up_their_transfers() {
+        let mut table = SessionTable::new(Duration::ZERO);
+        table.touch(addr(1), true);
+        table.start_upload(addr(1), "upload");
+        table.start_download(addr(1), "file");
+        assert_eq!(table.expire(), vec!["upload".to_string()]);
+        assert!(table.sessions.is_empty());
+    }
+
+    #[test]
+    fn idle_downloads_are_dropped_from_live_sessions() {
+        let mut table = SessionTable::new(Duration::from_millis(50));
+        table.touch(addr(1), true);
+        table.start_download(addr(1), "file");
+        std::thread::sleep(Duration::from_millis(60));
+        table.touch(addr(1), false);
+        assert!(table.expire().is_empty());
+        assert!(table.sessions[&addr(1)].downloads.is_empty());
+    }
+}
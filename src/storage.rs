@@ -0,0 +1,194 @@
+use crate::fsutil::{is_temp_file, write_file_atomic};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::prelude::*;
+use std::io::SeekFrom;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ObjectStat {
+    pub size: u64,
+}
+
+/**Backend the server keeps file contents in, objects are addressed by a flat key */
+pub trait Storage: Send + Sync {
+    /* Store an object, replacing any previous object with the same key */
+    fn put(&self, key: &str, data: &[u8]) -> std::io::Result<()>;
+    fn get(&self, key: &str) -> std::io::Result<Vec<u8>>;
+    fn delete(&self, key: &str) -> std::io::Result<()>;
+    fn list(&self) -> std::io::Result<Vec<String>>;
+    /* None if there is no object with that key */
+    fn stat(&self, key: &str) -> std::io::Result<Option<ObjectStat>>;
+
+    /* Read `len` bytes starting at `offset`, backends that can seek should override this */
+    fn get_range(&self, key: &str, offset: u64, len: u64) -> std::io::Result<Vec<u8>> {
+        let data = self.get(key)?;
+        let start = usize::min(offset as usize, data.len());
+        let end = usize::min(start.saturating_add(len as usize), data.len());
+        Ok(data[start..end].to_vec())
+    }
+
+    /* Whether objects survive a restart */
+    fn is_persistent(&self) -> bool {
+        true
+    }
+}
+
+fn not_found(key: &str) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::NotFound, format!("object {} not found", key))
+}
+
+/**Objects stored as files in a local directory, written atomically */
+#[derive(Debug, Clone)]
+pub struct FsStorage {
+    dir: PathBuf,
+}
+
+impl FsStorage {
+    /* Open the directory, removing temp files left behind by a crash in the middle of a put */
+    pub fn new(dir: &Path) -> FsStorage {
+        let _ = std::fs::create_dir_all(dir);
+        if let Ok(rd) = std::fs::read_dir(dir) {
+            for entry in rd.flatten() {
+                if is_temp_file(&entry.file_name().to_string_lossy()) {
+                    println!("Removing leftover temp file {:?}", entry.path());
+                    let _ = std::fs::remove_file(entry.path());
+                }
+            }
+        }
+        FsStorage { dir: dir.to_path_buf() }
+    }
+
+    fn path(&self, key: &str) -> PathBuf {
+        self.dir.join(key)
+    }
+}
+
+impl Storage for FsStorage {
+    fn put(&self, key: &str, data: &[u8]) -> std::io::Result<()> {
+        write_file_atomic(&self.path(key), data)
+    }
+
+    fn get(&self, key: &str) -> std::io::Result<Vec<u8>> {
+        std::fs::read(self.path(key))
+    }
+
+    fn delete(&self, key: &str) -> std::io::Result<()> {
+        std::fs::remove_file(self.path(key))
+    }
+
+    fn list(&self) -> std::io::Result<Vec<String>> {
+        Ok(std::fs::read_dir(&self.dir)?
+            .flatten()
+            .map(|entry| entry.file_name().to_string_lossy().to_string())
+            .filter(|name| !is_temp_file(name))
+            .collect())
+    }
+
+    fn stat(&self, key: &str) -> std::io::Result<Option<ObjectStat>> {
+        match std::fs::metadata(self.path(key)) {
+            Ok(metadata) if metadata.is_file() => Ok(Some(ObjectStat { size: metadata.len() })),
+            Ok(_) => Ok(None),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn get_range(&self, key: &str, offset: u64, len: u64) -> std::io::Result<Vec<u8>> {
+        let mut file = File::open(self.path(key))?;
+        let mut data = Vec::new();
+        file.seek(SeekFrom::Start(offset))?;
+        Read::by_ref(&mut file).take(len).read_to_end(&mut data)?;
+        Ok(data)
+    }
+}
+
+/**Objects kept in memory, nothing survives a restart */
+#[derive(Debug, Default)]
+pub struct MemoryStorage {
+    objects: Mutex<HashMap<String, Vec<u8>>>,
+}
+
+impl Storage for MemoryStorage {
+    fn put(&self, key: &str, data: &[u8]) -> std::io::Result<()> {
+        self.objects.lock().unwrap().insert(key.to_string(), data.to_vec());
+        Ok(())
+    }
+
+    fn get(&self, key: &str) -> std::io::Result<Vec<u8>> {
+        self.objects.lock().unwrap().get(key).cloned().ok_or_else(|| not_found(key))
+    }
+
+    fn delete(&self, key: &str) -> std::io::Result<()> {
+        self.objects.lock().unwrap().remove(key).map(|_| ()).ok_or_else(|| not_found(key))
+    }
+
+    fn list(&self) -> std::io::Result<Vec<String>> {
+        Ok(self.objects.lock().unwrap().keys().cloned().collect())
+    }
+
+    fn stat(&self, key: &str) -> std::io::Result<Option<ObjectStat>> {
+        Ok(self.objects.lock().unwrap().get(key).map(|data| ObjectStat { size: data.len() as u64 }))
+    }
+
+    fn is_persistent(&self) -> bool {
+        false
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    /**What the store relies on from every backend, whatever it keeps objects in */
+    pub(crate) fn check_backend(storage: &dyn Storage) {
+        assert_eq!(storage.stat("a").unwrap(), None);
+        assert_eq!(storage.get("a").unwrap_err().kind(), std::io::ErrorKind::NotFound);
+
+        storage.put("a", b"first").unwrap();
+        storage.put("a", b"contents").unwrap();
+        storage.put("b", b"").unwrap();
+        assert_eq!(storage.get("a").unwrap(), b"contents");
+        assert_eq!(storage.get("b").unwrap(), b"");
+        assert_eq!(storage.stat("a").unwrap(), Some(ObjectStat { size: 8 }));
+        assert_eq!(storage.stat("b").unwrap(), Some(ObjectStat { size: 0 }));
+
+        /* Ranges are cut off at the end of the object */
+        assert_eq!(storage.get_range("a", 3, 2).unwrap(), b"te");
+        assert_eq!(storage.get_range("a", 5, 100).unwrap(), b"nts");
+
+        let mut keys = storage.list().unwrap();
+        keys.sort();
+        assert_eq!(keys, vec!["a", "b"]);
+
+        storage.delete("a").unwrap();
+        assert_eq!(storage.stat("a").unwrap(), None);
+        assert_eq!(storage.get("a").unwrap_err().kind(), std::io::ErrorKind::NotFound);
+        assert_eq!(storage.list().unwrap(), vec!["b"]);
+    }
+
+    #[test]
+    fn memory_backend() {
+        let storage = MemoryStorage::default();
+        check_backend(&storage);
+        assert!(!storage.is_persistent());
+    }
+
+    #[test]
+    fn fs_backend() {
+        let dir = std::env::temp_dir().join(format!("storage-test-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let storage = FsStorage::new(&dir);
+        check_backend(&storage);
+        assert!(storage.is_persistent());
+
+        /* Objects survive a restart, temp files of a put that was cut short don't */
+        let temp = dir.join(".b.tmp");
+        std::fs::write(&temp, b"partial").unwrap();
+        let storage = FsStorage::new(&dir);
+        assert!(!temp.exists());
+        assert_eq!(storage.list().unwrap(), vec!["b"]);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
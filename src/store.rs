@@ -0,0 +1,832 @@
+use crate::atrest::EncryptedStorage;
+use crate::index::{is_valid_path, FileIndex, FileMeta, Version};
+use crate::merkletree::MerkleTree;
+use crate::objects::ObjectStore;
+use crate::protocol::{Message, Preconditions, ProtocolError, VersionInfo};
+use crate::quota::Limits;
+use crate::upload::UPLOAD_SESSION_TTL_SECS;
+use crate::wal::{Wal, WalOp, WalRecord};
+use chrono::{DateTime, Duration, Utc};
+use std::collections::BTreeMap;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock, RwLockReadGuard};
+use tokio::sync::mpsc::error::TryRecvError;
+use tokio::sync::mpsc::UnboundedSender;
+
+/* Number of journaled operations after which a checkpoint is taken */
+const CHECKPOINT_INTERVAL: u64 = 64;
+/* Versions kept per filename unless configured otherwise */
+pub(crate) const DEFAULT_VERSIONS: usize = 5;
+
+/* Mutations are queued for the I/O worker and applied in the order they were submitted,
+ * `shard` is the server worker the reply goes back to */
+#[derive(Debug)]
+pub enum StoreJob {
+    /* `expected_hash` is checked against the data, then the preconditions and the quotas of `identity` before anything is written.
+     * Chunked uploads pass their `session_id`, the server worker keeps the session until it hears back */
+    Save { filename: String, data: Contents, expected_hash: Option<String>, session_id: Option<String>, preconditions: Box<Preconditions>, identity: Option<String>, addr: SocketAddr, shard: usize },
+    Delete { filename: String, preconditions: Box<Preconditions>, addr: SocketAddr, shard: usize },
+    /* Point another filename at the contents of `from`, a copy counts towards the quotas of `identity`.
+     * An existing `to` is only replaced with `replace`, the client needs to be allowed to delete it */
+    Rename { from: String, to: String, if_match: Option<String>, if_none_match: Option<String>, replace: bool, addr: SocketAddr, shard: usize },
+    Copy { from: String, to: String, identity: Option<String>, replace: bool, addr: SocketAddr, shard: usize },
+    /* Make a kept version the current contents again, counted towards the quotas of `identity`.
+     * A version that deleted the file is only restored with `delete`, the client needs to be allowed to delete it */
+    Restore { filename: String, version: u64, identity: Option<String>, delete: bool, addr: SocketAddr, shard: usize },
+    /* Re-wrap every blob still under an older at-rest key, one blob at a time whenever no other job is waiting */
+    Rotate { storage: Arc<EncryptedStorage> },
+}
+
+/* Data of a file to save, chunked uploads are spooled to a file that the store worker reads */
+#[derive(Debug)]
+pub enum Contents {
+    Data(Vec<u8>),
+    Spooled(PathBuf),
+}
+
+/* Blobs left to look at in a rotation of the at-rest keys */
+struct Rotation {
+    storage: Arc<EncryptedStorage>,
+    pending: Vec<String>,
+    rewrapped: usize,
+}
+
+/* What the I/O workers hand back to the server workers */
+#[derive(Debug)]
+pub enum StoreEvent {
+    Reply(Message, SocketAddr),
+    /* Outcome of saving a chunked upload, `saved` is false when the assembled data didn't match its hash */
+    Uploaded { session_id: String, saved: bool, reply: Message, addr: SocketAddr },
+}
+
+/**The index and the tree built from it, shared with the server workers. The store worker is the only one that changes
+ * them, in place and before it replies, so a proof read after an ack matches the acked root */
+#[derive(Debug, Default)]
+pub struct Catalog {
+    pub index: FileIndex,
+    pub tree: MerkleTree,
+    /* Chunked uploads saved within the last UPLOAD_SESSION_TTL_SECS by session id, with when they were saved */
+    pub saved_uploads: BTreeMap<String, DateTime<Utc>>,
+}
+
+impl Catalog {
+    /**Whether the upload `session_id` was saved recently and the file still has its contents. Only then is an upload
+     * whose preconditions fail a retry of one whose ack got lost, matching contents alone don't make a retry */
+    pub fn is_saved_upload(&self, session_id: &str, filename: &str, hash: &str) -> bool {
+        let recent = Utc::now() - Duration::seconds(UPLOAD_SESSION_TTL_SECS);
+        self.saved_uploads.get(session_id).is_some_and(|saved| *saved > recent)
+            && self.index.get(filename).map(String::as_str) == Some(hash)
+    }
+}
+
+pub type SharedCatalog = Arc<RwLock<Catalog>>;
+
+/**The write-ahead log, the index and the object store. Owned by the I/O worker thread,
+ * so slow disks or a slow storage backend never hold up the flow */
+pub struct Store {
+    objects: ObjectStore,
+    wal: Wal,
+    /* Index and tree of the current epoch, only the directories a change touched are hashed again */
+    catalog: SharedCatalog,
+    state_dir: PathBuf,
+    limits: Limits,
+    /* Number of versions kept per filename */
+    versions: usize,
+    rotation: Option<Rotation>,
+}
+
+/* Proof of a file or directory as sent to clients, converted from OSString to Vec<Vec<u8>> */
+fn proof_bytes(tree: &MerkleTree, path: &str) -> Vec<Vec<u8>> {
+    tree.get_proof(path)
+        .unwrap_or_default()
+        .into_iter()
+        .map(|s| s.into_string().unwrap_or_default().into_bytes())
+        .collect()
+}
+
+/**Start a new epoch after a change to `filenames` in the index. The tree follows the index for those filenames only,
+ * then their new versions are recorded together with the root of the new tree and their proofs against it.
+ * A filename that is no longer indexed gets a version marking its deletion */
+fn new_epoch(index: &mut FileIndex, tree: &mut MerkleTree, filenames: &[&str], timestamp: DateTime<Utc>, keep: usize) {
+    index.epoch += 1;
+    tree.update(filenames.iter().map(|filename| (*filename, index.get(filename).map(String::as_str))));
+    let root = tree.root.clone().map(|root| root.into_string().unwrap_or_default()).unwrap_or_default();
+
+    for filename in filenames {
+        let hash = index.get(filename).cloned();
+        let meta = index.meta(filename).cloned().unwrap_or_default();
+        let merkle_proof = hash.as_ref().map(|_| proof_bytes(tree, filename)).unwrap_or_default();
+        let info = VersionInfo { version: index.next_version(filename), hash, size: meta.size, timestamp, owner: meta.owner, epoch: index.epoch };
+        index.push_version(filename, Version { info, root: root.clone(), merkle_proof }, keep);
+    }
+}
+
+impl Store {
+    /**Open the write-ahead log, replay what wasn't checkpointed yet and take a checkpoint. Fails if the log can't
+     * be read back completely. With a backend that doesn't keep its objects, the log and the index are kept in
+     * memory as well and nothing is written to `state_dir` */
+    pub async fn open(state_dir: &Path, data_dir: &Path, objects: ObjectStore, limits: Limits, versions: usize) -> std::io::Result<Store> {
+        let (wal, records, mut index) = if objects.is_persistent() {
+            let _ = tokio::fs::create_dir_all(state_dir).await;
+            let (wal, records) = Wal::open(&state_dir.join("wal"))?;
+            (wal, records, Self::load_index(&state_dir.join("index.json"))?)
+        } else {
+            (Wal::in_memory(), Vec::new(), FileIndex::default())
+        };
+        /* Versions made by the replayed operations need the tree of their epoch, it's kept up to date along the way */
+        let mut tree = index.tree();
+        for record in records {
+            Self::replay(&objects, &wal, &mut index, &mut tree, record, versions)?;
+        }
+        if objects.is_persistent() {
+            Self::migrate_flat_files(&objects, &mut index, data_dir).await;
+        }
+
+        /* The backend may have lost blobs the index still knows about, e.g. the in-memory one after a restart. Only blobs
+         * the backend says it doesn't have are dropped, if it can't be asked the store doesn't start */
+        let mut missing = Vec::new();
+        for (filename, hash) in index.files.iter() {
+            if !objects.contains(hash)? {
+                missing.push(filename.clone());
+            }
+        }
+        for filename in missing {
+            println!("Contents of {} are missing from storage, dropping it", filename);
+            index.remove(&filename);
+        }
+        let mut failed = None;
+        index.retain_versions(|version| match version.info.hash.as_ref().map_or(Ok(true), |hash| objects.contains(hash)) {
+            Ok(stored) => stored,
+            Err(e) => {
+                failed.get_or_insert(e);
+                true
+            }
+        });
+        if let Some(e) = failed {
+            return Err(e);
+        }
+        /* Sizes of files indexed by older versions, their uploader is unknown */
+        let unsized_files = index.files.iter()
+            .filter(|(filename, _)| index.meta(filename).is_none())
+            .map(|(filename, hash)| (filename.clone(), hash.clone()))
+            .collect::<Vec<(String, String)>>();
+        for (filename, hash) in unsized_files {
+            let size = objects.stat(&hash)?.map_or(0, |stat| stat.size);
+            index.insert(filename, hash, FileMeta { size, owner: None });
+        }
+
+        /* Files indexed by older versions have no history yet, their current contents become their first version */
+        let unversioned = index.files.keys()
+            .filter(|filename| index.versions(filename).is_empty())
+            .cloned()
+            .collect::<Vec<String>>();
+        /* Files may have been added and dropped since the replay, the tree is built once more from what's left */
+        let mut tree = index.tree();
+        if !unversioned.is_empty() {
+            new_epoch(&mut index, &mut tree, &unversioned.iter().map(String::as_str).collect::<Vec<&str>>(), Utc::now(), versions);
+        }
+        let catalog = Arc::new(RwLock::new(Catalog { index, tree, saved_uploads: BTreeMap::new() }));
+        let mut store = Store { objects, wal, catalog, state_dir: state_dir.to_path_buf(), limits, versions, rotation: None };
+        store.checkpoint();
+        Ok(store)
+    }
+
+    /**Only a store that never had an index starts from an empty one. Checkpointing an empty index in place of one
+     * that couldn't be read would garbage collect every blob, so that refuses to start instead */
+    fn load_index(path: &Path) -> std::io::Result<FileIndex> {
+        match FileIndex::load(path) {
+            Ok(index) => Ok(index),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(FileIndex::default()),
+            Err(e) => Err(std::io::Error::new(e.kind(), format!("unable to load {}: {}", path.display(), e))),
+        }
+    }
+
+    /**Apply a journaled operation again after a crash. Operations are replayed in order on top of the index
+     * of the last checkpoint, so the index ends up exactly as it was before the crash */
+    fn replay(objects: &ObjectStore, wal: &Wal, index: &mut FileIndex, tree: &mut MerkleTree, record: WalRecord, keep: usize) -> std::io::Result<()> {
+        println!("Replaying {:?}", record);
+
+        match record.op {
+            WalOp::Upload { filename, hash, size, owner } => {
+                /* Older logs staged the data of new blobs, newer ones store the blob before journaling */
+                let stored = objects.contains(&hash)? || match wal.staged_data(record.seq) {
+                    Ok(data) if blake3::hash(data.as_slice()).to_string() == hash => {
+                        objects.put(&hash, data.as_slice())?;
+                        true
+                    }
+                    _ => false,
+                };
+
+                if stored {
+                    let size = match size {
+                        Some(size) => size,
+                        None => objects.stat(&hash)?.map_or(0, |stat| stat.size),
+                    };
+                    index.insert(filename.clone(), hash, FileMeta { size, owner });
+                    new_epoch(index, tree, &[&filename], record.time, keep);
+                } else {
+                    println!("Data of {} is missing, skipping", filename);
+                }
+            }
+            WalOp::Delete { filename } => {
+                if index.remove(&filename).is_some() {
+                    new_epoch(index, tree, &[&filename], record.time, keep);
+                }
+            }
+            WalOp::Rename { from, to, hash } => {
+                if index.get(&from) == Some(&hash) {
+                    let meta = index.meta(&from).cloned().unwrap_or_default();
+                    index.remove(&from);
+                    index.insert(to.clone(), hash, meta);
+                    new_epoch(index, tree, &[&from, &to], record.time, keep);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /* Move files that earlier versions stored by name in the data folder into the object store */
+    async fn migrate_flat_files(objects: &ObjectStore, index: &mut FileIndex, data_dir: &Path) {
+        let legacy = FileIndex::from_folder(data_dir).await;
+        for (filename, hash) in legacy.files {
+            let path = data_dir.join(&filename);
+            if let Ok(data) = std::fs::read(&path) {
+                if objects.put(&hash, data.as_slice()).is_ok() {
+                    println!("Moved {} into the object store", filename);
+                    index.insert(filename, hash, FileMeta { size: data.len() as u64, owner: None });
+                    let _ = std::fs::remove_file(path);
+                }
+            }
+        }
+    }
+
+    /**Persist the index and compact the log, everything journaled so far has been applied at this point.
+     * Blobs no longer referenced by the persisted index are garbage collected afterwards */
+    fn checkpoint(&mut self) {
+        let catalog = self.catalog.read().unwrap();
+        let saved = if self.objects.is_persistent() { catalog.index.save(&self.state_dir.join("index.json")) } else { Ok(()) };
+        if let Err(e) = saved.and_then(|_| self.wal.checkpoint()) {
+            println!("Unable to take checkpoint: {}", e);
+            return;
+        }
+
+        let removed = self.objects.gc(|hash| catalog.index.is_referenced(hash));
+        if removed > 0 {
+            println!("Garbage collected {} unreferenced blobs", removed);
+        }
+    }
+
+    /* Index and tree for reading, the store worker never waits for it since it's the only writer */
+    fn catalog(&self) -> RwLockReadGuard<'_, Catalog> {
+        self.catalog.read().unwrap()
+    }
+
+    /* Shared with the server workers, which read from it directly */
+    pub fn shared(&self) -> SharedCatalog {
+        self.catalog.clone()
+    }
+
+    /**Change the index and start a new epoch for `filenames` in one step, so the server workers never see the index
+     * without the tree that goes with it. Only the changed entries are touched, nothing is copied */
+    fn apply(&self, filenames: &[&str], timestamp: DateTime<Utc>, change: impl FnOnce(&mut FileIndex)) {
+        let mut catalog = self.catalog.write().unwrap();
+        let Catalog { index, tree, .. } = &mut *catalog;
+        change(index);
+        new_epoch(index, tree, filenames, timestamp, self.versions);
+    }
+
+    fn maybe_checkpoint(&mut self) {
+        if self.wal.pending() >= CHECKPOINT_INTERVAL {
+            self.checkpoint();
+        }
+    }
+
+    /**Check the preconditions of a conditional write or delete against the current index and tree. This happens in
+     * the same step as the change itself, so no other change can get in between */
+    fn check_preconditions(&self, filename: &str, preconditions: &Preconditions) -> Result<(), Message> {
+        let catalog = self.catalog();
+        let root = catalog.tree.root.as_ref().map(|root| root.to_string_lossy().to_string());
+        preconditions.check(catalog.index.get(filename).map(String::as_str), root.as_deref()).map_err(|failed| {
+            println!("Precondition for {} failed: {}", filename, failed);
+            Message::Error { filename: filename.to_string(), error: ProtocolError::PreconditionFailed(failed) }
+        })
+    }
+
+    /* Paths are checked whenever a file is created, so the index always describes a valid tree */
+    fn check_path(&self, filename: &str) -> Result<(), Message> {
+        let invalid = if !is_valid_path(filename) {
+            format!("{} is not a valid path", filename)
+        } else if self.catalog().index.conflicts(filename) {
+            format!("{} is in the way of a directory or has a file as parent", filename)
+        } else {
+            return Ok(());
+        };
+        println!("{}", invalid);
+        Err(Message::Error { filename: filename.to_string(), error: ProtocolError::InvalidPath(invalid) })
+    }
+
+    /**Store a file in the object store and point its name at it, the blob is only written when it's new.
+     * Preconditions and quotas are checked here, so concurrent uploads on different server workers can't both squeeze in */
+    pub fn save(&mut self, filename: &str, data: &[u8], expected_hash: Option<&str>, session_id: Option<&str>, preconditions: &Preconditions, identity: Option<&str>) -> Message {
+        let hash = blake3::hash(data).to_string();
+        if expected_hash.is_some_and(|expected| expected != hash) {
+            println!("Upload of {} doesn't match its hash", filename);
+            return Message::Error { filename: filename.to_string(), error: ProtocolError::HashMismatch };
+        }
+        if let Err(failed) = self.check_path(filename).and_then(|_| self.check_preconditions(filename, preconditions)) {
+            /* This very upload was saved before and its ack got lost */
+            if session_id.is_some_and(|session_id| self.catalog().is_saved_upload(session_id, filename, &hash)) {
+                return Message::FileAck { filename: filename.to_string(), hash, root: self.root() };
+            }
+            return failed;
+        }
+        if let Err(limit) = self.limits.check(&self.catalog().index, filename, data.len() as u64, identity) {
+            println!("Upload of {} is over the {}", filename, limit);
+            return Message::Error { filename: filename.to_string(), error: ProtocolError::QuotaExceeded(limit) };
+        }
+        let meta = FileMeta { size: data.len() as u64, owner: identity.map(str::to_string) };
+
+        /* The blob goes in first, so the log never needs a copy of the data. If we crash before the record
+         * is written, the blob isn't referenced by anything and gets garbage collected */
+        let res = self.objects.put(&hash, data)
+            .and_then(|is_new| self.wal.append(WalOp::Upload { filename: filename.to_string(), hash: hash.clone(), size: Some(meta.size), owner: meta.owner.clone() }, None).map(|record| (is_new, record)));
+        let (is_new, record) = match res {
+            Ok(res) => res,
+            Err(e) => {
+                println!("Unable to save file {}: {}", filename, e);
+                return Message::Error { filename: filename.to_string(), error: ProtocolError::Io(e.to_string()) };
+            }
+        };
+        if is_new {
+            println!("Saved file {}", filename);
+        } else {
+            println!("Saved file {}, contents were already stored", filename);
+        }
+
+        self.apply(&[filename], record.time, |index| { index.insert(filename.to_string(), hash.clone(), meta); });
+        if let Some(session_id) = session_id {
+            let recent = record.time - Duration::seconds(UPLOAD_SESSION_TTL_SECS);
+            let mut catalog = self.catalog.write().unwrap();
+            catalog.saved_uploads.retain(|_, saved| *saved > recent);
+            catalog.saved_uploads.insert(session_id.to_string(), record.time);
+        }
+        self.maybe_checkpoint();
+
+        Message::FileAck { filename: filename.to_string(), hash, root: self.root() }
+    }
+
+    /**Remove a filename from the index, its blob goes away in the next garbage collection pass once nothing references it */
+    pub fn delete(&mut self, filename: &str, preconditions: &Preconditions) -> Message {
+        if let Err(failed) = self.check_preconditions(filename, preconditions) {
+            return failed;
+        }
+        if self.catalog().index.get(filename).is_none() {
+            println!("Unable to remove file {}", filename);
+            return Message::DeleteFileAck { filename: filename.to_string(), deleted: false, root: self.root() };
+        }
+
+        match self.wal.append(WalOp::Delete { filename: filename.to_string() }, None) {
+            Ok(record) => {
+                println!("Deleted file {}", filename);
+                self.apply(&[filename], record.time, |index| { index.remove(filename); });
+                self.maybe_checkpoint();
+                Message::DeleteFileAck { filename: filename.to_string(), deleted: true, root: self.root() }
+            }
+            Err(_) => {
+                println!("Unable to remove file {}", filename);
+                Message::DeleteFileAck { filename: filename.to_string(), deleted: false, root: self.root() }
+            }
+        }
+    }
+
+    /* Renames and copies only replace an existing file for clients that may delete it, checked against the current index */
+    fn check_replace(&self, to: &str, replace: bool) -> Result<(), Message> {
+        if !replace && self.catalog().index.get(to).is_some() {
+            println!("Denied replacing {}", to);
+            return Err(Message::Error { filename: to.to_string(), error: ProtocolError::AccessDenied });
+        }
+        Ok(())
+    }
+
+    /* Root of the current tree as sent to clients, empty for an empty store */
+    fn root(&self) -> String {
+        self.catalog().tree.root.as_ref().map(|root| root.to_string_lossy().to_string()).unwrap_or_default()
+    }
+
+    /**Move a file to another name without touching its contents, a file that already has that name is replaced if
+     * `replace` allows it. The old name gets a version marking its deletion and the new one a version with the contents,
+     * in one epoch */
+    pub fn rename(&mut self, from: &str, to: &str, if_match: Option<String>, if_none_match: Option<String>, replace: bool) -> Message {
+        let hash = match self.catalog().index.get(from).cloned() {
+            Some(hash) => hash,
+            None => {
+                println!("Unable to rename {}, it doesn't exist", from);
+                return Message::FileNotFound { filename: from.to_string() };
+            }
+        };
+        let checked = self.check_preconditions(from, &Preconditions { if_match, ..Default::default() })
+            .and_then(|_| self.check_preconditions(to, &Preconditions { if_none_match, ..Default::default() }));
+        if let Err(failed) = checked {
+            return failed;
+        }
+        if from == to {
+            return Message::RenameAck { from: from.to_string(), to: to.to_string(), root: self.root() };
+        }
+        if let Err(invalid) = self.check_path(to).and_then(|_| self.check_replace(to, replace)) {
+            return invalid;
+        }
+        if let Err(limit) = self.limits.check_rename(&self.catalog().index, from, to) {
+            println!("Rename of {} to {} is over the {}", from, to, limit);
+            return Message::Error { filename: to.to_string(), error: ProtocolError::QuotaExceeded(limit) };
+        }
+
+        match self.wal.append(WalOp::Rename { from: from.to_string(), to: to.to_string(), hash: hash.clone() }, None) {
+            Ok(record) => {
+                println!("Renamed {} to {}", from, to);
+                self.apply(&[from, to], record.time, |index| {
+                    let meta = index.meta(from).cloned().unwrap_or_default();
+                    index.remove(from);
+                    index.insert(to.to_string(), hash, meta);
+                });
+                self.maybe_checkpoint();
+                Message::RenameAck { from: from.to_string(), to: to.to_string(), root: self.root() }
+            }
+            Err(e) => {
+                println!("Unable to rename {}: {}", from, e);
+                Message::Error { filename: from.to_string(), error: ProtocolError::Io(e.to_string()) }
+            }
+        }
+    }
+
+    /**Give the contents of a file a second name, journaled as an upload of a blob that is already stored.
+     * The copy belongs to `identity` and counts towards its quotas like an upload would. A file that already has
+     * that name is replaced if `replace` allows it */
+    pub fn copy(&mut self, from: &str, to: &str, identity: Option<&str>, replace: bool) -> Message {
+        let hash = match self.catalog().index.get(from).cloned() {
+            Some(hash) => hash,
+            None => {
+                println!("Unable to copy {}, it doesn't exist", from);
+                return Message::FileNotFound { filename: from.to_string() };
+            }
+        };
+        let size = self.catalog().index.meta(from).map_or(0, |meta| meta.size);
+        if let Err(invalid) = self.check_path(to).and_then(|_| self.check_replace(to, replace)) {
+            return invalid;
+        }
+        if let Err(limit) = self.limits.check(&self.catalog().index, to, size, identity) {
+            println!("Copy of {} to {} is over the {}", from, to, limit);
+            return Message::Error { filename: to.to_string(), error: ProtocolError::QuotaExceeded(limit) };
+        }
+        let meta = FileMeta { size, owner: identity.map(str::to_string) };
+
+        match self.wal.append(WalOp::Upload { filename: to.to_string(), hash: hash.clone(), size: Some(size), owner: meta.owner.clone() }, None) {
+            Ok(record) => {
+                println!("Copied {} to {}", from, to);
+                self.apply(&[to], record.time, |index| { index.insert(to.to_string(), hash, meta); });
+                self.maybe_checkpoint();
+                Message::CopyAck { from: from.to_string(), to: to.to_string(), root: self.root() }
+            }
+            Err(e) => {
+                println!("Unable to copy {}: {}", from, e);
+                Message::Error { filename: to.to_string(), error: ProtocolError::Io(e.to_string()) }
+            }
+        }
+    }
+
+    /**Point a filename at the contents of one of its kept versions again, which adds a new version. The blob is
+     * still stored since the version references it. Restoring a version that deleted the file deletes it, if `delete` allows it */
+    pub fn restore(&mut self, filename: &str, version: u64, identity: Option<&str>, delete: bool) -> Message {
+        let info = match self.catalog().index.version(filename, version).map(|version| version.info.clone()) {
+            Some(info) => info,
+            None => {
+                println!("No version {} of {} to restore", version, filename);
+                return Message::FileNotFound { filename: filename.to_string() };
+            }
+        };
+        let hash = match info.hash {
+            Some(hash) => hash,
+            None if delete => return self.delete(filename, &Preconditions::default()),
+            None => {
+                println!("Denied restoring the deletion of {}", filename);
+                return Message::Error { filename: filename.to_string(), error: ProtocolError::AccessDenied };
+            }
+        };
+        if let Err(invalid) = self.check_path(filename) {
+            return invalid;
+        }
+        if let Err(limit) = self.limits.check(&self.catalog().index, filename, info.size, identity) {
+            println!("Restore of {} is over the {}", filename, limit);
+            return Message::Error { filename: filename.to_string(), error: ProtocolError::QuotaExceeded(limit) };
+        }
+        let meta = FileMeta { size: info.size, owner: identity.map(str::to_string) };
+
+        match self.wal.append(WalOp::Upload { filename: filename.to_string(), hash: hash.clone(), size: Some(meta.size), owner: meta.owner.clone() }, None) {
+            Ok(record) => {
+                println!("Restored version {} of {}", version, filename);
+                self.apply(&[filename], record.time, |index| { index.insert(filename.to_string(), hash.clone(), meta); });
+                self.maybe_checkpoint();
+                Message::FileAck { filename: filename.to_string(), hash, root: self.root() }
+            }
+            Err(e) => {
+                println!("Unable to restore {}: {}", filename, e);
+                Message::Error { filename: filename.to_string(), error: ProtocolError::Io(e.to_string()) }
+            }
+        }
+    }
+
+    /* Apply a job, returns the reply together with where it goes if there is one */
+    fn handle(&mut self, job: StoreJob) -> Option<(StoreEvent, usize)> {
+        match job {
+            StoreJob::Save { filename, data, expected_hash, session_id, preconditions, identity, addr, shard } => {
+                let data = match data {
+                    Contents::Data(data) => Ok(data),
+                    Contents::Spooled(path) => std::fs::read(path),
+                };
+                let reply = match data {
+                    Ok(data) => self.save(&filename, data.as_slice(), expected_hash.as_deref(), session_id.as_deref(), &preconditions, identity.as_deref()),
+                    Err(e) => {
+                        println!("Unable to read the upload of {}: {}", filename, e);
+                        Message::Error { filename: filename.clone(), error: ProtocolError::Io(e.to_string()) }
+                    }
+                };
+                let event = match session_id {
+                    Some(session_id) => {
+                        let saved = !matches!(reply, Message::Error { error: ProtocolError::HashMismatch, .. });
+                        StoreEvent::Uploaded { session_id, saved, reply, addr }
+                    }
+                    None => StoreEvent::Reply(reply, addr),
+                };
+                Some((event, shard))
+            }
+            StoreJob::Delete { filename, preconditions, addr, shard } => {
+                let reply = self.delete(&filename, &preconditions);
+                Some((StoreEvent::Reply(reply, addr), shard))
+            }
+            StoreJob::Rename { from, to, if_match, if_none_match, replace, addr, shard } => {
+                let reply = self.rename(&from, &to, if_match, if_none_match, replace);
+                Some((StoreEvent::Reply(reply, addr), shard))
+            }
+            StoreJob::Copy { from, to, identity, replace, addr, shard } => {
+                let reply = self.copy(&from, &to, identity.as_deref(), replace);
+                Some((StoreEvent::Reply(reply, addr), shard))
+            }
+            StoreJob::Restore { filename, version, identity, delete, addr, shard } => {
+                let reply = self.restore(&filename, version, identity.as_deref(), delete);
+                Some((StoreEvent::Reply(reply, addr), shard))
+            }
+            StoreJob::Rotate { storage } => {
+                match storage.objects() {
+                    Ok(pending) => {
+                        println!("Rotating the at-rest keys of {} blobs", pending.len());
+                        self.rotation = Some(Rotation { storage, pending, rewrapped: 0 });
+                    }
+                    Err(e) => println!("Unable to rotate at-rest keys: {}", e),
+                }
+                None
+            }
+        }
+    }
+
+    /**Re-wrap the next blob of a rotation in progress, returns false once there is nothing left to do. This runs on
+     * the store worker like garbage collection does, so a blob collected in between is never written back */
+    fn rotate_next(&mut self) -> bool {
+        let rotation = match self.rotation.as_mut() {
+            Some(rotation) => rotation,
+            None => return false,
+        };
+        let object = match rotation.pending.pop() {
+            Some(object) => object,
+            None => {
+                println!("Re-wrapped {} blobs with the current at-rest key", rotation.rewrapped);
+                self.rotation = None;
+                return false;
+            }
+        };
+        /* Blobs nothing references are left for garbage collection */
+        if self.catalog.read().unwrap().index.is_referenced(&object) {
+            match rotation.storage.rewrap(&object) {
+                Ok(true) => rotation.rewrapped += 1,
+                Ok(false) => {}
+                Err(e) => println!("Unable to re-wrap {}: {}", object, e),
+            }
+        }
+        true
+    }
+}
+
+/**Run the store on a dedicated thread, it's the single writer that serializes all mutations and root updates.
+ * Jobs are handled one at a time in the order they were submitted, which is also the order they are journaled in.
+ * Changes are in the shared catalog before the reply goes out */
+pub fn spawn_store_worker(mut store: Store, shards: Vec<UnboundedSender<StoreEvent>>) -> UnboundedSender<StoreJob> {
+    let (jobs, mut queue) = tokio::sync::mpsc::unbounded_channel::<StoreJob>();
+
+    std::thread::spawn(move || loop {
+        /* A rotation in progress moves on whenever there is no job waiting */
+        let job = match queue.try_recv() {
+            Ok(job) => job,
+            Err(TryRecvError::Empty) if store.rotate_next() => continue,
+            Err(TryRecvError::Empty) => match queue.blocking_recv() {
+                Some(job) => job,
+                None => break,
+            },
+            Err(TryRecvError::Disconnected) => break,
+        };
+        if let Some((event, shard)) = store.handle(job) {
+            if let Some(events) = shards.get(shard) {
+                let _ = events.send(event);
+            }
+        }
+    });
+
+    jobs
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::{FsStorage, MemoryStorage, ObjectStat, Storage};
+    use crate::upload::upload_session_id;
+    use std::ffi::OsString;
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    /* Backend whose requests fail while `failing` is set, like a store that can't be reached */
+    struct Unreachable {
+        inner: FsStorage,
+        failing: AtomicBool,
+    }
+
+    impl Unreachable {
+        fn check(&self) -> std::io::Result<()> {
+            match self.failing.load(Ordering::SeqCst) {
+                true => Err(std::io::Error::new(std::io::ErrorKind::TimedOut, "unreachable")),
+                false => Ok(()),
+            }
+        }
+    }
+
+    impl Storage for Unreachable {
+        fn put(&self, key: &str, data: &[u8]) -> std::io::Result<()> {
+            self.check().and_then(|_| self.inner.put(key, data))
+        }
+
+        fn get(&self, key: &str) -> std::io::Result<Vec<u8>> {
+            self.check().and_then(|_| self.inner.get(key))
+        }
+
+        fn delete(&self, key: &str) -> std::io::Result<()> {
+            self.check().and_then(|_| self.inner.delete(key))
+        }
+
+        fn list(&self) -> std::io::Result<Vec<String>> {
+            self.check().and_then(|_| self.inner.list())
+        }
+
+        fn stat(&self, key: &str) -> std::io::Result<Option<ObjectStat>> {
+            self.check().and_then(|_| self.inner.stat(key))
+        }
+    }
+
+    fn open(dir: &Path, objects: ObjectStore) -> std::io::Result<Store> {
+        let runtime = tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap();
+        runtime.block_on(Store::open(dir, &dir.join("data"), objects, Limits::default(), DEFAULT_VERSIONS))
+    }
+
+    #[test]
+    fn changes_are_in_the_shared_catalog_before_the_reply() {
+        let dir = std::env::temp_dir().join(format!("store-test-{}", std::process::id()));
+        let objects = ObjectStore::new(Arc::new(MemoryStorage::default()));
+        let mut store = open(&dir, objects).unwrap();
+        let shared = store.shared();
+
+        let root = match store.save("a/b", b"contents", None, None, &Preconditions::default(), None) {
+            Message::FileAck { root, .. } => root,
+            other => panic!("unexpected reply {:?}", other),
+        };
+        {
+            let catalog = shared.read().unwrap();
+            assert_eq!(catalog.index.get("a/b"), Some(&blake3::hash(b"contents").to_string()));
+            assert_eq!(catalog.tree.root.as_ref().map(|root| root.to_string_lossy().to_string()), Some(root));
+        }
+
+        assert!(matches!(store.rename("a/b", "c", None, None, false), Message::RenameAck { .. }));
+        let catalog = shared.read().unwrap();
+        assert!(catalog.index.get("a/b").is_none() && catalog.index.get("c").is_some());
+        assert_eq!(catalog.tree.node("a"), None);
+        drop(catalog);
+        /* Nothing of a store that lives in memory goes to disk */
+        assert!(!dir.exists());
+    }
+
+    #[test]
+    fn only_retries_of_a_saved_upload_get_past_failed_preconditions() {
+        let dir = std::env::temp_dir().join(format!("store-retry-test-{}", std::process::id()));
+        let mut store = open(&dir, ObjectStore::new(Arc::new(MemoryStorage::default()))).unwrap();
+        let create = Preconditions { if_none_match: Some("*".to_string()), ..Default::default() };
+        let first = upload_session_id(Some("alice"), "a", &blake3::hash(b"contents").to_string());
+        let second = upload_session_id(Some("bob"), "a", &blake3::hash(b"contents").to_string());
+
+        /* Two clients creating the same file with the same contents, only the first one gets to */
+        assert!(matches!(store.save("a", b"contents", None, Some(&first), &create, Some("alice")), Message::FileAck { .. }));
+        assert!(matches!(store.save("a", b"contents", None, Some(&second), &create, Some("bob")), Message::Error { error: ProtocolError::PreconditionFailed(_), .. }));
+        assert!(matches!(store.save("a", b"contents", None, None, &create, None), Message::Error { error: ProtocolError::PreconditionFailed(_), .. }));
+        /* The first one trying again after losing the ack */
+        assert!(matches!(store.save("a", b"contents", None, Some(&first), &create, Some("alice")), Message::FileAck { .. }));
+        assert_eq!(store.shared().read().unwrap().index.versions("a").len(), 1);
+
+        /* A root the tree doesn't have fails even when the contents match */
+        let stale_root = Preconditions { if_root_is: Some("stale".to_string()), ..Default::default() };
+        assert!(matches!(store.save("a", b"contents", None, None, &stale_root, None), Message::Error { error: ProtocolError::PreconditionFailed(_), .. }));
+
+        /* Once the file changed, the old session isn't a retry anymore */
+        store.save("a", b"other", None, None, &Preconditions::default(), None);
+        assert!(matches!(store.save("a", b"contents", None, Some(&first), &create, Some("alice")), Message::Error { error: ProtocolError::PreconditionFailed(_), .. }));
+    }
+
+    #[test]
+    fn restoring_a_deletion_needs_permission_to_delete() {
+        let dir = std::env::temp_dir().join(format!("store-restore-test-{}", std::process::id()));
+        let mut store = open(&dir, ObjectStore::new(Arc::new(MemoryStorage::default()))).unwrap();
+        store.save("a", b"contents", None, None, &Preconditions::default(), None);
+        store.delete("a", &Preconditions::default());
+        store.save("a", b"again", None, None, &Preconditions::default(), None);
+
+        assert!(matches!(store.restore("a", 2, None, false), Message::Error { error: ProtocolError::AccessDenied, .. }));
+        assert!(store.shared().read().unwrap().index.get("a").is_some());
+        assert!(matches!(store.restore("a", 1, None, false), Message::FileAck { .. }));
+        assert!(matches!(store.restore("a", 2, None, true), Message::DeleteFileAck { deleted: true, .. }));
+        assert!(store.shared().read().unwrap().index.get("a").is_none());
+    }
+
+    #[test]
+    fn kept_versions_can_be_verified_and_restored() {
+        let dir = std::env::temp_dir().join(format!("store-versions-test-{}", std::process::id()));
+        let mut store = open(&dir, ObjectStore::new(Arc::new(MemoryStorage::default()))).unwrap();
+        store.save("a", b"first", None, None, &Preconditions::default(), None);
+        store.save("b", b"other", None, None, &Preconditions::default(), None);
+        store.save("a", b"second", None, None, &Preconditions::default(), None);
+
+        /* The proof of a version still checks out against the root of its epoch after the tree moved on */
+        let first = store.shared().read().unwrap().index.version("a", 1).cloned().unwrap();
+        let hash = blake3::hash(b"first").to_string();
+        assert_eq!(first.info.hash.as_ref(), Some(&hash));
+        let proof = || first.merkle_proof.iter().map(|step| OsString::from(String::from_utf8(step.clone()).unwrap())).collect::<Vec<OsString>>();
+        assert!(MerkleTree::verify_file_with_proof("a", &hash, proof(), OsString::from(first.root.clone())));
+        assert!(!MerkleTree::verify_file_with_proof("a", &hash, proof(), OsString::from(store.root())));
+
+        assert!(matches!(store.restore("a", 3, None, true), Message::FileNotFound { .. }));
+        match store.restore("a", 1, None, true) {
+            Message::FileAck { hash: restored, .. } => assert_eq!(restored, hash),
+            other => panic!("unexpected reply {:?}", other),
+        }
+        let catalog = store.shared();
+        let catalog = catalog.read().unwrap();
+        assert_eq!(catalog.index.get("a"), Some(&hash));
+        let versions = catalog.index.versions("a").iter().map(|version| (version.info.version, version.info.hash.clone())).collect::<Vec<_>>();
+        assert_eq!(versions, vec![(1, Some(hash.clone())), (2, Some(blake3::hash(b"second").to_string())), (3, Some(hash))]);
+    }
+
+    #[test]
+    fn a_corrupt_index_keeps_the_store_from_starting() {
+        let dir = std::env::temp_dir().join(format!("store-index-test-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let objects = ObjectStore::new(Arc::new(FsStorage::new(&dir.join("objects"))));
+        let mut store = open(&dir, objects.clone()).unwrap();
+        assert!(matches!(store.save("a", b"contents", None, None, &Preconditions::default(), None), Message::FileAck { .. }));
+        store.checkpoint();
+        drop(store);
+
+        let index = dir.join("index.json");
+        let mut data = std::fs::read(&index).unwrap();
+        data[0] = b'#';
+        std::fs::write(&index, data).unwrap();
+        assert_eq!(open(&dir, objects.clone()).err().map(|e| e.kind()), Some(std::io::ErrorKind::InvalidData));
+        /* Nothing was collected from the index that didn't load */
+        assert!(objects.contains(&blake3::hash(b"contents").to_string()).unwrap());
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn files_are_only_dropped_when_the_backend_says_they_are_gone() {
+        let dir = std::env::temp_dir().join(format!("store-backend-test-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let storage = Arc::new(Unreachable { inner: FsStorage::new(&dir.join("objects")), failing: AtomicBool::new(false) });
+        let mut store = open(&dir, ObjectStore::new(storage.clone())).unwrap();
+        assert!(matches!(store.save("a", b"contents", None, None, &Preconditions::default(), None), Message::FileAck { .. }));
+        store.checkpoint();
+        drop(store);
+
+        storage.failing.store(true, Ordering::SeqCst);
+        assert_eq!(open(&dir, ObjectStore::new(storage.clone())).err().map(|e| e.kind()), Some(std::io::ErrorKind::TimedOut));
+        storage.failing.store(false, Ordering::SeqCst);
+        let store = open(&dir, ObjectStore::new(storage.clone())).unwrap();
+        assert!(store.shared().read().unwrap().index.get("a").is_some());
+
+        /* A blob that is really gone takes its file with it */
+        storage.inner.delete(&blake3::hash(b"contents").to_string()).unwrap();
+        drop(store);
+        let store = open(&dir, ObjectStore::new(storage)).unwrap();
+        assert!(store.shared().read().unwrap().index.get("a").is_none());
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
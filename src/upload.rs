@@ -0,0 +1,302 @@
+use crate::protocol::{chunk_count, Preconditions, CHUNK_SIZE};
+use chrono::prelude::*;
+use chrono::Duration;
+use std::collections::BTreeSet;
+use std::fs::{File, OpenOptions};
+use std::net::SocketAddr;
+use std::os::unix::fs::FileExt;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+
+/* How long an upload session is kept without receiving any chunks */
+pub const UPLOAD_SESSION_TTL_SECS: i64 = 15 * 60;
+
+/* Upper bound on the missing chunks returned in one reply, so the reply fits in a datagram */
+pub const MAX_MISSING_CHUNKS: usize = 1024;
+
+/* Largest upload the server takes, whatever the limits allow. Uploads are read into memory when they're saved */
+pub const MAX_UPLOAD_SIZE: u64 = 1 << 30;
+
+/* Most upload sessions a client may have open at once, counted by address and by identity */
+pub const MAX_CLIENT_UPLOADS: usize = 16;
+/* Most bytes the open upload sessions of a client may announce, counted by address and by identity */
+pub const MAX_CLIENT_UPLOAD_BYTES: u64 = 4 << 30;
+/* Most upload sessions and announced bytes over all clients and server workers */
+pub const MAX_UPLOADS: usize = 4096;
+pub const MAX_UPLOAD_BYTES: u64 = 64 << 30;
+
+/* Upload sessions open on all server workers and the bytes they announced */
+static OPEN_UPLOADS: AtomicUsize = AtomicUsize::new(0);
+static OPEN_UPLOAD_BYTES: AtomicU64 = AtomicU64::new(0);
+/**Session ids are derived from the identity of the client, the filename and the content hash, so a client that lost
+ * its state can compute the id again and resume where it left off, and different clients never share a session */
+pub fn upload_session_id(identity: Option<&str>, filename: &str, hash: &str) -> String {
+    blake3::hash(format!("{}\0{}\0{}", identity.unwrap_or_default(), filename, hash).as_bytes()).to_string()
+}
+
+/**Partial state of a chunked upload kept on the server until all chunks arrived. Chunks are spooled to a file, only
+ * the set of received chunks is kept in memory. The file is removed when the session is dropped, and the session counts
+ * towards MAX_UPLOADS and MAX_UPLOAD_BYTES until then */
+#[derive(Debug)]
+pub struct UploadSession {
+    pub filename: String,
+    pub hash: String,
+    pub size: u64,
+    /* Identity of the client that started the upload, only it may send chunks and finish it */
+    pub identity: Option<String>,
+    /* Address the upload was started from, the sessions of an address count towards its caps */
+    pub addr: SocketAddr,
+    chunk_count: u32,
+    /* Indices of the chunks written to the spool file so far */
+    received: BTreeSet<u32>,
+    spool: PathBuf,
+    /* Opened with the first chunk */
+    file: Option<File>,
+    /* The spooled data was handed to the store and we're waiting to hear whether it was saved */
+    saving: bool,
+    pub expires_at: DateTime<Utc>,
+    /* Preconditions of the UploadStart that created the session, checked when the file is saved. Later ones only resume it */
+    pub preconditions: Box<Preconditions>,
+}
+
+/**Check the sessions a client already has against the caps per address and per identity, before it opens another one
+ * announcing `size` bytes. The limit that would be exceeded is returned */
+pub fn check_client_uploads<'a>(sessions: impl Iterator<Item = &'a UploadSession>, addr: SocketAddr, identity: Option<&str>, size: u64) -> Result<(), String> {
+    let (mut by_addr, mut by_identity) = ((0, 0), (0, 0));
+    for session in sessions {
+        if session.addr == addr {
+            by_addr = (by_addr.0 + 1, by_addr.1 + session.size);
+        }
+        if identity.is_some() && session.identity.as_deref() == identity {
+            by_identity = (by_identity.0 + 1, by_identity.1 + session.size);
+        }
+    }
+    for (owner, (count, bytes)) in [("address", by_addr), ("identity", by_identity)] {
+        if count >= MAX_CLIENT_UPLOADS {
+            return Err(format!("limit of {} uploads in progress per {}", MAX_CLIENT_UPLOADS, owner));
+        }
+        if bytes + size > MAX_CLIENT_UPLOAD_BYTES {
+            return Err(format!("limit of {} bytes in uploads in progress per {}", MAX_CLIENT_UPLOAD_BYTES, owner));
+        }
+    }
+    Ok(())
+}
+
+impl UploadSession {
+    /* Fails with the limit that would be exceeded for uploads over MAX_UPLOAD_SIZE, or when the server has too many open */
+    pub fn new(filename: &str, hash: &str, size: u64, identity: Option<String>, addr: SocketAddr, spool: PathBuf) -> Result<UploadSession, String> {
+        let chunk_count = chunk_count(size)
+            .filter(|_| size <= MAX_UPLOAD_SIZE)
+            .ok_or_else(|| format!("server limit of {} bytes per upload", MAX_UPLOAD_SIZE))?;
+        /* Counted first and taken back if over, so workers opening sessions at the same time can't both squeeze in */
+        let sessions = OPEN_UPLOADS.fetch_add(1, Ordering::SeqCst);
+        let bytes = OPEN_UPLOAD_BYTES.fetch_add(size, Ordering::SeqCst);
+        if sessions >= MAX_UPLOADS || bytes + size > MAX_UPLOAD_BYTES {
+            OPEN_UPLOADS.fetch_sub(1, Ordering::SeqCst);
+            OPEN_UPLOAD_BYTES.fetch_sub(size, Ordering::SeqCst);
+            return Err(format!("server limit of {} uploads and {} bytes in progress", MAX_UPLOADS, MAX_UPLOAD_BYTES));
+        }
+
+        Ok(UploadSession {
+            filename: filename.to_string(),
+            hash: hash.to_string(),
+            size,
+            identity,
+            addr,
+            chunk_count,
+            received: BTreeSet::new(),
+            spool,
+            file: None,
+            saving: false,
+            expires_at: Utc::now() + Duration::seconds(UPLOAD_SESSION_TTL_SECS),
+            preconditions: Box::default(),
+        })
+    }
+
+    /* Push the expiry forward, called whenever the client shows signs of life */
+    pub fn touch(&mut self) {
+        self.expires_at = Utc::now() + Duration::seconds(UPLOAD_SESSION_TTL_SECS);
+    }
+
+    pub fn is_expired(&self, now: DateTime<Utc>) -> bool {
+        self.expires_at < now
+    }
+
+    /**Write a chunk to the spool file, returns false if the index or the chunk length doesn't fit the file, or the file
+     * is being saved. The write goes to the page cache and is never synced, the spool doesn't survive a restart anyway */
+    pub fn put_chunk(&mut self, index: u32, chunk: &[u8]) -> std::io::Result<bool> {
+        if self.saving || index >= self.chunk_count {
+            return Ok(false);
+        }
+
+        let start = index as u64 * CHUNK_SIZE as u64;
+        let end = u64::min(start + CHUNK_SIZE as u64, self.size);
+        if chunk.len() as u64 != end - start {
+            return Ok(false);
+        }
+
+        if self.file.is_none() {
+            self.file = Some(OpenOptions::new().create(true).write(true).truncate(true).open(&self.spool)?);
+        }
+        if let Some(file) = self.file.as_ref() {
+            file.write_all_at(chunk, start)?;
+        }
+        self.received.insert(index);
+        self.touch();
+        Ok(true)
+    }
+
+    /* Nothing is missing while the file is being saved */
+    pub fn missing(&self) -> Vec<u32> {
+        if self.saving {
+            return Vec::new();
+        }
+        (0..self.chunk_count)
+            .filter(|index| !self.received.contains(index))
+            .take(MAX_MISSING_CHUNKS)
+            .collect()
+    }
+
+    pub fn is_complete(&self) -> bool {
+        !self.saving && self.received.len() == self.chunk_count as usize
+    }
+
+    /**The spool file with the whole file in it once all chunks are in, the session waits for the store from now on. An
+     * empty file has no chunks and gets an empty spool file. The file stays until the session is dropped */
+    pub fn take_spool(&mut self) -> std::io::Result<PathBuf> {
+        if self.file.is_none() {
+            self.file = Some(OpenOptions::new().create(true).write(true).truncate(true).open(&self.spool)?);
+        }
+        self.saving = true;
+        Ok(self.spool.clone())
+    }
+
+    /* Forget all received chunks, used when the assembled data doesn't match the announced hash */
+    pub fn reset(&mut self) {
+        self.received.clear();
+        self.saving = false;
+        self.touch();
+    }
+}
+
+impl Drop for UploadSession {
+    fn drop(&mut self) {
+        self.file = None;
+        let _ = std::fs::remove_file(&self.spool);
+        OPEN_UPLOADS.fetch_sub(1, Ordering::SeqCst);
+        OPEN_UPLOAD_BYTES.fetch_sub(self.size, Ordering::SeqCst);
+    }
+}
+
+/* Spool files of a previous run belong to sessions that are gone, they are removed before serving anything */
+pub fn clear_spool(dir: &Path) -> std::io::Result<()> {
+    let _ = std::fs::remove_dir_all(dir);
+    std::fs::create_dir_all(dir)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addr(port: u16) -> SocketAddr {
+        SocketAddr::from(([127, 0, 0, 1], port))
+    }
+
+    /* Spool files of a test go into a directory of their own */
+    fn spool(test: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("upload-test-{}-{}", test, std::process::id()));
+        clear_spool(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn chunks_are_assembled_in_order() {
+        let dir = spool("order");
+        let size = CHUNK_SIZE as u64 * 2 + 5;
+        let mut session = UploadSession::new("a", "hash", size, None, addr(1), dir.join("a")).unwrap();
+        assert_eq!(session.missing(), vec![0, 1, 2]);
+
+        assert!(session.put_chunk(2, &[3; 5]).unwrap());
+        assert!(!session.put_chunk(1, &[2; 5]).unwrap());
+        assert!(!session.put_chunk(3, &[4; 5]).unwrap());
+        assert!(session.put_chunk(0, &[1; CHUNK_SIZE]).unwrap());
+        assert_eq!(session.missing(), vec![1]);
+        assert!(!session.is_complete());
+
+        assert!(session.put_chunk(1, &[2; CHUNK_SIZE]).unwrap());
+        assert!(session.is_complete());
+        let data = std::fs::read(session.take_spool().unwrap()).unwrap();
+        assert_eq!(data.len() as u64, size);
+        assert_eq!(&data[CHUNK_SIZE..CHUNK_SIZE + 2], &[2, 2]);
+        assert_eq!(&data[2 * CHUNK_SIZE..], &[3; 5]);
+
+        /* Nothing is left on disk once the session is gone */
+        drop(session);
+        assert!(!dir.join("a").exists());
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn sessions_wait_for_the_store_and_start_over_after_a_mismatch() {
+        let dir = spool("mismatch");
+        let size = CHUNK_SIZE as u64 + 5;
+        let mut session = UploadSession::new("a", "hash", size, None, addr(1), dir.join("a")).unwrap();
+        assert!(session.put_chunk(0, &[1; CHUNK_SIZE]).unwrap());
+        assert!(session.put_chunk(1, &[2; 5]).unwrap());
+        assert_eq!(std::fs::read(session.take_spool().unwrap()).unwrap().len() as u64, size);
+
+        /* Retransmitted chunks don't complete it a second time while it's being saved */
+        assert!(session.missing().is_empty());
+        assert!(!session.put_chunk(1, &[2; 5]).unwrap());
+        assert!(!session.is_complete());
+
+        session.reset();
+        assert_eq!(session.missing(), vec![0, 1]);
+        assert!(session.put_chunk(1, &[2; 5]).unwrap());
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn session_ids_are_bound_to_the_client() {
+        let id = upload_session_id(Some("alice"), "a", "hash");
+        assert_eq!(id, upload_session_id(Some("alice"), "a", "hash"));
+        assert_ne!(id, upload_session_id(Some("bob"), "a", "hash"));
+        assert_ne!(id, upload_session_id(None, "a", "hash"));
+        assert_ne!(upload_session_id(Some("a"), "b", "hash"), upload_session_id(None, "a\0b", "hash"));
+    }
+
+    #[test]
+    fn huge_uploads_are_refused() {
+        let dir = spool("huge");
+        assert!(UploadSession::new("a", "hash", MAX_UPLOAD_SIZE + 1, None, addr(1), dir.join("a")).is_err());
+        assert!(UploadSession::new("a", "hash", u64::MAX, None, addr(1), dir.join("a")).is_err());
+        let mut empty = UploadSession::new("a", "hash", 0, None, addr(1), dir.join("a")).unwrap();
+        assert!(empty.is_complete());
+        assert!(std::fs::read(empty.take_spool().unwrap()).unwrap().is_empty());
+        assert_eq!(chunk_count(u64::MAX), None);
+        assert_eq!(chunk_count(CHUNK_SIZE as u64 + 1), Some(2));
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn clients_are_capped_by_address_and_identity() {
+        let dir = spool("caps");
+        let sessions = (0..MAX_CLIENT_UPLOADS)
+            .map(|i| UploadSession::new(&i.to_string(), "hash", 1, Some("alice".to_string()), addr(i as u16 % 2), dir.join(i.to_string())).unwrap())
+            .collect::<Vec<UploadSession>>();
+        /* Each address has half of them, the identity all of them */
+        assert!(check_client_uploads(sessions.iter(), addr(0), None, 1).is_ok());
+        assert!(check_client_uploads(sessions.iter(), addr(0), Some("bob"), 1).is_ok());
+        assert!(check_client_uploads(sessions.iter(), addr(0), Some("alice"), 1).is_err());
+        assert!(check_client_uploads(sessions[..MAX_CLIENT_UPLOADS / 2].iter(), addr(2), Some("alice"), 1).is_ok());
+
+        let big = MAX_UPLOAD_SIZE;
+        let sessions = (0..MAX_CLIENT_UPLOAD_BYTES / big)
+            .map(|i| UploadSession::new(&i.to_string(), "hash", big, None, addr(1), dir.join(i.to_string())).unwrap())
+            .collect::<Vec<UploadSession>>();
+        assert!(check_client_uploads(sessions.iter(), addr(1), None, 0).is_ok());
+        assert!(check_client_uploads(sessions.iter(), addr(1), None, 1).is_err());
+        assert!(check_client_uploads(sessions.iter(), addr(2), None, big).is_ok());
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
@@ -0,0 +1,202 @@
+use crate::protocol::Message;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+type HmacSha256 = Hmac<Sha256>;
+
+/* Replies to an address that isn't validated may be at most this many times the size of its requests */
+const AMPLIFICATION_FACTOR: u64 = 3;
+/* Length of a retry token: when it was issued and a truncated MAC */
+pub const TOKEN_LEN: usize = 8 + 16;
+const TOKEN_TTL_SECS: u64 = 30;
+/* A validated address stays validated as long as it keeps sending requests */
+const VALIDATED_TTL: Duration = Duration::from_secs(10 * 60);
+/* Byte counts of addresses that stay quiet this long are forgotten */
+const BUDGET_TTL: Duration = Duration::from_secs(60);
+const SWEEP_INTERVAL: Duration = Duration::from_secs(1);
+const TOKEN_CONTEXT: &[u8] = b"zama-fileserver retry v1";
+/* Forget raw byte counts once there are this many addresses nothing was decoded from */
+const MAX_RAW_ADDRS: usize = 65536;
+/* Most a reply can grow below the serde layer: length prefix, codec bytes, and the Noise header and tag */
+const DATAGRAM_OVERHEAD: u64 = 4 + 2 + 1 + 8 + 16;
+
+/* Largest size a reply can take on the wire. Compression is only used when it makes a datagram smaller */
+pub fn wire_size(msg: &Message) -> u64 {
+    bincode::serialized_size(msg).map_or(u64::MAX, |size| size.saturating_add(DATAGRAM_OVERHEAD))
+}
+
+/**Bytes of the datagrams that came in from each address, counted at the socket before decryption and
+ * decompression. Filled by the receiving task and handed over to the validator with the next request */
+#[derive(Debug, Clone, Default)]
+pub struct RawBytes(Arc<Mutex<HashMap<SocketAddr, u64>>>);
+
+impl RawBytes {
+    pub fn add(&self, addr: SocketAddr, len: u64) {
+        let mut received = self.0.lock().unwrap();
+        if received.len() >= MAX_RAW_ADDRS && !received.contains_key(&addr) {
+            received.clear();
+        }
+        let total = received.entry(addr).or_insert(0);
+        *total = total.saturating_add(len);
+    }
+
+    fn take(&self, addr: &SocketAddr) -> u64 {
+        self.0.lock().unwrap().remove(addr).unwrap_or(0)
+    }
+}
+
+fn now_secs() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs())
+}
+
+fn token_mac(secret: &[u8], addr: &SocketAddr, issued_at: u64) -> HmacSha256 {
+    let mut mac = HmacSha256::new_from_slice(secret).expect("HMAC accepts keys of any length");
+    mac.update(TOKEN_CONTEXT);
+    mac.update(addr.to_string().as_bytes());
+    mac.update(&issued_at.to_be_bytes());
+    mac
+}
+
+/* Bytes received from and sent to an address that isn't validated yet */
+#[derive(Debug, Clone)]
+struct Budget {
+    received: u64,
+    sent: u64,
+    last_seen: Instant,
+}
+
+/**Address validation like QUIC's Retry. Until a client proves it receives datagrams at its source address by
+ * echoing a token, replies to it are capped at AMPLIFICATION_FACTOR times what it sent, so spoofed requests
+ * can't be used to reflect large replies at someone else. Tokens are stateless, a MAC over the address and
+ * the time they were issued, so handing them out costs nothing */
+#[derive(Debug)]
+pub struct AddressValidator {
+    secret: Arc<[u8; 32]>,
+    raw: RawBytes,
+    budgets: HashMap<SocketAddr, Budget>,
+    validated: HashMap<SocketAddr, Instant>,
+    last_sweep: Instant,
+}
+
+impl AddressValidator {
+    pub fn new(secret: Arc<[u8; 32]>, raw: RawBytes) -> AddressValidator {
+        AddressValidator { secret, raw, budgets: HashMap::new(), validated: HashMap::new(), last_sweep: Instant::now() }
+    }
+
+    fn sweep(&mut self, now: Instant) {
+        self.budgets.retain(|_, budget| now.duration_since(budget.last_seen) < BUDGET_TTL);
+        self.validated.retain(|_, last_seen| now.duration_since(*last_seen) < VALIDATED_TTL);
+        self.last_sweep = now;
+    }
+
+    pub fn issue_token(&self, addr: &SocketAddr) -> Vec<u8> {
+        let issued_at = now_secs();
+        let mac = token_mac(self.secret.as_slice(), addr, issued_at).finalize().into_bytes();
+
+        let mut token = issued_at.to_be_bytes().to_vec();
+        token.extend_from_slice(&mac[..TOKEN_LEN - 8]);
+        token
+    }
+
+    /**Check a token echoed by a client, the address counts as validated from now on if it's valid */
+    pub fn validate(&mut self, addr: SocketAddr, token: &[u8]) -> bool {
+        if token.len() != TOKEN_LEN {
+            return false;
+        }
+        let issued_at = u64::from_be_bytes(token[..8].try_into().unwrap());
+        if now_secs().saturating_sub(issued_at) > TOKEN_TTL_SECS {
+            return false;
+        }
+        if token_mac(self.secret.as_slice(), &addr, issued_at).verify_truncated_left(&token[8..]).is_err() {
+            return false;
+        }
+
+        self.budgets.remove(&addr);
+        self.validated.insert(addr, Instant::now());
+        true
+    }
+
+    /* Count the datagrams that came in from an address since its last request towards its budget */
+    pub fn received(&mut self, addr: SocketAddr) {
+        let now = Instant::now();
+        if now.duration_since(self.last_sweep) >= SWEEP_INTERVAL {
+            self.sweep(now);
+        }
+
+        let received = self.raw.take(&addr);
+        if let Some(last_seen) = self.validated.get_mut(&addr) {
+            *last_seen = now;
+            return;
+        }
+        let budget = self.budgets.entry(addr).or_insert(Budget { received: 0, sent: 0, last_seen: now });
+        budget.received = budget.received.saturating_add(received);
+        budget.last_seen = now;
+    }
+
+    /* Whether the address proved it receives what we send to it */
+    pub fn is_validated(&self, addr: &SocketAddr) -> bool {
+        self.validated.contains_key(addr)
+    }
+
+    /**Decide what goes out for a reply: the reply itself if the address is validated or the reply fits
+     * its budget, a Retry with a token if that fits instead, and nothing otherwise */
+    pub fn limit(&mut self, reply: Message, addr: SocketAddr) -> Option<Message> {
+        if self.validated.contains_key(&addr) {
+            return Some(reply);
+        }
+        let retry = Message::Retry { token: self.issue_token(&addr) };
+
+        let budget = self.budgets.get_mut(&addr)?;
+        let allowed = budget.received.saturating_mul(AMPLIFICATION_FACTOR);
+        for msg in [reply, retry] {
+            let size = wire_size(&msg);
+            if budget.sent.saturating_add(size) <= allowed {
+                budget.sent += size;
+                return Some(msg);
+            }
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn validator() -> (AddressValidator, RawBytes) {
+        let raw = RawBytes::default();
+        (AddressValidator::new(Arc::new([7; 32]), raw.clone()), raw)
+    }
+
+    #[test]
+    fn budget_counts_raw_datagrams() {
+        let (mut validator, raw) = validator();
+        let addr: SocketAddr = "192.0.2.1:4000".parse().unwrap();
+        let reply = Message::Retry { token: vec![0; 64] };
+
+        /* A request that decodes to a large message still only earns what its datagram weighed */
+        raw.add(addr, 10);
+        validator.received(addr);
+        assert_eq!(validator.limit(reply.clone(), addr), None);
+
+        raw.add(addr, wire_size(&reply));
+        validator.received(addr);
+        assert_eq!(validator.limit(reply.clone(), addr), Some(reply));
+    }
+
+    #[test]
+    fn validated_addresses_are_not_limited() {
+        let (mut validator, _) = validator();
+        let addr: SocketAddr = "192.0.2.1:4000".parse().unwrap();
+        let token = validator.issue_token(&addr);
+        assert!(validator.validate(addr, &token));
+        assert!(!validator.validate("192.0.2.2:4000".parse().unwrap(), &token));
+
+        let reply = Message::Retry { token: vec![0; 4096] };
+        assert_eq!(validator.limit(reply.clone(), addr), Some(reply));
+    }
+}
@@ -0,0 +1,222 @@
+use crate::fsutil::{sync_dir, write_file_atomic};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::fs::{File, OpenOptions};
+use std::io::prelude::*;
+use std::path::{Path, PathBuf};
+
+/* Every mutation of the file store, journaled before it is applied */
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum WalOp {
+    /* The uploaded data is staged in the log directory under the sequence number of the record */
+    /* Size and uploader are missing from records written by older versions */
+    Upload {
+        filename: String,
+        hash: String,
+        #[serde(default)]
+        size: Option<u64>,
+        #[serde(default)]
+        owner: Option<String>,
+    },
+    Delete { filename: String },
+    Rename { from: String, to: String, hash: String },
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct WalRecord {
+    pub seq: u64,
+    pub op: WalOp,
+    /* When the operation was journaled, records written by older versions are replayed as if it was now */
+    #[serde(default = "Utc::now")]
+    pub time: DateTime<Utc>,
+}
+
+/**Write-ahead log made of segments of JSON lines. A new segment is started at every checkpoint,
+ * and the segments and staged data covered by a checkpoint are removed */
+pub struct Wal {
+    next_seq: u64,
+    checkpoint: u64,
+    /* None for a log kept in memory, which numbers and timestamps the records without writing them anywhere */
+    disk: Option<Segments>,
+}
+
+/* Directory of the log and the segment records are appended to */
+struct Segments {
+    dir: PathBuf,
+    current: File,
+}
+
+fn segment_path(dir: &Path, first_seq: u64) -> PathBuf {
+    dir.join(format!("{:020}.log", first_seq))
+}
+
+fn staged_path(dir: &Path, seq: u64) -> PathBuf {
+    dir.join("data").join(seq.to_string())
+}
+
+/* Log segments sorted by the sequence number of their first record */
+fn segments(dir: &Path) -> std::io::Result<Vec<PathBuf>> {
+    let mut segments = std::fs::read_dir(dir)?
+        .flatten()
+        .map(|entry| entry.path())
+        .filter(|path| path.extension().map_or(false, |ext| ext == "log"))
+        .collect::<Vec<PathBuf>>();
+    segments.sort();
+    Ok(segments)
+}
+
+fn open_segment(dir: &Path, first_seq: u64) -> std::io::Result<File> {
+    let file = OpenOptions::new().create(true).append(true).open(segment_path(dir, first_seq))?;
+    sync_dir(dir)?;
+    Ok(file)
+}
+
+impl Wal {
+    /**Open the log in `dir`, returning it together with the records after the last checkpoint that still need to be replayed */
+    pub fn open(dir: &Path) -> std::io::Result<(Wal, Vec<WalRecord>)> {
+        std::fs::create_dir_all(dir.join("data"))?;
+
+        let checkpoint = std::fs::read_to_string(dir.join("checkpoint"))
+            .ok()
+            .and_then(|s| s.trim().parse::<u64>().ok())
+            .unwrap_or(0);
+
+        let mut records = Vec::new();
+        let mut last_seq = 0;
+        let segments = segments(dir)?;
+        for (i, segment) in segments.iter().enumerate() {
+            let data = std::fs::read(segment)?;
+            let mut valid = 0;
+            for line in data.split_inclusive(|byte| *byte == b'\n') {
+                match serde_json::from_slice::<WalRecord>(line) {
+                    Ok(record) => {
+                        valid += line.len();
+                        last_seq = record.seq;
+                        if record.seq > checkpoint {
+                            records.push(record);
+                        }
+                    }
+                    /* A torn line at the end of a segment was never acknowledged, if the log went on with the same
+                     * sequence number. It's cut off, so records appended to the segment later don't end up behind it */
+                    Err(_) if valid + line.len() == data.len() && segments.get(i + 1).map_or(true, |next| *next == segment_path(dir, last_seq + 1)) => {
+                        let file = OpenOptions::new().write(true).open(segment)?;
+                        file.set_len(valid as u64)?;
+                        file.sync_all()?;
+                    }
+                    /* Anything else would be replayed without the records that follow, which is worse than not starting */
+                    Err(e) => {
+                        let corrupt = format!("corrupt record in {} after {} bytes: {}", segment.display(), valid, e);
+                        return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, corrupt));
+                    }
+                }
+            }
+        }
+
+        let next_seq = records.last().map_or(checkpoint, |r| r.seq) + 1;
+        let segment = open_segment(dir, next_seq)?;
+
+        Ok((Wal { next_seq, checkpoint, disk: Some(Segments { dir: dir.to_path_buf(), current: segment }) }, records))
+    }
+
+    /* Log for a store whose objects don't survive a restart either, there is never anything to replay */
+    pub fn in_memory() -> Wal {
+        Wal { next_seq: 1, checkpoint: 0, disk: None }
+    }
+
+    /**Journal an operation and fsync it, together with the uploaded data if there is any */
+    pub fn append(&mut self, op: WalOp, data: Option<&[u8]>) -> std::io::Result<WalRecord> {
+        let seq = self.next_seq;
+        let record = WalRecord { seq, op, time: Utc::now() };
+
+        if let Some(disk) = self.disk.as_mut() {
+            if let Some(data) = data {
+                write_file_atomic(&staged_path(&disk.dir, seq), data)?;
+            }
+            let mut line = serde_json::to_vec(&record)?;
+            line.push(b'\n');
+            disk.current.write_all(line.as_slice())?;
+            disk.current.sync_data()?;
+        }
+
+        self.next_seq += 1;
+        Ok(record)
+    }
+
+    /* Data staged for an upload record */
+    pub fn staged_data(&self, seq: u64) -> std::io::Result<Vec<u8>> {
+        match self.disk.as_ref() {
+            Some(disk) => std::fs::read(staged_path(&disk.dir, seq)),
+            None => Err(std::io::Error::new(std::io::ErrorKind::NotFound, "nothing is staged in memory")),
+        }
+    }
+
+    /* Number of records appended since the last checkpoint */
+    pub fn pending(&self) -> u64 {
+        self.next_seq - 1 - self.checkpoint
+    }
+
+    /**Mark every record so far as applied, then compact the log by removing the old segments and staged data.
+     * Must only be called once the effects of all records are durable */
+    pub fn checkpoint(&mut self) -> std::io::Result<()> {
+        let checkpoint = self.next_seq - 1;
+        let disk = match self.disk.as_mut() {
+            Some(disk) => disk,
+            None => {
+                self.checkpoint = checkpoint;
+                return Ok(());
+            }
+        };
+        write_file_atomic(&disk.dir.join("checkpoint"), checkpoint.to_string().as_bytes())?;
+        self.checkpoint = checkpoint;
+
+        /* Start a new segment so that all older ones are covered by the checkpoint */
+        let current = segment_path(&disk.dir, self.next_seq);
+        disk.current = open_segment(&disk.dir, self.next_seq)?;
+
+        for segment in segments(&disk.dir)? {
+            if segment != current {
+                std::fs::remove_file(segment)?;
+            }
+        }
+        for entry in std::fs::read_dir(disk.dir.join("data"))?.flatten() {
+            std::fs::remove_file(entry.path())?;
+        }
+        sync_dir(&disk.dir)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn upload(filename: &str) -> WalOp {
+        WalOp::Upload { filename: filename.to_string(), hash: "hash".to_string(), size: Some(1), owner: None }
+    }
+
+    #[test]
+    fn torn_lines_are_cut_off_and_corrupt_ones_refused() {
+        let dir = std::env::temp_dir().join(format!("wal-test-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let (mut wal, records) = Wal::open(&dir).unwrap();
+        assert!(records.is_empty());
+        wal.append(upload("a"), None).unwrap();
+        drop(wal);
+
+        /* A crash in the middle of the next append, the record after it goes into the same segment */
+        let segment = segment_path(&dir, 1);
+        OpenOptions::new().append(true).open(&segment).unwrap().write_all(b"{\"seq\":2,\"op").unwrap();
+        let (mut wal, records) = Wal::open(&dir).unwrap();
+        assert_eq!(records.iter().map(|record| record.op.clone()).collect::<Vec<_>>(), vec![upload("a")]);
+        wal.append(upload("b"), None).unwrap();
+        drop(wal);
+        let (_, records) = Wal::open(&dir).unwrap();
+        assert_eq!(records.iter().map(|record| record.seq).collect::<Vec<_>>(), vec![1, 2]);
+
+        /* Damage in front of records that were acknowledged */
+        let mut data = std::fs::read(&segment).unwrap();
+        data[3] = b'#';
+        std::fs::write(&segment, data).unwrap();
+        assert_eq!(Wal::open(&dir).err().map(|e| e.kind()), Some(std::io::ErrorKind::InvalidData));
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
@@ -0,0 +1,157 @@
+use hydroflow::futures::StreamExt;
+use inotify::{EventMask, EventStream, Inotify, WatchDescriptor, WatchMask, Watches};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+/* Changes in the watched directory, paths are relative to it and separated by `/` */
+#[derive(Debug, Clone, PartialEq)]
+pub enum WatchEvent {
+    /* Something changed at the path, or anywhere below it for a directory */
+    Changed(String),
+    /* A file or a directory was moved within the watched directory, the source is reported as changed before */
+    Moved { from: String, to: String },
+    /* The kernel dropped events, anything may have changed */
+    Overflow,
+}
+
+/**Watches a directory and all of its subdirectories with inotify. New subdirectories are watched as they show up,
+ * and a moved subdirectory is watched again under its new path */
+pub struct Watcher {
+    root: PathBuf,
+    watches: Watches,
+    events: EventStream<[u8; 4096]>,
+    /* Path of the directory behind each watch */
+    dirs: HashMap<WatchDescriptor, String>,
+    /* Source of the last event if it was moved away, with the cookie that pairs it with its destination */
+    moved_from: Option<(u32, String)>,
+}
+
+fn watch_mask() -> WatchMask {
+    WatchMask::CREATE | WatchMask::MODIFY | WatchMask::CLOSE_WRITE | WatchMask::DELETE | WatchMask::MOVED_FROM | WatchMask::MOVED_TO
+}
+
+impl Watcher {
+    pub fn new(root: &Path) -> std::io::Result<Watcher> {
+        let inotify = Inotify::init()?;
+        let watches = inotify.watches();
+        let events = inotify.into_event_stream([0; 4096])?;
+        let mut watcher = Watcher { root: root.to_path_buf(), watches, events, dirs: HashMap::new(), moved_from: None };
+        watcher.add_watches("")?;
+        Ok(watcher)
+    }
+
+    /* Watch a directory and everything below it. Watching a directory again keeps its watch and updates its path */
+    fn add_watches(&mut self, dir: &str) -> std::io::Result<()> {
+        let mut dirs = vec![dir.to_string()];
+        while let Some(dir) = dirs.pop() {
+            let wd = self.watches.add(self.root.join(&dir), watch_mask())?;
+            self.dirs.insert(wd, dir.clone());
+            for entry in std::fs::read_dir(self.root.join(&dir))?.flatten() {
+                if entry.file_type().is_ok_and(|file_type| file_type.is_dir()) {
+                    let name = entry.file_name().to_string_lossy().into_owned();
+                    dirs.push(if dir.is_empty() { name } else { format!("{}/{}", dir, name) });
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /* Next change, None once the watch ended */
+    pub async fn next(&mut self) -> Option<std::io::Result<WatchEvent>> {
+        loop {
+            let event = match self.events.next().await? {
+                Ok(event) => event,
+                Err(e) => return Some(Err(e)),
+            };
+            /* The destination of a move is queued right after its source. A source that isn't followed by it was
+             * moved out of the watched directory, and is only reported as changed */
+            let moved_from = self.moved_from.take();
+            if event.mask.contains(EventMask::Q_OVERFLOW) {
+                return Some(Ok(WatchEvent::Overflow));
+            }
+            /* The watch is gone along with its directory */
+            if event.mask.contains(EventMask::IGNORED) {
+                self.dirs.remove(&event.wd);
+                continue;
+            }
+
+            let (dir, name) = match (self.dirs.get(&event.wd), event.name) {
+                (Some(dir), Some(name)) => (dir, name.to_string_lossy().into_owned()),
+                _ => continue,
+            };
+            let path = if dir.is_empty() { name } else { format!("{}/{}", dir, name) };
+
+            /* A directory that was just created may have files in it already, they're reported with it */
+            if event.mask.contains(EventMask::ISDIR) && event.mask.intersects(EventMask::CREATE | EventMask::MOVED_TO) {
+                if let Err(e) = self.add_watches(&path) {
+                    println!("Unable to watch {}: {}", path, e);
+                }
+            }
+            if event.mask.contains(EventMask::MOVED_FROM) {
+                self.moved_from = Some((event.cookie, path.clone()));
+            }
+            if event.mask.contains(EventMask::MOVED_TO) {
+                if let Some((_, from)) = moved_from.filter(|(cookie, _)| *cookie == event.cookie) {
+                    return Some(Ok(WatchEvent::Moved { from, to: path }));
+                }
+            }
+            return Some(Ok(WatchEvent::Changed(path)));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    /* Read events until the expected one shows up, false if it doesn't within a few seconds */
+    async fn expect(watcher: &mut Watcher, expected: WatchEvent) -> bool {
+        let wait = async {
+            while let Some(Ok(event)) = watcher.next().await {
+                if event == expected {
+                    return true;
+                }
+            }
+            false
+        };
+        tokio::time::timeout(Duration::from_secs(5), wait).await.unwrap_or(false)
+    }
+
+    #[test]
+    fn reports_changes_and_moves_below_the_root() {
+        let root = std::env::temp_dir().join(format!("watch-test-{}", std::process::id()));
+        let outside = std::env::temp_dir().join(format!("watch-test-{}-moved", std::process::id()));
+        std::fs::create_dir_all(&root).unwrap();
+        let runtime = tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap();
+
+        runtime.block_on(async {
+            let mut watcher = Watcher::new(&root).unwrap();
+            std::fs::write(root.join("a"), b"a").unwrap();
+            assert!(expect(&mut watcher, WatchEvent::Changed("a".to_string())).await);
+
+            /* Files in a new directory are reported once it's watched */
+            std::fs::create_dir(root.join("d")).unwrap();
+            assert!(expect(&mut watcher, WatchEvent::Changed("d".to_string())).await);
+            std::fs::write(root.join("d/b"), b"b").unwrap();
+            assert!(expect(&mut watcher, WatchEvent::Changed("d/b".to_string())).await);
+
+            std::fs::rename(root.join("d/b"), root.join("c")).unwrap();
+            assert!(expect(&mut watcher, WatchEvent::Changed("d/b".to_string())).await);
+            assert!(expect(&mut watcher, WatchEvent::Moved { from: "d/b".to_string(), to: "c".to_string() }).await);
+
+            std::fs::remove_file(root.join("a")).unwrap();
+            assert!(expect(&mut watcher, WatchEvent::Changed("a".to_string())).await);
+
+            /* A file moved out of the root is a change, and isn't kept waiting for a destination */
+            std::fs::rename(root.join("c"), &outside).unwrap();
+            assert!(expect(&mut watcher, WatchEvent::Changed("c".to_string())).await);
+            std::fs::write(root.join("e"), b"e").unwrap();
+            assert!(expect(&mut watcher, WatchEvent::Changed("e".to_string())).await);
+            assert!(watcher.moved_from.is_none());
+        });
+
+        let _ = std::fs::remove_dir_all(&root);
+        let _ = std::fs::remove_file(&outside);
+    }
+}